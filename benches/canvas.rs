@@ -0,0 +1,60 @@
+//! 10 万条弹幕的排布，运行 `cargo bench --bench canvas`
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use ddp2ass::{CanvasConfig, Danmu};
+use std::hint::black_box;
+
+/// 一部两小时的电影，平均每秒约 14 条弹幕，长度和时间由固定种子的伪随机数生成
+fn synthetic_danmus(count: usize) -> Vec<Danmu> {
+    let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+    let mut next = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed
+    };
+    let mut danmus: Vec<_> = (0..count as u64)
+        .map(|cid| Danmu {
+            timeline_s: (next() % 7_200_000) as f64 / 1000.0,
+            content: "弹".repeat(1 + (next() % 30) as usize),
+            cid,
+            ..Default::default()
+        })
+        .collect();
+    danmus.sort_by(|a, b| a.timeline_s.total_cmp(&b.timeline_s));
+    danmus
+}
+
+fn bench_draw(c: &mut Criterion) {
+    let danmus = synthetic_danmus(100_000);
+    let configs = [
+        ("default", CanvasConfig::default()),
+        (
+            "4k_full_screen",
+            CanvasConfig {
+                width: 3840,
+                height: 2160,
+                lane_size: 20,
+                font_size: 20,
+                float_percentage: 1.0,
+                ..Default::default()
+            },
+        ),
+    ];
+    for (name, config) in configs {
+        c.bench_function(&format!("draw_100k_{}", name), |b| {
+            b.iter_batched(
+                || danmus.clone(),
+                |danmus| {
+                    let mut canvas = config.clone().canvas();
+                    for danmu in danmus {
+                        black_box(canvas.draw(danmu).unwrap());
+                    }
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+}
+
+criterion_group!(benches, bench_draw);
+criterion_main!(benches);
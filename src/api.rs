@@ -0,0 +1,169 @@
+//! dandanplay API 请求的公共处理
+use anyhow::{Context, Result};
+use reqwest::{RequestBuilder, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+use crate::Error;
+
+/// 5xx、429、超时和连接错误时的最多重试次数
+const MAX_RETRIES: u32 = 3;
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+
+/// 失败时 dandanplay 返回的公共字段，成功的返回中这些字段可能不存在
+#[derive(Deserialize)]
+struct ApiStatus {
+    #[serde(default = "default_success")]
+    success: bool,
+    #[serde(rename = "errorCode", default)]
+    error_code: i64,
+    #[serde(rename = "errorMessage", default)]
+    error_message: Option<String>,
+}
+
+fn default_success() -> bool {
+    true
+}
+
+/// 发送请求并解析返回，遇到临时性错误时按带抖动的指数退避重试，
+/// `filename` 用于重试日志
+pub async fn request<T: DeserializeOwned>(
+    filename: &str,
+    build: impl Fn() -> RequestBuilder,
+) -> Result<T> {
+    let mut attempt = 0;
+    loop {
+        let result = fetch_text(&build).await;
+        let reason = match &result {
+            Ok((status, _)) if is_transient_status(*status) => Some(status.to_string()),
+            Err(e) if is_transient_error(e) => Some(e.to_string()),
+            _ => None,
+        };
+        match reason {
+            Some(reason) if attempt < MAX_RETRIES => {
+                attempt += 1;
+                let delay = retry_delay(attempt);
+                warn!(
+                    "{} 请求 dandanplay 失败（{}），{:.1} 秒后第 {} 次重试",
+                    filename,
+                    reason,
+                    delay.as_secs_f64(),
+                    attempt
+                );
+                tokio::time::sleep(delay).await;
+            }
+            _ => {
+                let (status, text) = result?;
+                return parse_text(status, &text);
+            }
+        }
+    }
+}
+
+/// 发送请求并读取全部内容，读取中断也需要重试
+async fn fetch_text(build: &impl Fn() -> RequestBuilder) -> reqwest::Result<(StatusCode, String)> {
+    let response = build()
+        .header("Accept", "application/json")
+        .header("User-Agent", "curl")
+        .send()
+        .await?;
+    let status = response.status();
+    Ok((status, response.text().await?))
+}
+
+/// 5xx 和 429 Too Many Requests
+fn is_transient_status(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// 连接失败、超时和读取内容中断，网址错误等构建请求的错误重试也不会成功
+fn is_transient_error(e: &reqwest::Error) -> bool {
+    e.is_connect() || e.is_timeout() || e.is_body() || e.is_decode()
+}
+
+/// 第 `attempt` 次重试前的等待时间，在指数退避的基础上乘以 0.5 ~ 1.5 的随机系数
+fn retry_delay(attempt: u32) -> Duration {
+    let jitter = RandomState::new().build_hasher().finish() % 1000;
+    RETRY_BASE_DELAY * 2u32.pow(attempt - 1) * (500 + jitter as u32) / 1000
+}
+
+/// 检查 dandanplay 的错误信息后再解析返回
+fn parse_text<T: DeserializeOwned>(status: StatusCode, text: &str) -> Result<T> {
+    if let Ok(api_status) = serde_json::from_str::<ApiStatus>(text) {
+        if !api_status.success || api_status.error_code != 0 {
+            return Err(Error::Api {
+                code: api_status.error_code,
+                message: api_status.error_message.unwrap_or_default(),
+            }
+            .into());
+        }
+    }
+    if !status.is_success() {
+        return Err(Error::Http {
+            status,
+            body: text.chars().take(200).collect(),
+        }
+        .into());
+    }
+    serde_json::from_str(text).context("dandanplay 返回的数据无法解析")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize, Debug)]
+    struct Comments {
+        count: i64,
+    }
+
+    #[test]
+    fn test_parse_text() {
+        let ok: Comments = parse_text(StatusCode::OK, r#"{"count": 3, "comments": []}"#).unwrap();
+        assert_eq!(ok.count, 3);
+
+        let err = parse_text::<Comments>(
+            StatusCode::NOT_FOUND,
+            r#"{"errorCode": 101, "success": false, "errorMessage": "节目不存在"}"#,
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "dandanplay 返回错误 101: 节目不存在");
+
+        let err = parse_text::<Comments>(StatusCode::BAD_GATEWAY, "Bad Gateway").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "dandanplay 请求失败 502 Bad Gateway: Bad Gateway"
+        );
+
+        assert!(parse_text::<Comments>(StatusCode::OK, r#"{"comments": []}"#).is_err());
+    }
+
+    #[test]
+    fn test_transient_status() {
+        assert!(is_transient_status(StatusCode::BAD_GATEWAY));
+        assert!(is_transient_status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!is_transient_status(StatusCode::NOT_FOUND));
+        assert!(!is_transient_status(StatusCode::OK));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_url_not_retried() {
+        let e = fetch_text(&|| reqwest::Client::new().get("not a url"))
+            .await
+            .unwrap_err();
+        assert!(!is_transient_error(&e));
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        for attempt in 1..=MAX_RETRIES {
+            let base = RETRY_BASE_DELAY * 2u32.pow(attempt - 1);
+            let delay = retry_delay(attempt);
+            assert!(delay >= base / 2 && delay < base * 3 / 2);
+        }
+    }
+}
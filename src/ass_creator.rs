@@ -1,9 +1,32 @@
-use crate::{CanvasConfig, DrawEffect, Drawable};
-use anyhow::Result;
+//! 生成 ASS 文件
+//!
+//! 除了绘制弹幕，也可以通过 [`AssCreator::builder`] 添加自定义样式和字幕：
+//!
+//! ```
+//! use ddp2ass::{AssCreator, CanvasConfig, StyleSpec};
+//!
+//! let config = CanvasConfig::default();
+//! let notice = StyleSpec {
+//!     fontsize: 48,
+//!     alignment: 8,
+//!     ..Default::default()
+//! };
+//! let mut ass = AssCreator::builder("ep01".to_string(), config)
+//!     .add_style("Notice", notice)
+//!     .build()?;
+//! ass.write_dialogue(0.0, 5.0, "Notice", "弹幕来自 dandanplay")?;
+//!
+//! let ass = String::from_utf8(ass.buf)?;
+//! assert!(ass.contains("Style: Notice,黑体,48,"));
+//! assert!(ass.contains("Dialogue: 0,0:00:00.00,0:00:05.00,Notice,,0,0,0,,弹幕来自 dandanplay\n"));
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use crate::{CanvasConfig, Danmu, DrawEffect, Drawable, Error};
+use anyhow::{Context, Result};
 use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::io::Write;
-use std::process::Command;
 
 struct TimePoint {
     t: f64,
@@ -39,81 +62,216 @@ impl fmt::Display for AssEffect {
     }
 }
 
-impl super::CanvasConfig {
-    pub fn ass_styles(&self) -> Vec<String> {
-        vec![
-            // Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, \
-            // Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, \
-            // Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding
-            format!(
-                "Style: Float,{font},{font_size},&H{a:02x}FFFFFF,&H00FFFFFF,&H{a:02x}000000,&H00000000,\
-                {bold}, 0, 0, 0, 100, 100, 0.00, 0.00, 1, \
-                {outline}, 0, 7, 0, 0, 0, 1",
-                a = self.opacity,
-                font = self.font,
-                font_size = self.font_size,
-                bold = self.bold,
-                outline = self.outline,
-            ),
-            format!(
-                "Style: Bottom,{font},{font_size},&H{a:02x}FFFFFF,&H00FFFFFF,&H{a:02x}000000,&H00000000,\
-                {bold}, 0, 0, 0, 100, 100, 0.00, 0.00, 1, \
-                {outline}, 0, 7, 0, 0, 0, 1",
-                a = self.opacity,
-                font = self.font,
-                font_size = self.font_size,
-                bold = self.bold,
-                outline = self.outline,
-            ),
-            format!(
-                "Style: Top,{font},{font_size},&H{a:02x}FFFFFF,&H00FFFFFF,&H{a:02x}000000,&H00000000,\
-                {bold}, 0, 0, 0, 100, 100, 0.00, 0.00, 1, \
-                {outline}, 0, 7, 0, 0, 0, 1",
-                a = self.opacity,
-                font = self.font,
-                font_size = self.font_size,
-                bold = self.bold,
-                outline = self.outline,
-            ),
-        ]
+/// ASS 中一个 Style 的全部字段，颜色为 ASS 格式，如 `&H00FFFFFF`
+#[derive(Debug, Clone)]
+pub struct StyleSpec {
+    pub fontname: String,
+    pub fontsize: u32,
+    pub primary_colour: String,
+    pub secondary_colour: String,
+    pub outline_colour: String,
+    pub back_colour: String,
+    /// 1 代表是，0 代表否
+    pub bold: u8,
+    pub italic: u8,
+    pub underline: u8,
+    pub strike_out: u8,
+    pub scale_x: u32,
+    pub scale_y: u32,
+    pub spacing: f64,
+    pub angle: f64,
+    pub border_style: u8,
+    pub outline: f64,
+    pub shadow: f64,
+    /// 小键盘方位，7 为左上
+    pub alignment: u8,
+    pub margin_l: i32,
+    pub margin_r: i32,
+    pub margin_v: i32,
+    pub encoding: u8,
+}
+
+impl Default for StyleSpec {
+    fn default() -> Self {
+        StyleSpec {
+            fontname: "黑体".to_string(),
+            fontsize: 35,
+            primary_colour: "&H00FFFFFF".to_string(),
+            secondary_colour: "&H00FFFFFF".to_string(),
+            outline_colour: "&H00000000".to_string(),
+            back_colour: "&H00000000".to_string(),
+            bold: 0,
+            italic: 0,
+            underline: 0,
+            strike_out: 0,
+            scale_x: 100,
+            scale_y: 100,
+            spacing: 0.0,
+            angle: 0.0,
+            border_style: 1,
+            outline: 0.8,
+            shadow: 0.0,
+            alignment: 7,
+            margin_l: 0,
+            margin_r: 0,
+            margin_v: 0,
+            encoding: 1,
+        }
     }
 }
 
-struct CanvasStyles(Vec<String>);
-impl fmt::Display for CanvasStyles {
+struct StyleLine<'a> {
+    name: &'a str,
+    spec: &'a StyleSpec,
+}
+impl fmt::Display for StyleLine<'_> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for style in &self.0 {
-            writeln!(f, "{}", style)?;
+        let s = self.spec;
+        // Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, \
+        // Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, \
+        // Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding
+        write!(
+            f,
+            "Style: {},{},{},{},{},{},{},\
+            {}, {}, {}, {}, {}, {}, {:.2}, {:.2}, {}, \
+            {}, {}, {}, {}, {}, {}, {}",
+            self.name,
+            s.fontname,
+            s.fontsize,
+            s.primary_colour,
+            s.secondary_colour,
+            s.outline_colour,
+            s.back_colour,
+            s.bold,
+            s.italic,
+            s.underline,
+            s.strike_out,
+            s.scale_x,
+            s.scale_y,
+            s.spacing,
+            s.angle,
+            s.border_style,
+            s.outline,
+            s.shadow,
+            s.alignment,
+            s.margin_l,
+            s.margin_r,
+            s.margin_v,
+            s.encoding,
+        )
+    }
+}
+
+impl super::CanvasConfig {
+    /// 弹幕使用的样式，Float、Bottom、Top 共用
+    pub fn danmu_style(&self) -> StyleSpec {
+        StyleSpec {
+            fontname: self.font.clone(),
+            fontsize: self.font_size,
+            primary_colour: format!("&H{:02x}FFFFFF", self.opacity),
+            secondary_colour: format!("&H{:08X}", self.secondary_colour),
+            outline_colour: format!("&H{:02x}000000", self.opacity),
+            back_colour: format!("&H{:08X}", self.back_colour),
+            bold: self.bold,
+            outline: self.outline,
+            ..Default::default()
         }
-        Ok(())
     }
 }
 
-pub struct AssCreator {
-    pub buf: Vec<u8>,
+/// 构建 [`AssCreator`]，可以在弹幕样式之外添加自定义样式
+pub struct AssCreatorBuilder {
     title: String,
     canvas_config: CanvasConfig,
+    styles: Vec<(String, StyleSpec)>,
 }
 
-impl AssCreator {
-    pub fn new(title: String, canvas_config: CanvasConfig) -> Result<Self> {
+impl AssCreatorBuilder {
+    /// 添加样式，同名样式会被替换
+    pub fn add_style(mut self, name: impl Into<String>, spec: StyleSpec) -> Self {
+        let name = name.into();
+        match self.styles.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, s)) => *s = spec,
+            None => self.styles.push((name, spec)),
+        }
+        self
+    }
+
+    pub fn build(self) -> Result<AssCreator, Error> {
         let mut this = AssCreator {
             buf: Vec::new(),
-            title,
-            canvas_config,
+            title: self.title,
+            canvas_config: self.canvas_config,
+            styles: self.styles,
         };
 
         this.init()?;
 
         Ok(this)
     }
+}
+
+/// 写在 Script Info 中，用于识别本工具生成的字幕
+const GENERATOR_COMMENT: &str = "; Script generated by danmu2ass";
+
+#[derive(Clone)]
+pub struct AssCreator {
+    pub buf: Vec<u8>,
+    title: String,
+    canvas_config: CanvasConfig,
+    styles: Vec<(String, StyleSpec)>,
+}
+
+impl AssCreator {
+    pub fn new(title: String, canvas_config: CanvasConfig) -> Result<Self, Error> {
+        Self::builder(title, canvas_config).build()
+    }
+
+    /// 已经包含 Float、Bottom、Top 三个弹幕样式的 builder
+    ///
+    /// 添加同名样式可以替换弹幕样式：
+    ///
+    /// ```
+    /// use ddp2ass::{AssCreator, CanvasConfig};
+    ///
+    /// let config = CanvasConfig::default();
+    /// let mut float = config.danmu_style();
+    /// float.italic = 1;
+    /// let ass = AssCreator::builder("ep01".to_string(), config)
+    ///     .add_style("Float", float)
+    ///     .build()?;
+    ///
+    /// let ass = String::from_utf8(ass.buf)?;
+    /// assert_eq!(ass.matches("Style: Float,").count(), 1);
+    /// assert!(ass.contains("Style: Float,黑体,35,&H4cFFFFFF,&H00FFFFFF,&H4c000000,&H00000000,0, 1, "));
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn builder(title: String, canvas_config: CanvasConfig) -> AssCreatorBuilder {
+        let style = canvas_config.danmu_style();
+        AssCreatorBuilder {
+            title,
+            canvas_config,
+            styles: Vec::new(),
+        }
+        .add_style("Float", style.clone())
+        .add_style("Bottom", style.clone())
+        .add_style("Top", style)
+    }
+
+    /// 是否为本工具生成的字幕，只检查 Script Info 段
+    pub fn is_generated(ass: &str) -> bool {
+        ass.lines()
+            .map(str::trim)
+            .take_while(|l| !l.starts_with("[V4"))
+            .any(|l| l == GENERATOR_COMMENT)
+    }
 
-    pub fn init(&mut self) -> Result<()> {
+    pub fn init(&mut self) -> Result<(), Error> {
         write!(
             self.buf,
             "\
             [Script Info]\n\
-            ; Script generated by danmu2ass\n\
+            {generator}\n\
             Title: {title}\n\
             Script Updated By: danmu2ass (https://github.com/gwy15/danmu2ass)\n\
             ScriptType: v4.00+\n\
@@ -130,24 +288,50 @@ impl AssCreator {
             Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, \
                     Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, \
                     Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding\n\
-            {styles}\
-            \n\
-            [Events]\n\
-            Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n\
             ",
+            generator = GENERATOR_COMMENT,
             title = self.title,
             width = self.canvas_config.width,
             height = self.canvas_config.height,
-            styles = CanvasStyles(self.canvas_config.ass_styles()),
+        )?;
+        for (name, spec) in &self.styles {
+            writeln!(self.buf, "{}", StyleLine { name, spec })?;
+        }
+        write!(
+            self.buf,
+            "\
+            \n\
+            [Events]\n\
+            Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n\
+            "
         )?;
         Ok(())
     }
 
-    pub fn write(&mut self, drawable: Drawable) -> Result<()> {
+    /// 写入自定义的 Dialogue，时间单位为秒
+    pub fn write_dialogue(
+        &mut self,
+        start: f64,
+        end: f64,
+        style: &str,
+        text: &str,
+    ) -> Result<(), Error> {
         writeln!(
             self.buf,
             // Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
-            "Dialogue: 2,{start},{end},{style},,0,0,0,,{{{effect}\\c&H{b:02x}{g:02x}{r:02x}&}}{text}",
+            "Dialogue: 0,{start},{end},{style},,0,0,0,,{text}",
+            start = TimePoint { t: start },
+            end = TimePoint { t: end },
+            text = escape_text(text),
+        )?;
+        Ok(())
+    }
+
+    pub fn write(&mut self, drawable: Drawable) -> Result<(), Error> {
+        writeln!(
+            self.buf,
+            // Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
+            "Dialogue: 2,{start},{end},{style},{name},0,0,0,,{{{effect}\\c&H{b:02x}{g:02x}{r:02x}&}}{text}",
             start = TimePoint {
                 t: drawable.danmu.timeline_s
             },
@@ -155,6 +339,7 @@ impl AssCreator {
                 t: drawable.danmu.timeline_s + drawable.duration
             },
             style = drawable.style_name,
+            name = self.name(&drawable.danmu),
             effect = AssEffect {
                 effect: drawable.effect
             },
@@ -167,29 +352,478 @@ impl AssCreator {
         Ok(())
     }
 
-    pub fn merge(&mut self, ass: String) -> Result<()> {
-        let mut ass_tmp1 = tempfile::Builder::new().suffix(".ass").tempfile()?;
-        write!(ass_tmp1, "{}", ass)?;
-        let mut ass_tmp2 = tempfile::Builder::new().suffix(".ass").tempfile()?;
-        write!(ass_tmp2, "{}", String::from_utf8(self.buf.clone())?)?;
-        let mut cmd = Command::new("ffmpeg");
-        cmd.args([
-            "-i",
-            &format!(
-                "concat:{}|{}",
-                ass_tmp1.path().to_string_lossy().to_string(),
-                ass_tmp2.path().to_string_lossy().to_string(),
-            ),
-            "-f",
-            "ass",
-            "pipe:1",
-        ]);
-        let ass = cmd.output()?;
-        self.buf = ass.stdout;
+    /// Dialogue 的 Name 字段，`emit_cid` 时为弹幕 id
+    fn name(&self, danmu: &Danmu) -> String {
+        match self.canvas_config.emit_cid {
+            true => danmu.cid.to_string(),
+            false => String::new(),
+        }
+    }
+
+    /// 以 Comment 形式写入被过滤的弹幕，播放器会忽略，过滤原因写在 Effect 字段
+    pub fn write_comment(&mut self, drawable: Drawable, reason: &str) -> Result<(), Error> {
+        let effect = match drawable.effect {
+            DrawEffect::Fixed {} => String::new(),
+            effect => AssEffect { effect }.to_string(),
+        };
+        writeln!(
+            self.buf,
+            // Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
+            "Comment: 2,{start},{end},{style},{name},0,0,0,{reason},{{{effect}\\c&H{b:02x}{g:02x}{r:02x}&}}{text}",
+            start = TimePoint {
+                t: drawable.danmu.timeline_s
+            },
+            end = TimePoint {
+                t: drawable.danmu.timeline_s + drawable.duration
+            },
+            style = drawable.style_name,
+            name = self.name(&drawable.danmu),
+            b = drawable.danmu.rgb.2,
+            g = drawable.danmu.rgb.1,
+            r = drawable.danmu.rgb.0,
+            text = escape_text(&drawable.danmu.content),
+        )?;
+        Ok(())
+    }
+
+    /// 合并其他 ASS（如视频内置字幕），只导入 Style 和 Dialogue/Comment，
+    /// 忽略对方的 Script Info 等其他段落，重名的 Style 会被重命名。
+    /// 对方的 PlayRes 与弹幕画布不同时，样式和位置会缩放到画布的分辨率
+    pub fn merge(&mut self, ass: String) -> Result<(), Error> {
+        let mut own =
+            String::from_utf8(std::mem::take(&mut self.buf)).context("ASS 内容不是有效的 UTF-8")?;
+        let mut style_names: HashSet<String> = own
+            .lines()
+            .filter_map(|l| l.strip_prefix("Style:"))
+            .filter_map(|l| l.split(',').next())
+            .map(|n| n.trim().to_string())
+            .collect();
+        let mut renames: HashMap<String, String> = HashMap::new();
+        let mut styles: Vec<String> = Vec::new();
+        let mut events: Vec<String> = Vec::new();
+
+        let sections = AssSection::parse(&ass);
+        let rescale = Rescale::new(
+            &sections,
+            self.canvas_config.width,
+            self.canvas_config.height,
+        );
+        let mut unscaled = false;
+        for section in sections {
+            match section.name.to_ascii_lowercase().as_str() {
+                "v4+ styles" | "v4 styles" => {
+                    let format = section.format().unwrap_or(&STYLE_FORMAT);
+                    for (_, value) in section.entries(&["Style"]) {
+                        let Some(mut fields) = remap_fields(value, format, &STYLE_FORMAT) else {
+                            warn!("跳过无法解析的内置字幕样式：{}", value);
+                            continue;
+                        };
+                        if let Some(rescale) = rescale {
+                            rescale.style(&mut fields);
+                        }
+                        if let Some(font) = self.canvas_config.merged_font.as_ref() {
+                            fields[1] = font.clone();
+                        }
+                        if let Some(font_size) = self.canvas_config.merged_font_size {
+                            fields[2] = font_size.to_string();
+                        }
+                        let name = fields[0].clone();
+                        let mut new_name = name.clone();
+                        let mut i = 1;
+                        while style_names.contains(&new_name) {
+                            new_name = format!("{}_{}", name, i);
+                            i += 1;
+                        }
+                        if new_name != name {
+                            renames.insert(name, new_name.clone());
+                            fields[0] = new_name.clone();
+                        }
+                        style_names.insert(new_name);
+                        styles.push(format!("Style: {}", fields.join(",")));
+                    }
+                }
+                "events" => {
+                    let format = section.format().unwrap_or(&EVENT_FORMAT);
+                    for (kind, value) in section.entries(&["Dialogue", "Comment"]) {
+                        let Some(mut fields) = remap_fields(value, format, &EVENT_FORMAT) else {
+                            warn!("跳过无法解析的内置字幕事件：{}", value);
+                            continue;
+                        };
+                        if let Some(new_name) = renames.get(&fields[3]) {
+                            fields[3] = new_name.clone();
+                        }
+                        if let Some(rescale) = rescale {
+                            unscaled |= rescale.event(&mut fields);
+                        }
+                        events.push(format!("{}: {}", kind, fields.join(",")));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if unscaled {
+            warn!("内置字幕中的绘图和矢量裁剪没有缩放到弹幕画布的分辨率，位置可能不正确");
+        }
+
+        let events_pos = own.find("\n[Events]\n").context("ASS 缺少 [Events] 段落")? + 1;
+        own.insert_str(
+            events_pos,
+            &styles
+                .iter()
+                .map(|s| format!("{}\n", s))
+                .collect::<String>(),
+        );
+        for event in events {
+            own.push_str(&event);
+            own.push('\n');
+        }
+        self.buf = own.into_bytes();
         Ok(())
     }
 }
 
+const STYLE_FORMAT: [&str; 23] = [
+    "Name",
+    "Fontname",
+    "Fontsize",
+    "PrimaryColour",
+    "SecondaryColour",
+    "OutlineColour",
+    "BackColour",
+    "Bold",
+    "Italic",
+    "Underline",
+    "StrikeOut",
+    "ScaleX",
+    "ScaleY",
+    "Spacing",
+    "Angle",
+    "BorderStyle",
+    "Outline",
+    "Shadow",
+    "Alignment",
+    "MarginL",
+    "MarginR",
+    "MarginV",
+    "Encoding",
+];
+
+const EVENT_FORMAT: [&str; 10] = [
+    "Layer", "Start", "End", "Style", "Name", "MarginL", "MarginR", "MarginV", "Effect", "Text",
+];
+
+/// 合并的字幕 PlayRes 与弹幕画布不同时，把样式和 override 标签中的坐标、大小缩放到画布的分辨率
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Rescale {
+    x: f64,
+    y: f64,
+}
+
+impl Rescale {
+    /// 分辨率相同时为 None。缺少 PlayRes 时按 ASS 规范的默认值 384x288，只有一个时按 4:3 推算
+    fn new(sections: &[AssSection], width: u32, height: u32) -> Option<Rescale> {
+        let mut play_res = (None, None);
+        for section in sections
+            .iter()
+            .filter(|s| s.name.eq_ignore_ascii_case("script info"))
+        {
+            for (key, value) in section.entries(&["PlayResX", "PlayResY"]) {
+                let value = value.trim().parse::<f64>().ok().filter(|v| *v > 0.0);
+                match key {
+                    "PlayResX" => play_res.0 = value,
+                    _ => play_res.1 = value,
+                }
+            }
+        }
+        let (from_width, from_height) = match play_res {
+            (Some(x), Some(y)) => (x, y),
+            (Some(x), None) => (x, x * 3.0 / 4.0),
+            (None, Some(y)) => (y * 4.0 / 3.0, y),
+            (None, None) => (384.0, 288.0),
+        };
+        if from_width == width as f64 && from_height == height as f64 {
+            return None;
+        }
+        info!(
+            "内置字幕的分辨率为 {}x{}，缩放到弹幕画布的 {}x{}",
+            from_width, from_height, width, height
+        );
+        Some(Rescale {
+            x: width as f64 / from_width,
+            y: height as f64 / from_height,
+        })
+    }
+
+    /// 字段顺序为 [`STYLE_FORMAT`]
+    fn style(&self, fields: &mut [String]) {
+        for (i, factor) in [
+            (2, self.y),  // Fontsize
+            (13, self.x), // Spacing
+            (16, self.y), // Outline
+            (17, self.y), // Shadow
+            (19, self.x), // MarginL
+            (20, self.x), // MarginR
+            (21, self.y), // MarginV
+        ] {
+            fields[i] = scale_number(&fields[i], factor);
+        }
+    }
+
+    /// 字段顺序为 [`EVENT_FORMAT`]，有无法缩放的绘图或矢量裁剪时返回 true
+    fn event(&self, fields: &mut [String]) -> bool {
+        for (i, factor) in [(5, self.x), (6, self.x), (7, self.y)] {
+            fields[i] = scale_number(&fields[i], factor);
+        }
+        let mut unscaled = false;
+        let mut text = String::with_capacity(fields[9].len());
+        let mut rest = fields[9].as_str();
+        while let Some(start) = rest.find('{') {
+            let Some(len) = rest[start..].find('}') else {
+                break;
+            };
+            text.push_str(&rest[..start]);
+            let block = &rest[start..start + len];
+            let mut tags = block.split('\\');
+            text.push_str(tags.next().unwrap_or_default());
+            for tag in tags {
+                text.push('\\');
+                text.push_str(&self.tag(tag, &mut unscaled));
+            }
+            rest = &rest[start + len..];
+        }
+        text.push_str(rest);
+        fields[9] = text;
+        unscaled
+    }
+
+    /// 缩放单个 override 标签，如 `pos(960,540)`、`fs48`，`\t(...)` 中的标签会被分开处理
+    fn tag(&self, tag: &str, unscaled: &mut bool) -> String {
+        for name in ["pos", "move", "org", "clip", "iclip"] {
+            let Some(args) = tag.strip_prefix(name).and_then(|t| t.strip_prefix('(')) else {
+                continue;
+            };
+            let (args, rest) = args.split_at(args.find(')').unwrap_or(args.len()));
+            let args: Vec<&str> = args.split(',').collect();
+            // 矢量裁剪的参数为绘图命令
+            if name.ends_with("clip") && args.len() != 4 {
+                *unscaled = true;
+                return tag.to_string();
+            }
+            let args: Vec<String> = args
+                .iter()
+                .enumerate()
+                .map(|(i, arg)| match i {
+                    0 | 2 => scale_number(arg, self.x),
+                    1 | 3 => scale_number(arg, self.y),
+                    // \move 的时间参数
+                    _ => arg.to_string(),
+                })
+                .collect();
+            return format!("{}({}{}", name, args.join(","), rest);
+        }
+        if tag
+            .strip_prefix('p')
+            .and_then(|t| t.trim().parse::<u32>().ok())
+            .is_some_and(|scale| scale > 0)
+        {
+            *unscaled = true;
+            return tag.to_string();
+        }
+        // 较长的名称在前，避免 fs 匹配到 fscx
+        for (name, factor) in [
+            ("fscx", None),
+            ("fscy", None),
+            ("fsp", Some(self.x)),
+            ("fs", Some(self.y)),
+            ("xbord", Some(self.x)),
+            ("ybord", Some(self.y)),
+            ("bord", Some(self.y)),
+            ("xshad", Some(self.x)),
+            ("yshad", Some(self.y)),
+            ("shad", Some(self.y)),
+        ] {
+            let Some(value) = tag.strip_prefix(name) else {
+                continue;
+            };
+            let Some(factor) = factor else {
+                break;
+            };
+            let end = value
+                .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-'))
+                .unwrap_or(value.len());
+            if end == 0 {
+                break;
+            }
+            return format!(
+                "{}{}{}",
+                name,
+                scale_number(&value[..end], factor),
+                &value[end..]
+            );
+        }
+        tag.to_string()
+    }
+}
+
+/// 无法解析的数字原样返回，保留两位小数
+fn scale_number(value: &str, factor: f64) -> String {
+    match value.trim().parse::<f64>() {
+        Ok(v) => {
+            let v = (v * factor * 100.0).round() / 100.0;
+            format!("{}", v + 0.0)
+        }
+        Err(_) => value.to_string(),
+    }
+}
+
+/// ASS 中的一个段落，如 `[Events]`
+struct AssSection<'a> {
+    name: &'a str,
+    format: Option<Vec<&'a str>>,
+    lines: Vec<&'a str>,
+}
+
+impl<'a> AssSection<'a> {
+    fn parse(ass: &'a str) -> Vec<AssSection<'a>> {
+        let mut sections: Vec<AssSection> = Vec::new();
+        for line in ass.trim_start_matches('\u{feff}').lines() {
+            let line = line.trim_end_matches('\r');
+            if line.starts_with('[') && line.trim_end().ends_with(']') {
+                sections.push(AssSection {
+                    name: line
+                        .trim_end()
+                        .trim_start_matches('[')
+                        .trim_end_matches(']'),
+                    format: None,
+                    lines: Vec::new(),
+                });
+            } else if let Some(section) = sections.last_mut() {
+                match line.strip_prefix("Format:") {
+                    Some(format) => {
+                        section.format = Some(format.split(',').map(str::trim).collect())
+                    }
+                    None => section.lines.push(line),
+                }
+            }
+        }
+        sections
+    }
+
+    fn format(&self) -> Option<&[&'a str]> {
+        self.format.as_deref()
+    }
+
+    /// 返回 `key: value` 形式且 key 在 keys 中的行
+    fn entries(
+        &self,
+        keys: &'static [&'static str],
+    ) -> impl Iterator<Item = (&'static str, &'a str)> + '_ {
+        self.lines.iter().filter_map(move |line| {
+            let (key, value) = line.split_once(':')?;
+            let key = keys.iter().find(|k| **k == key.trim())?;
+            Some((*key, value.trim_start()))
+        })
+    }
+}
+
+/// 读取 ASS 中的样式和事件，用于检查或合并已经生成的字幕
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ParsedAss {
+    pub styles: Vec<String>,
+    pub events: Vec<AssEvent>,
+    /// 字段数量不对或时间无法解析的 Dialogue/Comment
+    pub invalid_events: Vec<String>,
+}
+
+/// `Dialogue` 或 `Comment`，时间单位为秒
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssEvent {
+    pub comment: bool,
+    pub start: f64,
+    pub end: f64,
+    pub style: String,
+    pub text: String,
+}
+
+impl ParsedAss {
+    /// 与 [`AssCreator::merge`] 相同，按各段落的 Format 读取字段
+    pub fn parse(ass: &str) -> Self {
+        let mut parsed = ParsedAss::default();
+        for section in AssSection::parse(ass) {
+            match section.name.to_ascii_lowercase().as_str() {
+                "v4+ styles" | "v4 styles" => {
+                    let format = section.format().unwrap_or(&STYLE_FORMAT);
+                    for (_, value) in section.entries(&["Style"]) {
+                        if let Some(fields) = remap_fields(value, format, &STYLE_FORMAT) {
+                            parsed.styles.push(fields[0].clone());
+                        }
+                    }
+                }
+                "events" => {
+                    let format = section.format().unwrap_or(&EVENT_FORMAT);
+                    for (kind, value) in section.entries(&["Dialogue", "Comment"]) {
+                        let event = remap_fields(value, format, &EVENT_FORMAT).and_then(|f| {
+                            Some(AssEvent {
+                                comment: kind == "Comment",
+                                start: parse_time(&f[1])?,
+                                end: parse_time(&f[2])?,
+                                style: f[3].clone(),
+                                text: f[9].clone(),
+                            })
+                        });
+                        match event {
+                            Some(event) => parsed.events.push(event),
+                            None => parsed.invalid_events.push(format!("{}: {}", kind, value)),
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        parsed
+    }
+}
+
+/// `h:mm:ss.cc` 转换为秒
+fn parse_time(s: &str) -> Option<f64> {
+    let mut parts = s.trim().splitn(3, ':');
+    let hour: u32 = parts.next()?.parse().ok()?;
+    let minute: u32 = parts.next()?.parse().ok()?;
+    let second: f64 = parts.next()?.parse().ok()?;
+    Some(hour as f64 * 3600.0 + minute as f64 * 60.0 + second)
+}
+
+/// 按 Format 将字段转换为目标 Format 的顺序，缺失的字段使用默认值
+fn remap_fields(value: &str, from: &[&str], to: &[&str]) -> Option<Vec<String>> {
+    let values: Vec<&str> = value.splitn(from.len(), ',').collect();
+    if values.len() != from.len() {
+        return None;
+    }
+    let get = |name: &str| {
+        from.iter()
+            .position(|f| f.eq_ignore_ascii_case(name))
+            .map(|i| values[i])
+    };
+    Some(
+        to.iter()
+            .map(|name| {
+                let v = match *name {
+                    "OutlineColour" => get(name).or_else(|| get("TertiaryColour")),
+                    _ => get(name),
+                };
+                match (v, *name) {
+                    (Some(v), "Text") => v.to_string(),
+                    (Some(v), _) => v.trim().to_string(),
+                    (None, "ScaleX" | "ScaleY") => "100".to_string(),
+                    (None, "Name" | "Effect" | "Text") => String::new(),
+                    (None, _) => "0".to_string(),
+                }
+            })
+            .collect(),
+    )
+}
+
 fn escape_text(text: &str) -> Cow<str> {
     let text = text.trim();
     if memchr::memchr(b'\n', text.as_bytes()).is_some() {
@@ -202,6 +836,7 @@ fn escape_text(text: &str) -> Cow<str> {
 #[cfg(test)]
 mod tests {
     use super::*;
+
     #[test]
     fn time_point_fmt() {
         assert_eq!(format!("{}", TimePoint { t: 0.0 }), "0:00:00.00");
@@ -245,4 +880,211 @@ mod tests {
             r"呵\N呵\N比\N你\N们\N更\N喜\N欢\N晚\N晚"
         );
     }
+
+    #[test]
+    fn test_write_comment() -> Result<()> {
+        let config = CanvasConfig::default();
+        let canvas = config.clone().canvas();
+        let mut ass = AssCreator::new("test".to_string(), config)?;
+        ass.buf.clear();
+        let danmu = crate::Danmu {
+            timeline_s: 1.0,
+            content: "剧透".to_string(),
+            rgb: (255, 0, 0),
+            ..Default::default()
+        };
+        ass.write_comment(canvas.draw_filtered(danmu), "denylisted")?;
+        assert_eq!(
+            String::from_utf8(ass.buf)?,
+            "Comment: 2,0:00:01.00,0:00:16.00,Float,,0,0,0,denylisted,{\\c&H0000ff&}剧透\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_emit_cid() -> Result<()> {
+        let config = CanvasConfig {
+            emit_cid: true,
+            ..Default::default()
+        };
+        let mut canvas = config.clone().canvas();
+        let mut ass = AssCreator::new("test".to_string(), config)?;
+        ass.buf.clear();
+        let danmu = crate::Danmu {
+            timeline_s: 1.0,
+            content: "弹幕".to_string(),
+            cid: 1234,
+            ..Default::default()
+        };
+        ass.write(canvas.draw(danmu)?.unwrap())?;
+        assert!(String::from_utf8(ass.buf)?
+            .starts_with("Dialogue: 2,0:00:01.00,0:00:16.00,Float,1234,0,0,0,,"));
+        Ok(())
+    }
+
+    const FANSUB_ASS: &str = "\u{feff}[Script Info]\r
+; Script generated by Aegisub 3.2.2\r
+Title: Fansub Ep01\r
+ScriptType: v4.00+\r
+PlayResX: 1920\r
+PlayResY: 1080\r
+\r
+[Aegisub Project Garbage]\r
+Video File: ep01.mkv\r
+\r
+[V4+ Styles]\r
+Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding\r
+Style: Default,方正准圆_GBK,60,&H00FFFFFF,&H000000FF,&H00000000,&H00000000,0,0,0,0,100,100,0,0,1,2,1,2,10,10,30,1\r
+Style: Top,方正准圆_GBK,50,&H00FFFFFF,&H000000FF,&H00000000,&H00000000,0,0,0,0,100,100,0,0,1,2,1,8,10,10,30,1\r
+\r
+[Events]\r
+Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\r
+Comment: 0,0:00:00.00,0:00:05.00,Default,,0,0,0,,翻译：某字幕组\r
+Dialogue: 0,0:00:01.00,0:00:03.00,Default,,0,0,0,,你好，世界, hello\r
+Dialogue: 0,0:00:04.00,0:00:06.00,Top,,0,0,0,,{\\fn黑体}标题\r
+";
+
+    #[test]
+    fn test_merge() -> Result<()> {
+        let config = CanvasConfig {
+            font: "黑体".to_string(),
+            ..Default::default()
+        };
+        let mut ass = AssCreator::new("test".to_string(), config)?;
+        ass.merge(FANSUB_ASS.to_string())?;
+        let ass = String::from_utf8(ass.buf)?;
+
+        for header in ["[Script Info]", "[V4+ Styles]", "[Events]"] {
+            assert_eq!(ass.matches(header).count(), 1, "{}", header);
+        }
+        assert_eq!(ass.matches("Format: Name,").count(), 1);
+        assert_eq!(ass.matches("Format: Layer,").count(), 1);
+        assert!(!ass.contains("Aegisub Project Garbage"));
+        assert!(!ass.contains("Fansub Ep01"));
+        assert!(!ass.contains('\r'));
+        assert!(AssCreator::is_generated(&ass));
+        assert!(!AssCreator::is_generated(FANSUB_ASS));
+
+        let styles_end = ass.find("[Events]").unwrap();
+        // 1920x1080 的字幕缩放到 1280x720 的画布
+        assert!(ass[..styles_end].contains(
+            "Style: Default,方正准圆_GBK,40,&H00FFFFFF,&H000000FF,&H00000000,&H00000000,\
+             0,0,0,0,100,100,0,0,1,1.33,0.67,2,6.67,6.67,20,1\n"
+        ));
+        assert!(ass[..styles_end].contains("Style: Top_1,方正准圆_GBK,33.33,"));
+        assert!(ass[styles_end..]
+            .contains("Comment: 0,0:00:00.00,0:00:05.00,Default,,0,0,0,,翻译：某字幕组\n"));
+        assert!(ass[styles_end..]
+            .contains("Dialogue: 0,0:00:01.00,0:00:03.00,Default,,0,0,0,,你好，世界, hello\n"));
+        assert!(ass[styles_end..]
+            .contains("Dialogue: 0,0:00:04.00,0:00:06.00,Top_1,,0,0,0,,{\\fn黑体}标题\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let parsed = ParsedAss::parse(FANSUB_ASS);
+        assert_eq!(parsed.styles, ["Default", "Top"]);
+        assert_eq!(parsed.events.len(), 3);
+        assert!(parsed.events[0].comment);
+        assert_eq!(
+            parsed.events[2],
+            AssEvent {
+                comment: false,
+                start: 4.0,
+                end: 6.0,
+                style: "Top".to_string(),
+                text: "{\\fn黑体}标题".to_string(),
+            }
+        );
+        assert!(parsed.invalid_events.is_empty());
+
+        let parsed =
+            ParsedAss::parse("[Events]\nDialogue: 0,0:00:0x.00,0:00:01.00,Float,,0,0,0,,a\n");
+        assert!(parsed.events.is_empty());
+        assert_eq!(parsed.invalid_events.len(), 1);
+        assert_eq!(parse_time("1:01:01.50"), Some(3661.5));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_font() -> Result<()> {
+        let config = CanvasConfig {
+            merged_font: Some("思源黑体".to_string()),
+            merged_font_size: Some(48),
+            ..Default::default()
+        };
+        let mut ass = AssCreator::new("test".to_string(), config)?;
+        ass.merge(FANSUB_ASS.to_string())?;
+        let ass = String::from_utf8(ass.buf)?;
+
+        assert!(ass.contains("Style: Default,思源黑体,48,&H00FFFFFF,&H000000FF,"));
+        assert!(ass.contains("Style: Top_1,思源黑体,48,&H00FFFFFF,&H000000FF,"));
+        assert!(ass.contains("{\\fn黑体}标题"));
+        assert!(!ass.contains("方正准圆_GBK"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rescale() {
+        let rescale = Rescale {
+            x: 2.0 / 3.0,
+            y: 2.0 / 3.0,
+        };
+        let mut fields: Vec<String> = "0,0:00:01.00,0:00:02.00,Default,,0,0,30,,\
+            {\\pos(960,540)\\fs48\\fscx120\\t(0,500,\\bord3)}a{\\move(0,0,1920,1080,0,500)}b"
+            .split(',')
+            .map(str::to_string)
+            .collect();
+        let text = fields[9..].join(",");
+        fields.truncate(9);
+        fields.push(text);
+        assert!(!rescale.event(&mut fields));
+        assert_eq!(fields[7], "20");
+        assert_eq!(
+            fields[9],
+            "{\\pos(640,360)\\fs32\\fscx120\\t(0,500,\\bord2)}a{\\move(0,0,1280,720,0,500)}b"
+        );
+
+        let mut fields = vec![String::new(); 9];
+        fields.push("{\\clip(m 0 0 l 10 10)\\p1}m 0 0 l 100 0{\\p0}".to_string());
+        assert!(rescale.event(&mut fields));
+        assert_eq!(fields[9], "{\\clip(m 0 0 l 10 10)\\p1}m 0 0 l 100 0{\\p0}");
+
+        let sections = AssSection::parse("[Script Info]\nPlayResX: 1280\nPlayResY: 720\n");
+        assert_eq!(Rescale::new(&sections, 1280, 720), None);
+        let sections = AssSection::parse("[Script Info]\nTitle: a\n");
+        assert_eq!(
+            Rescale::new(&sections, 768, 576),
+            Some(Rescale { x: 2.0, y: 2.0 })
+        );
+    }
+
+    #[test]
+    fn test_merge_remap_format() {
+        assert_eq!(
+            remap_fields(
+                "Marked=0,0:00:01.00,0:00:02.00,Default,,0000,0000,0000,,a,b",
+                &[
+                    "Marked", "Start", "End", "Style", "Name", "MarginL", "MarginR", "MarginV",
+                    "Effect", "Text"
+                ],
+                &EVENT_FORMAT,
+            )
+            .unwrap(),
+            vec![
+                "0",
+                "0:00:01.00",
+                "0:00:02.00",
+                "Default",
+                "",
+                "0000",
+                "0000",
+                "0000",
+                "",
+                "a,b"
+            ]
+        );
+        assert!(remap_fields("Default,Arial", &STYLE_FORMAT, &STYLE_FORMAT).is_none());
+    }
 }
@@ -0,0 +1,195 @@
+//! B 站格式的 xml 弹幕，`<d p="时间,类型,字号,颜色,发送时间,弹幕池,用户,id">内容</d>`
+use crate::{Danmu, DanmuType};
+
+pub const BILIBILI_SOURCE: &str = "BiliBili";
+
+/// 返回解析出的弹幕和跳过的数量，高级弹幕、代码弹幕等不支持的类型也会跳过
+pub fn parse(xml: &str) -> (Vec<Danmu>, usize) {
+    let mut danmus = Vec::new();
+    let mut skipped = 0;
+    let mut rest = xml;
+    while let Some(start) = rest.find("<d ") {
+        rest = &rest[start..];
+        let Some(end) = rest.find("</d>") else {
+            skipped += 1;
+            break;
+        };
+        match parse_element(&rest[..end]) {
+            Some(danmu) => danmus.push(danmu),
+            None => skipped += 1,
+        }
+        rest = &rest[end + "</d>".len()..];
+    }
+    (danmus, skipped)
+}
+
+fn parse_element(element: &str) -> Option<Danmu> {
+    let (attrs, text) = element.split_once('>')?;
+    let p = attr(attrs, "p")?;
+    let fields: Vec<&str> = p.split(',').collect();
+    let [time, mode, size, color, _, _, uid, ..] = fields[..] else {
+        return None;
+    };
+    let timeline_s = time.parse::<f64>().ok().filter(|t| t.is_finite())?;
+    let r#type = match mode {
+        "1" | "2" | "3" => DanmuType::Float,
+        "4" => DanmuType::Bottom,
+        "5" => DanmuType::Top,
+        "6" => DanmuType::Reverse,
+        _ => return None,
+    };
+    let color = color.parse::<i64>().ok()? & 0xFFFFFF;
+    Some(Danmu {
+        timeline_s,
+        content: unescape(text)?,
+        r#type,
+        fontsize: size.parse().ok()?,
+        rgb: ((color >> 16) as u8, (color >> 8) as u8, color as u8),
+        source: BILIBILI_SOURCE.to_string(),
+        user_id: format!("[{}]{}", BILIBILI_SOURCE, uid),
+        cid: fields.get(7).and_then(|id| id.parse().ok()).unwrap_or(0),
+    })
+}
+
+/// 转换为 B 站格式的 xml，发送时间和弹幕池为 0，用户 id 保留来源前缀
+#[cfg(feature = "network")]
+pub fn write(danmus: &[Danmu]) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<i>\n");
+    for danmu in danmus {
+        let mode = match danmu.r#type {
+            DanmuType::Float => 1,
+            DanmuType::Bottom => 4,
+            DanmuType::Top => 5,
+            DanmuType::Reverse => 6,
+        };
+        // dandanplay 的弹幕没有字号，使用 B 站默认的 25
+        let fontsize = match danmu.fontsize {
+            0 => 25,
+            size => size,
+        };
+        let (r, g, b) = danmu.rgb;
+        let color = (r as u32) << 16 | (g as u32) << 8 | b as u32;
+        xml.push_str(&format!(
+            "<d p=\"{:.5},{},{},{},0,0,{},{}\">{}</d>\n",
+            danmu.timeline_s,
+            mode,
+            fontsize,
+            color,
+            // p 以逗号分隔
+            escape(&danmu.user_id.replace(',', "_")),
+            danmu.cid,
+            escape(&danmu.content)
+        ));
+    }
+    xml.push_str("</i>\n");
+    xml
+}
+
+#[cfg(feature = "network")]
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn attr<'a>(attrs: &'a str, name: &str) -> Option<&'a str> {
+    ['"', '\''].into_iter().find_map(|quote| {
+        let prefix = format!(" {}={}", name, quote);
+        let start = attrs.find(&prefix)? + prefix.len();
+        let len = attrs[start..].find(quote)?;
+        Some(&attrs[start..start + len])
+    })
+}
+
+/// 还原 xml 实体，包括 `&#NN;` 和 `&#xHH;`，无法识别的实体返回 None
+fn unescape(s: &str) -> Option<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('&') {
+        out.push_str(&rest[..i]);
+        let end = rest[i..].find(';')? + i;
+        let entity = &rest[i + 1..end];
+        let ch = match entity {
+            "amp" => '&',
+            "lt" => '<',
+            "gt" => '>',
+            "quot" => '"',
+            "apos" => '\'',
+            _ => {
+                let code = match entity.strip_prefix("#x").or(entity.strip_prefix("#X")) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                    None => entity.strip_prefix('#')?.parse().ok()?,
+                };
+                char::from_u32(code)?
+            }
+        };
+        out.push(ch);
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?><i>
+<chatserver>chat.bilibili.com</chatserver>
+<d p="1.5,1,25,16777215,1600000000,0,abc123,1">hello</d>
+<d p="2.0,5,36,16711680,1600000000,0,def456,2">&lt;top&gt; &amp; &#x4e2d;&#25991;</d>
+<d p="3.0,6,25,65280,1600000000,0,abc123,3">reverse</d>
+<d p="4.0,7,25,0,1600000000,0,abc123,4">[0,0,"1-1",4.5,"advanced"]</d>
+<d p="5.0,1,25">truncated</d>
+<d p="6.0,1,25,0,1600000000,0,abc123,5">&bogus;</d>
+</i>"#;
+        let (danmus, skipped) = parse(xml);
+        assert_eq!(skipped, 3);
+        assert_eq!(danmus.len(), 3);
+
+        assert_eq!(danmus[0].timeline_s, 1.5);
+        assert_eq!(danmus[0].content, "hello");
+        assert_eq!(danmus[0].r#type, DanmuType::Float);
+        assert_eq!(danmus[0].fontsize, 25);
+        assert_eq!(danmus[0].rgb, (255, 255, 255));
+        assert_eq!(danmus[0].source, "BiliBili");
+        assert_eq!(danmus[0].user_id, "[BiliBili]abc123");
+        assert_eq!(danmus[0].cid, 1);
+
+        assert_eq!(danmus[1].content, "<top> & 中文");
+        assert_eq!(danmus[1].r#type, DanmuType::Top);
+        assert_eq!(danmus[1].fontsize, 36);
+        assert_eq!(danmus[1].rgb, (255, 0, 0));
+
+        assert_eq!(danmus[2].r#type, DanmuType::Reverse);
+        assert_eq!(danmus[2].rgb, (0, 255, 0));
+    }
+
+    #[cfg(feature = "network")]
+    #[test]
+    fn test_write() {
+        let danmu = Danmu {
+            timeline_s: 2.0,
+            content: "<top> & \"中文\"".to_string(),
+            r#type: DanmuType::Top,
+            fontsize: 25,
+            rgb: (255, 0, 16),
+            source: "Gamer".to_string(),
+            user_id: "[Gamer]a,b".to_string(),
+            cid: 7,
+        };
+        let xml = write(std::slice::from_ref(&danmu));
+        assert!(xml.contains(
+            r#"<d p="2.00000,5,25,16711696,0,0,[Gamer]a_b,7">&lt;top&gt; &amp; &quot;中文&quot;</d>"#
+        ));
+        let (danmus, skipped) = parse(&xml);
+        assert_eq!(skipped, 0);
+        assert_eq!(danmus[0].content, danmu.content);
+        assert_eq!(danmus[0].r#type, DanmuType::Top);
+        assert_eq!(danmus[0].rgb, danmu.rgb);
+        assert_eq!(danmus[0].cid, 7);
+    }
+}
@@ -0,0 +1,54 @@
+//! 批量处理时的取消，CLI 在 Ctrl-C 时触发，正在处理的文件在下一个阶段开始前停止
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use crate::Error;
+
+/// clone 后共享同一个状态，可以传给多个任务
+#[derive(Debug, Clone, Default)]
+pub struct CancelFlag(Arc<AtomicBool>);
+
+impl CancelFlag {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// 已取消时返回 `Error::Cancelled`
+    pub fn check(&self) -> Result<(), Error> {
+        match self.is_cancelled() {
+            true => Err(Error::Cancelled),
+            false => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "network")]
+pub(crate) fn is_cancelled_error(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<Error>(), Some(Error::Cancelled))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel_flag() {
+        let cancel = CancelFlag::default();
+        let task = cancel.clone();
+        assert!(task.check().is_ok());
+        cancel.cancel();
+        assert!(task.is_cancelled());
+        #[cfg(feature = "network")]
+        {
+            use anyhow::Context;
+            let e = task.check().context("获取弹幕").unwrap_err();
+            assert!(is_cancelled_error(&e));
+        }
+    }
+}
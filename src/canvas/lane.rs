@@ -33,8 +33,21 @@ impl Lane {
         }
     }
 
+    /// 不比上一条长的弹幕从这个时间开始可以发射，更长的弹幕需要更晚，
+    /// 延迟发射时需要的时间也不会早于这个时间，作为槽位的最早空闲时间
+    pub fn free_time(&self, config: &CanvasConfig) -> f64 {
+        let v1 = (config.width as f64 + self.last_length) / config.duration;
+        self.last_shoot_time + (self.last_length + config.horizontal_gap) / v1
+    }
+
     /// 这个槽位是否可以发射另外一条弹幕，返回可能的情形
-    pub fn available_for(&self, other: &Danmu, config: &super::Config) -> Collision {
+    /// `other_length` 为 `other.length(config)`，检查多个槽位时只需要计算一次
+    pub fn available_for(
+        &self,
+        other: &Danmu,
+        other_length: f64,
+        config: &super::Config,
+    ) -> Collision {
         #[allow(non_snake_case)]
         let T = config.duration;
         #[allow(non_snake_case)]
@@ -45,7 +58,7 @@ impl Lane {
         let t1 = self.last_shoot_time;
         let t2 = other.timeline_s;
         let l1 = self.last_length;
-        let l2 = other.length(config);
+        let l2 = other_length;
 
         let v1 = (W + l1) as f64 / T;
         let v2 = (W + l2) as f64 / T;
@@ -98,3 +111,79 @@ impl Lane {
         }
     }
 }
+
+/// 一组槽位，按最早空闲时间建立最小值线段树，只需要检查可能空闲的槽位
+#[derive(Debug, Clone)]
+pub struct Lanes {
+    lanes: Vec<Option<Lane>>,
+    /// 使用过的槽位数量，总是先使用下标最小的未使用槽位，因此未使用的槽位都在最后
+    used: usize,
+    /// 叶子为各槽位的最早空闲时间，未使用的槽位为正无穷
+    tree: Vec<f64>,
+}
+
+impl Lanes {
+    pub fn new(count: usize) -> Self {
+        Lanes {
+            lanes: vec![None; count],
+            used: 0,
+            tree: vec![f64::INFINITY; 2 * count.next_power_of_two()],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lanes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lanes.is_empty()
+    }
+
+    pub fn get(&self, idx: usize) -> Option<&Lane> {
+        self.lanes.get(idx)?.as_ref()
+    }
+
+    /// 下标最小的未使用槽位
+    pub fn first_unused(&self) -> Option<usize> {
+        (self.used < self.lanes.len()).then_some(self.used)
+    }
+
+    pub fn set(&mut self, idx: usize, lane: Lane, config: &CanvasConfig) {
+        let mut node = self.tree.len() / 2 + idx;
+        self.tree[node] = lane.free_time(config);
+        while node > 1 {
+            node /= 2;
+            self.tree[node] = self.tree[2 * node].min(self.tree[2 * node + 1]);
+        }
+        self.lanes[idx] = Some(lane);
+        self.used = self.used.max(idx + 1);
+    }
+
+    /// 下标不小于 `from` 且最早空闲时间不晚于 `time` 的第一个已使用槽位
+    pub fn next_free(&self, from: usize, time: f64) -> Option<usize> {
+        let leaves = self.tree.len() / 2;
+        if from >= leaves {
+            return None;
+        }
+        // 向上找到右侧第一个包含空闲槽位的子树
+        let mut node = leaves + from;
+        while self.tree[node] > time {
+            while node % 2 == 1 {
+                node /= 2;
+            }
+            if node == 0 {
+                return None;
+            }
+            node += 1;
+        }
+        // 再向下找到其中最左侧的空闲槽位
+        while node < leaves {
+            node = if self.tree[2 * node] <= time {
+                2 * node
+            } else {
+                2 * node + 1
+            };
+        }
+        Some(node - leaves)
+    }
+}
@@ -2,12 +2,33 @@
 mod lane;
 
 use super::{Danmu, Drawable};
-use crate::{canvas::lane::Collision, DrawEffect};
+#[cfg(feature = "network")]
+use crate::Chapter;
+use crate::{canvas::lane::Collision, DrawEffect, SimplifiedOrTraditional};
 use anyhow::Result;
 use float_ord::FloatOrd;
-use lane::Lane;
+use lane::{Lane, Lanes};
 
-#[derive(Clone)]
+/// 没有空闲的槽位时，最多延迟弹幕的秒数
+const MAX_DELAY_S: f64 = 1.0;
+/// 最早空闲时间与逐条计算碰撞的浮点误差，只用于缩小需要检查的槽位
+const FREE_TIME_EPS: f64 = 1e-6;
+/// 弹幕开始时间超过视频时长这么多秒以上才丢弃，避免时长不准确时误删结尾的弹幕
+const DURATION_TOLERANCE_S: f64 = 5.0;
+
+/// 时间轴偏移后开始时间为负数的弹幕的处理方式
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+pub enum NegativeOffset {
+    /// 丢弃
+    #[default]
+    #[serde(rename = "drop")]
+    Drop,
+    /// 还在屏幕上的弹幕从 0 秒开始显示剩下的部分，已经离开屏幕的仍然丢弃
+    #[serde(rename = "clamp")]
+    Clamp,
+}
+
+#[derive(Clone, Debug)]
 pub struct Config {
     pub duration: f64,
     pub width: u32,
@@ -23,6 +44,8 @@ pub struct Config {
     pub float_percentage: f64,
     /// 屏幕上底部弹幕最多高度百分比
     pub bottom_percentage: f64,
+    /// 屏幕上顶部弹幕最多高度百分比
+    pub top_percentage: f64,
     /// 透明度
     pub opacity: u8,
     /// 是否加粗，1代表是，0代表否
@@ -31,76 +54,175 @@ pub struct Config {
     pub outline: f64,
     /// 时间轴偏移
     pub time_offset: f64,
+    /// 偏移后开始时间为负数的弹幕的处理方式
+    pub negative_offset: NegativeOffset,
+    /// 样式的 SecondaryColour，ASS 格式 AABBGGRR
+    pub secondary_colour: u32,
+    /// 样式的 BackColour，ASS 格式 AABBGGRR
+    pub back_colour: u32,
+    /// 合并内置字幕时覆盖其样式的字体
+    pub merged_font: Option<String>,
+    /// 合并内置字幕时覆盖其样式的字体大小
+    pub merged_font_size: Option<u32>,
+    /// 视频时长，时间轴偏移后超过时长的弹幕会被丢弃，None 时不检查
+    pub video_duration: Option<f64>,
+    /// 在 Dialogue 的 Name 字段写入弹幕 id，用于对应到原始弹幕
+    pub emit_cid: bool,
+    /// 在本地将弹幕转换为简体或繁体，弹幕缓存记录的写法已经相同时跳过，None 时不转换
+    pub ch_convert: Option<SimplifiedOrTraditional>,
+    #[cfg(feature = "network")]
+    /// 视频章节，有 OP、ED 时按弹幕密度推算时间轴偏移，None 时不推算
+    pub chapters: Option<Vec<Chapter>>,
+    #[cfg(feature = "network")]
+    /// 使用推算的时间轴偏移代替 `time_offset`
+    pub auto_offset: bool,
+}
+
+/// 与命令行参数的默认值相同
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            duration: 15.0,
+            width: 1280,
+            height: 720,
+            font: "黑体".to_string(),
+            font_size: 35,
+            width_ratio: 1.2,
+            horizontal_gap: 20.0,
+            lane_size: 35,
+            float_percentage: 0.4,
+            bottom_percentage: 0.3,
+            top_percentage: 0.0,
+            opacity: ((1.0 - 0.7) * 255.0) as u8,
+            bold: 0,
+            outline: 0.8,
+            time_offset: 0.0,
+            negative_offset: NegativeOffset::Drop,
+            secondary_colour: 0x00FFFFFF,
+            back_colour: 0x00000000,
+            merged_font: None,
+            merged_font_size: None,
+            video_duration: None,
+            emit_cid: false,
+            ch_convert: Some(SimplifiedOrTraditional::Simplified),
+            #[cfg(feature = "network")]
+            chapters: None,
+            #[cfg(feature = "network")]
+            auto_offset: false,
+        }
+    }
 }
 
 impl Config {
+    /// `timeline_s` 为时间轴偏移后的时间，`clamp` 时只丢弃在 0 秒之前已经离开屏幕的弹幕
+    pub fn is_before_start(&self, timeline_s: f64) -> bool {
+        match self.negative_offset {
+            NegativeOffset::Drop => timeline_s < 0.0,
+            NegativeOffset::Clamp => timeline_s + self.duration <= 0.0,
+        }
+    }
+
+    /// `timeline_s` 为时间轴偏移后的时间
+    pub fn is_beyond_duration(&self, timeline_s: f64) -> bool {
+        self.video_duration
+            .is_some_and(|duration| timeline_s > duration + DURATION_TOLERANCE_S)
+    }
+
     pub fn canvas(self) -> Canvas {
         let float_lanes_cnt =
             (self.float_percentage * self.height as f64 / self.lane_size as f64) as usize;
         let bottom_lanes_cnt =
             (self.bottom_percentage * self.height as f64 / self.lane_size as f64) as usize;
+        let top_lanes_cnt =
+            (self.top_percentage * self.height as f64 / self.lane_size as f64) as usize;
 
         Canvas {
             config: self,
-            float_lanes: vec![None; float_lanes_cnt],
-            bottom_lanes: vec![None; bottom_lanes_cnt],
+            float_lanes: Lanes::new(float_lanes_cnt),
+            bottom_lanes: Lanes::new(bottom_lanes_cnt),
+            top_lanes: Lanes::new(top_lanes_cnt),
         }
     }
 }
 
 pub struct Canvas {
     pub config: Config,
-    pub float_lanes: Vec<Option<Lane>>,
-    pub bottom_lanes: Vec<Option<Lane>>,
+    pub float_lanes: Lanes,
+    pub bottom_lanes: Lanes,
+    pub top_lanes: Lanes,
 }
 
 impl Canvas {
     pub fn draw(&mut self, mut danmu: Danmu) -> Result<Option<Drawable>> {
         danmu.timeline_s += self.config.time_offset;
-        if danmu.timeline_s < 0.0 {
+        if self.config.is_before_start(danmu.timeline_s)
+            || self.config.is_beyond_duration(danmu.timeline_s)
+        {
             return Ok(None);
         }
-        match danmu.r#type {
-            crate::danmu::DanmuType::Float => Ok(self.draw_float(danmu)),
+        let drawable = match danmu.r#type {
+            crate::danmu::DanmuType::Float => self.draw_float(danmu),
             crate::danmu::DanmuType::Bottom
             | crate::danmu::DanmuType::Top
             | crate::danmu::DanmuType::Reverse => {
                 // 不喜欢底部弹幕，直接转成 Float
                 // 这是 feature 不是 bug
                 danmu.r#type = crate::danmu::DanmuType::Float;
-                Ok(self.draw_float(danmu))
+                self.draw_float(danmu)
             }
-        }
+        };
+        // 按原本的时间占用槽位，再截掉 0 秒之前的部分
+        Ok(drawable.map(Drawable::clamp_start))
+    }
+
+    /// 不占用槽位，用于以 Comment 形式输出被过滤的弹幕
+    pub fn draw_filtered(&self, mut danmu: Danmu) -> Drawable {
+        danmu.timeline_s = (danmu.timeline_s + self.config.time_offset).max(0.0);
+        Drawable::new(danmu, self.config.duration, "Float", DrawEffect::Fixed {})
     }
 
     fn draw_float(&mut self, mut danmu: Danmu) -> Option<Drawable> {
-        let mut collisions = Vec::with_capacity(self.float_lanes.len());
-        for (idx, lane) in self.float_lanes.iter_mut().enumerate() {
-            match lane {
-                // 优先画不存在的槽位
-                None => {
-                    return Some(self.draw_float_in_lane(danmu, idx));
-                }
-                Some(l) => {
-                    let col = l.available_for(&danmu, &self.config);
-                    match col {
-                        Collision::Separate { .. } | Collision::NotEnoughTime { .. } => {
-                            return Some(self.draw_float_in_lane(danmu, idx));
-                        }
-                        Collision::Collide { time_needed } => {
-                            collisions.push((FloatOrd(time_needed), idx));
-                        }
-                    }
+        let t = danmu.timeline_s;
+        let length = danmu.length(&self.config);
+        let lanes = &self.float_lanes;
+        // 可以发射的槽位最早空闲时间一定不晚于弹幕时间，按下标顺序取第一个
+        let mut available = None;
+        let mut from = 0;
+        while let Some(idx) = lanes.next_free(from, t + FREE_TIME_EPS) {
+            if let Collision::Separate { .. } | Collision::NotEnoughTime { .. } =
+                lanes.get(idx)?.available_for(&danmu, length, &self.config)
+            {
+                available = Some(idx);
+                break;
+            }
+            from = idx + 1;
+        }
+        // 其次优先画不存在的槽位
+        if let Some(idx) = available.or(lanes.first_unused()) {
+            return Some(self.draw_float_in_lane(danmu, idx));
+        }
+        // 允许部分弹幕在延迟后填充，需要延迟的时间不少于最早空闲时间减去弹幕时间，
+        // 只检查可能比已经找到的槽位延迟更少的槽位
+        let mut collision: Option<(FloatOrd<f64>, usize)> = None;
+        let mut from = 0;
+        loop {
+            let bound = collision.map_or(MAX_DELAY_S, |(FloatOrd(t), _)| t.min(MAX_DELAY_S));
+            let Some(idx) = lanes.next_free(from, t + bound + FREE_TIME_EPS) else {
+                break;
+            };
+            if let Collision::Collide { time_needed } =
+                lanes.get(idx)?.available_for(&danmu, length, &self.config)
+            {
+                if collision.is_none_or(|(t, _)| FloatOrd(time_needed) < t) {
+                    collision = Some((FloatOrd(time_needed), idx));
                 }
             }
+            from = idx + 1;
         }
-        // 允许部分弹幕在延迟后填充
-        if !collisions.is_empty() {
-            collisions.sort_unstable();
-            let (FloatOrd(time_need), lane_idx) = collisions[0];
-            if time_need < 1.0 {
+        if let Some((FloatOrd(time_need), lane_idx)) = collision {
+            // 只允许延迟 1s
+            if time_need < MAX_DELAY_S {
                 debug!("延迟弹幕 {} 秒", time_need);
-                // 只允许延迟 1s
                 danmu.timeline_s += time_need + 0.01; // 间隔也不要太小了
                 return Some(self.draw_float_in_lane(danmu, lane_idx));
             }
@@ -110,7 +232,8 @@ impl Canvas {
     }
 
     fn draw_float_in_lane(&mut self, danmu: Danmu, lane_idx: usize) -> Drawable {
-        self.float_lanes[lane_idx] = Some(Lane::draw(&danmu, &self.config));
+        self.float_lanes
+            .set(lane_idx, Lane::draw(&danmu, &self.config), &self.config);
         let y = lane_idx as i32 * self.config.lane_size as i32;
         let l = danmu.length(&self.config);
         Drawable::new(
@@ -124,3 +247,81 @@ impl Canvas {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 逐个检查所有槽位，返回选中的槽位和发射时间
+    fn draw_linear(
+        config: &Config,
+        lanes: &mut [Option<Lane>],
+        mut danmu: Danmu,
+    ) -> Option<(usize, f64)> {
+        let mut collisions = vec![];
+        for (idx, lane) in lanes.iter().enumerate() {
+            let Some(l) = lane else {
+                lanes[idx] = Some(Lane::draw(&danmu, config));
+                return Some((idx, danmu.timeline_s));
+            };
+            match l.available_for(&danmu, danmu.length(config), config) {
+                Collision::Separate { .. } | Collision::NotEnoughTime { .. } => {
+                    lanes[idx] = Some(Lane::draw(&danmu, config));
+                    return Some((idx, danmu.timeline_s));
+                }
+                Collision::Collide { time_needed } => collisions.push((FloatOrd(time_needed), idx)),
+            }
+        }
+        collisions.sort_unstable();
+        let &(FloatOrd(time_need), idx) = collisions.first()?;
+        if time_need >= MAX_DELAY_S {
+            return None;
+        }
+        danmu.timeline_s += time_need + 0.01;
+        lanes[idx] = Some(Lane::draw(&danmu, config));
+        Some((idx, danmu.timeline_s))
+    }
+
+    #[test]
+    fn test_same_lane_as_linear_scan() {
+        let mut seed = 0x9e37_79b9_7f4a_7c15_u64;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        let mut danmus: Vec<_> = (0..20_000)
+            .map(|_| Danmu {
+                timeline_s: (next() % 600_000) as f64 / 1000.0,
+                content: "弹".repeat(1 + (next() % 40) as usize),
+                ..Default::default()
+            })
+            .collect();
+        danmus.sort_by(|a, b| a.timeline_s.total_cmp(&b.timeline_s));
+
+        let dense = Config {
+            height: 2160,
+            lane_size: 20,
+            float_percentage: 1.0,
+            ..Default::default()
+        };
+        for config in [Config::default(), dense] {
+            let mut canvas = config.clone().canvas();
+            let mut lanes = vec![None; canvas.float_lanes.len()];
+            let mut drawn = 0;
+            for danmu in danmus.iter().cloned() {
+                let expected = draw_linear(&config, &mut lanes, danmu.clone());
+                let actual = canvas.draw(danmu).unwrap().map(|d| match d.effect {
+                    DrawEffect::Move { start: (_, y), .. } => {
+                        (y as usize / config.lane_size as usize, d.danmu.timeline_s)
+                    }
+                    DrawEffect::Fixed {} => unreachable!(),
+                });
+                assert_eq!(actual, expected);
+                drawn += actual.is_some() as usize;
+            }
+            assert!(drawn > 0 && drawn < danmus.len(), "{}", drawn);
+        }
+    }
+}
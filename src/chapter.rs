@@ -0,0 +1,231 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::{util::time_label, Danmu};
+
+/// 视频中的一个章节，时间单位为秒
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chapter {
+    pub title: String,
+    pub start_s: f64,
+    pub end_s: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct FfprobeChapterJson {
+    #[serde(default)]
+    chapters: Vec<FfprobeChapter>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FfprobeChapter {
+    start_time: String,
+    end_time: String,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+/// 解析 `ffprobe -show_chapters -of json` 的输出，时间无法解析的章节会被忽略
+pub fn parse_chapters(json: &str) -> serde_json::Result<Vec<Chapter>> {
+    let chapters = serde_json::from_str::<FfprobeChapterJson>(json)?.chapters;
+    Ok(chapters
+        .into_iter()
+        .filter_map(|c| {
+            Some(Chapter {
+                title: c.tags.get("title").cloned().unwrap_or_default(),
+                start_s: c.start_time.parse().ok()?,
+                end_s: c.end_time.parse().ok()?,
+            })
+        })
+        .collect())
+}
+
+const OPENING_TITLES: [&str; 5] = ["op", "opening", "片头", "オープニング", "主题曲"];
+const ENDING_TITLES: [&str; 4] = ["ed", "ending", "片尾", "エンディング"];
+const MAIN_TITLES: [&str; 4] = ["本编", "本編", "main", "part a"];
+
+/// 大多数 OP、ED 在 60 到 120 秒之间，章节长度超出时按这个范围统计弹幕
+const THEME_MIN_S: f64 = 60.0;
+const THEME_MAX_S: f64 = 120.0;
+/// 只在章节前后这个范围内查找弹幕最密集的位置
+const SEARCH_RANGE_S: f64 = 300.0;
+/// 窗口内的弹幕少于这个数量时不给出建议
+const MIN_PEAK_DANMUS: usize = 20;
+
+/// 推算的时间轴偏移
+#[derive(Debug, Clone, PartialEq)]
+pub struct OffsetSuggestion {
+    /// 与 `--time-offset` 含义相同，>0 表示弹幕需要延后
+    pub offset: f64,
+    /// 用于推算的章节，如 `OP`
+    pub chapter: String,
+    /// 章节在视频中的开始时间
+    pub chapter_start_s: f64,
+    /// 弹幕中对应位置的开始时间
+    pub danmu_start_s: f64,
+}
+
+impl OffsetSuggestion {
+    pub fn describe(&self) -> String {
+        format!(
+            "{} 章节开始于 {}，弹幕最密集的位置开始于 {}，建议 --time-offset {:.1}",
+            self.chapter,
+            time_label(self.chapter_start_s),
+            time_label(self.danmu_start_s),
+            self.offset
+        )
+    }
+}
+
+fn is_title(title: &str, names: &[&str]) -> bool {
+    let title = title.trim().to_lowercase();
+    names.iter().any(|name| {
+        title == *name
+            || title
+                .split(|c: char| !c.is_alphanumeric())
+                .any(|word| word == *name)
+            || (!name.is_ascii() && title.contains(name))
+    })
+}
+
+/// 名称为 OP 的章节，没有时使用 `本编` 前面的章节，都没有时使用 ED
+fn theme_chapter(chapters: &[Chapter]) -> Option<(String, &Chapter)> {
+    if let Some(op) = chapters
+        .iter()
+        .find(|c| is_title(&c.title, &OPENING_TITLES))
+    {
+        return Some(("OP".to_string(), op));
+    }
+    let main = chapters
+        .iter()
+        .position(|c| is_title(&c.title, &MAIN_TITLES))
+        .filter(|&i| i > 0);
+    if let Some(main) = main {
+        return Some((
+            format!("{} 前的章节", chapters[main].title),
+            &chapters[main - 1],
+        ));
+    }
+    chapters
+        .iter()
+        .find(|c| is_title(&c.title, &ENDING_TITLES))
+        .map(|ed| ("ED".to_string(), ed))
+}
+
+/// OP、ED 开始时弹幕最密集，比较章节开始时间和弹幕密度峰值的开始时间推算偏移，
+/// 峰值不明显时为 None
+pub fn suggest_offset(chapters: &[Chapter], danmus: &[Danmu]) -> Option<OffsetSuggestion> {
+    let (name, chapter) = theme_chapter(chapters)?;
+    let window = (chapter.end_s - chapter.start_s)
+        .clamp(THEME_MIN_S, THEME_MAX_S)
+        .round() as usize;
+
+    let mut per_second: Vec<usize> = vec![];
+    for danmu in danmus.iter().filter(|d| d.timeline_s >= 0.0) {
+        let second = danmu.timeline_s as usize;
+        if per_second.len() <= second {
+            per_second.resize(second + 1, 0);
+        }
+        per_second[second] += 1;
+    }
+    let count_from =
+        |start: usize| -> usize { per_second.iter().skip(start).take(window).sum::<usize>() };
+
+    let chapter_start = chapter.start_s.round() as usize;
+    let first = chapter_start.saturating_sub(SEARCH_RANGE_S as usize);
+    let last = chapter_start + SEARCH_RANGE_S as usize;
+    // 数量相同时取离章节最近的位置，已经对齐时建议为 0
+    let (peak_start, peak_count) = (first..=last)
+        .map(|start| (start, count_from(start)))
+        .max_by(|(a_start, a), (b_start, b)| {
+            a.cmp(b).then(
+                b_start
+                    .abs_diff(chapter_start)
+                    .cmp(&a_start.abs_diff(chapter_start)),
+            )
+        })?;
+
+    // 峰值需要明显高于整体的平均密度
+    let average = danmus.len() as f64 * window as f64 / per_second.len().max(1) as f64;
+    if peak_count < MIN_PEAK_DANMUS || (peak_count as f64) < average * 1.5 {
+        return None;
+    }
+    let offset = ((chapter.start_s - peak_start as f64) * 10.0).round() / 10.0;
+    Some(OffsetSuggestion {
+        offset,
+        chapter: name,
+        chapter_start_s: chapter.start_s,
+        danmu_start_s: peak_start as f64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chapter(title: &str, start_s: f64, end_s: f64) -> Chapter {
+        Chapter {
+            title: title.to_string(),
+            start_s,
+            end_s,
+        }
+    }
+
+    #[test]
+    fn test_parse_chapters() -> serde_json::Result<()> {
+        let json = r#"{"chapters": [
+            {"id": 0, "start_time": "0.000000", "end_time": "95.011000", "tags": {"title": "OP"}},
+            {"id": 1, "start_time": "95.011000", "end_time": "1300.000000"}
+        ]}"#;
+        assert_eq!(
+            parse_chapters(json)?,
+            [chapter("OP", 0.0, 95.011), chapter("", 95.011, 1300.0)]
+        );
+        assert_eq!(parse_chapters("{}")?, []);
+        Ok(())
+    }
+
+    #[test]
+    fn test_suggest_offset() {
+        // 正片前多了 10 秒的片头广告，弹幕在 90 秒开始刷 OP
+        let mut danmus: Vec<Danmu> = (0..1400)
+            .step_by(20)
+            .map(|s| Danmu {
+                timeline_s: s as f64,
+                ..Default::default()
+            })
+            .collect();
+        danmus.extend((0..180).map(|i| Danmu {
+            timeline_s: 90.0 + i as f64 / 2.0,
+            ..Default::default()
+        }));
+        let chapters = [
+            chapter("Avant", 0.0, 100.0),
+            chapter("Opening", 100.0, 190.0),
+            chapter("Part A", 190.0, 1400.0),
+        ];
+        let suggestion = suggest_offset(&chapters, &danmus).unwrap();
+        assert_eq!(suggestion.chapter, "OP");
+        assert_eq!(suggestion.danmu_start_s, 90.0);
+        assert_eq!(suggestion.offset, 10.0);
+        assert_eq!(
+            suggestion.describe(),
+            "OP 章节开始于 0:01:40，弹幕最密集的位置开始于 0:01:30，建议 --time-offset 10.0"
+        );
+
+        // 没有 OP 时使用本编前面的章节
+        let chapters = [
+            chapter("序章", 100.0, 190.0),
+            chapter("本编", 190.0, 1400.0),
+        ];
+        assert_eq!(suggest_offset(&chapters, &danmus).unwrap().offset, 10.0);
+
+        // 弹幕没有明显的峰值
+        let flat: Vec<Danmu> = danmus[..70].to_vec();
+        assert_eq!(suggest_offset(&chapters, &flat), None);
+        assert_eq!(
+            suggest_offset(&[chapter("Chapter 1", 0.0, 90.0)], &danmus),
+            None
+        );
+    }
+}
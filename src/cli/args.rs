@@ -1,30 +1,48 @@
-use crate::{CanvasConfig, Dandan, InputFile};
-use anyhow::{anyhow, Result};
-use clap::{Parser, ValueEnum};
-use std::{collections::HashSet, path::PathBuf};
-
-use super::input_path_to_list;
-
-#[derive(Clone, Debug, ValueEnum, serde::Deserialize)]
-pub enum SimplifiedOrTraditional {
-    #[serde(rename = "simplified")]
-    Simplified,
-    #[serde(rename = "traditional")]
-    Traditional,
-    #[serde(rename = "original")]
-    Original,
-}
+use crate::{
+    cancel::{is_cancelled_error, CancelFlag},
+    dandan::{CacheOptions, CommentsJson, ProcessOptions},
+    dandan_match::MatchOptions,
+    error::is_skipped_error,
+    set_ffmpeg_paths,
+    util::{
+        command_output, display_filename, ffmpeg_command_with, ffprobe_command_with,
+        video_chapters, video_duration, video_resolution,
+    },
+    CanvasConfig, ConversionReport, ConvertOptions, Dandan, DandanplayProvider, DanmuExport,
+    Denylist, EmbedConfig, Error, FileOutcome, FileReport, InputFile, KeepFilteredAsComments,
+    LocalDanmu, LogProgress, MergeBuiltIn, NegativeOffset, OnExisting, Progress, Selector,
+    SimplifiedOrTraditional, SourceOptions, SplitOutput, UpdateCheck,
+};
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use std::{collections::VecDeque, fs, path::PathBuf, sync::Arc, time::Duration};
+use tokio::task::JoinHandle;
+
+use super::{input_path_to_list, is_local_input, terminal_selector, ScanOptions, SearchType};
+
+/// 后台获取弹幕的任务
+type Fetching = JoinHandle<Result<(Option<CommentsJson>, Option<UpdateCheck>)>>;
 
 #[derive(Parser, Debug, serde::Deserialize)]
 pub struct Args {
-    #[clap(help = "需要转换的输入，可以是视频、文件夹", default_value = ".")]
+    #[clap(
+        help = "需要转换的输入，可以是视频、文件夹、弹幕 json 或 B 站格式的 xml",
+        default_value = "."
+    )]
     pub input: String,
 
-    #[clap(long = "width", help = "屏幕宽度", default_value = "1280")]
-    width: u32,
+    #[clap(long = "width", help = "屏幕宽度，默认为 1280")]
+    width: Option<u32>,
 
-    #[clap(long = "height", help = "屏幕高度", default_value = "720")]
-    height: u32,
+    #[clap(long = "height", help = "屏幕高度，默认为 720")]
+    height: Option<u32>,
+
+    #[clap(
+        long = "auto-resolution",
+        help = "使用 ffprobe 读取每个视频的分辨率作为屏幕宽高，指定了 --width、--height 时使用指定的值"
+    )]
+    #[serde(default)]
+    auto_resolution: bool,
 
     #[clap(
         value_enum,
@@ -34,6 +52,13 @@ pub struct Args {
     )]
     simplified_or_traditional: SimplifiedOrTraditional,
 
+    #[clap(
+        long = "no-local-convert",
+        help = "不在本地转换简繁，弹幕缓存和本地弹幕保持原来的写法，只使用 dandanplay 转换的结果"
+    )]
+    #[serde(default)]
+    no_local_convert: bool,
+
     #[clap(
         long = "merge-built-in-interactive",
         short = 'm',
@@ -43,11 +68,91 @@ pub struct Args {
 
     #[clap(
         long = "merge-built-in",
-        help = "与视频内置字幕合并，值为内置弹幕 stream index， 需要 ffmpeg 命令， 例如: --merge-built-in=0",
+        help = "与视频内置字幕合并，值为内置弹幕 stream index， 需要 ffmpeg 命令， 例如: --merge-built-in=0，逗号分隔多个 index 时每个字幕输出一个 ASS，如 --merge-built-in=3,5",
         default_value = ""
     )]
     merge_built_in: String,
 
+    #[clap(
+        long = "merge-built-in-lang",
+        help = "与视频内置字幕合并，按语言代码选择字幕，如 chi、eng，没有匹配时与 -m 一起使用可以手动选择，需要 ffmpeg 命令，可以多次使用，每个语言输出一个 ASS"
+    )]
+    #[serde(default)]
+    merge_built_in_lang: Vec<String>,
+
+    #[clap(
+        value_enum,
+        long = "merge-built-in-prefer",
+        help = "--merge-built-in-lang 匹配到多条字幕时，优先标题中带有简或繁的字幕，original 时使用第一条",
+        default_value = "simplified"
+    )]
+    merge_built_in_prefer: SimplifiedOrTraditional,
+
+    #[clap(
+        long = "ffmpeg-path",
+        help = "ffmpeg 的路径，默认使用环境变量 FFMPEG，没有时使用 PATH 中的 ffmpeg"
+    )]
+    #[serde(default)]
+    ffmpeg_path: Option<PathBuf>,
+
+    #[clap(
+        long = "ffprobe-path",
+        help = "ffprobe 的路径，默认使用环境变量 FFPROBE，没有时使用 PATH 中的 ffprobe"
+    )]
+    #[serde(default)]
+    ffprobe_path: Option<PathBuf>,
+
+    #[clap(
+        long = "ffmpeg-timeout",
+        help = "提取内置字幕时 ffmpeg、ffprobe 的超时时间，单位为秒，超时后跳过这个视频",
+        default_value = "600"
+    )]
+    ffmpeg_timeout: u64,
+
+    #[clap(
+        long = "probe-duration",
+        help = "使用 ffprobe 读取视频时长，丢弃超过时长的弹幕，读取失败时不处理"
+    )]
+    #[serde(default)]
+    probe_duration: bool,
+
+    #[clap(
+        long = "suggest-offset",
+        help = "使用 ffprobe 读取视频章节，比较 OP、ED 章节与弹幕最密集的位置，输出建议的 --time-offset，不会修改时间轴"
+    )]
+    #[serde(default)]
+    suggest_offset: bool,
+
+    #[clap(
+        long = "auto-offset",
+        help = "与 --suggest-offset 相同地推算时间轴偏移并直接使用，无法推算时不偏移",
+        conflicts_with = "time_offset"
+    )]
+    #[serde(default)]
+    auto_offset: bool,
+
+    #[clap(
+        long = "split-output",
+        help = "合并内置字幕时，额外输出一份只有弹幕的 ASS"
+    )]
+    #[serde(default)]
+    split_output: bool,
+
+    #[clap(
+        long = "split-danmu-suffix",
+        help = "--split-output 时只有弹幕的 ASS 后缀，例如 ep01.danmu.ass",
+        default_value = "danmu"
+    )]
+    split_danmu_suffix: String,
+
+    #[clap(
+        long = "split-merged-suffix",
+        help = "--split-output 时合并后的 ASS 后缀，为空时为 ep01.ass",
+        default_value = ""
+    )]
+    #[serde(default)]
+    split_merged_suffix: String,
+
     #[clap(
         long = "font",
         short = 'f',
@@ -59,6 +164,17 @@ pub struct Args {
     #[clap(long = "font-size", help = "弹幕字体大小", default_value = "35")]
     font_size: u32,
 
+    #[clap(
+        long = "merged-font",
+        help = "合并内置字幕时替换其样式的字体，不影响弹幕和行内 \\fn 标签"
+    )]
+    #[serde(default)]
+    merged_font: Option<String>,
+
+    #[clap(long = "merged-font-size", help = "合并内置字幕时替换其样式的字体大小")]
+    #[serde(default)]
+    merged_font_size: Option<u32>,
+
     #[clap(
         long = "lane-size",
         short = 'l',
@@ -98,6 +214,22 @@ pub struct Args {
     )]
     float_percentage: f64,
 
+    #[clap(
+        long = "bottom-percentage",
+        help = "屏幕上底部弹幕最多高度百分比",
+        default_value = "0.3"
+    )]
+    #[serde(default)]
+    pub bottom_percentage: f64,
+
+    #[clap(
+        long = "top-percentage",
+        help = "屏幕上顶部弹幕最多高度百分比",
+        default_value = "0"
+    )]
+    #[serde(default)]
+    pub top_percentage: f64,
+
     #[clap(
         long = "alpha",
         short = 'a',
@@ -106,18 +238,179 @@ pub struct Args {
     )]
     alpha: f64,
 
+    #[clap(
+        long = "source-dedupe-window",
+        help = "不同来源的相同弹幕在这个时间范围内只保留一条，0 表示不去重。单位：秒",
+        default_value = "2.0"
+    )]
+    #[serde(default)]
+    pub source_dedupe_window: f64,
+
+    #[clap(
+        long = "sources",
+        help = "只保留这些来源的弹幕，逗号分隔，例如 bilibili,gamer，弹弹 play 自身的弹幕为 dandanplay",
+        value_delimiter = ','
+    )]
+    #[serde(default)]
+    pub sources: Vec<String>,
+
+    #[clap(
+        long = "exclude-sources",
+        help = "去掉这些来源的弹幕，逗号分隔，例如 acfun",
+        value_delimiter = ','
+    )]
+    #[serde(default)]
+    pub exclude_sources: Vec<String>,
+
+    #[clap(
+        long = "align-sources",
+        help = "根据不同来源的相同弹幕估计各来源的时间偏移并修正"
+    )]
+    #[serde(default)]
+    pub align_sources: bool,
+
+    #[clap(
+        long = "align-min-anchors",
+        help = "--align-sources 修正时间偏移至少需要的相同弹幕数量",
+        default_value = "10"
+    )]
+    #[serde(default)]
+    pub align_min_anchors: usize,
+
+    #[clap(long = "label-sources", help = "在弹幕前加上来源标签，如 [B站]")]
+    #[serde(default)]
+    pub label_sources: bool,
+
     #[clap(
         long = "force",
         help = "默认会跳过已经存在 json 缓存的文件，此参数会强制更新"
     )]
     pub force: bool,
 
+    #[clap(
+        long = "cache-ttl",
+        help = "弹幕缓存有效期，超过后自动重新获取，0 表示永不过期。单位：小时",
+        default_value = "0"
+    )]
+    #[serde(default)]
+    pub cache_ttl: f64,
+
+    #[clap(
+        long = "update",
+        help = "重新获取弹幕并合并到已有缓存，保留已不再返回的弹幕"
+    )]
+    #[serde(default)]
+    pub update: bool,
+
+    #[clap(
+        long = "trust-cache",
+        help = "视频与获取弹幕时不同（如替换了版本）时仍然使用弹幕缓存"
+    )]
+    #[serde(default)]
+    pub trust_cache: bool,
+
+    #[clap(
+        long = "check-updates",
+        help = "使用缓存时重新请求一次弹幕，弹幕数量明显增加时提示更新缓存"
+    )]
+    #[serde(default)]
+    pub check_updates: bool,
+
+    #[clap(
+        long = "check-updates-threshold",
+        help = "--check-updates 时弹幕数量增加超过这个比例认为缓存已过时。单位：%",
+        default_value = "20"
+    )]
+    #[serde(default)]
+    pub check_updates_threshold: f64,
+
+    #[clap(
+        long = "prefetch",
+        help = "转换当前文件时提前获取后续文件弹幕的数量，0 表示不提前获取",
+        default_value = "2"
+    )]
+    #[serde(default)]
+    pub prefetch: usize,
+
     #[clap(long = "change-match", help = "修改识别结果")]
     pub change_match: bool,
 
+    #[clap(
+        long = "season-match",
+        help = "同一文件夹只需手动选择一次动画，其余文件根据文件名中的集数自动匹配"
+    )]
+    #[serde(default)]
+    pub season_match: bool,
+
+    #[clap(
+        long = "episode-offset",
+        help = "文件名中的集数与 dandanplay 不一致时的偏移，用于按集数搜索和 --season-match，例如文件为 01 而 dandanplay 为 13 时设为 12",
+        default_value = "0",
+        allow_hyphen_values = true
+    )]
+    #[serde(default)]
+    pub episode_offset: i32,
+
+    #[clap(
+        long = "search-type",
+        help = "手动搜索时只显示这种类型的动画",
+        value_enum,
+        default_value_t = SearchType::All
+    )]
+    #[serde(default)]
+    pub search_type: SearchType,
+
+    #[clap(long = "no-history", help = "不读取和保存手动搜索时输入的名字")]
+    #[serde(default)]
+    pub no_history: bool,
+
+    #[clap(
+        long = "strict-match",
+        help = "精确匹配到多个结果时报错，不选择也不自动使用第一个"
+    )]
+    #[serde(default)]
+    pub strict_match: bool,
+
+    #[clap(
+        long = "refresh",
+        help = "重新计算视频 hash 并请求匹配，不使用匹配结果缓存"
+    )]
+    #[serde(default)]
+    pub refresh: bool,
+
+    #[clap(
+        long = "report-match",
+        help = "把手动选择的匹配结果提交给 dandanplay，帮助改进自动匹配"
+    )]
+    #[serde(default)]
+    pub report_match: bool,
+
+    #[clap(
+        long = "no-hash",
+        help = "不计算视频 hash，只用文件名匹配，适合网络共享上的文件或重新编码过的视频"
+    )]
+    #[serde(default)]
+    pub no_hash: bool,
+
+    #[clap(
+        long = "yes",
+        short = 'y',
+        help = "不进行交互，无法精确匹配时自动选择搜索结果中最相似的一项"
+    )]
+    #[serde(default)]
+    pub yes: bool,
+
+    #[clap(
+        long = "auto-select-threshold",
+        help = "--yes 时自动选择需要的最低相似度，范围 0 ~ 1，低于这个值时跳过该文件",
+        default_value = "0.5"
+    )]
+    #[serde(default)]
+    pub auto_select_threshold: f64,
+
     #[clap(
         long = "denylist",
-        help = "黑名单，需要过滤的关键词列表文件，每行一个关键词"
+        help = "黑名单，需要过滤的关键词列表文件，每行一个关键词，user: 开头的行为屏蔽的用户，例如 user:[BiliBili]abc123"
     )]
     denylist: Option<PathBuf>,
 
@@ -127,6 +420,20 @@ pub struct Args {
     #[clap(long = "outline", help = "描边宽度", default_value = "0.8")]
     pub outline: f64,
 
+    #[clap(
+        long = "secondary-color",
+        help = "样式的 SecondaryColour，格式为 RRGGBB 或 RRGGBBAA，可带 # 前缀，AA 为不透明度",
+        default_value = "FFFFFF"
+    )]
+    pub secondary_color: String,
+
+    #[clap(
+        long = "back-color",
+        help = "样式的 BackColour（阴影/背景框颜色），格式为 RRGGBB 或 RRGGBBAA，可带 # 前缀，AA 为不透明度",
+        default_value = "000000"
+    )]
+    pub back_color: String,
+
     #[clap(long = "bold", help = "加粗")]
     #[serde(default)]
     pub bold: bool,
@@ -134,10 +441,172 @@ pub struct Args {
     #[clap(
         long = "time-offset",
         help = "时间轴偏移，>0 会让弹幕延后，<0 会让弹幕提前，单位为秒",
-        default_value = "0.0"
+        default_value = "0.0",
+        allow_negative_numbers = true
     )]
     #[serde(default)]
     pub time_offset: f64,
+
+    #[clap(
+        value_enum,
+        long = "negative-offset",
+        help = "时间轴偏移后开始时间小于 0 的弹幕的处理方式，clamp 会从 0 秒开始显示还在屏幕上的部分",
+        default_value = "drop"
+    )]
+    #[serde(default)]
+    pub negative_offset: NegativeOffset,
+
+    #[clap(
+        long = "emit-cid",
+        help = "在 Dialogue 的 Name 字段写入弹幕 id，方便在反馈问题时指出具体的弹幕"
+    )]
+    #[serde(default)]
+    pub emit_cid: bool,
+
+    #[clap(
+        long = "export-csv",
+        help = "导出过滤和排版后的弹幕列表为 CSV，值为目录或处理多个视频时按视频文件名生成"
+    )]
+    #[serde(default)]
+    pub export_csv: Option<PathBuf>,
+
+    #[clap(
+        long = "export-json",
+        help = "导出过滤和排版后的弹幕列表为 JSON，值为目录或处理多个视频时按视频文件名生成"
+    )]
+    #[serde(default)]
+    pub export_json: Option<PathBuf>,
+
+    #[clap(
+        long = "embed",
+        help = "将生成的 ASS 作为字幕轨封装进 mkv，输出为 file.danmu.mkv，需要 ffmpeg 命令"
+    )]
+    #[serde(default)]
+    pub embed: bool,
+
+    #[clap(
+        long = "embed-in-place",
+        help = "封装成功后直接替换原 mkv 文件，隐含 --embed"
+    )]
+    #[serde(default)]
+    pub embed_in_place: bool,
+
+    #[clap(
+        long = "embed-language",
+        help = "封装字幕轨的语言标签",
+        default_value = "chi"
+    )]
+    #[serde(default)]
+    pub embed_language: String,
+
+    #[clap(
+        long = "embed-title",
+        help = "封装字幕轨的标题",
+        default_value = "Danmaku"
+    )]
+    #[serde(default)]
+    pub embed_title: String,
+
+    #[clap(
+        value_enum,
+        long = "keep-filtered-as-comments",
+        help = "将被过滤的弹幕以 Comment 行写入 ASS，方便在 Aegisub 中调试黑名单，值为 all 时同时保留被丢弃的弹幕",
+        num_args = 0..=1,
+        default_missing_value = "denylist"
+    )]
+    #[serde(default)]
+    pub keep_filtered_as_comments: Option<KeepFilteredAsComments>,
+
+    #[clap(
+        value_enum,
+        long = "on-existing",
+        help = "输出的 ASS 已经存在时的处理方式，backup 会先备份为 .bak 文件，skip 会保留已有文件",
+        default_value = "overwrite"
+    )]
+    #[serde(default)]
+    pub on_existing: OnExisting,
+
+    #[clap(
+        long = "output-suffix",
+        help = "输出的 ASS 后缀，例如 danmu 输出为 ep01.danmu.ass，为空时为 ep01.ass",
+        default_value = ""
+    )]
+    #[serde(default)]
+    pub output_suffix: String,
+
+    #[clap(
+        long = "include-json",
+        help = "扫描文件夹时同时转换找不到对应视频的 .dandanplay.json 弹幕缓存"
+    )]
+    #[serde(default)]
+    pub include_json: bool,
+
+    #[clap(
+        long = "skip-samples",
+        help = "扫描文件夹时跳过文件名中带有单独 sample 一词的样片，--skip-samples false 时处理",
+        default_value_t = true,
+        action = clap::ArgAction::Set
+    )]
+    pub skip_samples: bool,
+
+    #[clap(
+        long = "local-danmu",
+        help = "使用本地弹幕文件代替获取弹幕，支持弹幕 json 和 B 站格式的 xml，只能用于单个视频"
+    )]
+    #[serde(default)]
+    pub local_danmu: Option<PathBuf>,
+
+    #[clap(
+        long = "extra-danmu",
+        help = "合并额外的本地弹幕文件，可以多次使用，支持弹幕 json 和 B 站格式的 xml，FILE:+3.5 表示时间延后 3.5 秒"
+    )]
+    #[serde(default)]
+    pub extra_danmu: Vec<String>,
+
+    #[clap(
+        long = "ext-url",
+        help = "不进行匹配，通过 dandanplay 从第三方视频网址（B 站、巴哈姆特等）获取弹幕，可以多次使用，结果会合并去重，只能用于单个视频"
+    )]
+    #[serde(default)]
+    pub ext_url: Vec<String>,
+
+    #[clap(
+        long = "report-json",
+        help = "将每个文件的处理结果以 json 格式写入文件，包括匹配结果、弹幕数量和耗时"
+    )]
+    #[serde(default)]
+    pub report_json: Option<PathBuf>,
+}
+
+/// `--report-json` 中单个文件的结果，失败时只有 `input` 和 `error`
+#[derive(Debug, serde::Serialize)]
+pub(super) struct FileReportJson {
+    input: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(flatten)]
+    pub(super) report: Option<ConversionReport>,
+}
+
+impl FileReportJson {
+    fn new(input_file: &InputFile, result: &Result<ConversionReport>) -> Self {
+        let (report, error) = match result {
+            Ok(report) => (Some(report.clone()), None),
+            Err(e) if is_skipped_error(e) => (
+                Some(ConversionReport {
+                    skipped: true,
+                    ..Default::default()
+                }),
+                None,
+            ),
+            Err(e) => (None, Some(format!("{:#}", e))),
+        };
+        Self {
+            input: input_file.path.clone(),
+            error,
+            report,
+        }
+    }
 }
 
 impl Args {
@@ -150,20 +619,140 @@ impl Args {
                 return Err(anyhow!("黑名单文件不能是目录"));
             }
         }
-        if self.float_percentage < 0.0 {
-            return Err(anyhow!("滚动弹幕最大高度百分比不能小于 0"));
+        if let Some(f) = &self.local_danmu {
+            if !f.is_file() {
+                return Err(anyhow!("本地弹幕文件 {} 不存在", f.display()));
+            }
+        }
+        for url in &self.ext_url {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err(anyhow!("--ext-url 应为 http 或 https 网址：{}", url));
+            }
+        }
+        if self.report_json.as_ref().is_some_and(|f| f.is_dir()) {
+            return Err(anyhow!("--report-json 不能是目录"));
+        }
+        if !self.ext_url.is_empty() && self.local_danmu.is_some() {
+            return Err(anyhow!("--ext-url 和 --local-danmu 不能同时使用"));
+        }
+        for extra in &self.extra_danmu {
+            let (path, _) = parse_extra_danmu(extra)
+                .with_context(|| format!("--extra-danmu 格式错误：{}", extra))?;
+            if !path.is_file() {
+                return Err(anyhow!("额外弹幕文件 {} 不存在", path.display()));
+            }
+        }
+        if !(0.0..=1.0).contains(&self.auto_select_threshold) {
+            return Err(anyhow!("自动选择的相似度阈值应在 0 ~ 1 之间"));
+        }
+        if self.check_updates_threshold < 0.0 {
+            return Err(anyhow!("检查更新的阈值不能小于 0"));
         }
-        if self.float_percentage > 1.0 {
-            return Err(anyhow!("滚动弹幕最大高度百分比不能大于 1"));
+        if self.cache_ttl < 0.0 {
+            return Err(anyhow!("弹幕缓存有效期不能小于 0"));
         }
+        for (arg, name, percentage) in self.areas() {
+            if percentage < 0.0 {
+                return Err(anyhow!("{}最大高度百分比不能小于 0", name));
+            }
+            if percentage > 1.0 {
+                return Err(anyhow!("{}最大高度百分比不能大于 1", name));
+            }
+            if arg != "--float-percentage" && self.float_percentage + percentage > 1.0 {
+                return Err(anyhow!(
+                    "--float-percentage {} 与 {} {} 之和超过 1，{}会与滚动弹幕重叠，请调小其中一个",
+                    self.float_percentage,
+                    arg,
+                    percentage,
+                    name
+                ));
+            }
+        }
+        if self.float_percentage + self.bottom_percentage + self.top_percentage > 1.0 {
+            return Err(anyhow!(
+                "--float-percentage {}、--bottom-percentage {} 与 --top-percentage {} 之和超过 1，\
+                 弹幕区域会重叠，请调小其中一个",
+                self.float_percentage,
+                self.bottom_percentage,
+                self.top_percentage
+            ));
+        }
+        if self.lane_size == 0 {
+            return Err(anyhow!("--lane-size 不能为 0"));
+        }
+        // --auto-resolution 且没有指定 --height 时，读取视频分辨率后再检查
+        if !(self.auto_resolution && self.height.is_none()) {
+            self.check_lane_areas(self.height.unwrap_or(720))?;
+        }
+        if self.split_output {
+            if self.split_danmu_suffix == self.split_merged_suffix {
+                return Err(anyhow!(
+                    "--split-danmu-suffix 与 --split-merged-suffix 不能相同"
+                ));
+            }
+            if self.merge_built_in.is_empty()
+                && !self.merge_built_in_interactive
+                && self.merge_built_in_lang.is_empty()
+            {
+                warn!("--split-output 需要配合 --merge-built-in、--merge-built-in-lang 或 -m 使用");
+            }
+        }
+        // 提前确认 ffmpeg 可以运行，避免获取完弹幕才报错
+        if !self.merge_built_in.is_empty()
+            || self.merge_built_in_interactive
+            || !self.merge_built_in_lang.is_empty()
+        {
+            command_output(ffmpeg_command_with(self.ffmpeg_path.clone()).arg("-version"))?;
+        }
+        if self.merge_built_in_interactive || !self.merge_built_in_lang.is_empty() {
+            command_output(ffprobe_command_with(self.ffprobe_path.clone()).arg("-version"))?;
+        }
+        parse_ass_colour(&self.secondary_color)
+            .with_context(|| format!("--secondary-color 格式错误：{}", self.secondary_color))?;
+        parse_ass_colour(&self.back_color)
+            .with_context(|| format!("--back-color 格式错误：{}", self.back_color))?;
 
         Ok(())
     }
 
+    /// 各个弹幕区域的参数名、名称和高度百分比
+    fn areas(&self) -> [(&'static str, &'static str, f64); 3] {
+        [
+            ("--float-percentage", "滚动弹幕", self.float_percentage),
+            ("--bottom-percentage", "底部弹幕", self.bottom_percentage),
+            ("--top-percentage", "顶部弹幕", self.top_percentage),
+        ]
+    }
+
+    /// 区域放不下一行时所有弹幕都会被丢弃，为 0 时视为不需要这种弹幕
+    fn check_lane_areas(&self, height: u32) -> Result<()> {
+        for (arg, name, percentage) in self.areas() {
+            if percentage > 0.0 && percentage * (height as f64) < self.lane_size as f64 {
+                return Err(anyhow!(
+                    "{} {} 时{}区域只有 {:.0} 像素，放不下一行 --lane-size {} 的弹幕，\
+                     请将 {} 调到 {:.2} 以上或调小 --lane-size",
+                    arg,
+                    percentage,
+                    name,
+                    percentage * height as f64,
+                    self.lane_size,
+                    arg,
+                    (self.lane_size as f64 / height as f64 * 100.0).ceil() / 100.0
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// 使用 `--ffmpeg-path`、`--ffprobe-path` 作为整个进程的 ffmpeg、ffprobe，在 main 中调用一次
+    pub fn set_ffmpeg_paths(&self) {
+        set_ffmpeg_paths(self.ffmpeg_path.clone(), self.ffprobe_path.clone());
+    }
+
     pub fn canvas_config(&self) -> CanvasConfig {
         CanvasConfig {
-            width: self.width,
-            height: self.height,
+            width: self.width.unwrap_or(1280),
+            height: self.height.unwrap_or(720),
             font: self.font.clone(),
             font_size: self.font_size,
             width_ratio: self.width_ratio,
@@ -172,59 +761,402 @@ impl Args {
             lane_size: self.lane_size,
             float_percentage: self.float_percentage,
             opacity: ((1.0 - self.alpha) * 255.0) as u8,
-            bottom_percentage: 0.3,
+            bottom_percentage: self.bottom_percentage,
+            top_percentage: self.top_percentage,
             outline: self.outline,
             bold: u8::from(self.bold),
             time_offset: self.time_offset,
+            negative_offset: self.negative_offset,
+            secondary_colour: parse_ass_colour(&self.secondary_color).unwrap_or(0x00FFFFFF),
+            back_colour: parse_ass_colour(&self.back_color).unwrap_or(0x00000000),
+            merged_font: self.merged_font.clone(),
+            merged_font_size: self.merged_font_size,
+            video_duration: None,
+            emit_cid: self.emit_cid,
+            ch_convert: match (&self.simplified_or_traditional, self.no_local_convert) {
+                (SimplifiedOrTraditional::Original, _) | (_, true) => None,
+                (to, false) => Some(to.clone()),
+            },
+            chapters: None,
+            auto_offset: self.auto_offset,
         }
     }
 
-    fn denylist(&self) -> Result<Option<HashSet<String>>> {
+    /// 开启 `--probe-duration`、`--auto-resolution`、`--suggest-offset` 时填入视频时长、分辨率和章节，
+    /// ffprobe 不可用时与不开启相同，视频分辨率放不下弹幕区域时返回错误
+    fn canvas_config_for(
+        &self,
+        input_file: &InputFile,
+        canvas_config: &CanvasConfig,
+    ) -> Result<CanvasConfig> {
+        let mut canvas_config = canvas_config.clone();
+        if self.auto_resolution && (self.width.is_none() || self.height.is_none()) {
+            match video_resolution(&input_file.path) {
+                Ok(probed) => {
+                    let (width, height) = self.resolution(probed);
+                    info!(
+                        "{}",
+                        input_file.log(&format!(
+                            "视频分辨率 {}x{}，屏幕宽高使用 {}x{}",
+                            probed.0, probed.1, width, height
+                        ))
+                    );
+                    canvas_config.width = width;
+                    canvas_config.height = height;
+                    self.check_lane_areas(height)?;
+                }
+                Err(e) => warn!(
+                    "{} {:#}",
+                    input_file.log(&format!(
+                        "无法读取视频分辨率，使用 {}x{}",
+                        canvas_config.width, canvas_config.height
+                    )),
+                    e
+                ),
+            }
+        }
+        if self.suggest_offset || self.auto_offset {
+            match video_chapters(&input_file.path) {
+                Ok(chapters) => canvas_config.chapters = Some(chapters),
+                Err(e) => warn!(
+                    "{} {:#}",
+                    input_file.log("无法读取视频章节，不推算时间轴偏移"),
+                    e
+                ),
+            }
+        }
+        if self.probe_duration {
+            match video_duration(&input_file.path) {
+                Ok(duration) => canvas_config.video_duration = Some(duration),
+                Err(e) => warn!(
+                    "{} {:#}",
+                    input_file.log("无法读取视频时长，不丢弃超过时长的弹幕"),
+                    e
+                ),
+            }
+        }
+        Ok(canvas_config)
+    }
+
+    /// 只指定了宽或高时，另一边按视频的宽高比计算
+    fn resolution(&self, (probed_width, probed_height): (u32, u32)) -> (u32, u32) {
+        let scale =
+            |value: u32, num: u32, den: u32| (value as u64 * num as u64 / den as u64) as u32;
+        match (self.width, self.height) {
+            (Some(width), Some(height)) => (width, height),
+            (Some(width), None) => (width, scale(width, probed_height, probed_width)),
+            (None, Some(height)) => (scale(height, probed_width, probed_height), height),
+            (None, None) => (probed_width, probed_height),
+        }
+    }
+
+    pub fn merge_built_in(&self) -> MergeBuiltIn {
+        MergeBuiltIn {
+            index: self.merge_built_in.clone(),
+            lang: self.merge_built_in_lang.clone(),
+            prefer: self.merge_built_in_prefer.clone(),
+            interactive: self.merge_built_in_interactive,
+            timeout: Duration::from_secs(self.ffmpeg_timeout),
+        }
+    }
+
+    pub fn danmu_export(&self) -> DanmuExport {
+        DanmuExport {
+            csv: self.export_csv.clone(),
+            json: self.export_json.clone(),
+            per_file: false,
+        }
+    }
+
+    pub fn source_options(&self) -> SourceOptions {
+        SourceOptions {
+            dedupe_window: self.source_dedupe_window,
+            label: self.label_sources,
+            include: self.sources.clone(),
+            exclude: self.exclude_sources.clone(),
+            align: self.align_sources,
+            align_min_anchors: self.align_min_anchors,
+        }
+    }
+
+    pub fn split_output(&self) -> Option<SplitOutput> {
+        self.split_output.then(|| SplitOutput {
+            danmu_suffix: self.split_danmu_suffix.clone(),
+            merged_suffix: self.split_merged_suffix.clone(),
+        })
+    }
+
+    pub fn embed_config(&self) -> Option<EmbedConfig> {
+        if !self.embed && !self.embed_in_place {
+            return None;
+        }
+        Some(EmbedConfig {
+            in_place: self.embed_in_place,
+            language: self.embed_language.clone(),
+            title: self.embed_title.clone(),
+        })
+    }
+
+    /// 标准输入不是终端时（作为服务运行、被其他程序调用）或没有 `interactive` 功能时不进行交互，
+    /// 需要选择时返回错误
+    pub fn selector(&self) -> Arc<dyn Selector> {
+        terminal_selector()
+    }
+
+    /// 匹配弹幕的参数
+    pub fn match_options(&self) -> MatchOptions {
+        MatchOptions {
+            change_match: self.change_match,
+            season_match: self.season_match,
+            episode_offset: self.episode_offset,
+            auto_select: self.yes.then_some(self.auto_select_threshold),
+            no_hash: self.no_hash,
+            report_match: self.report_match,
+            refresh: self.refresh,
+            search_type: self.search_type,
+            no_history: self.no_history,
+            strict_match: self.strict_match,
+            seasons: Default::default(),
+        }
+    }
+
+    /// 匹配、获取弹幕和读写弹幕缓存的参数
+    pub fn cache_options(&self) -> CacheOptions {
+        CacheOptions {
+            force: self.force,
+            simplified_or_traditional: self.simplified_or_traditional.clone(),
+            cache_ttl: self.cache_ttl,
+            update: self.update,
+            check_updates: self.check_updates.then_some(self.check_updates_threshold),
+            trust_cache: self.trust_cache,
+            match_options: self.match_options(),
+            ext_urls: self.ext_url.clone(),
+        }
+    }
+
+    /// 按参数匹配并获取弹幕的 dandanplay 来源
+    pub fn provider(
+        &self,
+        selector: Arc<dyn Selector>,
+        progress: Arc<dyn Progress>,
+    ) -> DandanplayProvider {
+        DandanplayProvider {
+            options: self.cache_options(),
+            selector,
+            progress,
+        }
+    }
+
+    /// 生成 ASS 的参数，处理视频时还会按 `canvas_config_for` 填入每个视频的信息
+    pub fn process_options(&self) -> Result<ProcessOptions> {
+        Ok(ProcessOptions {
+            force: self.force,
+            canvas_config: self.canvas_config(),
+            denylist: self.denylist()?,
+            export: self.danmu_export(),
+            keep_filtered: self.keep_filtered_as_comments,
+            on_existing: self.on_existing,
+            output_suffix: self.output_suffix.clone(),
+            sources: self.source_options(),
+            merge_built_in: self.merge_built_in(),
+            split_output: self.split_output(),
+            embed: self.embed_config(),
+        })
+    }
+
+    /// 弹幕转换的参数，`export` 开启时记录每条弹幕的处理结果
+    pub(super) fn convert_options(
+        &self,
+        title: String,
+        export: &DanmuExport,
+    ) -> Result<ConvertOptions> {
+        Ok(ConvertOptions {
+            denylist: self.denylist()?,
+            keep_filtered: self.keep_filtered_as_comments,
+            sources: self.source_options(),
+            export_records: export.is_enabled(),
+            ..ConvertOptions::new(title, self.canvas_config())
+        })
+    }
+
+    pub(super) fn denylist(&self) -> Result<Option<Denylist>> {
         match self.denylist.as_ref() {
             None => Ok(None),
             Some(path) => {
-                let denylist = std::fs::read_to_string(path)?;
-                let list: HashSet<String> = denylist
-                    .split('\n')
-                    .map(|s| s.trim().to_string())
-                    .filter(|s| !s.is_empty())
-                    .collect();
-                info!("黑名单载入 {} 个", list.len());
+                let list = Denylist::load(path)?;
+                info!(
+                    "黑名单载入 {} 个关键词，{} 个用户",
+                    list.keywords.len(),
+                    list.users.len()
+                );
                 debug!("黑名单：{:?}", list);
                 Ok(Some(list))
             }
         }
     }
 
-    pub async fn process(&self) -> Result<()> {
-        let canvas_config = self.canvas_config();
-        let denylist = self.denylist()?;
+    /// `cancel` 取消后不再处理后续文件，输出已处理文件的统计并返回 `Error::Cancelled`
+    pub async fn process(&self, cancel: &CancelFlag) -> Result<()> {
+        self.process_with_progress(Arc::new(LogProgress), cancel)
+            .await
+    }
+
+    /// 每个文件的处理进度和结果通过 `progress` 通知
+    pub async fn process_with_progress(
+        &self,
+        progress: Arc<dyn Progress>,
+        cancel: &CancelFlag,
+    ) -> Result<()> {
+        self.run(progress, cancel).await?;
+        Ok(())
+    }
+
+    /// 返回每个文件的处理结果，顺序为先处理的 json、xml，再处理视频
+    pub(super) async fn run(
+        &self,
+        progress: Arc<dyn Progress>,
+        cancel: &CancelFlag,
+    ) -> Result<Vec<FileReportJson>> {
+        let mut opts = self.process_options()?;
+        let selector = self.selector();
+        let provider = Arc::new(self.provider(selector.clone(), progress.clone()));
 
-        let filepaths = input_path_to_list(&self.input)?;
-        if filepaths.is_empty() {
+        let input_files = input_path_to_list(
+            &self.input,
+            &ScanOptions {
+                include_json: self.include_json,
+                skip_samples: self.skip_samples,
+            },
+        )?;
+        if input_files.is_empty() {
             return Err(anyhow!("没有找到任何文件"));
         }
 
-        info!("共找到 {} 个文件", filepaths.len());
+        info!("共找到 {} 个文件", input_files.len());
+        let input_total = input_files.len();
+        opts.export.per_file = input_total > 1;
         let t = std::time::Instant::now();
         let mut process_file_total = 0;
         let mut process_danmu_total = 0;
+        let mut checked_total = 0;
+        let mut skipped_total = 0;
+        let mut stale_files = vec![];
+        let mut reports = vec![];
 
-        for filepath in filepaths {
-            let input_file = InputFile::from(&filepath);
-            let (file_count, danmu_count) = match Dandan::process_by_path(
+        // json、xml 输入直接离线转换
+        let (local_files, input_files): (Vec<_>, Vec<_>) =
+            input_files.into_iter().partition(is_local_input);
+        for input_file in local_files {
+            if cancel.is_cancelled() {
+                break;
+            }
+            let result = Dandan::process_by_local_file(
                 &input_file,
-                self.force,
-                self.change_match,
-                self.simplified_or_traditional.clone(),
-                self.merge_built_in_interactive,
-                self.merge_built_in.clone(),
-                canvas_config.clone(),
-                &denylist,
-            )
-            .await
-            {
-                Ok(danmu_count) => (1, danmu_count),
+                &opts,
+                &Dandan::local_output_path(&input_file, &self.output_suffix),
+                progress.as_ref(),
+            );
+            progress.on_file_done(&FileReport {
+                file: input_file.clone(),
+                outcome: FileOutcome::from_result(&result),
+            });
+            reports.push(FileReportJson::new(&input_file, &result));
+            match result {
+                Ok(report) => {
+                    process_file_total += 1;
+                    process_danmu_total += report.danmu_count;
+                }
+                Err(e) => error!("{} {:?}", input_file.log("文件转换错误"), e),
+            }
+        }
+
+        if (self.local_danmu.is_some() || !self.extra_danmu.is_empty() || !self.ext_url.is_empty())
+            && input_files.len() != 1
+        {
+            return Err(anyhow!(
+                "--local-danmu、--extra-danmu 和 --ext-url 只能用于单个视频"
+            ));
+        }
+        let mut local_danmus = vec![];
+        if let Some(path) = &self.local_danmu {
+            local_danmus.push(LocalDanmu::load(path, 0.0)?);
+        }
+        for extra in &self.extra_danmu {
+            let (path, offset) = parse_extra_danmu(extra)?;
+            local_danmus.push(LocalDanmu::load(&path, offset)?);
+        }
+
+        // 转换当前文件时，后续文件的匹配和下载在后台进行
+        let mut input_files = input_files.into_iter();
+        let mut pending: VecDeque<(InputFile, Fetching)> = VecDeque::new();
+        loop {
+            if cancel.is_cancelled() {
+                for (_, fetching) in pending.drain(..) {
+                    fetching.abort();
+                }
+                break;
+            }
+            while pending.len() <= self.prefetch {
+                let Some(input_file) = input_files.next() else {
+                    break;
+                };
+                pending.push_back(self.spawn_fetch(input_file, &opts.embed, &provider, cancel));
+            }
+            let Some((input_file, fetching)) = pending.pop_front() else {
+                break;
+            };
+            let file_t = std::time::Instant::now();
+            let result = match fetching.await {
+                Ok(Ok((comments_json, update_check))) => {
+                    if let Some(update_check) = update_check {
+                        checked_total += 1;
+                        if update_check.stale {
+                            stale_files.push(input_file.display_filename());
+                        }
+                    }
+                    async {
+                        let file_opts = ProcessOptions {
+                            canvas_config: self
+                                .canvas_config_for(&input_file, &opts.canvas_config)?,
+                            ..opts.clone()
+                        };
+                        Dandan::process_by_comments(
+                            &input_file,
+                            comments_json,
+                            &local_danmus,
+                            &file_opts,
+                            &selector,
+                            progress.as_ref(),
+                            cancel,
+                        )
+                        .await
+                    }
+                    .await
+                }
+                Ok(Err(e)) => Err(e),
+                Err(e) => Err(e.into()),
+            };
+            // 获取弹幕在后台进行，从等待获取完成开始计时
+            let result = result.map(|report| ConversionReport {
+                elapsed_s: file_t.elapsed().as_secs_f64(),
+                ..report
+            });
+            progress.on_file_done(&FileReport {
+                file: input_file.clone(),
+                outcome: FileOutcome::from_result(&result),
+            });
+            reports.push(FileReportJson::new(&input_file, &result));
+            let (file_count, danmu_count) = match result {
+                Ok(report) => (1, report.danmu_count),
+                Err(e) if is_skipped_error(&e) => {
+                    info!("{}", input_file.log("已手动跳过"));
+                    skipped_total += 1;
+                    (0, 0)
+                }
+                Err(e) if is_cancelled_error(&e) => {
+                    warn!("{}", input_file.log("已取消"));
+                    (0, 0)
+                }
                 Err(e) => {
                     error!("{} {:?}", input_file.log("文件转换错误"), e);
                     (0, 0)
@@ -240,7 +1172,334 @@ impl Args {
             process_danmu_total,
             t.elapsed()
         );
+        let converted = reports.iter().filter_map(|r| r.report.as_ref());
+        // 合并多条内置字幕时一个视频会输出多个 ASS
+        let written: Vec<_> = converted
+            .clone()
+            .flat_map(|r| r.written.iter().map(display_filename))
+            .collect();
+        if written.len() > process_file_total {
+            info!("共写入 {} 个 ASS：{}", written.len(), written.join(", "));
+        }
+        let (unchanged_total, filtered_total, dropped_total) =
+            converted.fold((0, 0, 0), |(unchanged, filtered, dropped), r| {
+                (
+                    unchanged + r.skipped as usize,
+                    filtered + r.filtered,
+                    dropped + r.dropped,
+                )
+            });
+        if filtered_total > 0 || dropped_total > 0 {
+            info!(
+                "黑名单过滤 {} 条弹幕，没有位置丢弃 {} 条弹幕",
+                filtered_total, dropped_total
+            );
+        }
+        if unchanged_total > skipped_total {
+            info!(
+                "{} 个文件的 ASS 已经存在或未变化，跳过生成",
+                unchanged_total - skipped_total
+            );
+        }
+        if skipped_total > 0 {
+            info!(
+                "手动跳过 {} 个文件，使用 --change-match 参数重新选择",
+                skipped_total
+            );
+        }
+        if self.check_updates {
+            if stale_files.is_empty() {
+                info!("检查了 {} 个弹幕缓存，均无需更新", checked_total);
+            } else {
+                warn!(
+                    "检查了 {} 个弹幕缓存，{} 个已过时，使用 --update 或 --force 参数更新：{}",
+                    checked_total,
+                    stale_files.len(),
+                    stale_files.join(", ")
+                );
+            }
+        }
+        if let Some(path) = &self.report_json {
+            fs::write(path, serde_json::to_string_pretty(&reports)?)
+                .with_context(|| format!("处理结果写入 {} 失败", display_filename(path)))?;
+            info!("处理结果已写入 {}", display_filename(path));
+        }
+        if cancel.is_cancelled() {
+            warn!("已取消，{} 个文件没有处理", input_total - reports.len());
+            return Err(Error::Cancelled.into());
+        }
+
+        Ok(reports)
+    }
+
+    /// 只匹配并下载弹幕到 `.dandanplay.json` 缓存，不生成 ASS，返回每个视频缓存的弹幕数量
+    pub(super) async fn fetch_only(
+        &self,
+        progress: Arc<dyn Progress>,
+        cancel: &CancelFlag,
+    ) -> Result<Vec<(InputFile, Result<usize>)>> {
+        let embed = self.embed_config();
+        let provider = Arc::new(self.provider(self.selector(), progress));
+        let input_files: Vec<_> = input_path_to_list(&self.input, &ScanOptions::default())?
+            .into_iter()
+            .filter(|f| !is_local_input(f))
+            .collect();
+        if input_files.is_empty() {
+            return Err(anyhow!("没有找到任何视频"));
+        }
+
+        let mut input_files = input_files.into_iter();
+        let mut pending: VecDeque<(InputFile, Fetching)> = VecDeque::new();
+        let mut results = vec![];
+        loop {
+            if cancel.is_cancelled() {
+                for (_, fetching) in pending.drain(..) {
+                    fetching.abort();
+                }
+                break;
+            }
+            while pending.len() <= self.prefetch {
+                let Some(input_file) = input_files.next() else {
+                    break;
+                };
+                pending.push_back(self.spawn_fetch(input_file, &embed, &provider, cancel));
+            }
+            let Some((input_file, fetching)) = pending.pop_front() else {
+                break;
+            };
+            let result = fetching
+                .await
+                .unwrap_or_else(|e| Err(e.into()))
+                .map(|(comments_json, _)| comments_json.map_or(0, |c| c.comments.len()));
+            results.push((input_file, result));
+        }
+        Ok(results)
+    }
+
+    /// 在后台匹配并下载弹幕
+    fn spawn_fetch(
+        &self,
+        input_file: InputFile,
+        embed: &Option<EmbedConfig>,
+        provider: &Arc<DandanplayProvider>,
+        cancel: &CancelFlag,
+    ) -> (InputFile, Fetching) {
+        let embed = embed.clone();
+        let use_local = self.local_danmu.is_some();
+        let provider = provider.clone();
+        let cancel = cancel.clone();
+        let task_input_file = input_file.clone();
+        let fetching = tokio::spawn(async move {
+            let input_file = task_input_file;
+            Dandan::check_input(&input_file, &embed)?;
+            cancel.check()?;
+            if use_local {
+                return Ok((None, None));
+            }
+            let (comments_json, update_check) =
+                provider.fetch_with_update_check(&input_file).await?;
+            Ok((Some(comments_json), update_check))
+        });
+        (input_file, fetching)
+    }
+}
+
+/// `FILE` 或 `FILE:+3.5`，偏移必须带符号，避免和 Windows 盘符混淆
+fn parse_extra_danmu(s: &str) -> Result<(PathBuf, f64)> {
+    if let Some((path, offset)) = s.rsplit_once(':') {
+        if offset.starts_with(['+', '-']) {
+            let offset = offset
+                .parse::<f64>()
+                .ok()
+                .filter(|o| o.is_finite())
+                .context("时间偏移应为 +秒数 或 -秒数")?;
+            return Ok((PathBuf::from(path), offset));
+        }
+    }
+    Ok((PathBuf::from(s), 0.0))
+}
+
+/// 将 `#RRGGBB` / `RRGGBBAA` 转为 ASS 的 AABBGGRR，ASS 中 alpha 表示透明度
+fn parse_ass_colour(s: &str) -> Result<u32> {
+    let hex = s.trim().trim_start_matches('#');
+    if !(hex.len() == 6 || hex.len() == 8) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!("颜色应为 RRGGBB 或 RRGGBBAA"));
+    }
+    let value = u32::from_str_radix(hex, 16)?;
+    let (rgb, opacity) = if hex.len() == 6 {
+        (value, 0xFF)
+    } else {
+        (value >> 8, value & 0xFF)
+    };
+    let (r, g, b) = ((rgb >> 16) & 0xFF, (rgb >> 8) & 0xFF, rgb & 0xFF);
+    Ok(((0xFF - opacity) << 24) | (b << 16) | (g << 8) | r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ass_colour() {
+        assert_eq!(parse_ass_colour("FFFFFF").unwrap(), 0x00FFFFFF);
+        assert_eq!(parse_ass_colour("#000000").unwrap(), 0x00000000);
+        assert_eq!(parse_ass_colour("#112233").unwrap(), 0x00332211);
+        assert_eq!(parse_ass_colour("11223380").unwrap(), 0x7F332211);
+        assert_eq!(parse_ass_colour("#11223300").unwrap(), 0xFF332211);
+        assert!(parse_ass_colour("").is_err());
+        assert!(parse_ass_colour("#FFF").is_err());
+        assert!(parse_ass_colour("GGGGGG").is_err());
+        assert!(parse_ass_colour("+FFFFF").is_err());
+    }
+
+    #[test]
+    fn test_parse_extra_danmu() {
+        assert_eq!(
+            parse_extra_danmu("chat.json").unwrap(),
+            (PathBuf::from("chat.json"), 0.0)
+        );
+        assert_eq!(
+            parse_extra_danmu("chat.xml:+3.5").unwrap(),
+            (PathBuf::from("chat.xml"), 3.5)
+        );
+        assert_eq!(
+            parse_extra_danmu(r"C:\danmu\chat.xml:-2").unwrap(),
+            (PathBuf::from(r"C:\danmu\chat.xml"), -2.0)
+        );
+        assert_eq!(
+            parse_extra_danmu(r"C:\chat.xml").unwrap(),
+            (PathBuf::from(r"C:\chat.xml"), 0.0)
+        );
+        assert!(parse_extra_danmu("chat.xml:+abc").is_err());
+    }
+
+    #[test]
+    fn test_default_canvas_config() {
+        assert_eq!(
+            format!("{:?}", Args::parse_from(["ddp2ass"]).canvas_config()),
+            format!("{:?}", CanvasConfig::default())
+        );
+    }
+
+    #[test]
+    fn test_resolution() {
+        let resolution = |args: &[&str]| {
+            Args::parse_from(["ddp2ass"].iter().chain(args)).resolution((1920, 1080))
+        };
+        assert_eq!(resolution(&[]), (1920, 1080));
+        assert_eq!(resolution(&["--width", "1280"]), (1280, 720));
+        assert_eq!(resolution(&["--height", "720"]), (1280, 720));
+        assert_eq!(
+            resolution(&["--width", "800", "--height", "600"]),
+            (800, 600)
+        );
+    }
+
+    #[test]
+    fn test_check_areas() {
+        let check = |args: &[&str]| {
+            Args::parse_from([&["ddp2ass"], args].concat())
+                .check()
+                .map_err(|e| e.to_string())
+        };
+        assert!(check(&["--float-percentage", "0.05"]).is_ok());
+        assert!(check(&["--float-percentage", "0"]).is_ok());
+        assert_eq!(
+            check(&["--float-percentage", "0.04"]).unwrap_err(),
+            "--float-percentage 0.04 时滚动弹幕区域只有 29 像素，放不下一行 --lane-size 35 的弹幕，\
+             请将 --float-percentage 调到 0.05 以上或调小 --lane-size"
+        );
+        assert!(check(&["--float-percentage", "0.04", "--lane-size", "25"]).is_ok());
+        assert!(check(&["--float-percentage", "0.1", "--height", "240"]).is_err());
+        assert!(check(&["--lane-size", "0"]).is_err());
+
+        // --auto-resolution 时高度来自视频，只指定 --width 时也一样
+        assert!(check(&["--float-percentage", "0.04", "--auto-resolution"]).is_ok());
+        assert!(check(&[
+            "--float-percentage",
+            "0.04",
+            "--auto-resolution",
+            "--width",
+            "1920"
+        ])
+        .is_ok());
+        assert!(check(&[
+            "--float-percentage",
+            "0.1",
+            "--auto-resolution",
+            "--height",
+            "240"
+        ])
+        .is_err());
+        assert!(check(&["--float-percentage", "0.04", "--width", "1920"]).is_err());
+        let args = Args::parse_from(["ddp2ass", "--float-percentage", "0.04"]);
+        assert!(args.check_lane_areas(1080).is_ok());
+
+        // 滚动弹幕与底部弹幕区域重叠
+        assert_eq!(
+            check(&["--float-percentage", "0.9"]).unwrap_err(),
+            "--float-percentage 0.9 与 --bottom-percentage 0.3 之和超过 1，底部弹幕会与滚动弹幕重叠，请调小其中一个"
+        );
+        assert!(check(&["--float-percentage", "0.9", "--bottom-percentage", "0.3"]).is_err());
+        assert!(check(&["--float-percentage", "0.9", "--bottom-percentage", "0.1"]).is_ok());
+        assert!(check(&["--float-percentage", "1", "--bottom-percentage", "0"]).is_ok());
+        assert_eq!(
+            check(&["--float-percentage", "0.4", "--top-percentage", "0.4"]).unwrap_err(),
+            "--float-percentage 0.4、--bottom-percentage 0.3 与 --top-percentage 0.4 之和超过 1，\
+             弹幕区域会重叠，请调小其中一个"
+        );
+        assert!(check(&["--top-percentage", "0.3"]).is_ok());
+        assert!(check(&["--bottom-percentage=-0.1"]).is_err());
+        assert_eq!(
+            check(&["--bottom-percentage", "0.04"]).unwrap_err(),
+            "--bottom-percentage 0.04 时底部弹幕区域只有 29 像素，放不下一行 --lane-size 35 的弹幕，\
+             请将 --bottom-percentage 调到 0.05 以上或调小 --lane-size"
+        );
+        assert!(check(&["--top-percentage", "0.04", "--height", "1080"]).is_ok());
+    }
+
+    #[test]
+    fn test_check_ffmpeg_path() {
+        let e = Args::parse_from([
+            "ddp2ass",
+            "--merge-built-in",
+            "0",
+            "--ffmpeg-path",
+            "/ddp2ass-not-exists/ffmpeg",
+        ])
+        .check()
+        .unwrap_err();
+        assert!(matches!(Error::from(e), Error::FfmpegMissing(_)));
+    }
+
+    #[test]
+    fn test_file_report_json() -> Result<()> {
+        let input_file = InputFile {
+            path: PathBuf::from("/video/ep01.mkv"),
+        };
+        let report = ConversionReport {
+            anime_title: Some("葬送的芙莉莲".to_string()),
+            fetched: 3,
+            danmu_count: 2,
+            dropped: 1,
+            ..Default::default()
+        };
+        let json = serde_json::to_value(FileReportJson::new(&input_file, &Ok(report)))?;
+        assert_eq!(json["input"], "/video/ep01.mkv");
+        assert_eq!(json["anime_title"], "葬送的芙莉莲");
+        assert_eq!(json["danmu_count"], 2);
+        assert!(json.get("error").is_none());
+
+        let json =
+            serde_json::to_value(FileReportJson::new(&input_file, &Err(anyhow!("网络错误"))))?;
+        assert_eq!(json["error"], "网络错误");
+        assert!(json.get("danmu_count").is_none());
 
+        let json = serde_json::to_value(FileReportJson::new(
+            &input_file,
+            &Err(Error::Skipped.into()),
+        ))?;
+        assert_eq!(json["skipped"], true);
         Ok(())
     }
 }
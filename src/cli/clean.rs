@@ -0,0 +1,232 @@
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    util::{display_filename, sibling_path},
+    AssCreator, InputFile,
+};
+
+use super::{input_path_to_list, is_local_input, ScanOptions, CACHE_EXTS};
+
+#[derive(Parser, Debug)]
+pub struct CleanArgs {
+    #[clap(help = "输入文件路径", default_value = ".")]
+    pub input: String,
+
+    #[clap(
+        long = "caches",
+        help = "删除 .dandanplay.json 弹幕缓存、.dandanplay.match.json 匹配记录和 .dandanplay.api-cache.json 匹配接口缓存"
+    )]
+    pub caches: bool,
+
+    #[clap(
+        long = "subtitles",
+        help = "删除生成的 ASS 字幕，包括 ep01.danmu.ass 等带后缀的字幕"
+    )]
+    pub subtitles: bool,
+
+    #[clap(long = "all", help = "同时删除弹幕缓存和生成的字幕")]
+    pub all: bool,
+
+    #[clap(long = "dry-run", help = "只列出会删除的文件，不实际删除")]
+    pub dry_run: bool,
+
+    #[clap(long = "force", help = "同时删除没有 ddp2ass 生成标记的 ASS 字幕")]
+    pub force: bool,
+}
+
+impl CleanArgs {
+    pub fn process(&self) -> Result<()> {
+        if !(self.caches || self.subtitles || self.all) {
+            bail!("请指定 --caches、--subtitles 或 --all");
+        }
+
+        let mut count = 0;
+        let mut bytes = 0;
+        for input_file in input_path_to_list(&self.input, &ScanOptions::default())? {
+            if is_local_input(&input_file) || !input_file.path.is_file() {
+                warn!("{}", input_file.log("不是视频文件，跳过"));
+                continue;
+            }
+            for path in self.sidecar_files(&input_file)? {
+                let size = fs::metadata(&path)?.len();
+                if self.dry_run {
+                    info!(
+                        "将删除 {}（{}）",
+                        display_filename(&path),
+                        format_size(size)
+                    );
+                } else {
+                    fs::remove_file(&path)
+                        .with_context(|| format!("{} 删除失败", display_filename(&path)))?;
+                    info!(
+                        "已删除 {}（{}）",
+                        display_filename(&path),
+                        format_size(size)
+                    );
+                }
+                count += 1;
+                bytes += size;
+            }
+        }
+
+        info!(
+            "{} {} 个文件，共 {}",
+            if self.dry_run {
+                "将删除"
+            } else {
+                "已删除"
+            },
+            count,
+            format_size(bytes)
+        );
+        Ok(())
+    }
+
+    /// 视频旁边需要删除的文件，不是本工具生成的字幕只在 `--force` 时删除
+    fn sidecar_files(&self, input_file: &InputFile) -> Result<Vec<PathBuf>> {
+        let mut files = vec![];
+        if self.caches || self.all {
+            files.extend(
+                CACHE_EXTS
+                    .map(|ext| sibling_path(&input_file.path, ext))
+                    .into_iter()
+                    .filter(|path| path.is_file()),
+            );
+        }
+
+        if self.subtitles || self.all {
+            let (Some(dir), Some(stem)) = (input_file.path.parent(), input_file.path.file_stem())
+            else {
+                return Ok(files);
+            };
+            let stem = stem.to_string_lossy();
+            let mut subtitles: Vec<_> = dir
+                .read_dir()?
+                .filter_map(|f| f.ok())
+                .map(|f| f.path())
+                .filter(|path| path.is_file() && is_subtitle_of(&stem, path))
+                .collect();
+            subtitles.sort();
+            for path in subtitles {
+                let generated = fs::read_to_string(&path)
+                    .map(|ass| AssCreator::is_generated(&ass))
+                    .unwrap_or(false);
+                if generated || self.force {
+                    files.push(path);
+                } else {
+                    info!(
+                        "{} 不是 ddp2ass 生成的字幕，跳过，可以使用 --force 删除",
+                        display_filename(&path)
+                    );
+                }
+            }
+        }
+        Ok(files)
+    }
+}
+
+/// `ep01.ass` 或带一个后缀的 `ep01.danmu.ass`
+pub(super) fn is_subtitle_of(stem: &str, path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    let Some(suffix) = name
+        .strip_prefix(stem)
+        .and_then(|rest| rest.strip_suffix(".ass"))
+    else {
+        return false;
+    };
+    suffix.is_empty()
+        || suffix
+            .strip_prefix('.')
+            .is_some_and(|s| !s.is_empty() && !s.contains('.'))
+}
+
+fn format_size(bytes: u64) -> String {
+    match bytes {
+        0..1024 => format!("{} B", bytes),
+        1024..1_048_576 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / 1_048_576.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CanvasConfig, ConvertOptions, Dandan};
+
+    #[test]
+    fn test_clean() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (_, generated) =
+            Dandan::danmu_to_ass([], &ConvertOptions::new("ep01", CanvasConfig::default()))?;
+        for (name, content) in [
+            ("ep01.mkv", "video"),
+            ("ep01.dandanplay.json", "{}"),
+            ("ep01.dandanplay.match.json", "{}"),
+            ("ep01.dandanplay.api-cache.json", "{}"),
+            ("ep01.dandanplay.matches.json", "{}"),
+            ("ep01.ass", generated.as_str()),
+            ("ep01.danmu.ass", generated.as_str()),
+            ("ep01.zh.ass", "[Script Info]\nTitle: 字幕组"),
+            ("ep02.ass", generated.as_str()),
+        ] {
+            fs::write(dir.path().join(name), content)?;
+        }
+        let input = dir.path().to_string_lossy().to_string();
+        let video = InputFile::from(&dir.path().join("ep01.mkv"));
+        let clean =
+            |flags: &[&str]| CleanArgs::parse_from(["clean", input.as_str()].iter().chain(flags));
+        let names = |files: Vec<PathBuf>| -> Vec<String> {
+            files
+                .iter()
+                .map(|f| f.file_name().unwrap().to_string_lossy().to_string())
+                .collect()
+        };
+
+        assert_eq!(
+            names(clean(&["--caches"]).sidecar_files(&video)?),
+            [
+                "ep01.dandanplay.json",
+                "ep01.dandanplay.match.json",
+                "ep01.dandanplay.api-cache.json",
+                "ep01.dandanplay.matches.json"
+            ]
+        );
+        assert_eq!(
+            names(clean(&["--subtitles"]).sidecar_files(&video)?),
+            ["ep01.ass", "ep01.danmu.ass"]
+        );
+        assert_eq!(
+            names(clean(&["--subtitles", "--force"]).sidecar_files(&video)?),
+            ["ep01.ass", "ep01.danmu.ass", "ep01.zh.ass"]
+        );
+        assert!(clean(&[]).process().is_err());
+
+        clean(&["--all", "--dry-run"]).process()?;
+        assert!(dir.path().join("ep01.ass").exists());
+
+        clean(&["--all"]).process()?;
+        let mut left: Vec<_> = names(
+            dir.path()
+                .read_dir()?
+                .map(|f| f.map(|f| f.path()))
+                .collect::<Result<_, _>>()?,
+        );
+        left.sort();
+        assert_eq!(left, ["ep01.mkv", "ep01.zh.ass", "ep02.ass"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 * 1_048_576), "3.0 MiB");
+    }
+}
@@ -0,0 +1,89 @@
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use std::path::PathBuf;
+
+use crate::{util::display_filename, Dandan, InputFile, LogProgress};
+
+use super::{is_local_input, Args};
+
+#[derive(Parser, Debug)]
+pub struct ConvertArgs {
+    #[clap(
+        long = "output",
+        help = "输出的 ASS，不指定时输出到弹幕文件旁边，如 ep01.xml 输出为 ep01.ass"
+    )]
+    pub output: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub args: Args,
+}
+
+impl ConvertArgs {
+    /// 只转换单个弹幕 json 或 B 站格式的 xml，没有视频时使用 `--width`、`--height` 指定分辨率
+    pub fn process(&self) -> Result<()> {
+        let input_file = InputFile::from(&PathBuf::from(&self.args.input));
+        if !input_file.path.is_file() || !is_local_input(&input_file) {
+            return Err(anyhow!(
+                "{} 不是弹幕 json 或 xml 文件",
+                display_filename(&input_file.path)
+            ));
+        }
+        let output = match &self.output {
+            Some(output) => output.clone(),
+            None => Dandan::local_output_path(&input_file, &self.args.output_suffix),
+        };
+        let report = Dandan::process_by_local_file(
+            &input_file,
+            &self.args.process_options()?,
+            &output,
+            &LogProgress,
+        )?;
+        if !report.skipped {
+            info!(
+                "{} {} 条弹幕到 {}",
+                input_file.log("写入"),
+                report.danmu_count,
+                display_filename(&output)
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_convert() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let xml = dir.path().join("ep01.xml");
+        fs::write(
+            &xml,
+            r#"<?xml version="1.0" encoding="UTF-8"?><i><d p="1.5,1,25,16777215,0,0,0,1">弹幕</d></i>"#,
+        )?;
+        let input = xml.to_string_lossy().to_string();
+
+        ConvertArgs::parse_from(["convert", &input, "--width", "1920", "--height", "1080"])
+            .process()?;
+        let ass = fs::read_to_string(dir.path().join("ep01.ass"))?;
+        assert!(ass.contains("PlayResX: 1920"));
+        assert!(ass.contains("弹幕"));
+
+        let output = dir.path().join("out").join("danmu.ass");
+        fs::create_dir(dir.path().join("out"))?;
+        ConvertArgs::parse_from(["convert", &input, "--output", &output.to_string_lossy()])
+            .process()?;
+        assert!(fs::read_to_string(&output)?.contains("PlayResX: 1280"));
+
+        let video = dir.path().join("ep01.mkv");
+        fs::write(&video, "")?;
+        assert!(
+            ConvertArgs::parse_from(["convert", &video.to_string_lossy()])
+                .process()
+                .is_err()
+        );
+        Ok(())
+    }
+}
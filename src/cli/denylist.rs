@@ -0,0 +1,195 @@
+use anyhow::{anyhow, Context, Result};
+use clap::{Parser, Subcommand};
+use std::{fs, path::PathBuf};
+
+use crate::{
+    util::{display_filename, sibling_path, time_label},
+    Dandan, DenyReason, Denylist, InputFile,
+};
+
+use super::is_local_input;
+
+#[derive(Parser, Debug)]
+pub struct DenylistArgs {
+    #[clap(
+        long = "denylist",
+        help = "黑名单文件，与 download 的 --denylist 相同",
+        global = true
+    )]
+    pub denylist: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub action: DenylistAction,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DenylistAction {
+    #[clap(about = "添加关键词，user: 开头时为屏蔽的用户")]
+    Add { keyword: String },
+
+    #[clap(about = "删除关键词或用户")]
+    Remove { keyword: String },
+
+    #[clap(about = "列出黑名单")]
+    List,
+
+    #[clap(about = "使用视频的弹幕缓存、弹幕 json 或 xml 查看会被过滤的弹幕")]
+    Test { input: PathBuf },
+}
+
+impl DenylistArgs {
+    /// 修改时只增删对应的行，其他行保持原样
+    pub fn process(&self) -> Result<()> {
+        let path = self
+            .denylist
+            .as_ref()
+            .context("请使用 --denylist 指定黑名单文件")?;
+        let content = match (path.exists(), &self.action) {
+            (true, _) => fs::read_to_string(path)
+                .with_context(|| format!("黑名单 {} 读取失败", display_filename(path)))?,
+            (false, DenylistAction::Add { .. }) => String::new(),
+            (false, _) => return Err(anyhow!("黑名单文件 {} 不存在", display_filename(path))),
+        };
+
+        match &self.action {
+            DenylistAction::Add { keyword } => match add_line(&content, keyword) {
+                Some(content) => {
+                    fs::write(path, content)?;
+                    info!("已添加 {}", keyword.trim());
+                }
+                None => info!("{} 已在黑名单中", keyword.trim()),
+            },
+            DenylistAction::Remove { keyword } => {
+                let content = remove_line(&content, keyword)
+                    .ok_or_else(|| anyhow!("黑名单中没有 {}", keyword.trim()))?;
+                fs::write(path, content)?;
+                info!("已删除 {}", keyword.trim());
+            }
+            DenylistAction::List => {
+                for line in content
+                    .trim_start_matches('\u{feff}')
+                    .lines()
+                    .map(str::trim)
+                    .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                {
+                    println!("{}", line);
+                }
+            }
+            DenylistAction::Test { input } => {
+                let denylist = Denylist::parse(&content);
+                let input_file = InputFile::from(input);
+                let danmu_path = match is_local_input(&input_file) {
+                    true => input_file.path.clone(),
+                    false => sibling_path(&input_file.path, "dandanplay.json"),
+                };
+                if !danmu_path.is_file() {
+                    return Err(anyhow!(
+                        "{} 没有弹幕缓存，请先运行 download",
+                        display_filename(&input_file.path)
+                    ));
+                }
+                let danmus = Dandan::load_local_danmus(&danmu_path)?;
+                let mut filtered = 0;
+                for danmu in &danmus {
+                    let Some(reason) = denylist.check(danmu) else {
+                        continue;
+                    };
+                    filtered += 1;
+                    let reason = match reason {
+                        DenyReason::Keyword => "关键词",
+                        DenyReason::User => "用户",
+                    };
+                    println!(
+                        "{}  {}  {}",
+                        time_label(danmu.timeline_s),
+                        reason,
+                        danmu.content
+                    );
+                }
+                info!("{} 条弹幕中有 {} 条会被过滤", danmus.len(), filtered);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 已经存在时返回 None
+fn add_line(content: &str, keyword: &str) -> Option<String> {
+    let keyword = keyword.trim();
+    if keyword.is_empty() || content.lines().any(|l| l.trim() == keyword) {
+        return None;
+    }
+    let mut content = content.to_string();
+    if !content.is_empty() && !content.ends_with('\n') {
+        content.push('\n');
+    }
+    content.push_str(keyword);
+    content.push('\n');
+    Some(content)
+}
+
+/// 不存在时返回 None
+fn remove_line(content: &str, keyword: &str) -> Option<String> {
+    let keyword = keyword.trim();
+    let lines: Vec<&str> = content.split_inclusive('\n').collect();
+    let kept: String = lines
+        .iter()
+        .filter(|l| l.trim() != keyword)
+        .copied()
+        .collect();
+    (kept.len() != content.len()).then_some(kept)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edit_lines() {
+        let content = "剧透\n\nuser:[BiliBili]abc123\n前方高能";
+        assert_eq!(add_line(content, "剧透"), None);
+        assert_eq!(
+            add_line(content, " 草 ").as_deref(),
+            Some("剧透\n\nuser:[BiliBili]abc123\n前方高能\n草\n")
+        );
+        assert_eq!(add_line("", "草").as_deref(), Some("草\n"));
+
+        assert_eq!(
+            remove_line(content, "user:[BiliBili]abc123").as_deref(),
+            Some("剧透\n\n前方高能")
+        );
+        assert_eq!(
+            remove_line(content, "前方高能").as_deref(),
+            Some("剧透\n\nuser:[BiliBili]abc123\n")
+        );
+        assert_eq!(remove_line(content, "草"), None);
+    }
+
+    #[test]
+    fn test_process() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let list = dir.path().join("denylist.txt");
+        let list_arg = list.to_string_lossy().to_string();
+        let run = |args: &[&str]| {
+            DenylistArgs::parse_from(["denylist", "--denylist", &list_arg].iter().chain(args))
+                .process()
+        };
+        assert!(run(&["list"]).is_err());
+        run(&["add", "剧透"])?;
+        run(&["add", "user:[Gamer]a"])?;
+        run(&["add", "剧透"])?;
+        assert_eq!(fs::read_to_string(&list)?, "剧透\nuser:[Gamer]a\n");
+        run(&["remove", "user:[Gamer]a"])?;
+        assert_eq!(fs::read_to_string(&list)?, "剧透\n");
+        assert!(run(&["remove", "草"]).is_err());
+
+        let video = dir.path().join("ep01.mkv");
+        assert!(run(&["test", &video.to_string_lossy()]).is_err());
+        fs::write(
+            video.with_extension("dandanplay.json"),
+            r#"{ "count": 1, "comments": [{ "cid": 1, "p": "1.00,1,16777215,[Gamer]a", "m": "有剧透" }] }"#,
+        )?;
+        run(&["test", &video.to_string_lossy()])?;
+        Ok(())
+    }
+}
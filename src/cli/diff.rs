@@ -0,0 +1,177 @@
+use anyhow::{Context, Result};
+use clap::Parser;
+use serde::Serialize;
+use std::{collections::HashSet, fs, path::PathBuf};
+
+use crate::{dandan::CommentsJson, util::sibling_path, Dandan, Danmu, DanmuStats, FetchOptions};
+
+#[derive(Parser, Debug)]
+pub struct DiffArgs {
+    #[clap(
+        help = "旧的弹幕缓存，也可以是 B 站格式的 xml",
+        required_unless_present = "against_live"
+    )]
+    pub old: Option<PathBuf>,
+
+    #[clap(help = "新的弹幕缓存", required_unless_present = "against_live")]
+    pub new: Option<PathBuf>,
+
+    #[clap(
+        long = "against-live",
+        help = "比较视频的弹幕缓存和 dandanplay 当前返回的弹幕，不会修改缓存",
+        conflicts_with_all = ["old", "new"]
+    )]
+    pub against_live: Option<PathBuf>,
+
+    #[clap(long = "json", help = "输出 json")]
+    pub json: bool,
+}
+
+/// 按弹幕 id 比较的结果
+#[derive(Debug, Serialize)]
+struct CommentsDiff {
+    old_total: usize,
+    new_total: usize,
+    added: Vec<DiffItem>,
+    removed: Vec<DiffItem>,
+    /// 数量有变化的分钟
+    minutes: Vec<MinuteChange>,
+}
+
+#[derive(Debug, Serialize)]
+struct DiffItem {
+    cid: u64,
+    timeline_s: f64,
+    content: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct MinuteChange {
+    minute: usize,
+    old: usize,
+    new: usize,
+}
+
+impl DiffArgs {
+    pub async fn process(&self) -> Result<()> {
+        let (old, new) = match (&self.against_live, &self.old, &self.new) {
+            (Some(video), _, _) => {
+                let cache = sibling_path(video, "dandanplay.json");
+                let json =
+                    fs::read_to_string(&cache).context("视频没有弹幕缓存，请先运行 fetch")?;
+                let comments_json: CommentsJson =
+                    serde_json::from_str(&json).context("弹幕缓存无法解析")?;
+                let episode_id = comments_json
+                    .episode_id
+                    .context("弹幕缓存中没有 episodeId，无法获取当前的弹幕")?;
+                let live = Dandan::fetch_comments(episode_id, &FetchOptions::default()).await?;
+                let title = video.display().to_string();
+                (comments_json.into_danmus(&title), live.into_danmus(&title))
+            }
+            (None, Some(old), Some(new)) => (
+                Dandan::load_local_danmus(old)?,
+                Dandan::load_local_danmus(new)?,
+            ),
+            _ => unreachable!("clap 保证指定了两个文件或 --against-live"),
+        };
+        let diff = diff(&old, &new);
+        match self.json {
+            true => println!("{}", serde_json::to_string(&diff)?),
+            false => print!("{}", render_text(&diff)),
+        }
+        Ok(())
+    }
+}
+
+fn diff(old: &[Danmu], new: &[Danmu]) -> CommentsDiff {
+    let changed = |from: &[Danmu], to: &[Danmu]| -> Vec<DiffItem> {
+        let ids: HashSet<u64> = to.iter().map(|d| d.cid).collect();
+        from.iter()
+            .filter(|d| !ids.contains(&d.cid))
+            .map(|d| DiffItem {
+                cid: d.cid,
+                timeline_s: d.timeline_s,
+                content: d.content.clone(),
+            })
+            .collect()
+    };
+    let old_minutes = DanmuStats::from_danmus(old, 0).per_minute;
+    let new_minutes = DanmuStats::from_danmus(new, 0).per_minute;
+    let minutes = (0..old_minutes.len().max(new_minutes.len()))
+        .map(|minute| MinuteChange {
+            minute,
+            old: old_minutes.get(minute).copied().unwrap_or(0),
+            new: new_minutes.get(minute).copied().unwrap_or(0),
+        })
+        .filter(|m| m.old != m.new)
+        .collect();
+    CommentsDiff {
+        old_total: old.len(),
+        new_total: new.len(),
+        added: changed(new, old),
+        removed: changed(old, new),
+        minutes,
+    }
+}
+
+fn render_text(diff: &CommentsDiff) -> String {
+    let mut out = format!("旧 {} 条，新 {} 条\n", diff.old_total, diff.new_total);
+    out += &format!(
+        "新增 {} 条，删除 {} 条\n",
+        diff.added.len(),
+        diff.removed.len()
+    );
+    if diff.minutes.is_empty() {
+        return out + "每分钟的弹幕数量没有变化\n";
+    }
+    out += "每分钟弹幕数量的变化\n";
+    for m in &diff.minutes {
+        out += &format!(
+            "{:02}:{:02}  {} → {}（{:+}）\n",
+            m.minute / 60,
+            m.minute % 60,
+            m.old,
+            m.new,
+            m.new as i64 - m.old as i64
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let danmu = |cid: u64, timeline_s: f64| Danmu {
+            cid,
+            timeline_s,
+            content: format!("弹幕{}", cid),
+            ..Default::default()
+        };
+        let old = [danmu(1, 1.0), danmu(2, 61.0), danmu(3, 62.0)];
+        let new = [
+            danmu(1, 1.0),
+            danmu(3, 62.0),
+            danmu(4, 10.0),
+            danmu(5, 130.0),
+        ];
+        let diff = diff(&old, &new);
+        assert_eq!(diff.added.iter().map(|d| d.cid).collect::<Vec<_>>(), [4, 5]);
+        assert_eq!(diff.removed.iter().map(|d| d.cid).collect::<Vec<_>>(), [2]);
+        assert_eq!(
+            render_text(&diff),
+            [
+                "旧 3 条，新 4 条",
+                "新增 2 条，删除 1 条",
+                "每分钟弹幕数量的变化",
+                "00:00  1 → 2（+1）",
+                "00:01  2 → 1（-1）",
+                "00:02  0 → 1（+1）",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+}
@@ -0,0 +1,151 @@
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use std::{
+    path::{absolute, Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+
+use crate::util::{command_output, ffmpeg_command, ffprobe_command};
+
+#[derive(Parser, Debug)]
+pub struct DoctorArgs {
+    #[clap(
+        help = "检查是否可以写入的文件夹，一般为视频所在的文件夹",
+        default_value = "."
+    )]
+    pub input: String,
+}
+
+/// 请求 dandanplay 的超时时间
+const API_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 一项检查的结果，失败时 `hint` 为解决方法
+struct Check {
+    name: &'static str,
+    result: Result<String>,
+    hint: &'static str,
+}
+
+impl DoctorArgs {
+    /// 依次检查 ffmpeg、ffprobe、dandanplay 和输出目录，有检查失败时返回错误
+    pub async fn process(&self) -> Result<()> {
+        let dir = absolute(PathBuf::from(&self.input))?;
+        let checks = [
+            Check {
+                name: "ffmpeg",
+                result: program_version(ffmpeg_command()),
+                hint: "安装 ffmpeg 并将所在目录加入 PATH，或设置环境变量 FFMPEG 为 ffmpeg 的路径，--embed 和 --merge-built-in 需要 ffmpeg",
+            },
+            Check {
+                name: "ffprobe",
+                result: program_version(ffprobe_command()),
+                hint: "ffprobe 一般与 ffmpeg 一起安装，也可以设置环境变量 FFPROBE，读取内置字幕轨需要 ffprobe",
+            },
+            Check {
+                name: "dandanplay",
+                result: check_api().await,
+                hint: "检查网络连接，需要代理时设置 HTTPS_PROXY 环境变量",
+            },
+            Check {
+                name: "输出目录",
+                result: check_writable(&dir),
+                hint: "检查文件夹是否存在以及是否有写入权限，弹幕缓存和 ASS 都写入到视频旁边",
+            },
+        ];
+        print!("{}", render(&checks));
+        match checks.iter().filter(|c| c.result.is_err()).count() {
+            0 => Ok(()),
+            n => Err(anyhow!("{} 项检查未通过", n)),
+        }
+    }
+}
+
+fn render(checks: &[Check]) -> String {
+    checks
+        .iter()
+        .map(|check| match &check.result {
+            Ok(detail) => format!("✓ {}：{}\n", check.name, detail),
+            Err(e) => format!("✗ {}：{:#}\n  {}\n", check.name, e, check.hint),
+        })
+        .collect()
+}
+
+/// `ffmpeg -version` 输出的第一行
+fn program_version(mut command: Command) -> Result<String> {
+    let output = command_output(command.arg("-version"))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} -version 运行失败",
+            command.get_program().to_string_lossy()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string())
+}
+
+/// 搜索一次动画，只检查是否可以连接，不解析返回的内容
+async fn check_api() -> Result<String> {
+    let response = reqwest::Client::builder()
+        .timeout(API_TIMEOUT)
+        .build()?
+        .get("https://api.dandanplay.net/api/v2/search/anime")
+        .query(&[("keyword", "ddp2ass")])
+        .header("Accept", "application/json")
+        .header("User-Agent", "curl")
+        .send()
+        .await?;
+    match response.status() {
+        status if status.is_success() => Ok(format!("api.dandanplay.net 可以访问（{}）", status)),
+        status => Err(anyhow!("api.dandanplay.net 返回 {}", status)),
+    }
+}
+
+/// 在文件夹中创建并删除临时文件
+fn check_writable(dir: &Path) -> Result<String> {
+    if !dir.is_dir() {
+        return Err(anyhow!("{} 不是文件夹", dir.display()));
+    }
+    tempfile::Builder::new()
+        .prefix(".ddp2ass-doctor")
+        .tempfile_in(dir)
+        .map_err(|e| anyhow!("{} 无法写入：{}", dir.display(), e))?;
+    Ok(format!("{} 可以写入", dir.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let checks = [
+            Check {
+                name: "ffmpeg",
+                result: Ok("ffmpeg version 6.1".to_string()),
+                hint: "",
+            },
+            Check {
+                name: "ffprobe",
+                result: Err(anyhow!("找不到 ffprobe")),
+                hint: "安装 ffmpeg",
+            },
+        ];
+        assert_eq!(
+            render(&checks),
+            "✓ ffmpeg：ffmpeg version 6.1\n✗ ffprobe：找不到 ffprobe\n  安装 ffmpeg\n"
+        );
+    }
+
+    #[test]
+    fn test_check_writable() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        check_writable(dir.path())?;
+        assert_eq!(dir.path().read_dir()?.count(), 0);
+        assert!(check_writable(&dir.path().join("missing")).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+use anyhow::{Context, Result};
+use clap::{Parser, ValueEnum};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    bilibili_xml,
+    export::to_csv,
+    util::{display_filename, file_name},
+    Dandan, ExportRecord,
+};
+
+use super::{match_result::render_table, regen::find_caches};
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum ExportFormat {
+    /// B 站格式的 xml
+    Xml,
+    /// 与 --export-csv 相同的表格，处理结果为空
+    Csv,
+}
+
+#[derive(Parser, Debug)]
+pub struct ExportArgs {
+    #[clap(help = "视频、弹幕缓存或文件夹", default_value = ".")]
+    pub input: String,
+
+    #[clap(long = "to", help = "导出的格式")]
+    pub to: ExportFormat,
+
+    #[clap(long = "output-dir", help = "导出到这个文件夹，默认为弹幕缓存旁边")]
+    pub output_dir: Option<PathBuf>,
+}
+
+impl ExportArgs {
+    /// 把 `.dandanplay.json` 缓存转换为 `.danmu.xml` 或 `.danmu.csv`，不做过滤
+    pub fn process(&self) -> Result<()> {
+        let caches: Vec<_> = find_caches(&self.input)?
+            .into_iter()
+            .filter(|cache| cache.is_file())
+            .collect();
+        if caches.is_empty() {
+            info!("没有找到弹幕缓存");
+            return Ok(());
+        }
+        if let Some(dir) = &self.output_dir {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("无法创建文件夹 {}", display_filename(dir)))?;
+        }
+
+        let mut total = 0;
+        let mut rows = vec![];
+        for cache in caches {
+            let output = self.output_path(&cache);
+            let status = match self.export(&cache, &output) {
+                Ok(count) => {
+                    total += 1;
+                    format!("{} 条弹幕", count)
+                }
+                Err(e) => {
+                    warn!("{} 导出失败 {:#}", display_filename(&cache), e);
+                    format!("{:#}", e)
+                }
+            };
+            rows.push([file_name(&cache), file_name(&output), status]);
+        }
+        print!("{}", render_table(["弹幕缓存", "导出文件", "结果"], &rows));
+        info!("共导出 {} 个文件", total);
+        Ok(())
+    }
+
+    fn export(&self, cache: &PathBuf, output: &PathBuf) -> Result<usize> {
+        let danmus = Dandan::load_local_danmus(cache)?;
+        let content = match self.to {
+            ExportFormat::Xml => bilibili_xml::write(&danmus),
+            ExportFormat::Csv => to_csv(
+                &danmus
+                    .iter()
+                    .map(ExportRecord::from_danmu)
+                    .collect::<Vec<_>>(),
+            ),
+        };
+        fs::write(output, content)?;
+        Ok(danmus.len())
+    }
+
+    /// `ep01.dandanplay.json` 导出为 `ep01.danmu.xml`
+    fn output_path(&self, cache: &Path) -> PathBuf {
+        let ext = match self.to {
+            ExportFormat::Xml => "danmu.xml",
+            ExportFormat::Csv => "danmu.csv",
+        };
+        let name = file_name(cache);
+        let stem = name.strip_suffix(".dandanplay.json").unwrap_or(&name);
+        let name = format!("{}.{}", stem, ext);
+        match &self.output_dir {
+            Some(dir) => dir.join(name),
+            None => cache.with_file_name(name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = r#"{
+            "count": 1,
+            "comments": [{ "cid": 1, "p": "1.00,5,16711680,[Gamer]a", "m": "a<b" }]
+        }"#;
+        fs::write(dir.path().join("ep01.dandanplay.json"), cache)?;
+        fs::write(dir.path().join("ep02.dandanplay.json"), "{")?;
+        let input = dir.path().to_string_lossy().to_string();
+
+        ExportArgs::parse_from(["export", &input, "--to", "xml"]).process()?;
+        let xml = fs::read_to_string(dir.path().join("ep01.danmu.xml"))?;
+        assert!(xml.contains(r#"<d p="1.00000,5,25,16711680,0,0,[Gamer]a,1">a&lt;b</d>"#));
+        assert!(!dir.path().join("ep02.danmu.xml").exists());
+
+        let out = dir.path().join("out");
+        let out_arg = out.to_string_lossy().to_string();
+        ExportArgs::parse_from(["export", &input, "--to", "csv", "--output-dir", &out_arg])
+            .process()?;
+        assert_eq!(
+            fs::read_to_string(out.join("ep01.danmu.csv"))?,
+            "timestamp,type,color,user,content,status\n1.00,top,#FF0000,[Gamer]a,a<b,\n"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use std::sync::Arc;
+
+use crate::{cancel::is_cancelled_error, error::is_skipped_error, CancelFlag, LogProgress};
+
+use super::{match_result::render_table, Args};
+
+#[derive(Parser, Debug)]
+pub struct FetchArgs {
+    #[clap(flatten)]
+    pub args: Args,
+}
+
+impl FetchArgs {
+    /// 与 download 相同地匹配和获取弹幕，写入缓存后不生成 ASS，之后可以使用 regen 离线生成
+    pub async fn process(&self, cancel: &CancelFlag) -> Result<()> {
+        if self.args.local_danmu.is_some() || !self.args.extra_danmu.is_empty() {
+            return Err(anyhow!("fetch 不能使用 --local-danmu 和 --extra-danmu"));
+        }
+        let t = std::time::Instant::now();
+        let results = self.args.fetch_only(Arc::new(LogProgress), cancel).await?;
+
+        let mut file_total = 0;
+        let mut danmu_total = 0;
+        let rows: Vec<_> = results
+            .into_iter()
+            .map(|(input_file, result)| {
+                let (count, status) = match result {
+                    Ok(count) => {
+                        file_total += 1;
+                        danmu_total += count;
+                        (count.to_string(), "已缓存".to_string())
+                    }
+                    Err(e) if is_skipped_error(&e) => (String::new(), "已跳过".to_string()),
+                    Err(e) if is_cancelled_error(&e) => (String::new(), "已取消".to_string()),
+                    Err(e) => {
+                        error!("{} {:?}", input_file.log("获取弹幕错误"), e);
+                        (String::new(), format!("失败：{:#}", e))
+                    }
+                };
+                [input_file.file_name(), count, status]
+            })
+            .collect();
+        print!("{}", render_table(["文件", "弹幕", "结果"], &rows));
+        info!(
+            "共缓存 {} 个文件，{} 条弹幕，耗时 {:?}",
+            file_total,
+            danmu_total,
+            t.elapsed()
+        );
+        cancel.check()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_fetch_args() {
+        let args = FetchArgs::parse_from(["fetch", ".", "--local-danmu", "ep01.xml"]);
+        let e = args.process(&CancelFlag::default()).await.unwrap_err();
+        assert!(e.to_string().contains("--local-danmu"));
+    }
+}
@@ -0,0 +1,136 @@
+use anyhow::{Context, Result};
+use clap::Parser;
+use std::fs;
+
+use crate::{dandan::CommentsJson, util::sibling_path, InputFile};
+
+use super::{input_path_to_list, match_result::render_table, ScanOptions};
+
+#[derive(Parser, Debug)]
+pub struct InfoArgs {
+    #[clap(help = "输入文件路径", default_value = ".")]
+    pub input: String,
+}
+
+impl InfoArgs {
+    /// 只读取视频旁边的弹幕缓存和 ASS，不请求 dandanplay
+    pub fn process(&self) -> Result<()> {
+        let rows: Vec<_> = input_path_to_list(&self.input, &ScanOptions::default())?
+            .iter()
+            .map(table_row)
+            .collect();
+        print!("{}", render_table(TABLE_HEADER, &rows));
+        Ok(())
+    }
+}
+
+const TABLE_HEADER: [&str; 6] = ["文件", "动画", "剧集", "弹幕", "缓存时间", "ASS"];
+
+fn table_row(input_file: &InputFile) -> [String; 6] {
+    let filename = input_file.file_name();
+    let ass = match fs::read_to_string(sibling_path(&input_file.path, "ass")) {
+        Ok(ass) => {
+            let count = ass.lines().filter(|l| l.starts_with("Dialogue:")).count();
+            format!("{} 条", count)
+        }
+        Err(_) => "无".to_string(),
+    };
+
+    let json_path = sibling_path(&input_file.path, "dandanplay.json");
+    if !json_path.is_file() {
+        return [
+            filename,
+            "未匹配".to_string(),
+            String::new(),
+            String::new(),
+            String::new(),
+            ass,
+        ];
+    }
+    let cache = fs::read_to_string(&json_path)
+        .context("读取失败")
+        .and_then(|json| serde_json::from_str::<CommentsJson>(&json).context("无法解析"));
+    match cache {
+        Ok(cache) => {
+            let age = cache
+                .cache_age(&json_path)
+                .map(|age| format!("{:.1} 小时前", age.as_secs_f64() / 3600.0))
+                .unwrap_or_default();
+            let episode = match (&cache.episode_title, cache.episode_id) {
+                (Some(title), Some(id)) => format!("{}（{}）", title, id),
+                (Some(title), None) => title.clone(),
+                (None, Some(id)) => id.to_string(),
+                (None, None) => String::new(),
+            };
+            [
+                filename,
+                cache.anime_title.unwrap_or_else(|| "未匹配".to_string()),
+                episode,
+                cache.comments.len().to_string(),
+                age,
+                ass,
+            ]
+        }
+        Err(e) => [
+            filename,
+            format!("弹幕缓存{:#}", e),
+            String::new(),
+            String::new(),
+            String::new(),
+            ass,
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn test_table_row() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let fetched_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() - 2 * 3600;
+        let cache = format!(
+            r#"{{
+                "count": 2, "episodeId": 171220001, "animeId": 17122,
+                "animeTitle": "葬送的芙莉莲", "episodeTitle": "第1话",
+                "fetchedAt": {},
+                "comments": [
+                    {{ "cid": 1, "p": "1.00,1,16777215,1", "m": "a" }},
+                    {{ "cid": 2, "p": "2.00,1,16777215,1", "m": "b" }}
+                ]
+            }}"#,
+            fetched_at
+        );
+        for (name, content) in [
+            ("ep01.mkv", ""),
+            ("ep01.dandanplay.json", cache.as_str()),
+            (
+                "ep01.ass",
+                "[Events]\nDialogue: a\nComment: b\nDialogue: c\n",
+            ),
+            ("ep02.mkv", ""),
+            ("ep03.mkv", ""),
+            ("ep03.dandanplay.json", "{"),
+        ] {
+            fs::write(dir.path().join(name), content)?;
+        }
+        let row = |name: &str| table_row(&InputFile::from(&dir.path().join(name)));
+
+        assert_eq!(
+            row("ep01.mkv"),
+            [
+                "ep01.mkv",
+                "葬送的芙莉莲",
+                "第1话（171220001）",
+                "2",
+                "2.0 小时前",
+                "2 条"
+            ]
+        );
+        assert_eq!(row("ep02.mkv"), ["ep02.mkv", "未匹配", "", "", "", "无"]);
+        assert!(row("ep03.mkv")[1].starts_with("弹幕缓存无法解析"));
+        Ok(())
+    }
+}
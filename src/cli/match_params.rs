@@ -1,9 +1,9 @@
-use anyhow::{Context, Result};
+use anyhow::Result;
 use clap::Parser;
 
-use crate::{dandan_match::DandanMatch, InputFile};
+use crate::dandan_match::DandanMatch;
 
-use super::input_path_to_list;
+use super::{input_path_to_list, ScanOptions};
 
 #[derive(Parser, Debug)]
 pub struct MatchParamsArgs {
@@ -13,17 +13,10 @@ pub struct MatchParamsArgs {
 
 impl MatchParamsArgs {
     pub fn process(&self) -> Result<()> {
-        let filepaths = input_path_to_list(&self.input)?;
-        for filepath in filepaths {
-            let input = InputFile::from(&filepath);
-            let params = DandanMatch::get_match_params(&input)?;
-            println!(
-                "{}",
-                filepath
-                    .file_name()
-                    .context("文件名错误")?
-                    .to_string_lossy()
-            );
+        let input_files = input_path_to_list(&self.input, &ScanOptions::default())?;
+        for input in input_files {
+            let params = DandanMatch::get_match_params(&input, false, false)?;
+            println!("{}", input.file_name());
             println!("{}", serde_json::to_string(&params.json)?);
         }
         Ok(())
@@ -1,32 +1,190 @@
-use anyhow::{Context, Result};
+use anyhow::Result;
 use clap::Parser;
+use tokio::task::JoinSet;
 
-use crate::{dandan_match::DandanMatch, InputFile};
+use crate::dandan_match::{DandanMatch, MatchesJson};
 
-use super::input_path_to_list;
+use super::{input_path_to_list, ScanOptions};
 
 #[derive(Parser, Debug)]
 pub struct MatchResultArgs {
     #[clap(help = "输入文件路径", default_value = ".")]
     pub input: String,
+
+    #[clap(
+        long = "refresh",
+        help = "重新计算视频 hash 并请求匹配，不使用匹配结果缓存"
+    )]
+    pub refresh: bool,
+
+    #[clap(long = "json", help = "输出 dandanplay 返回的原始 json")]
+    pub json: bool,
+
+    #[clap(long = "jobs", help = "同时匹配的文件数量", default_value = "4")]
+    pub jobs: usize,
 }
 
 impl MatchResultArgs {
     pub async fn process(&self) -> Result<()> {
-        let filepaths = input_path_to_list(&self.input)?;
-        for filepath in filepaths {
-            let input = InputFile::from(&filepath);
-            let params = DandanMatch::get_match_params(&input)?;
-            let result = DandanMatch::get_matches_json(&params).await?;
-            println!(
-                "{}",
-                filepath
-                    .file_name()
-                    .context("文件名错误")?
-                    .to_string_lossy()
-            );
-            println!("{}", serde_json::to_string(&result)?);
+        let input_files = input_path_to_list(&self.input, &ScanOptions::default())?;
+        let mut results: Vec<Option<Result<MatchesJson>>> =
+            input_files.iter().map(|_| None).collect();
+
+        // 计算 hash 和请求匹配都比较慢，多个文件同时进行
+        let mut tasks = JoinSet::new();
+        for (i, input) in input_files.iter().enumerate() {
+            if tasks.len() >= self.jobs.max(1) {
+                if let Some(done) = tasks.join_next().await {
+                    let (i, result) = done?;
+                    results[i] = Some(result);
+                }
+            }
+            let input = input.clone();
+            let refresh = self.refresh;
+            tasks.spawn(async move {
+                let result = async {
+                    let params = tokio::task::block_in_place(|| {
+                        DandanMatch::get_match_params(&input, false, refresh)
+                    })?;
+                    Ok(DandanMatch::get_matches_json(&input, &params, refresh).await?)
+                }
+                .await;
+                (i, result)
+            });
+        }
+        while let Some(done) = tasks.join_next().await {
+            let (i, result) = done?;
+            results[i] = Some(result);
+        }
+
+        let mut rows = vec![];
+        for (input, result) in input_files.iter().zip(results) {
+            let filename = input.file_name();
+            let Some(result) = result else {
+                continue;
+            };
+            if self.json {
+                match result {
+                    Ok(matches_json) => {
+                        println!("{}", filename);
+                        println!("{}", serde_json::to_string(&matches_json)?);
+                    }
+                    Err(e) => error!("{} {:?}", input.log("匹配错误"), e),
+                }
+            } else {
+                rows.push(table_row(filename, result));
+            }
+        }
+        if !self.json {
+            print!("{}", render_table(TABLE_HEADER, &rows));
+        }
+        Ok(())
+    }
+}
+
+const TABLE_HEADER: [&str; 5] = ["文件", "匹配", "动画", "剧集", "偏移"];
+
+fn table_row(filename: String, result: Result<MatchesJson>) -> [String; 5] {
+    match result {
+        Ok(matches_json) => match matches_json.matches.first() {
+            Some(item) => [
+                filename,
+                match matches_json.is_matched {
+                    true => "是".to_string(),
+                    false => format!("否（{} 个候选）", matches_json.matches.len()),
+                },
+                item.anime_title.clone(),
+                item.episode_title.clone(),
+                item.shift.to_string(),
+            ],
+            None => [
+                filename,
+                "否".to_string(),
+                String::new(),
+                String::new(),
+                String::new(),
+            ],
+        },
+        Err(e) => [
+            filename,
+            "错误".to_string(),
+            format!("{:#}", e),
+            String::new(),
+            String::new(),
+        ],
+    }
+}
+
+/// 终端中的显示宽度，中日韩文字和全角字符占两列
+pub(super) fn display_width(s: &str) -> usize {
+    s.chars()
+        .map(|c| match c as u32 {
+            0x1100..=0x115F
+            | 0x2E80..=0xA4CF
+            | 0xAC00..=0xD7A3
+            | 0xF900..=0xFAFF
+            | 0xFE30..=0xFE4F
+            | 0xFF00..=0xFF60
+            | 0xFFE0..=0xFFE6 => 2,
+            _ => 1,
+        })
+        .sum()
+}
+
+/// 按显示宽度对齐各列，每行去掉末尾的空格
+pub(super) fn render_table<const N: usize>(header: [&str; N], rows: &[[String; N]]) -> String {
+    let header = header.map(String::from);
+    let mut widths = [0; N];
+    for row in std::iter::once(&header).chain(rows) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(display_width(cell));
         }
+    }
+    let mut out = String::new();
+    for row in std::iter::once(&header).chain(rows) {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{}{}", cell, " ".repeat(width - display_width(cell))))
+            .collect();
+        out.push_str(cells.join("  ").trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_render_table() -> Result<()> {
+        let matched: MatchesJson = serde_json::from_str(
+            r#"{
+                "isMatched": true,
+                "matches": [{
+                    "episodeId": 171220001, "animeId": 17122,
+                    "animeTitle": "葬送的芙莉莲", "episodeTitle": "第1话",
+                    "type": "tvseries", "typeDescription": "TV动画", "shift": 0.0
+                }],
+                "errorCode": 0, "success": true, "errorMessage": ""
+            }"#,
+        )?;
+        let rows = [
+            table_row("ep01.mkv".to_string(), Ok(matched)),
+            table_row("ep02.mkv".to_string(), Err(anyhow!("网络错误"))),
+        ];
+        assert_eq!(
+            render_table(TABLE_HEADER, &rows),
+            [
+                "文件      匹配  动画          剧集   偏移",
+                "ep01.mkv  是    葬送的芙莉莲  第1话  0",
+                "ep02.mkv  错误  网络错误",
+                "",
+            ]
+            .join("\n")
+        );
         Ok(())
     }
 }
@@ -0,0 +1,194 @@
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    chinese,
+    dandan::AssOutputs,
+    util::{display_filename, sibling_path},
+    AssCreator, ConvertOptions, Dandan, InputFile,
+};
+
+use super::{clean::is_subtitle_of, is_local_input, Args};
+
+#[derive(Parser, Debug)]
+pub struct MergeArgs {
+    #[clap(
+        long = "danmu",
+        help = "弹幕 json 或 B 站格式的 xml，不指定时使用视频旁边的 .dandanplay.json"
+    )]
+    pub danmu: Option<PathBuf>,
+
+    #[clap(
+        long = "subtitle",
+        help = "需要合并的 ASS 字幕，不指定时使用视频旁边唯一一个不是 ddp2ass 生成的 ASS"
+    )]
+    pub subtitle: Option<PathBuf>,
+
+    #[clap(
+        long = "output",
+        help = "输出的 ASS，不指定时与 download 相同，输出到视频旁边"
+    )]
+    pub output: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub args: Args,
+}
+
+impl MergeArgs {
+    /// 不获取弹幕，也不需要 ffmpeg，使用 download 的排布参数
+    pub fn process(&self) -> Result<()> {
+        let video = Some(InputFile::from(&PathBuf::from(&self.args.input)))
+            .filter(|f| f.path.is_file() && !is_local_input(f));
+
+        let danmu = match (&self.danmu, &video) {
+            (Some(danmu), _) => danmu.clone(),
+            (None, Some(video)) => sibling_path(&video.path, "dandanplay.json"),
+            (None, None) => return Err(anyhow!("没有指定视频时需要使用 --danmu 指定弹幕")),
+        };
+        if !danmu.is_file() {
+            return Err(anyhow!(
+                "弹幕文件 {} 不存在，请使用 --danmu 指定",
+                display_filename(&danmu)
+            ));
+        }
+        let subtitle = match (&self.subtitle, &video) {
+            (Some(subtitle), _) => subtitle.clone(),
+            (None, Some(video)) => find_subtitle(&video.path)?,
+            (None, None) => return Err(anyhow!("没有指定视频时需要使用 --subtitle 指定字幕")),
+        };
+        let output = match (&self.output, &video) {
+            (Some(output), _) => output.clone(),
+            (None, Some(video)) => match self.args.output_suffix.as_str() {
+                "" => sibling_path(&video.path, "ass"),
+                suffix => sibling_path(&video.path, &format!("{}.ass", suffix)),
+            },
+            (None, None) => return Err(anyhow!("没有指定视频时需要使用 --output 指定输出")),
+        };
+        if output == subtitle {
+            return Err(anyhow!(
+                "输出会覆盖字幕 {}，请使用 --output 或 --output-suffix 输出到其他文件",
+                display_filename(&subtitle)
+            ));
+        }
+        Dandan::check_output_path(&output, self.args.force, self.args.on_existing)?;
+
+        let input_file = video.unwrap_or_else(|| InputFile::from(&danmu));
+        let mut danmus = Dandan::load_local_danmus(&danmu)?;
+        let built_in_ass = fs::read_to_string(&subtitle)
+            .with_context(|| format!("字幕 {} 读取失败", display_filename(&subtitle)))?;
+        let export = self.args.danmu_export().for_file(&input_file);
+        let opts = ConvertOptions {
+            built_in_ass: Some(built_in_ass),
+            ..self.args.convert_options(input_file.file_name(), &export)?
+        };
+        chinese::convert_danmus(&mut danmus, opts.canvas_config.ch_convert.as_ref());
+        let report = Dandan::process_by_json(
+            &input_file,
+            &AssOutputs::single(output.clone()),
+            danmus,
+            &opts,
+            &export,
+            self.args.on_existing,
+        )?;
+        if !report.skipped {
+            info!(
+                "{} 与 {} 合并，写入 {} 条弹幕到 {}",
+                display_filename(&danmu),
+                display_filename(&subtitle),
+                report.danmu_count,
+                display_filename(&output)
+            );
+        }
+        Ok(())
+    }
+}
+
+/// 视频旁边不是 ddp2ass 生成的 ASS，有多个时需要手动指定
+fn find_subtitle(video: &Path) -> Result<PathBuf> {
+    let (Some(dir), Some(stem)) = (video.parent(), video.file_stem()) else {
+        return Err(anyhow!("视频路径无法解析"));
+    };
+    let stem = stem.to_string_lossy();
+    let mut subtitles: Vec<_> = dir
+        .read_dir()?
+        .filter_map(|f| f.ok())
+        .map(|f| f.path())
+        .filter(|path| path.is_file() && is_subtitle_of(&stem, path))
+        .filter(|path| fs::read_to_string(path).is_ok_and(|ass| !AssCreator::is_generated(&ass)))
+        .collect();
+    match subtitles.len() {
+        1 => Ok(subtitles.remove(0)),
+        0 => Err(anyhow!(
+            "{} 旁边没有可以合并的字幕，请使用 --subtitle 指定",
+            display_filename(&video.to_path_buf())
+        )),
+        n => Err(anyhow!(
+            "{} 旁边有 {} 个字幕，请使用 --subtitle 指定",
+            display_filename(&video.to_path_buf()),
+            n
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUBTITLE: &str = "[Script Info]\nTitle: 字幕组\n\n[V4+ Styles]\nFormat: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding\nStyle: Default,Arial,20,&H00FFFFFF,&H000000FF,&H00000000,&H00000000,0,0,0,0,100,100,0,0,1,2,2,2,10,10,10,1\n\n[Events]\nFormat: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\nDialogue: 0,0:00:01.00,0:00:03.00,Default,,0,0,0,,字幕组的字幕\n";
+
+    #[test]
+    fn test_merge() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = r#"{
+            "count": 1,
+            "comments": [{ "cid": 1, "p": "1.00,1,16777215,[Gamer]a", "m": "弹幕" }]
+        }"#;
+        for (name, content) in [
+            ("ep01.mkv", ""),
+            ("ep01.dandanplay.json", cache),
+            ("ep01.sc.ass", SUBTITLE),
+        ] {
+            fs::write(dir.path().join(name), content)?;
+        }
+        let video = dir.path().join("ep01.mkv");
+        assert_eq!(find_subtitle(&video)?, dir.path().join("ep01.sc.ass"));
+
+        let video_arg = video.to_string_lossy().to_string();
+        MergeArgs::parse_from(["merge", &video_arg, "--width", "1920"]).process()?;
+        let merged = fs::read_to_string(dir.path().join("ep01.ass"))?;
+        assert!(merged.contains("PlayResX: 1920"));
+        assert!(merged.contains("弹幕"));
+        assert!(merged.contains("字幕组的字幕"));
+
+        // 生成的 ASS 不作为需要合并的字幕
+        assert_eq!(find_subtitle(&video)?, dir.path().join("ep01.sc.ass"));
+
+        let output = dir.path().join("out.ass");
+        MergeArgs::parse_from([
+            "merge",
+            "--danmu",
+            &dir.path().join("ep01.dandanplay.json").to_string_lossy(),
+            "--subtitle",
+            &dir.path().join("ep01.sc.ass").to_string_lossy(),
+            "--output",
+            &output.to_string_lossy(),
+        ])
+        .process()?;
+        assert!(fs::read_to_string(&output)?.contains("字幕组的字幕"));
+
+        let e = MergeArgs::parse_from([
+            "merge",
+            &video_arg,
+            "--output",
+            &dir.path().join("ep01.sc.ass").to_string_lossy(),
+        ])
+        .process()
+        .unwrap_err();
+        assert!(e.to_string().contains("会覆盖字幕"));
+        Ok(())
+    }
+}
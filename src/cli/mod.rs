@@ -1,14 +1,53 @@
 mod args;
+mod clean;
+mod convert;
+mod denylist;
+mod diff;
+mod doctor;
+mod export;
+mod fetch;
+mod info;
 mod match_params;
 mod match_result;
+mod merge;
+mod play;
+mod regen;
+mod rename;
+mod search;
+mod stats;
+mod verify;
 
-use std::path::{absolute, PathBuf};
+use std::{
+    path::{absolute, Path, PathBuf},
+    sync::Arc,
+};
 
+pub use crate::dandan_match::SearchType;
 use anyhow::Result;
 pub use args::*;
 use clap::{command, Parser, Subcommand};
+pub use clean::*;
+pub use convert::*;
+pub use denylist::*;
+pub use diff::*;
+pub use doctor::*;
+pub use export::*;
+pub use fetch::*;
+pub use info::*;
 pub use match_params::*;
 pub use match_result::*;
+pub use merge::*;
+pub use play::*;
+pub use regen::*;
+pub use rename::*;
+pub use search::*;
+pub use stats::*;
+pub use verify::*;
+
+use crate::{
+    util::{display_filename, has_video_ext, sibling_path},
+    InputFile, NonInteractiveSelector, Selector,
+};
 
 #[derive(Parser, Debug)]
 #[clap(
@@ -26,26 +65,293 @@ pub enum Commands {
     #[clap(about = "下载弹幕 (默认命令)")]
     Download(Args),
 
+    #[clap(about = "只匹配并下载弹幕到缓存，不生成 ASS")]
+    Fetch(FetchArgs),
+
+    #[clap(about = "使用弹幕缓存重新生成 ASS，不请求 dandanplay")]
+    Regen(RegenArgs),
+
     #[clap(about = "匹配弹幕参数")]
     MatchParams(MatchParamsArgs),
 
     #[clap(about = "匹配弹幕结果")]
     MatchResult(MatchResultArgs),
+
+    #[clap(about = "将弹幕 json 或 B 站格式的 xml 离线转换为 ASS，不需要视频")]
+    Convert(ConvertArgs),
+
+    #[clap(about = "将弹幕缓存与已有的 ASS 字幕合并，不获取弹幕")]
+    Merge(MergeArgs),
+
+    #[clap(about = "转换单个视频的弹幕后使用 mpv 等播放器播放")]
+    Play(PlayArgs),
+
+    #[clap(about = "搜索 dandanplay 的剧集，查看 episodeId")]
+    Search(SearchArgs),
+
+    #[clap(about = "统计每分钟的弹幕数量和出现最多的弹幕")]
+    Stats(StatsArgs),
+
+    #[clap(about = "按弹幕 id 比较两个弹幕缓存，或比较缓存与 dandanplay 当前的弹幕")]
+    Diff(DiffArgs),
+
+    #[clap(about = "把弹幕缓存批量导出为 B 站格式的 xml 或 CSV")]
+    Export(ExportArgs),
+
+    #[clap(about = "查看视频的匹配结果和弹幕缓存，不请求 dandanplay")]
+    Info(InfoArgs),
+
+    #[clap(about = "按匹配到的动画和剧集重命名视频，字幕和弹幕缓存一起重命名")]
+    Rename(RenameArgs),
+
+    #[clap(about = "删除视频旁边的弹幕缓存和生成的字幕")]
+    Clean(CleanArgs),
+
+    #[clap(about = "管理黑名单，查看会被过滤的弹幕")]
+    Denylist(DenylistArgs),
+
+    #[clap(about = "检查生成的 ASS，有问题时返回非零退出码")]
+    Verify(VerifyArgs),
+
+    #[clap(about = "检查 ffmpeg、dandanplay 连接和输出目录是否可用")]
+    Doctor(DoctorArgs),
+}
+
+/// 视频旁边的弹幕缓存、手动选择的匹配记录和匹配接口的返回缓存，
+/// `dandanplay.matches.json` 为旧版本的匹配接口缓存
+const CACHE_EXTS: [&str; 4] = [
+    "dandanplay.json",
+    "dandanplay.match.json",
+    "dandanplay.api-cache.json",
+    "dandanplay.matches.json",
+];
+
+/// 下载工具在视频旁边创建的未完成标记，如 aria2 的 `ep01.mkv.aria2`
+const INCOMPLETE_EXTS: [&str; 5] = ["part", "aria2", "!qb", "!ut", "crdownload"];
+
+/// 扫描文件夹的选项，直接指定的文件不受影响
+#[derive(Debug, Clone, Copy)]
+pub struct ScanOptions {
+    /// 同时返回找不到对应视频的弹幕缓存
+    pub include_json: bool,
+    /// 跳过文件名中 sample 单独作为一个词的样片
+    pub skip_samples: bool,
 }
 
-pub fn input_path_to_list(input: &str) -> Result<Vec<PathBuf>> {
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self {
+            include_json: false,
+            skip_samples: true,
+        }
+    }
+}
+
+/// 扫描文件夹时跳过的原因：下载中、隐藏文件、空文件和样片
+fn skip_reason(path: &Path, skip_samples: bool) -> Option<&'static str> {
+    let name = path.file_name()?.to_string_lossy();
+    if name.starts_with('.') {
+        return Some("隐藏文件");
+    }
+    if INCOMPLETE_EXTS.iter().any(|ext| {
+        path.extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case(ext))
+            || path.with_file_name(format!("{}.{}", name, ext)).exists()
+    }) {
+        return Some("正在下载");
+    }
+    if path.metadata().is_ok_and(|m| m.len() == 0) {
+        return Some("空文件");
+    }
+    if skip_samples && is_sample(&path.file_stem()?.to_string_lossy()) {
+        return Some("样片，使用 --skip-samples false 处理");
+    }
+    None
+}
+
+/// 文件名中 sample 作为单独的词出现，`Show.Sample`、`ep01-sample` 是样片，`Samples of Love` 不是
+fn is_sample(stem: &str) -> bool {
+    stem.split(['.', '-', '_', ' '])
+        .any(|word| word.eq_ignore_ascii_case("sample"))
+}
+
+/// 扫描文件夹时按 `options` 跳过不需要处理的文件，返回的路径都是绝对路径。
+/// 直接指定文件时不会跳过
+pub fn input_path_to_list(input: &str, options: &ScanOptions) -> Result<Vec<InputFile>> {
     let input_path = absolute(PathBuf::from(&input))?;
-    let match_exts = vec![
-        ".mp4", ".mov", ".wmv", ".avi", ".flv", ".f4v", ".swf", ".mkv", ".webm",
-    ];
-    Ok(if input_path.is_dir() {
-        input_path
+    let paths = if input_path.is_dir() {
+        let files: Vec<_> = input_path
             .read_dir()?
             .filter_map(|f| f.ok())
             .map(|f| f.path())
-            .filter(|f| match_exts.iter().any(|m| f.to_string_lossy().ends_with(m)))
-            .collect()
+            .collect();
+        let mut videos: Vec<_> = files
+            .iter()
+            .filter(|f| has_video_ext(f) && f.is_file())
+            // 跳过 --embed 生成的视频
+            .filter(|f| !f.to_string_lossy().to_lowercase().ends_with(".danmu.mkv"))
+            .cloned()
+            .collect();
+        if options.include_json {
+            let orphans: Vec<_> = files
+                .iter()
+                .filter(|f| f.to_string_lossy().ends_with(".dandanplay.json") && f.is_file())
+                .filter(|f| {
+                    !videos
+                        .iter()
+                        .any(|v| sibling_path(v, "dandanplay.json") == **f)
+                })
+                .cloned()
+                .collect();
+            videos.extend(orphans);
+        }
+        videos.retain(|f| match skip_reason(f, options.skip_samples) {
+            Some(reason) => {
+                info!("跳过 {}：{}", display_filename(f), reason);
+                false
+            }
+            None => true,
+        });
+        videos
     } else {
         [input_path].to_vec()
-    })
+    };
+    Ok(paths.iter().map(InputFile::from).collect())
+}
+
+/// 终端中使用 `TerminalSelector`，否则不进行交互
+pub(crate) fn terminal_selector() -> Arc<dyn Selector> {
+    #[cfg(feature = "interactive")]
+    {
+        use std::io::IsTerminal;
+        if std::io::stdin().is_terminal() {
+            return Arc::new(crate::TerminalSelector);
+        }
+    }
+    Arc::new(NonInteractiveSelector::default())
+}
+
+/// 弹幕 json 或 xml，不需要视频直接转换
+pub fn is_local_input(input_file: &InputFile) -> bool {
+    input_file
+        .path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("json") || ext.eq_ignore_ascii_case("xml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_sample() {
+        for stem in [
+            "sample",
+            "Show.Sample",
+            "ep01-sample",
+            "Show_SAMPLE_1080p",
+            "Show sample",
+        ] {
+            assert!(is_sample(stem), "{}", stem);
+        }
+        for stem in ["Samples of Love", "Show.S01E01", "resample", "sample01"] {
+            assert!(!is_sample(stem), "{}", stem);
+        }
+    }
+
+    #[test]
+    fn test_input_path_to_list() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        for name in [
+            "ep01.mkv",
+            "ep01.dandanplay.json",
+            "ep01.danmu.mkv",
+            "ep02.dandanplay.json",
+            "notes.txt",
+        ] {
+            std::fs::write(dir.path().join(name), "video")?;
+        }
+        let names = |files: Vec<InputFile>| {
+            let mut names: Vec<_> = files.iter().map(|f| f.file_name()).collect();
+            names.sort();
+            names
+        };
+
+        let input = dir.path().to_string_lossy().to_string();
+        assert_eq!(
+            names(input_path_to_list(&input, &ScanOptions::default())?),
+            ["ep01.mkv"]
+        );
+        assert_eq!(
+            names(input_path_to_list(
+                &input,
+                &ScanOptions {
+                    include_json: true,
+                    ..Default::default()
+                }
+            )?),
+            ["ep01.mkv", "ep02.dandanplay.json"]
+        );
+
+        let files = input_path_to_list(
+            &dir.path().join("ep01.mkv").to_string_lossy(),
+            &ScanOptions::default(),
+        )?;
+        assert_eq!(files, [InputFile::from(&dir.path().join("ep01.mkv"))]);
+
+        // 扩展名不区分大小写，跳过像视频的文件夹和没有扩展名的文件
+        let dir = tempfile::tempdir()?;
+        for name in ["EPISODE.MKV", "movie.Mp4", "EP01.Danmu.MKV", "README"] {
+            std::fs::write(dir.path().join(name), "video")?;
+        }
+        std::fs::create_dir(dir.path().join("backup.mkv"))?;
+        assert_eq!(
+            names(input_path_to_list(
+                &dir.path().to_string_lossy(),
+                &ScanOptions::default()
+            )?),
+            ["EPISODE.MKV", "movie.Mp4"]
+        );
+
+        // 跳过下载中、隐藏、空的文件和样片，直接指定时不跳过
+        let dir = tempfile::tempdir()?;
+        for (name, content) in [
+            ("ep01.mkv", "video"),
+            ("ep02.mkv", "video"),
+            ("ep02.mkv.aria2", "video"),
+            ("ep03.mkv.!qb", "video"),
+            ("._ep01.mkv", "video"),
+            ("ep04.mkv", ""),
+            ("Show.Sample.mkv", "video"),
+        ] {
+            std::fs::write(dir.path().join(name), content)?;
+        }
+        let input = dir.path().to_string_lossy().to_string();
+        assert_eq!(
+            names(input_path_to_list(&input, &ScanOptions::default())?),
+            ["ep01.mkv"]
+        );
+        let options = ScanOptions {
+            skip_samples: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            names(input_path_to_list(&input, &options)?),
+            ["Show.Sample.mkv", "ep01.mkv"]
+        );
+        let sample = dir.path().join("Show.Sample.mkv");
+        assert_eq!(
+            input_path_to_list(&sample.to_string_lossy(), &ScanOptions::default())?,
+            [InputFile::from(&sample)]
+        );
+
+        // 相对路径基于当前目录
+        let files = input_path_to_list("ep01.mkv", &ScanOptions::default())?;
+        assert!(files[0].path.is_absolute());
+        assert_eq!(files[0].path, std::env::current_dir()?.join("ep01.mkv"));
+        assert!(is_local_input(
+            &input_path_to_list("ep02.dandanplay.json", &ScanOptions::default())?[0]
+        ));
+        Ok(())
+    }
 }
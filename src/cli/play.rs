@@ -0,0 +1,83 @@
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use std::{ffi::OsString, path::Path, process::Command, sync::Arc};
+
+use crate::{CancelFlag, LogProgress};
+
+use super::{input_path_to_list, is_local_input, Args, ScanOptions};
+
+#[derive(Parser, Debug)]
+pub struct PlayArgs {
+    #[clap(
+        long = "player",
+        help = "播放器，以 `<播放器> <视频> --sub-file=<ASS>` 的形式启动",
+        default_value = "mpv"
+    )]
+    pub player: String,
+
+    #[clap(flatten)]
+    pub args: Args,
+}
+
+impl PlayArgs {
+    /// 只能用于单个视频，ASS 已经存在且弹幕和参数都没有变化时不重新生成。
+    /// 转换成功后启动播放器，播放器启动失败不作为转换失败
+    pub async fn process(&self, cancel: &CancelFlag) -> Result<()> {
+        let input_files = input_path_to_list(&self.args.input, &ScanOptions::default())?;
+        let [input_file] = &input_files[..] else {
+            return Err(anyhow!(
+                "play 只能用于单个视频，找到 {} 个文件",
+                input_files.len()
+            ));
+        };
+        if is_local_input(input_file) {
+            return Err(anyhow!(
+                "play 需要视频文件，{} 不是视频",
+                input_file.file_name()
+            ));
+        }
+
+        let reports = self.args.run(Arc::new(LogProgress), cancel).await?;
+        let ass_path = reports
+            .into_iter()
+            .find_map(|r| r.report?.output_path)
+            .filter(|p| p.is_file())
+            .ok_or_else(|| anyhow!("{}", input_file.log("没有生成 ASS，不启动播放器")))?;
+
+        info!("{}", input_file.log(&format!("使用 {} 播放", self.player)));
+        let mut command = player_command(&self.player, &input_file.path, &ass_path);
+        match tokio::task::block_in_place(|| command.status()) {
+            Ok(status) if !status.success() => warn!("播放器退出：{}", status),
+            Ok(_) => {}
+            Err(e) => error!("播放器 {} 启动失败：{}", self.player, e),
+        }
+        Ok(())
+    }
+}
+
+/// 继承标准输入输出，可以直接在终端中控制播放器
+fn player_command(player: &str, video: &Path, ass: &Path) -> Command {
+    let mut sub_file = OsString::from("--sub-file=");
+    sub_file.push(ass);
+    let mut command = Command::new(player);
+    command.arg(video).arg(sub_file);
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_play_args() {
+        let play = PlayArgs::parse_from(["play", "ep01.mkv", "--player", "vlc", "--width", "1920"]);
+        assert_eq!(play.player, "vlc");
+        assert_eq!(play.args.input, "ep01.mkv");
+        assert_eq!(play.args.canvas_config().width, 1920);
+
+        let command = player_command("mpv", Path::new("/a/ep01.mkv"), Path::new("/a/ep01.ass"));
+        assert_eq!(command.get_program(), "mpv");
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(args, ["/a/ep01.mkv", "--sub-file=/a/ep01.ass"]);
+    }
+}
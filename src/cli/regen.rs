@@ -0,0 +1,165 @@
+use anyhow::{Context, Result};
+use clap::Parser;
+use std::{
+    fs,
+    path::{absolute, Path, PathBuf},
+};
+
+use crate::{
+    dandan::{AssOutputs, CommentsJson},
+    util::{self, display_filename, sibling_path},
+    Dandan, DanmuExport, InputFile,
+};
+
+use super::{match_result::render_table, Args};
+
+#[derive(Parser, Debug)]
+pub struct RegenArgs {
+    #[clap(flatten)]
+    pub args: Args,
+}
+
+impl RegenArgs {
+    /// 只读取 `.dandanplay.json` 缓存重新生成 ASS，不请求 dandanplay，视频不存在时也会生成
+    pub fn process(&self) -> Result<()> {
+        let caches = find_caches(&self.args.input)?;
+        if caches.is_empty() {
+            info!("没有找到弹幕缓存");
+            return Ok(());
+        }
+
+        let export = DanmuExport {
+            per_file: caches.len() > 1,
+            ..self.args.danmu_export()
+        };
+        let mut file_total = 0;
+        let mut danmu_total = 0;
+        let mut rows = vec![];
+        for cache in caches {
+            let input_file = InputFile::from(&video_of(&cache).unwrap_or_else(|| cache.clone()));
+            let (count, status) = match self.regen(&cache, &input_file, &export) {
+                Ok(Some(count)) => {
+                    file_total += 1;
+                    danmu_total += count;
+                    (count.to_string(), "已生成".to_string())
+                }
+                Ok(None) => (String::new(), "已存在，跳过".to_string()),
+                Err(e) => {
+                    warn!("{} {:#}", input_file.log("跳过"), e);
+                    (String::new(), format!("{:#}", e))
+                }
+            };
+            rows.push([input_file.file_name(), count, status]);
+        }
+        print!("{}", render_table(["文件", "弹幕", "结果"], &rows));
+        info!("共生成 {} 个文件，{} 条弹幕", file_total, danmu_total);
+        Ok(())
+    }
+
+    /// 返回写入的弹幕数量，`--on-existing skip` 跳过时为 None
+    fn regen(
+        &self,
+        cache: &PathBuf,
+        input_file: &InputFile,
+        export: &DanmuExport,
+    ) -> Result<Option<u64>> {
+        let mut comments_json = fs::read_to_string(cache)
+            .context("弹幕缓存读取失败")
+            .and_then(|json| {
+                serde_json::from_str::<CommentsJson>(&json).context("弹幕缓存无法解析")
+            })?;
+        let output = Dandan::local_output_path(&InputFile::from(cache), &self.args.output_suffix);
+        Dandan::check_output_path(&output, self.args.force, self.args.on_existing)?;
+
+        let title = comments_json.ass_title(&input_file.file_name());
+        let export = export.for_file(input_file);
+        let opts = self.args.convert_options(title.clone(), &export)?;
+        comments_json.convert_chinese(opts.canvas_config.ch_convert.as_ref(), &title);
+        let danmus = comments_json.into_danmus(&title);
+        let report = Dandan::process_by_json(
+            input_file,
+            &AssOutputs::single(output.clone()),
+            danmus,
+            &opts,
+            &export,
+            self.args.on_existing,
+        )?;
+        if report.skipped {
+            return Ok(None);
+        }
+        info!(
+            "{} {} 条弹幕到 {}",
+            input_file.log("写入"),
+            report.danmu_count,
+            display_filename(&output)
+        );
+        Ok(Some(report.danmu_count))
+    }
+}
+
+/// 文件夹中所有的 `.dandanplay.json`，输入为视频时使用视频旁边的缓存
+pub(super) fn find_caches(input: &str) -> Result<Vec<PathBuf>> {
+    let input = absolute(PathBuf::from(input))?;
+    let mut caches: Vec<_> = match input.is_dir() {
+        true => input
+            .read_dir()?
+            .filter_map(|f| f.ok())
+            .map(|f| f.path())
+            .filter(|f| f.to_string_lossy().ends_with(".dandanplay.json"))
+            .collect(),
+        false if input.to_string_lossy().ends_with(".dandanplay.json") => vec![input],
+        false => vec![sibling_path(&input, "dandanplay.json")],
+    };
+    caches.sort();
+    Ok(caches)
+}
+
+/// 缓存对应的视频，已经删除或移动时为 None
+fn video_of(cache: &Path) -> Option<PathBuf> {
+    util::video_of(cache, |video| {
+        sibling_path(video, "dandanplay.json") == cache
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_regen() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = r#"{
+            "count": 1,
+            "comments": [{ "cid": 1, "p": "1.00,1,16777215,[Gamer]a", "m": "弹幕" }],
+            "animeTitle": "葬送的芙莉莲",
+            "episodeTitle": "第1话"
+        }"#;
+        for (name, content) in [
+            ("ep01.mkv", ""),
+            ("ep01.dandanplay.json", cache),
+            ("ep02.dandanplay.json", cache),
+            ("ep03.dandanplay.json", "{"),
+        ] {
+            fs::write(dir.path().join(name), content)?;
+        }
+        let input = dir.path().to_string_lossy().to_string();
+        RegenArgs::parse_from(["regen", &input, "--width", "1920"]).process()?;
+
+        let ass = fs::read_to_string(dir.path().join("ep01.ass"))?;
+        assert!(ass.contains("PlayResX: 1920"));
+        assert!(ass.contains("Title: 葬送的芙莉莲 第1话 (ep01.mkv)"));
+        let ass = fs::read_to_string(dir.path().join("ep02.ass"))?;
+        assert!(ass.contains("Title: 葬送的芙莉莲 第1话 (ep02.dandanplay.json)"));
+        assert!(!dir.path().join("ep03.ass").exists());
+
+        assert_eq!(
+            video_of(&dir.path().join("ep01.dandanplay.json")),
+            Some(dir.path().join("ep01.mkv"))
+        );
+        assert_eq!(
+            find_caches(&dir.path().join("ep01.mkv").to_string_lossy())?,
+            [dir.path().join("ep01.dandanplay.json")]
+        );
+        Ok(())
+    }
+}
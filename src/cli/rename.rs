@@ -0,0 +1,343 @@
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    dandan::CommentsJson,
+    dandan_match::{AnimeEpisodeItem, DandanMatch, MatchOptions},
+    util::{display_filename, file_name, sibling_path},
+    InputFile, Selector,
+};
+
+use super::{
+    clean::is_subtitle_of, input_path_to_list, is_local_input, match_result::render_table,
+    terminal_selector, ScanOptions, CACHE_EXTS,
+};
+
+#[derive(Parser, Debug)]
+pub struct RenameArgs {
+    #[clap(help = "输入文件路径", default_value = ".")]
+    pub input: String,
+
+    #[clap(
+        long = "template",
+        help = "新文件名的模板，可以使用 anime_title、episode_title、episode_no、anime_id、episode_id，{episode_no:02} 表示补零到 2 位",
+        default_value = "{anime_title} - {episode_no:02} - {episode_title}"
+    )]
+    pub template: String,
+
+    #[clap(long = "yes", help = "不确认，直接重命名")]
+    pub yes: bool,
+}
+
+const TEMPLATE_FIELDS: &str = "anime_title、episode_title、episode_no、anime_id、episode_id";
+
+impl RenameArgs {
+    pub async fn process(&self) -> Result<()> {
+        self.process_with(terminal_selector().as_ref()).await
+    }
+
+    /// 视频和旁边的字幕、弹幕缓存一起重命名，有文件冲突的视频整个跳过
+    async fn process_with(&self, selector: &dyn Selector) -> Result<()> {
+        let mut rows = vec![];
+        let mut plans: Vec<Vec<(PathBuf, PathBuf)>> = vec![];
+        let mut targets: HashSet<PathBuf> = HashSet::new();
+        for input_file in input_path_to_list(&self.input, &ScanOptions::default())? {
+            if is_local_input(&input_file) || !input_file.path.is_file() {
+                continue;
+            }
+            let filename = input_file.file_name();
+            let item = match resolve_match(&input_file, selector).await {
+                Ok(item) => item,
+                Err(e) => {
+                    rows.push([filename, String::new(), format!("匹配失败：{:#}", e)]);
+                    continue;
+                }
+            };
+            let stem = sanitize_filename(&render_template(&self.template, &item)?);
+            if stem.is_empty() {
+                rows.push([filename, String::new(), "新文件名为空".to_string()]);
+                continue;
+            }
+            let renames = sidecar_renames(&input_file.path, &stem)?;
+            let new_name = file_name(&renames[0].1);
+            if renames[0].0 == renames[0].1 {
+                rows.push([filename, new_name, "无需重命名".to_string()]);
+                continue;
+            }
+            // 新文件名旁边已有的字幕等文件会被误认为是这个视频的，也视为冲突
+            let conflict = renames
+                .iter()
+                .map(|(_, to)| to.clone())
+                .find(|to| to.exists() || targets.contains(to))
+                .or(sidecars(&renames[0].1)?
+                    .into_iter()
+                    .find(|p| !renames.iter().any(|(from, _)| from == p)));
+            if let Some(to) = conflict {
+                rows.push([
+                    filename,
+                    new_name,
+                    format!("{} 已存在，跳过", file_name(&to)),
+                ]);
+                continue;
+            }
+            targets.extend(renames.iter().map(|(_, to)| to.clone()));
+            let note = match renames.len() {
+                1 => String::new(),
+                n => format!("同时重命名 {} 个字幕和缓存", n - 1),
+            };
+            rows.push([filename, new_name, note]);
+            plans.push(renames);
+        }
+        print!("{}", render_table(["文件", "新文件名", "说明"], &rows));
+        if plans.is_empty() {
+            info!("没有需要重命名的文件");
+            return Ok(());
+        }
+
+        if !self.yes {
+            let options = ["是".to_string(), "否".to_string()];
+            let title = format!("确认重命名 {} 个视频？", plans.len());
+            let confirmed = selector
+                .select(&title, &options)
+                .context("没有终端时请使用 --yes 确认")?;
+            if confirmed != 0 {
+                info!("已取消重命名");
+                return Ok(());
+            }
+        }
+        for renames in plans {
+            for (from, to) in renames {
+                fs::rename(&from, &to).with_context(|| {
+                    format!(
+                        "{} 重命名为 {} 失败",
+                        display_filename(&from),
+                        display_filename(&to)
+                    )
+                })?;
+                info!(
+                    "{} 重命名为 {}",
+                    display_filename(&from),
+                    display_filename(&to)
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 优先使用弹幕缓存中记录的匹配结果，没有时与 download 相同进行匹配
+async fn resolve_match(
+    input_file: &InputFile,
+    selector: &dyn Selector,
+) -> Result<AnimeEpisodeItem> {
+    let cached = fs::read_to_string(sibling_path(&input_file.path, "dandanplay.json"))
+        .ok()
+        .and_then(|json| serde_json::from_str::<CommentsJson>(&json).ok())
+        .and_then(|cache| cache.anime_episode_item());
+    match cached {
+        Some(item) => Ok(item),
+        None => {
+            DandanMatch::get_anime_episode_item(input_file, &MatchOptions::default(), selector)
+                .await
+        }
+    }
+}
+
+/// `{name}` 替换为字段，`{name:02}` 前面补零到 2 位，`{name:3}` 前面补空格到 3 位
+fn render_template(template: &str, item: &AnimeEpisodeItem) -> Result<String> {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or_else(|| anyhow!("模板中的 {{ 没有对应的 }}：{}", template))?;
+        let (name, spec) = rest[start + 1..end]
+            .split_once(':')
+            .unwrap_or((&rest[start + 1..end], ""));
+        let value = match name.trim() {
+            "anime_title" => item.anime_title.clone(),
+            "episode_title" => item.episode_title.clone(),
+            // episodeId 为 animeId * 10000 + 集数
+            "episode_no" => (item.episode_id % 10000).to_string(),
+            "anime_id" => item.anime_id.to_string(),
+            "episode_id" => item.episode_id.to_string(),
+            name => {
+                return Err(anyhow!(
+                    "模板中未知的字段 {}，可以使用 {}",
+                    name,
+                    TEMPLATE_FIELDS
+                ))
+            }
+        };
+        let width: usize = match spec.trim_start_matches('0') {
+            "" => 0,
+            width => width
+                .parse()
+                .map_err(|_| anyhow!("模板中的格式无法解析：{}", spec))?,
+        };
+        let fill = if spec.starts_with('0') { "0" } else { " " };
+        let len = value.chars().count();
+        if len < width {
+            out.push_str(&fill.repeat(width - len));
+        }
+        out.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// 替换文件名中不能使用的字符，去掉结尾的 `.` 和空格
+fn sanitize_filename(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    name.trim().trim_end_matches(['.', ' ']).to_string()
+}
+
+/// 视频旁边已有的同名字幕和弹幕缓存，视频不存在时也可以使用
+fn sidecars(video: &Path) -> Result<Vec<PathBuf>> {
+    let (Some(dir), Some(stem)) = (video.parent(), video.file_stem()) else {
+        return Err(anyhow!("视频路径无法解析"));
+    };
+    let stem = stem.to_string_lossy();
+    let mut sidecars: Vec<_> = dir
+        .read_dir()?
+        .filter_map(|f| f.ok())
+        .map(|f| f.path())
+        .filter(|path| path != video && path.is_file())
+        .filter(|path| {
+            is_subtitle_of(&stem, path)
+                || CACHE_EXTS
+                    .iter()
+                    .any(|ext| *path == sibling_path(video, ext))
+        })
+        .collect();
+    sidecars.sort();
+    Ok(sidecars)
+}
+
+/// 视频和字幕、弹幕缓存重命名前后的路径，视频在第一个
+fn sidecar_renames(video: &Path, new_stem: &str) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let stem_len = video.file_stem().map_or(0, |stem| stem.len());
+    Ok([video.to_path_buf()]
+        .into_iter()
+        .chain(sidecars(video)?)
+        .map(|from| {
+            let name = file_name(&from);
+            let to = from.with_file_name(format!("{}{}", new_stem, &name[stem_len..]));
+            (from, to)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NonInteractiveSelector;
+
+    fn item() -> AnimeEpisodeItem {
+        AnimeEpisodeItem {
+            anime_id: 17122,
+            anime_title: "葬送的芙莉莲".to_string(),
+            episode_id: 171220005,
+            episode_title: "第5话 死者的幻影".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_render_template() -> Result<()> {
+        assert_eq!(
+            render_template("{anime_title} - {episode_no:02} - {episode_title}", &item())?,
+            "葬送的芙莉莲 - 05 - 第5话 死者的幻影"
+        );
+        assert_eq!(render_template("[{episode_no:3}]", &item())?, "[  5]");
+        assert!(render_template("{unknown}", &item()).is_err());
+        assert!(render_template("{anime_title", &item()).is_err());
+        assert_eq!(sanitize_filename("Re:Zero / 第1话?. "), "Re_Zero _ 第1话_");
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rename() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = r#"{
+            "count": 0, "comments": [],
+            "animeId": 17122, "animeTitle": "葬送的芙莉莲",
+            "episodeId": 171220005, "episodeTitle": "第5话 死者的幻影"
+        }"#;
+        for (name, content) in [
+            ("ep05.mkv", "video"),
+            ("ep05.dandanplay.json", cache),
+            ("ep05.ass", ""),
+            ("ep05.sc.ass", ""),
+            ("ep050.ass", ""),
+        ] {
+            fs::write(dir.path().join(name), content)?;
+        }
+        let input = dir.path().to_string_lossy().to_string();
+        let args = RenameArgs::parse_from([
+            "rename",
+            &input,
+            "--template",
+            "{episode_no:02} {anime_title}",
+        ]);
+
+        // 没有终端也没有 --yes 时不重命名
+        assert!(args
+            .process_with(&NonInteractiveSelector::default())
+            .await
+            .is_err());
+        assert!(dir.path().join("ep05.mkv").exists());
+
+        args.process_with(&NonInteractiveSelector::with_answers(["是"]))
+            .await?;
+        let mut names: Vec<_> = dir
+            .path()
+            .read_dir()?
+            .map(|f| file_name(&f.unwrap().path()))
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "05 葬送的芙莉莲.ass",
+                "05 葬送的芙莉莲.dandanplay.json",
+                "05 葬送的芙莉莲.mkv",
+                "05 葬送的芙莉莲.sc.ass",
+                "ep050.ass",
+            ]
+        );
+
+        // 目标已存在时跳过
+        fs::write(dir.path().join("ep06.mkv"), "video")?;
+        fs::write(
+            dir.path().join("ep06.dandanplay.json"),
+            cache.replace("171220005", "171220006"),
+        )?;
+        fs::write(dir.path().join("06 葬送的芙莉莲.ass"), "")?;
+        RenameArgs::parse_from([
+            "rename",
+            &input,
+            "--template",
+            "{episode_no:02} {anime_title}",
+            "--yes",
+        ])
+        .process_with(&NonInteractiveSelector::default())
+        .await?;
+        assert!(dir.path().join("ep06.mkv").exists());
+        Ok(())
+    }
+}
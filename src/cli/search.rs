@@ -0,0 +1,95 @@
+use anyhow::Result;
+use clap::Parser;
+
+use crate::{
+    dandan_match::{DandanMatch, SearchJson},
+    Error,
+};
+
+use super::match_result::render_table;
+
+#[derive(Parser, Debug)]
+pub struct SearchArgs {
+    #[clap(help = "动画名称")]
+    pub anime: String,
+
+    #[clap(
+        long = "episode",
+        help = "集数，如 5，剧场版等没有集数时可以使用 movie"
+    )]
+    pub episode: Option<String>,
+
+    #[clap(long = "json", help = "输出 dandanplay 返回的原始 json")]
+    pub json: bool,
+}
+
+impl SearchArgs {
+    /// 没有搜索到剧集时返回 `Error::NoMatch`
+    pub async fn process(&self) -> Result<()> {
+        let search_json =
+            DandanMatch::search_episodes(&self.anime, &self.anime, self.episode.as_deref()).await?;
+        if search_json.animes.iter().all(|a| a.episodes.is_empty()) {
+            return Err(Error::NoMatch(format!("搜索 {} 结果为空", self.anime)).into());
+        }
+        if self.json {
+            println!("{}", serde_json::to_string(&search_json)?);
+        } else {
+            print!("{}", render_table(TABLE_HEADER, &table_rows(&search_json)));
+        }
+        if search_json.has_more {
+            warn!("搜索结果过多，只显示了部分动画，可以使用更完整的名称搜索");
+        }
+        Ok(())
+    }
+}
+
+const TABLE_HEADER: [&str; 4] = ["animeId", "动画", "episodeId", "剧集"];
+
+fn table_rows(search_json: &SearchJson) -> Vec<[String; 4]> {
+    search_json
+        .animes
+        .iter()
+        .flat_map(|anime| {
+            anime.episodes.iter().map(|episode| {
+                [
+                    anime.anime_id.to_string(),
+                    anime.anime_title.clone(),
+                    episode.episode_id.to_string(),
+                    episode.episode_title.clone(),
+                ]
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table_rows() -> Result<()> {
+        let search_json: SearchJson = serde_json::from_str(
+            r#"{
+                "hasMore": false,
+                "animes": [{
+                    "animeId": 17122, "animeTitle": "葬送的芙莉莲",
+                    "type": "tvseries", "typeDescription": "TV动画",
+                    "episodes": [
+                        { "episodeId": 171220005, "episodeTitle": "第5话 亡灵的幻影" }
+                    ]
+                }],
+                "errorCode": 0, "success": true, "errorMessage": ""
+            }"#,
+        )?;
+        assert_eq!(
+            render_table(TABLE_HEADER, &table_rows(&search_json)),
+            [
+                "animeId  动画          episodeId  剧集",
+                "17122    葬送的芙莉莲  171220005  第5话 亡灵的幻影",
+                "",
+            ]
+            .join("\n")
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use std::sync::Arc;
+
+use crate::{
+    CacheOptions, Dandan, DandanplayProvider, DanmuProvider, DanmuStats, InputFile, LogProgress,
+};
+
+use super::{input_path_to_list, is_local_input, terminal_selector, ScanOptions};
+
+#[derive(Parser, Debug)]
+pub struct StatsArgs {
+    #[clap(
+        help = "输入文件路径，可以是视频、文件夹、弹幕 json 或 B 站格式的 xml",
+        default_value = "."
+    )]
+    pub input: String,
+
+    #[clap(long = "force", help = "不使用弹幕缓存，重新获取")]
+    pub force: bool,
+
+    #[clap(long = "top", help = "显示出现最多的弹幕数量", default_value = "20")]
+    pub top: usize,
+
+    #[clap(long = "chart", help = "以字符柱状图显示每分钟的弹幕数量")]
+    pub chart: bool,
+
+    #[clap(long = "json", help = "输出 json", conflicts_with = "csv")]
+    pub json: bool,
+
+    #[clap(long = "csv", help = "以 csv 输出每分钟的弹幕数量")]
+    pub csv: bool,
+}
+
+/// 柱状图最长的字符数
+const CHART_WIDTH: usize = 50;
+
+impl StatsArgs {
+    /// 视频使用弹幕缓存，没有缓存或 `--force` 时匹配并获取弹幕
+    pub async fn process(&self) -> Result<()> {
+        let input_files = input_path_to_list(&self.input, &ScanOptions::default())?;
+        if input_files.is_empty() {
+            return Err(anyhow!("没有找到任何文件"));
+        }
+        let provider = DandanplayProvider {
+            options: CacheOptions {
+                force: self.force,
+                ..Default::default()
+            },
+            selector: terminal_selector(),
+            progress: Arc::new(LogProgress),
+        };
+
+        if self.csv {
+            println!("file,minute,count");
+        }
+        for input_file in &input_files {
+            let danmus = match is_local_input(input_file) {
+                true => Dandan::load_local_danmus(&input_file.path)?,
+                false => {
+                    Dandan::check_input(input_file, &None)?;
+                    provider.fetch(input_file).await?
+                }
+            };
+            let stats = DanmuStats::from_danmus(&danmus, self.top);
+            if self.json {
+                println!("{}", input_file.file_name());
+                println!("{}", serde_json::to_string(&stats)?);
+            } else if self.csv {
+                print!("{}", render_csv(input_file, &stats));
+            } else {
+                print!("{}", self.render_text(input_file, &stats));
+            }
+        }
+        Ok(())
+    }
+
+    fn render_text(&self, input_file: &InputFile, stats: &DanmuStats) -> String {
+        let mut out = format!("{}\n", input_file.file_name());
+        out += &format!("弹幕总数 {}\n", stats.total);
+        let types = &stats.types;
+        out += &format!(
+            "滚动 {}，顶部 {}，底部 {}，逆向 {}\n",
+            types.float, types.top, types.bottom, types.reverse
+        );
+        if let Some(peak) = stats.peak_minute {
+            out += &format!(
+                "弹幕最多的一分钟 {}，{} 条\n",
+                minute_label(peak),
+                stats.per_minute[peak]
+            );
+        }
+        if !stats.top_contents.is_empty() {
+            out += "出现最多的弹幕\n";
+            for c in &stats.top_contents {
+                out += &format!("{:>6}  {}\n", c.count, c.content);
+            }
+        }
+        out += "每分钟弹幕数量\n";
+        let max = stats.per_minute.iter().copied().max().unwrap_or(0).max(1);
+        for (minute, count) in stats.per_minute.iter().enumerate() {
+            match self.chart {
+                true => {
+                    let bar = "█".repeat((count * CHART_WIDTH).div_ceil(max));
+                    out += &format!(
+                        "{}  {:<width$}  {}\n",
+                        minute_label(minute),
+                        bar,
+                        count,
+                        width = CHART_WIDTH
+                    );
+                }
+                false => out += &format!("{}  {}\n", minute_label(minute), count),
+            }
+        }
+        out + "\n"
+    }
+}
+
+/// 如 `01:05` 表示第 65 分钟
+fn minute_label(minute: usize) -> String {
+    format!("{:02}:{:02}", minute / 60, minute % 60)
+}
+
+fn render_csv(input_file: &InputFile, stats: &DanmuStats) -> String {
+    let file = input_file.file_name().replace('"', "\"\"");
+    stats
+        .per_minute
+        .iter()
+        .enumerate()
+        .map(|(minute, count)| format!("\"{}\",{},{}\n", file, minute, count))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Danmu;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_render() {
+        let danmu = |timeline_s: f64, content: &str| Danmu {
+            timeline_s,
+            content: content.to_string(),
+            ..Default::default()
+        };
+        let stats = DanmuStats::from_danmus(
+            &[danmu(1.0, "草"), danmu(2.0, "草"), danmu(70.0, "好耶")],
+            20,
+        );
+        let input_file = InputFile::from(&PathBuf::from("/a/ep01.mkv"));
+        let args = StatsArgs::parse_from(["stats", "--chart"]);
+        assert_eq!(
+            args.render_text(&input_file, &stats),
+            [
+                "ep01.mkv",
+                "弹幕总数 3",
+                "滚动 3，顶部 0，底部 0，逆向 0",
+                "弹幕最多的一分钟 00:00，2 条",
+                "出现最多的弹幕",
+                "     2  草",
+                "     1  好耶",
+                "每分钟弹幕数量",
+                &format!("00:00  {}  2", "█".repeat(50)),
+                &format!("00:01  {}{}  1", "█".repeat(25), " ".repeat(25)),
+                "",
+                "",
+            ]
+            .join("\n")
+        );
+        assert_eq!(
+            render_csv(&input_file, &stats),
+            "\"ep01.mkv\",0,2\n\"ep01.mkv\",1,1\n"
+        );
+    }
+}
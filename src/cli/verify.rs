@@ -0,0 +1,226 @@
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use std::{
+    fs,
+    path::{absolute, Path, PathBuf},
+};
+
+use crate::{
+    util::{self, display_filename, time_label},
+    AssCreator, ParsedAss,
+};
+
+use super::{clean::is_subtitle_of, match_result::render_table};
+
+#[derive(Parser, Debug)]
+pub struct VerifyArgs {
+    #[clap(help = "ASS 文件或所在的文件夹", default_value = ".")]
+    pub input: String,
+
+    #[clap(
+        long = "max-simultaneous",
+        help = "同时显示的 Dialogue 超过这个数量时视为失败",
+        default_value = "200"
+    )]
+    pub max_simultaneous: usize,
+}
+
+impl VerifyArgs {
+    /// 只检查 ddp2ass 生成的 ASS，有文件未通过时返回错误
+    pub fn process(&self) -> Result<()> {
+        let input = absolute(PathBuf::from(&self.input))?;
+        let mut files: Vec<_> = match input.is_dir() {
+            true => input
+                .read_dir()?
+                .filter_map(|f| f.ok())
+                .map(|f| f.path())
+                .filter(|f| f.extension().is_some_and(|ext| ext == "ass"))
+                .collect(),
+            false => vec![input],
+        };
+        files.sort();
+
+        let mut rows = vec![];
+        let mut failed = 0;
+        for path in files {
+            let bytes = fs::read(&path)?;
+            if !AssCreator::is_generated(&String::from_utf8_lossy(&bytes)) {
+                debug!("{} 不是 ddp2ass 生成的字幕，跳过", display_filename(&path));
+                continue;
+            }
+            let duration = video_of(&path).and_then(|video| video_duration(&video));
+            let problems = check_ass(&bytes, duration, self.max_simultaneous);
+            if !problems.is_empty() {
+                failed += 1;
+            }
+            rows.push([
+                path.file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+                if problems.is_empty() {
+                    "通过"
+                } else {
+                    "失败"
+                }
+                .to_string(),
+                problems.join("；"),
+            ]);
+        }
+        if rows.is_empty() {
+            info!("没有找到 ddp2ass 生成的 ASS");
+            return Ok(());
+        }
+        print!("{}", render_table(["文件", "结果", "问题"], &rows));
+        match failed {
+            0 => Ok(()),
+            n => Err(anyhow!("{} 个文件未通过检查", n)),
+        }
+    }
+}
+
+/// `duration` 为视频时长，找不到视频或 ffprobe 时为 None，不检查超出时长的事件
+fn check_ass(bytes: &[u8], duration: Option<f64>, max_simultaneous: usize) -> Vec<String> {
+    let mut problems = vec![];
+    if let Err(e) = std::str::from_utf8(bytes) {
+        problems.push(format!("第 {} 字节开始不是有效的 UTF-8", e.valid_up_to()));
+    }
+    let parsed = ParsedAss::parse(&String::from_utf8_lossy(bytes));
+    if !parsed.invalid_events.is_empty() {
+        problems.push(format!(
+            "{} 个事件无法解析，如 {}",
+            parsed.invalid_events.len(),
+            parsed.invalid_events[0]
+        ));
+    }
+
+    let dialogues: Vec<_> = parsed.events.iter().filter(|e| !e.comment).collect();
+    let reversed: Vec<_> = dialogues.iter().filter(|e| e.end < e.start).collect();
+    if let Some(first) = reversed.first() {
+        problems.push(format!(
+            "{} 个 Dialogue 的结束时间早于开始时间，如 {}",
+            reversed.len(),
+            time_label(first.start)
+        ));
+    }
+    if let Some(duration) = duration {
+        let outside: Vec<_> = dialogues.iter().filter(|e| e.start > duration).collect();
+        if let Some(first) = outside.first() {
+            problems.push(format!(
+                "{} 个 Dialogue 在视频结束（{}）之后开始，如 {}",
+                outside.len(),
+                time_label(duration),
+                time_label(first.start)
+            ));
+        }
+    }
+    let mut undefined: Vec<&str> = dialogues
+        .iter()
+        .map(|e| e.style.as_str())
+        .filter(|style| !parsed.styles.iter().any(|s| s == style))
+        .collect();
+    undefined.sort();
+    undefined.dedup();
+    if !undefined.is_empty() {
+        problems.push(format!("使用了未定义的样式 {}", undefined.join("、")));
+    }
+
+    // 结束和开始时间相同时先结束，不算同时显示
+    let mut points: Vec<(f64, i32)> = dialogues
+        .iter()
+        .filter(|e| e.end > e.start)
+        .flat_map(|e| [(e.start, 1), (e.end, -1)])
+        .collect();
+    points.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+    let (mut current, mut peak, mut peak_time) = (0, 0, 0.0);
+    for (t, delta) in points {
+        current += delta;
+        if current > peak {
+            peak = current;
+            peak_time = t;
+        }
+    }
+    if peak as usize > max_simultaneous {
+        problems.push(format!(
+            "{} 同时显示 {} 个 Dialogue，超过 {}",
+            time_label(peak_time),
+            peak,
+            max_simultaneous
+        ));
+    }
+    problems
+}
+
+/// 同目录下 `ep01.ass`、`ep01.danmu.ass` 对应的 `ep01.mkv` 等视频
+fn video_of(ass_path: &Path) -> Option<PathBuf> {
+    util::video_of(ass_path, |video| {
+        video
+            .file_stem()
+            .is_some_and(|stem| is_subtitle_of(&stem.to_string_lossy(), ass_path))
+    })
+}
+
+fn video_duration(video: &Path) -> Option<f64> {
+    match util::video_duration(video) {
+        Ok(duration) => Some(duration),
+        Err(e) => {
+            debug!("无法读取视频时长，跳过时长检查：{:#}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = "[V4+ Styles]\nStyle: Float,黑体,25,&H00FFFFFF,&H00FFFFFF,&H00000000,&H00000000,0,0,0,0,100,100,0,0,1,1,0,7,0,0,0,1\n\n[Events]\nFormat: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n";
+
+    #[test]
+    fn test_check_ass() {
+        let ass = |events: &str| format!("{}{}", HEADER, events);
+        assert!(check_ass(
+            ass("Dialogue: 2,0:00:01.00,0:00:05.00,Float,,0,0,0,,a\n").as_bytes(),
+            Some(60.0),
+            1
+        )
+        .is_empty());
+
+        let events = "\
+            Dialogue: 2,0:00:05.00,0:00:01.00,Float,,0,0,0,,a\n\
+            Dialogue: 2,0:01:05.00,0:01:10.00,Top,,0,0,0,,b\n\
+            Dialogue: 2,0:01:06.00,0:01:10.00,Float,,0,0,0,,c\n\
+            Comment: 2,0:02:00.00,0:01:00.00,Missing,,0,0,0,,d\n";
+        assert_eq!(
+            check_ass(ass(events).as_bytes(), Some(60.0), 1),
+            [
+                "1 个 Dialogue 的结束时间早于开始时间，如 0:00:05",
+                "2 个 Dialogue 在视频结束（0:01:00）之后开始，如 0:01:05",
+                "使用了未定义的样式 Top",
+                "0:01:06 同时显示 2 个 Dialogue，超过 1",
+            ]
+        );
+        // 没有视频时长时不检查超出时长
+        assert_eq!(check_ass(ass(events).as_bytes(), None, 2).len(), 2);
+
+        let mut bytes = ass("Dialogue: 2,0:00:01.00,0:00:05.00,Float,,0,0,0,,a\n").into_bytes();
+        bytes.push(0xff);
+        assert_eq!(
+            check_ass(&bytes, None, 1),
+            [format!("第 {} 字节开始不是有效的 UTF-8", bytes.len() - 1)]
+        );
+    }
+
+    #[test]
+    fn test_video_of() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        for name in ["ep01.mkv", "ep01.danmu.ass", "ep02.ass"] {
+            fs::write(dir.path().join(name), "")?;
+        }
+        assert_eq!(
+            video_of(&dir.path().join("ep01.danmu.ass")),
+            Some(dir.path().join("ep01.mkv"))
+        );
+        assert_eq!(video_of(&dir.path().join("ep02.ass")), None);
+        Ok(())
+    }
+}
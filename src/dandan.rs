@@ -1,22 +1,48 @@
+#[cfg(feature = "network")]
 use crate::{
-    cli::SimplifiedOrTraditional, dandan_match::DandanMatch, util::display_filename, AssCreator,
-    CanvasConfig, Danmu, DanmuType, InputFile,
+    api,
+    cancel::CancelFlag,
+    chapter::suggest_offset,
+    dandan_match::{AnimeEpisodeItem, DandanMatch, MatchOptions},
+    error::is_skipped_error,
+    progress::{FileOutcome, FileReport},
+    provider::DanmuProvider,
+    selector::Selector,
+    util::{
+        command_output_timeout, ffmpeg_command, ffprobe_command, modified_ms, stderr_tail,
+        underlined,
+    },
+};
+use crate::{
+    bilibili_xml,
+    chinese::{self, SimplifiedOrTraditional},
+    embed::EmbedConfig,
+    export::{DanmuExport, ExportRecord, ExportStatus},
+    progress::{Progress, Stage},
+    source::{self, SourceOptions},
+    util::{display_filename, sibling_path, write_atomic},
+    AssCreator, CanvasConfig, Danmu, DanmuType, DenyReason, Denylist, Error, InputFile,
 };
 use anyhow::{anyhow, Context, Result};
-use promkit::preset::listbox::Listbox;
 use serde::{Deserialize, Serialize};
 use std::{
     cmp::Ordering,
     collections::HashSet,
-    fs::{self, read_to_string, File},
+    fs::{self, read_to_string},
     io::Write,
-    path::PathBuf,
-    process::Command,
+    path::{Path, PathBuf},
+};
+#[cfg(feature = "network")]
+use std::{
+    ffi::OsString,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+/// dandanplay 返回的弹幕，同时也是 `.dandanplay.json` 缓存的格式
 #[derive(Serialize, Deserialize)]
-struct CommentsJson {
-    count: i64,
+pub struct CommentsJson {
+    pub count: i64,
     #[serde(rename = "episodeId")]
     pub episode_id: Option<i64>,
     #[serde(rename = "animeId")]
@@ -25,41 +51,378 @@ struct CommentsJson {
     pub anime_title: Option<String>,
     #[serde(rename = "episodeTitle")]
     pub episode_title: Option<String>,
-    comments: Vec<CommentItem>,
+    /// 弹幕获取时间，unix 时间戳，单位为秒
+    #[serde(rename = "fetchedAt", default, skip_serializing_if = "Option::is_none")]
+    pub fetched_at: Option<u64>,
+    /// 上次生成 ASS 时弹幕和生成参数的指纹
+    #[serde(
+        rename = "assFingerprint",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub ass_fingerprint: Option<String>,
+    /// 获取弹幕时视频的 hash，与匹配时的 hash 算法相同
+    #[serde(rename = "fileHash", default, skip_serializing_if = "Option::is_none")]
+    pub file_hash: Option<String>,
+    /// 获取弹幕时视频的大小
+    #[serde(rename = "fileSize", default, skip_serializing_if = "Option::is_none")]
+    pub file_size: Option<u64>,
+    /// 获取弹幕时视频的修改时间，unix 时间戳，单位为毫秒。与大小都不变时不再计算 hash
+    #[serde(
+        rename = "fileModifiedMs",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub file_modified_ms: Option<u64>,
+    /// 匹配时使用的 `--episode-offset`
+    #[serde(
+        rename = "episodeOffset",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub episode_offset: Option<i32>,
+    /// 使用 `--ext-url` 从第三方网址获取的弹幕，记录获取的网址
+    #[serde(rename = "extUrls", default, skip_serializing_if = "Option::is_none")]
+    pub ext_urls: Option<Vec<String>>,
+    /// dandanplay 按这个写法转换了弹幕，None 时不知道弹幕的写法
+    #[serde(rename = "chConvert", default, skip_serializing_if = "Option::is_none")]
+    pub ch_convert: Option<SimplifiedOrTraditional>,
+    pub comments: Vec<CommentItem>,
+    /// 本次直接使用了缓存，没有请求 dandanplay，不写入缓存
+    #[serde(skip)]
+    pub from_cache: bool,
+}
+
+#[cfg(feature = "network")]
+impl CommentsJson {
+    /// 缓存获取至今的时长，旧缓存没有记录获取时间时使用文件修改时间
+    pub(crate) fn cache_age(&self, json_path: &Path) -> Result<Duration> {
+        let fetched_at = match self.fetched_at {
+            Some(t) => UNIX_EPOCH + Duration::from_secs(t),
+            None => json_path.metadata()?.modified()?,
+        };
+        Ok(SystemTime::now()
+            .duration_since(fetched_at)
+            .unwrap_or_default())
+    }
+
+    /// 合并旧缓存中的弹幕，按 cid 去重，返回新增的数量和只在旧缓存中的数量
+    fn merge_cached(&mut self, cached: CommentsJson) -> (usize, usize) {
+        let cached_cids: HashSet<u64> = cached.comments.iter().map(|c| c.cid).collect();
+        let cids: HashSet<u64> = self.comments.iter().map(|c| c.cid).collect();
+        let added = cids.difference(&cached_cids).count();
+        let kept: Vec<_> = cached
+            .comments
+            .into_iter()
+            .filter(|c| !cids.contains(&c.cid))
+            .collect();
+        let kept_count = kept.len();
+        // 保留的旧弹幕与新弹幕的写法可能不同
+        if kept_count > 0 && self.ch_convert != cached.ch_convert {
+            self.ch_convert = None;
+        }
+        self.comments.extend(kept);
+        self.comments.sort_by_key(|c| c.cid);
+        self.count = self.comments.len() as i64;
+        (added, kept_count)
+    }
+
+    /// 合并其他网址获取的弹幕，参数和内容都相同的视为重复，返回新增的数量
+    fn merge_ext(&mut self, other: CommentsJson) -> usize {
+        let mut seen: HashSet<(String, String)> = self
+            .comments
+            .iter()
+            .map(|c| (c.p.clone(), c.m.clone()))
+            .collect();
+        let before = self.comments.len();
+        self.comments.extend(
+            other
+                .comments
+                .into_iter()
+                .filter(|c| seen.insert((c.p.clone(), c.m.clone()))),
+        );
+        self.count = self.comments.len() as i64;
+        self.comments.len() - before
+    }
+}
+
+impl CommentsJson {
+    /// 解析弹幕参数，跳过无法解析的弹幕，`title` 用于日志。
+    /// `withRelated=true` 时同一条弹幕可能同时出现在多个弹幕池中，cid 相同的只保留第一条
+    pub fn into_danmus(self, title: &str) -> Vec<Danmu> {
+        let mut danmus = Vec::new();
+        let mut skipped = 0;
+        let mut cids = HashSet::new();
+        let mut duplicated = 0;
+        let mut color_clamped = 0;
+        for c in self.comments {
+            if !cids.insert(c.cid) {
+                duplicated += 1;
+                continue;
+            }
+            let pos = match Position::parse(c.p) {
+                Ok(pos) => pos,
+                Err(e) => {
+                    debug!("跳过弹幕 {}：{:?}", c.cid, e);
+                    skipped += 1;
+                    continue;
+                }
+            };
+            color_clamped += usize::from(pos.color_clamped);
+            danmus.push(Danmu {
+                content: c.m,
+                timeline_s: pos.timestamp_s,
+                fontsize: 0,
+                r#type: pos.mode,
+                rgb: pos.color,
+                source: pos.source,
+                user_id: pos.user_id,
+                cid: c.cid,
+            });
+        }
+
+        if skipped > 0 {
+            warn!("跳过 {} 条无法解析的弹幕（{}）", skipped, title);
+        }
+        if duplicated > 0 {
+            info!("去掉 {} 条 cid 重复的弹幕（{}）", duplicated, title);
+        }
+        if color_clamped > 0 {
+            warn!(
+                "{} 条弹幕的颜色超出范围，按白色显示（{}）",
+                color_clamped, title
+            );
+        }
+        danmus
+    }
+
+    /// 记录的写法与 `to` 不同时在本地转换简繁，dandanplay 已经转换过时不重复转换
+    pub fn convert_chinese(&mut self, to: Option<&SimplifiedOrTraditional>, title: &str) {
+        let Some(to) = to else {
+            return;
+        };
+        if self.ch_convert.as_ref() == Some(to) {
+            return;
+        }
+        for comment in &mut self.comments {
+            comment.m = chinese::convert(&comment.m, to);
+        }
+        info!(
+            "弹幕缓存的写法为{}，在本地转换为{}（{}）",
+            self.ch_convert.as_ref().map_or("未知", |c| c.label()),
+            to.label(),
+            title
+        );
+        self.ch_convert = Some(to.clone());
+    }
+}
+
+#[cfg(feature = "network")]
+impl CommentsJson {
+    /// 记录获取弹幕时的视频，`no_hash` 时只记录大小
+    fn record_source(&mut self, video_path: &PathBuf, no_hash: bool) -> Result<()> {
+        let metadata = video_path.metadata()?;
+        self.file_size = Some(metadata.len());
+        self.file_modified_ms = Some(modified_ms(&metadata)?);
+        self.file_hash = match no_hash {
+            true => None,
+            false => Some(DandanMatch::get_file_hash(video_path)?),
+        };
+        Ok(())
+    }
+
+    /// 与获取弹幕时的视频不同时返回原因，旧缓存没有记录时不检查，`no_hash` 时不比较 hash。
+    /// 大小和修改时间都不变时不计算 hash，hash 相同时记录新的修改时间
+    fn source_changed(&mut self, video_path: &PathBuf, no_hash: bool) -> Result<Option<String>> {
+        let metadata = video_path.metadata()?;
+        if let Some(cached) = self.file_size {
+            if cached != metadata.len() {
+                return Ok(Some(format!("视频大小 {} → {}", cached, metadata.len())));
+            }
+        }
+        let modified = modified_ms(&metadata)?;
+        if self.file_modified_ms == Some(modified) {
+            return Ok(None);
+        }
+        if let Some(cached) = self.file_hash.as_ref().filter(|_| !no_hash) {
+            if *cached != DandanMatch::get_file_hash(video_path)? {
+                return Ok(Some("视频 hash 不同".to_string()));
+            }
+            self.file_modified_ms = Some(modified);
+        }
+        Ok(None)
+    }
+
+    /// ASS 的标题，如 `葬送的芙莉莲 第1话 (ep01.mkv)`，旧缓存没有匹配结果时为文件名
+    pub(crate) fn ass_title(&self, filename: &str) -> String {
+        match (&self.anime_title, &self.episode_title) {
+            (Some(anime_title), Some(episode_title)) => {
+                format!("{} {} ({})", anime_title, episode_title, filename)
+            }
+            _ => filename.to_string(),
+        }
+    }
+
+    /// 缓存中记录的匹配结果
+    pub(crate) fn anime_episode_item(&self) -> Option<AnimeEpisodeItem> {
+        Some(AnimeEpisodeItem {
+            anime_id: self.anime_id?,
+            anime_title: self.anime_title.clone()?,
+            episode_id: self.episode_id?,
+            episode_title: self.episode_title.clone()?,
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize)]
-struct CommentItem {
+pub struct CommentItem {
     /// comment id
-    cid: u64,
-    /// position
-    p: String,
+    pub cid: u64,
+    /// 弹幕参数，`时间,模式,颜色,用户 id`
+    pub p: String,
     /// comment
-    m: String,
+    pub m: String,
 }
 
+#[cfg(feature = "network")]
 #[derive(Serialize, Deserialize)]
 struct FfprobeSubJson {
     #[serde(rename = "streams")]
     streams: Vec<FfprobeSubStream>,
 }
 
+#[cfg(feature = "network")]
 #[derive(Serialize, Deserialize)]
 struct FfprobeSubStream {
     index: i64,
-    tags: FfprobeSubStreamTag,
+    #[serde(default)]
+    codec_name: String,
+    /// 没有任何标签的字幕流没有 `tags`
+    tags: Option<FfprobeSubStreamTag>,
 }
 
+#[cfg(feature = "network")]
 #[derive(Serialize, Deserialize)]
 struct FfprobeSubStreamTag {
-    language: String,
+    language: Option<String>,
+    title: Option<String>,
+}
+
+/// 语言代码的别名，同一组中的代码视为相同的语言
+#[cfg(feature = "network")]
+const LANGUAGE_ALIASES: [&[&str]; 4] = [
+    &[
+        "chi", "zho", "chs", "cht", "zh", "chn", "cn", "zh-cn", "zh-hans", "zh-tw", "zh-hant",
+        "zh-hk", "sc", "tc",
+    ],
+    &["eng", "en"],
+    &["jpn", "ja", "jp"],
+    &["kor", "ko"],
+];
+
+/// 图形字幕无法转换为 ASS
+#[cfg(feature = "network")]
+const BITMAP_SUBTITLE_CODECS: [&str; 4] =
+    ["hdmv_pgs_subtitle", "dvd_subtitle", "dvb_subtitle", "xsub"];
+
+#[cfg(feature = "network")]
+impl FfprobeSubStream {
+    fn is_text(&self) -> bool {
+        !BITMAP_SUBTITLE_CODECS.contains(&self.codec_name.as_str())
+    }
+
+    /// 没有语言标签时为 `und`
+    fn language(&self) -> &str {
+        self.tags
+            .as_ref()
+            .and_then(|tags| tags.language.as_deref())
+            .unwrap_or("und")
+    }
+
+    fn title(&self) -> &str {
+        self.tags
+            .as_ref()
+            .and_then(|tags| tags.title.as_deref())
+            .unwrap_or_default()
+    }
+
+    /// 列表中显示的 `index 语言 标题`
+    fn label(&self) -> String {
+        match self.title() {
+            "" => format!("{} {}", self.index, self.language()),
+            title => format!("{} {} {}", self.index, self.language(), title),
+        }
+    }
+}
+
+/// 以 `、` 分隔的字幕列表，用于日志和错误信息
+#[cfg(feature = "network")]
+fn labels<'a>(streams: impl IntoIterator<Item = &'a FfprobeSubStream>) -> String {
+    streams
+        .into_iter()
+        .map(|s| s.label())
+        .collect::<Vec<_>>()
+        .join("、")
+}
+
+/// `--merge-built-in` 指定的是图形字幕时返回错误，不是数字的 index 不检查
+#[cfg(feature = "network")]
+fn check_text_stream(streams: &[FfprobeSubStream], index: &str) -> Result<()> {
+    let Ok(index) = index.trim().parse::<i64>() else {
+        return Ok(());
+    };
+    match streams.iter().find(|s| s.index == index) {
+        Some(stream) if !stream.is_text() => Err(anyhow!(
+            "字幕 {} 为图形字幕（{}），无法转换为 ASS，请选择文字字幕",
+            stream.label(),
+            stream.codec_name
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(feature = "network")]
+fn is_same_language(a: &str, b: &str) -> bool {
+    let (a, b) = (a.trim().to_lowercase(), b.trim().to_lowercase());
+    a == b
+        || LANGUAGE_ALIASES
+            .iter()
+            .any(|group| group.contains(&a.as_str()) && group.contains(&b.as_str()))
+}
+
+/// 选择语言匹配的第一条文字字幕，有多条时优先标题中带有简、繁等文字的字幕
+#[cfg(feature = "network")]
+fn select_stream_by_lang<'a>(
+    streams: &'a [FfprobeSubStream],
+    lang: &str,
+    prefer: &SimplifiedOrTraditional,
+) -> Option<&'a FfprobeSubStream> {
+    let keywords: &[&str] = match prefer {
+        SimplifiedOrTraditional::Simplified => &["简", "chs", "sc", "hans", "gb"],
+        SimplifiedOrTraditional::Traditional => &["繁", "cht", "tc", "hant", "big5"],
+        SimplifiedOrTraditional::Original => &[],
+    };
+    let matched: Vec<_> = streams
+        .iter()
+        .filter(|s| s.is_text() && is_same_language(s.language(), lang))
+        .collect();
+    matched
+        .iter()
+        .find(|s| {
+            let title = s.title().to_lowercase();
+            keywords.iter().any(|k| title.contains(k))
+        })
+        .or(matched.first())
+        .copied()
 }
 
 struct Position {
     timestamp_s: f64,
     mode: DanmuType,
     color: (u8, u8, u8),
-    #[allow(dead_code)]
+    /// 颜色超出 24 位，已经截断
+    color_clamped: bool,
+    source: String,
     user_id: String,
 }
 
@@ -74,356 +437,2442 @@ impl Position {
         }
     }
 
-    fn parse_color(s: String) -> Result<(u8, u8, u8)> {
-        let value = s.parse::<i32>()?;
-        let b = (value % 256) as u8;
-        let g = ((value / 256) % 256) as u8;
-        let r = (value / (256 * 256)) as u8;
+    /// 颜色为 RGB 的十进制整数。部分来源为有符号整数，负数按补码取低 24 位；
+    /// 大于 0xFFFFFF 的值无法确定高位的含义，按 0xFFFFFF（白色，弹幕的默认颜色）处理，
+    /// 不丢弃整条弹幕。第二个值表示是否发生了这种截断
+    fn parse_color(s: String) -> Result<((u8, u8, u8), bool)> {
+        let value = s.trim().parse::<i64>()?;
+        let (value, clamped) = match value {
+            v if v < 0 => (v & 0xFFFFFF, false),
+            v if v > 0xFFFFFF => (0xFFFFFF, true),
+            v => (v, false),
+        };
+        let r = (value >> 16) as u8;
+        let g = (value >> 8) as u8;
+        let b = value as u8;
 
-        Ok((r, g, b))
+        Ok(((r, g, b), clamped))
     }
 
     fn parse(s: String) -> Result<Position> {
-        let split: Vec<_> = s.split(",").map(|v| v.to_string()).collect();
-        let (timestamp_seconds, mode, color, user_id) = (
-            split[0].clone(),
-            split[1].clone(),
-            split[2].clone(),
-            split[3].clone(),
-        );
-        let color = Position::parse_color(color)?;
+        let split: Vec<_> = s.split(',').collect();
+        let [timestamp_seconds, mode, color_value, user_id] = split[..] else {
+            return Err(anyhow!(
+                "弹幕参数应为 4 个字段，实际为 {} 个：{:?}",
+                split.len(),
+                s
+            ));
+        };
+        let timestamp_s = timestamp_seconds
+            .parse::<f64>()
+            .with_context(|| format!("弹幕时间无法解析：{:?}", s))?;
+        let mode = Position::parse_mode(mode.to_string())
+            .with_context(|| format!("弹幕类型无法解析：{:?}", s))?;
+        let (color, color_clamped) = Position::parse_color(color_value.to_string())
+            .with_context(|| format!("弹幕颜色无法解析：{:?}", s))?;
+        let (source, _) = source::split_user_id(user_id);
         Ok(Position {
-            timestamp_s: timestamp_seconds.parse::<f64>()?,
-            mode: Position::parse_mode(mode)?,
+            timestamp_s,
+            mode,
             color,
-            user_id,
+            color_clamped,
+            source: source.to_string(),
+            user_id: user_id.to_string(),
         })
     }
 }
 
-pub struct Dandan {}
+#[derive(Clone, Copy, Debug, clap::ValueEnum, serde::Deserialize)]
+pub enum KeepFilteredAsComments {
+    /// 只保留黑名单过滤的弹幕
+    #[serde(rename = "denylist")]
+    Denylist,
+    /// 同时保留因为没有槽位等原因被丢弃的弹幕
+    #[serde(rename = "all")]
+    All,
+}
 
-impl Dandan {
-    async fn fetch_comments_json(
-        input_file: &InputFile,
-        force: bool,
-        change_match: bool,
-        simplified_or_traditional: SimplifiedOrTraditional,
-    ) -> Result<CommentsJson> {
-        let json_path = input_file.path.with_extension("dandanplay.json");
+/// 输出的 ASS 已经存在时的处理方式
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+pub enum OnExisting {
+    /// 直接覆盖
+    #[default]
+    #[serde(rename = "overwrite")]
+    Overwrite,
+    /// 先重命名为 `name.ass.bak`
+    #[serde(rename = "backup")]
+    Backup,
+    /// 保留已有文件，不再写入
+    #[serde(rename = "skip")]
+    Skip,
+}
 
-        if json_path.is_dir() {
-            return Err(anyhow!(
-                "弹幕缓存 {} 文件不能是一个目录",
-                display_filename(&json_path),
-            ));
-        }
+/// 合并内置字幕时，同时输出只有弹幕的 ASS
+#[derive(Debug, Clone)]
+pub struct SplitOutput {
+    /// 只有弹幕的 ASS 后缀，如 `danmu` 输出为 `ep01.danmu.ass`
+    pub danmu_suffix: String,
+    /// 合并后的 ASS 后缀，为空时输出为 `ep01.ass`
+    pub merged_suffix: String,
+}
 
-        if json_path.exists() && !change_match && !force {
-            warn!(
-                "{}",
-                input_file.log("弹幕缓存已经存在，使用 --force 参数强制更新")
-            );
-            let json = read_to_string(json_path)?;
-            return Ok(serde_json::from_str::<CommentsJson>(&json)?);
-        }
+/// 带后缀的 ASS 路径，如 `danmu` 为 `ep01.danmu.ass`，为空时为 `ep01.ass`
+fn ass_path(input_file: &InputFile, suffix: &str) -> PathBuf {
+    if suffix.is_empty() {
+        sibling_path(&input_file.path, "ass")
+    } else {
+        sibling_path(&input_file.path, &format!("{}.ass", suffix))
+    }
+}
 
-        let anime_episode_item =
-            DandanMatch::get_anime_episode_item(input_file, change_match).await?;
+/// 传给 ffmpeg、ffprobe 的输入，`file:` 前缀避免 `-` 开头或带有 `:` 的文件名被当作参数或协议
+#[cfg(feature = "network")]
+fn ffmpeg_input(path: &Path) -> OsString {
+    let mut input = OsString::from("file:");
+    input.push(path);
+    input
+}
 
-        let comments_url = format!(
-            "https://api.dandanplay.net/api/v2/comment/{}?withRelated=true&chConvert={}",
-            anime_episode_item.episode_id,
-            match simplified_or_traditional {
-                SimplifiedOrTraditional::Original => 0,
-                SimplifiedOrTraditional::Simplified => 1,
-                SimplifiedOrTraditional::Traditional => 2,
-            }
-        );
-        let mut comments_json = reqwest::Client::new()
-            .get(comments_url)
-            .header("Accept", "application/json")
-            .header("User-Agent", "curl")
-            .send()
-            .await?
-            .json::<CommentsJson>()
-            .await?;
+/// ffmpeg 原样输出内置字幕的文字，不是 UTF-8 时分别按 GB18030、Big5 解码。
+/// GB18030 几乎能解码所有 Big5 的双字节，所以选择落在私用区的字符更少的结果
+/// （Big5 的全角标点等在 GB18030 中是私用区），相同时使用 GB18030。
+/// 都无法解码时替换无法解码的字符，返回的编码为 None 时是 UTF-8
+#[cfg(feature = "network")]
+fn decode_subtitle(bytes: Vec<u8>) -> (String, Option<&'static str>) {
+    let bytes = match String::from_utf8(bytes) {
+        Ok(s) => return (s, None),
+        Err(e) => e.into_bytes(),
+    };
+    let private_use = |s: &str| {
+        s.chars()
+            .filter(|c| ('\u{E000}'..='\u{F8FF}').contains(c))
+            .count()
+    };
+    let decoded = [encoding_rs::GB18030, encoding_rs::BIG5]
+        .into_iter()
+        .filter_map(|encoding| {
+            let s = encoding.decode_without_bom_handling_and_without_replacement(&bytes)?;
+            Some((s.into_owned(), encoding.name()))
+        })
+        .min_by_key(|(s, _)| private_use(s));
+    if let Some((s, name)) = decoded {
+        return (s, Some(name));
+    }
+    (
+        String::from_utf8_lossy(&bytes).into_owned(),
+        Some("UTF-8，替换无法解码的字符"),
+    )
+}
 
-        comments_json.episode_id = Some(anime_episode_item.episode_id);
-        comments_json.anime_id = Some(anime_episode_item.anime_id);
-        comments_json.anime_title = Some(anime_episode_item.anime_title.clone());
-        comments_json.episode_title = Some(anime_episode_item.episode_title.clone());
+/// 从视频中提取的一条内置字幕
+#[cfg(feature = "network")]
+#[derive(Debug)]
+struct BuiltInSubtitle {
+    /// 合并多条字幕时加在输出文件名上，如 `chs` 输出为 `ep01.chs.ass`
+    suffix: String,
+    ass: String,
+}
 
-        fs::write(json_path, serde_json::to_string(&comments_json)?)?;
+/// 选择与弹幕合并的视频内置字幕
+#[cfg(feature = "network")]
+#[derive(Debug, Clone)]
+pub struct MergeBuiltIn {
+    /// 字幕的 stream index，逗号分隔多个时每个字幕输出一个 ASS，为空时不按 index 选择
+    pub index: String,
+    /// 按语言代码选择字幕，如 `chi`、`eng`，多个时每个语言输出一个 ASS
+    pub lang: Vec<String>,
+    /// 同一语言有多条字幕时优先简体或繁体，`Original` 时不区分
+    pub prefer: SimplifiedOrTraditional,
+    /// 没有指定或没有匹配的字幕时让用户选择
+    pub interactive: bool,
+    /// ffmpeg、ffprobe 的超时时间，损坏的视频可能让 ffmpeg 一直不退出
+    pub timeout: Duration,
+}
 
-        Ok(comments_json)
+#[cfg(feature = "network")]
+impl Default for MergeBuiltIn {
+    fn default() -> Self {
+        Self {
+            index: String::new(),
+            lang: vec![],
+            prefer: SimplifiedOrTraditional::Simplified,
+            interactive: false,
+            timeout: DEFAULT_FFMPEG_TIMEOUT,
+        }
     }
+}
 
-    fn built_in_ass_from(input_path_str: String, merge_built_in: String) -> Result<String> {
-        Ok(String::from_utf8(
-            Command::new("ffmpeg")
-                .args([
-                    "-i",
-                    &input_path_str,
-                    "-map",
-                    &format!("0:{}", merge_built_in),
-                    "-f",
-                    "ass",
-                    "pipe:1",
-                ])
-                .output()?
-                .stdout,
-        )?)
-    }
-
-    fn built_in_ass_by(
-        input_path_str: String,
-        merge_built_in: String,
-        merge_built_in_interactive: bool,
-    ) -> Result<Option<String>> {
-        if !merge_built_in.is_empty() {
-            Ok(Some(Self::built_in_ass_from(
-                input_path_str,
-                merge_built_in,
-            )?))
-        } else if merge_built_in_interactive {
-            let sub_json = String::from_utf8(
-                Command::new("ffprobe")
-                    .args([
-                        "-v",
-                        "error",
-                        "-of",
-                        "json",
-                        "-show_entries",
-                        "stream=index:stream_tags=language",
-                        "-select_streams",
-                        "s",
-                        &input_path_str,
-                    ])
-                    .output()?
-                    .stdout,
-            )?;
-            let sub_json: FfprobeSubJson = serde_json::from_str(&sub_json)?;
-            let options: Vec<_> = sub_json
-                .streams
-                .iter()
-                .map(|s| format!("{} {}", s.index, s.tags.language))
-                .collect();
-            let mut select_prompt = Listbox::new(options.clone())
-                .title("请选择合并的字幕")
-                .prompt()?;
-            let ans = select_prompt.run()?;
-            let idx = options
-                .iter()
-                .position(|o| o == &ans)
-                .context("Select matches not found")?;
-            let sub_index = sub_json.streams[idx].index;
-            Ok(Some(Self::built_in_ass_from(
-                input_path_str,
-                sub_index.to_string(),
-            )?))
-        } else {
-            Ok(None)
-        }
+/// 提取字幕需要读完整个视频，网络硬盘上可能需要几分钟
+#[cfg(feature = "network")]
+const DEFAULT_FFMPEG_TIMEOUT: Duration = Duration::from_secs(600);
+
+impl SplitOutput {
+    pub fn danmu_path(&self, input_file: &InputFile) -> PathBuf {
+        ass_path(input_file, &self.danmu_suffix)
     }
 
-    pub async fn process_by_path(
-        input_file: &InputFile,
-        force: bool,
-        change_match: bool,
-        simplified_or_traditional: SimplifiedOrTraditional,
-        merge_built_in_interactive: bool,
-        merge_built_in: String,
-        canvas_config: CanvasConfig,
-        denylist: &Option<HashSet<String>>,
-    ) -> Result<u64> {
-        if !input_file.path.exists() {
-            return Err(anyhow!(
-                "视频文件 {} 不存在",
-                &input_file.display_filename()
-            ));
+    pub fn merged_path(&self, input_file: &InputFile) -> PathBuf {
+        ass_path(input_file, &self.merged_suffix)
+    }
+}
+
+/// 将弹幕转换为 ASS 的参数，不包含任何文件路径
+#[derive(Debug, Clone)]
+pub struct ConvertOptions {
+    /// ASS 的标题，也用于日志
+    pub title: String,
+    pub canvas_config: CanvasConfig,
+    pub denylist: Option<Denylist>,
+    pub keep_filtered: Option<KeepFilteredAsComments>,
+    pub sources: SourceOptions,
+    /// 合并到弹幕之后的字幕，如视频的内置字幕
+    pub built_in_ass: Option<String>,
+    /// 在 `ConversionReport::records` 中记录每条弹幕的处理结果，用于导出
+    pub export_records: bool,
+}
+
+impl ConvertOptions {
+    pub fn new(title: impl Into<String>, canvas_config: CanvasConfig) -> Self {
+        Self {
+            title: title.into(),
+            canvas_config,
+            denylist: None,
+            keep_filtered: None,
+            sources: SourceOptions::default(),
+            built_in_ass: None,
+            export_records: false,
         }
+    }
+}
 
-        let input_path_str = input_file.path.to_str().context("视频路径无法解析")?;
+/// 处理视频或弹幕文件生成 ASS 的参数，`process_by_*` 共用，
+/// 离线转换弹幕文件时不使用内置字幕、拆分输出和封装的参数
+#[derive(Debug, Clone, Default)]
+pub struct ProcessOptions {
+    /// 覆盖不是 ddp2ass 生成的 ASS，弹幕和参数都没有变化时也重新生成
+    pub force: bool,
+    pub canvas_config: CanvasConfig,
+    pub denylist: Option<Denylist>,
+    pub export: DanmuExport,
+    pub keep_filtered: Option<KeepFilteredAsComments>,
+    pub on_existing: OnExisting,
+    /// 输出的 ASS 后缀，为空时输出为 `ep01.ass`
+    pub output_suffix: String,
+    pub sources: SourceOptions,
+    #[cfg(feature = "network")]
+    pub merge_built_in: MergeBuiltIn,
+    pub split_output: Option<SplitOutput>,
+    pub embed: Option<EmbedConfig>,
+}
 
-        let built_in_ass = Self::built_in_ass_by(
-            input_path_str.to_string(),
-            merge_built_in,
-            merge_built_in_interactive,
-        )?;
+/// 一次转换的结果，`convert` 只填写弹幕数量，处理文件时还会填写路径、匹配结果等
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ConversionReport {
+    /// 输出的 ASS，合并内置字幕时为合并后的文件
+    pub output_path: Option<PathBuf>,
+    pub anime_title: Option<String>,
+    pub episode_title: Option<String>,
+    /// 获取到的弹幕数量，包括本地弹幕
+    pub fetched: usize,
+    /// 写入 ASS 的弹幕数量
+    pub danmu_count: u64,
+    /// 被黑名单过滤的弹幕数量，包括屏蔽的用户
+    pub filtered: u64,
+    /// 没有槽位、时间为负或超过视频时长而被丢弃的弹幕数量
+    pub dropped: u64,
+    /// 超过视频时长而被丢弃的弹幕数量，已计入 `dropped`
+    pub beyond_duration: u64,
+    /// 写入的所有 ASS，包括只有弹幕的 ASS 和合并多条内置字幕时的每个输出
+    pub written: Vec<PathBuf>,
+    /// 使用了弹幕缓存
+    pub cache_used: bool,
+    /// 手动跳过、输出已经存在或弹幕和参数都没有变化，没有生成 ASS
+    pub skipped: bool,
+    /// 处理这个文件的耗时，单位为秒
+    pub elapsed_s: f64,
+    /// 开启 `export_records` 时每条弹幕的处理结果
+    #[serde(skip)]
+    pub records: Vec<ExportRecord>,
+}
 
-        let output_path = input_file.path.with_extension("ass");
+/// 获取弹幕的参数
+#[cfg(feature = "network")]
+#[derive(Debug, Clone)]
+pub struct FetchOptions {
+    pub simplified_or_traditional: SimplifiedOrTraditional,
+    /// 同时获取 B 站、巴哈姆特等第三方来源的弹幕
+    pub with_related: bool,
+}
 
-        if output_path.is_dir() {
-            return Err(anyhow!(
-                "输出文件 {} 不能是一个目录",
-                display_filename(&output_path)
-            ));
+#[cfg(feature = "network")]
+impl Default for FetchOptions {
+    fn default() -> Self {
+        Self {
+            simplified_or_traditional: SimplifiedOrTraditional::Simplified,
+            with_related: true,
         }
+    }
+}
 
-        let comments_json =
-            Self::fetch_comments_json(&input_file, force, change_match, simplified_or_traditional)
-                .await?;
+/// 匹配视频、获取弹幕并读写 `.dandanplay.json` 缓存的参数
+#[cfg(feature = "network")]
+#[derive(Debug, Clone)]
+pub struct CacheOptions {
+    /// 不使用缓存，重新获取
+    pub force: bool,
+    pub simplified_or_traditional: SimplifiedOrTraditional,
+    /// 缓存的有效时间，单位为小时，小于等于 0 时一直有效
+    pub cache_ttl: f64,
+    /// 增量更新，保留接口已不再返回的弹幕
+    pub update: bool,
+    /// 使用缓存时重新请求一次，弹幕数量变化超过这个比例时提示更新，None 时不检查
+    pub check_updates: Option<f64>,
+    /// 视频变化后继续使用缓存
+    pub trust_cache: bool,
+    pub match_options: MatchOptions,
+    /// 通过 dandanplay 从第三方视频网址获取弹幕，为空时匹配剧集
+    pub ext_urls: Vec<String>,
+}
 
-        let count = Self::process_by_json(
-            &input_file,
-            &output_path,
-            comments_json,
-            built_in_ass,
-            &denylist,
-            canvas_config,
-        )?;
+#[cfg(feature = "network")]
+impl Default for CacheOptions {
+    fn default() -> Self {
+        Self {
+            force: false,
+            simplified_or_traditional: SimplifiedOrTraditional::Simplified,
+            cache_ttl: 0.0,
+            update: false,
+            check_updates: None,
+            trust_cache: false,
+            match_options: MatchOptions::default(),
+            ext_urls: vec![],
+        }
+    }
+}
 
-        Ok(count)
+/// 生成的 ASS，合并多条内置字幕时有多个输出
+#[derive(Debug)]
+pub(crate) struct AssOutputs {
+    pub output_path: PathBuf,
+    /// 拆分输出时只有弹幕的 ASS
+    pub danmu_only_path: Option<PathBuf>,
+    /// 另外合并的内置字幕，与 `ConvertOptions::built_in_ass` 使用同一份弹幕排布
+    pub merged_outputs: Vec<(PathBuf, String)>,
+}
+
+impl AssOutputs {
+    pub fn single(output_path: PathBuf) -> Self {
+        Self {
+            output_path,
+            danmu_only_path: None,
+            merged_outputs: vec![],
+        }
     }
 
-    fn process_by_json(
-        input_file: &InputFile,
-        output_path: &PathBuf,
-        input_json: CommentsJson,
-        built_in_ass: Option<String>,
-        denylist: &Option<HashSet<String>>,
-        canvas_config: CanvasConfig,
-    ) -> Result<u64> {
-        let title = input_file
-            .path
-            .file_name()
-            .context("Filename not found")?
-            .to_string_lossy()
-            .to_string();
+    fn paths(&self) -> impl Iterator<Item = &PathBuf> {
+        [&self.output_path]
+            .into_iter()
+            .chain(self.danmu_only_path.as_ref())
+            .chain(self.merged_outputs.iter().map(|(path, _)| path))
+    }
+}
 
-        let mut file = File::create(output_path)?;
+/// dandanplay 的 `chConvert` 参数
+#[cfg(feature = "network")]
+fn ch_convert(simplified_or_traditional: &SimplifiedOrTraditional) -> &'static str {
+    match simplified_or_traditional {
+        SimplifiedOrTraditional::Original => "0",
+        SimplifiedOrTraditional::Simplified => "1",
+        SimplifiedOrTraditional::Traditional => "2",
+    }
+}
 
-        let (count, s) =
-            Self::json_to_ass(input_json, built_in_ass, title, denylist, canvas_config)?;
+#[cfg(feature = "network")]
+fn comments_url(episode_id: i64, opts: &FetchOptions) -> String {
+    format!(
+        "https://api.dandanplay.net/api/v2/comment/{}?withRelated={}&chConvert={}",
+        episode_id,
+        opts.with_related,
+        ch_convert(&opts.simplified_or_traditional)
+    )
+}
 
-        file.write(s.as_bytes())?;
+/// `--check-updates` 的比较结果
+#[derive(Debug, Clone, Copy)]
+pub struct UpdateCheck {
+    /// 缓存中的弹幕数量
+    pub cached: usize,
+    /// 当前接口返回的弹幕数量
+    pub live: usize,
+    /// 增长超过阈值
+    pub stale: bool,
+}
 
-        Ok(count)
+impl UpdateCheck {
+    /// `threshold` 为百分比
+    pub fn new(cached: usize, live: usize, threshold: f64) -> Self {
+        Self {
+            cached,
+            live,
+            stale: live as f64 > cached as f64 * (1.0 + threshold / 100.0),
+        }
     }
+}
 
-    fn json_to_ass(
-        input_json: CommentsJson,
-        built_in_ass: Option<String>,
-        title: String,
-        denylist: &Option<HashSet<String>>,
-        canvas_config: CanvasConfig,
-    ) -> Result<(u64, String)> {
-        let mut ass = AssCreator::new(title.clone(), canvas_config.clone())?;
+/// `--local-danmu`、`--extra-danmu` 读取的本地弹幕
+#[derive(Debug, Clone)]
+pub struct LocalDanmu {
+    /// 用于日志的文件名
+    pub name: String,
+    pub danmus: Vec<Danmu>,
+}
 
-        let mut count = 0;
-        let mut canvas = canvas_config.canvas();
-        let t = std::time::Instant::now();
-        let mut danmus: Vec<Danmu> = Vec::new();
+impl LocalDanmu {
+    /// 读取本地弹幕文件，时间加上 `offset` 秒
+    pub fn load(path: &PathBuf, offset: f64) -> Result<Self> {
+        let mut danmus = Dandan::load_local_danmus(path)?;
+        danmus.iter_mut().for_each(|d| d.timeline_s += offset);
+        Ok(Self {
+            name: display_filename(path),
+            danmus,
+        })
+    }
 
-        for c in input_json.comments {
-            let pos = Position::parse(c.p)?;
-            let danmu = Danmu {
-                content: c.m,
-                timeline_s: pos.timestamp_s,
-                fontsize: 0,
-                r#type: pos.mode,
-                rgb: pos.color,
-            };
-            danmus.push(danmu);
+    /// 追加到弹幕池，跳过内容和时间都相同的弹幕，返回追加的数量
+    #[cfg(feature = "network")]
+    fn merge_into(&self, pool: &mut Vec<Danmu>) -> usize {
+        let mut seen: HashSet<(String, u64)> = pool
+            .iter()
+            .map(|d| (d.content.clone(), d.timeline_s.to_bits()))
+            .collect();
+        let before = pool.len();
+        for danmu in &self.danmus {
+            if seen.insert((danmu.content.clone(), danmu.timeline_s.to_bits())) {
+                pool.push(danmu.clone());
+            }
         }
+        pool.len() - before
+    }
+}
 
-        danmus.sort_by(|a, b| {
-            a.timeline_s
-                .partial_cmp(&b.timeline_s)
-                .unwrap_or(Ordering::Equal)
-        });
+pub struct Dandan {}
 
-        for danmu in danmus {
-            if let Some(denylist) = denylist.as_ref() {
-                if denylist.iter().any(|s| danmu.content.contains(s)) {
-                    continue;
-                }
-            }
-            if let Some(drawable) = canvas.draw(danmu)? {
-                count += 1;
-                ass.write(drawable)?;
+impl Dandan {
+    /// 获取弹幕前的检查，提前获取弹幕时也会先检查
+    pub fn check_input(input_file: &InputFile, embed: &Option<EmbedConfig>) -> Result<()> {
+        if !input_file.path.exists() {
+            return Err(Error::FileNotFound {
+                what: "视频文件",
+                path: input_file.path.clone(),
             }
+            .into());
         }
 
-        if let Some(built_in_ass) = built_in_ass {
-            ass.merge(built_in_ass)?;
+        if embed.is_some() {
+            EmbedConfig::check(input_file)?;
         }
-
-        info!("弹幕数量：{}, 耗时 {:?}（{}）", count, t.elapsed(), title);
-
-        Ok((count, String::from_utf8(ass.buf)?))
+        Ok(())
     }
 }
 
-#[cfg(test)]
-mod tests {
+#[cfg(feature = "network")]
+impl Dandan {
+    pub async fn fetch_comments_json(
+        input_file: &InputFile,
+        opts: &CacheOptions,
+        selector: &dyn Selector,
+        progress: &dyn Progress,
+    ) -> Result<(CommentsJson, Option<UpdateCheck>)> {
+        let CacheOptions {
+            force,
+            cache_ttl,
+            update,
+            check_updates,
+            trust_cache,
+            ref match_options,
+            ref ext_urls,
+            ..
+        } = *opts;
+        let simplified_or_traditional = opts.simplified_or_traditional.clone();
+        let change_match = match_options.change_match;
+        let json_path = sibling_path(&input_file.path, "dandanplay.json");
 
-    use crate::{Args, Dandan};
-    use anyhow::Result;
-    use clap::Parser;
+        if json_path.is_dir() {
+            return Err(anyhow!(
+                "弹幕缓存 {} 文件不能是一个目录",
+                display_filename(&json_path),
+            ));
+        }
 
-    #[test]
-    fn test_convert() -> Result<()> {
-        let json = serde_json::from_str(
-            r#"
-            {
-                "count": 1008,
-                "comments": [
-                    {
-                        "cid": 1684989836,
-                        "p": "0.00,1,16777215,[Gamer]bill88919",
-                        "m": "頭香"
-                    },
-                    {
-                        "cid": 1684989837,
-                        "p": "0.00,1,16777215,[Gamer]mia105067",
-                        "m": ":)"
-                    },
-                    {
-                        "cid": 1684989838,
-                        "p": "0.00,1,16777215,[Gamer]renwendy",
-                        "m": "簽"
-                    },
-                    {
-                        "cid": 1684989839,
-                        "p": "0.00,1,16777215,[Gamer]s39101149",
-                        "m": "簽"
-                    },
-                    {
-                        "cid": 1684989840,
-                        "p": "0.00,1,16777215,[Gamer]huryan951006",
-                        "m": "我已經等三年了！"
-                    },
+        let mut cached_item = None;
+        // 之前用 --ext-url 获取的缓存，更新时继续使用记录的网址
+        let mut ext_urls = ext_urls.to_vec();
+        let mut update_base = None;
+        let loaded = match json_path.exists() && !change_match && (update || !force) {
+            true => Self::load_cache(input_file, &json_path)?,
+            false => None,
+        };
+        let cached = if let Some(mut comments_json) = loaded {
+            match comments_json.source_changed(&input_file.path, match_options.no_hash)? {
+                _ if !ext_urls.is_empty()
+                    && comments_json.ext_urls.as_deref() != Some(&ext_urls[..]) =>
+                {
+                    info!(
+                        "{}",
+                        input_file.log("弹幕缓存不是从 --ext-url 指定的网址获取的，重新获取")
+                    );
+                    None
+                }
+                _ if comments_json.episode_offset.unwrap_or(0) != match_options.episode_offset => {
+                    info!(
+                        "{}",
+                        input_file.log(&format!(
+                            "弹幕缓存匹配时的 --episode-offset 为 {}，与当前的 {} 不同，重新匹配并获取弹幕",
+                            comments_json.episode_offset.unwrap_or(0),
+                            match_options.episode_offset
+                        ))
+                    );
+                    None
+                }
+                Some(reason) if !trust_cache => {
+                    warn!(
+                        "{}",
+                        input_file.log(&format!(
+                            "弹幕缓存对应的视频已变化（{}），重新匹配并获取弹幕，使用 --trust-cache 参数继续使用缓存",
+                            reason
+                        ))
+                    );
+                    None
+                }
+                _ => {
+                    if let (Some(anime_title), Some(episode_title)) =
+                        (&comments_json.anime_title, &comments_json.episode_title)
                     {
-                        "cid": 1684989841,
-                        "p": "0.50,1,16777215,[Gamer]Vigar09995",
-                        "m": "22:00馬上簽到 2023/4/3"
+                        info!(
+                            "{}, {}, 话数 {}",
+                            input_file.log("弹幕缓存匹配"),
+                            underlined(anime_title),
+                            underlined(episode_title)
+                        );
+                    }
+                    Some(comments_json)
+                }
+            }
+        } else {
+            None
+        };
+        if ext_urls.is_empty() {
+            if let Some(cached_urls) = cached.as_ref().and_then(|c| c.ext_urls.clone()) {
+                ext_urls = cached_urls;
+            }
+        }
+        if update {
+            cached_item = cached.as_ref().and_then(|c| c.anime_episode_item());
+            update_base = cached;
+        } else if let Some(comments_json) = cached {
+            if cache_ttl <= 0.0 {
+                warn!(
+                    "{}",
+                    input_file.log("弹幕缓存已经存在，使用 --force 参数强制更新")
+                );
+                let update_check = Self::check_updates(
+                    input_file,
+                    &comments_json,
+                    simplified_or_traditional,
+                    check_updates,
+                )
+                .await;
+                progress.on_comments_fetched(input_file, comments_json.comments.len());
+                return Ok((
+                    CommentsJson {
+                        from_cache: true,
+                        ..comments_json
                     },
-                    {
-                        "cid": 1684989842,
-                        "p": "0.50,1,16777215,[Gamer]thomas627",
-                        "m": "Kuma~~~"
+                    update_check,
+                ));
+            }
+            let age_hours = comments_json.cache_age(&json_path)?.as_secs_f64() / 3600.0;
+            if age_hours <= cache_ttl {
+                info!(
+                    "{}",
+                    input_file.log(&format!(
+                        "弹幕缓存未过期（{:.1} 小时前获取），使用 --force 参数强制更新",
+                        age_hours
+                    ))
+                );
+                let update_check = Self::check_updates(
+                    input_file,
+                    &comments_json,
+                    simplified_or_traditional,
+                    check_updates,
+                )
+                .await;
+                progress.on_comments_fetched(input_file, comments_json.comments.len());
+                return Ok((
+                    CommentsJson {
+                        from_cache: true,
+                        ..comments_json
                     },
-                    {
-                        "cid": 1684989843,
-                        "p": "0.60,1,16777215,[Gamer]ppleo8888",
-                        "m": "2023/04/16直接看完第一季過來 真的太爽啦"
-                    }
-                ]
+                    update_check,
+                ));
             }
-        "#,
-        )?;
+            info!(
+                "{}",
+                input_file.log(&format!(
+                    "弹幕缓存已过期（{:.1} 小时前获取），重新获取",
+                    age_hours
+                ))
+            );
+            cached_item = comments_json.anime_episode_item();
+        }
 
-        let args = Args::parse_from(["test"]);
-        let (_count, ass) =
-            Dandan::json_to_ass(json, None, "test".to_string(), &None, args.canvas_config())?;
+        let mut comments_json = if ext_urls.is_empty() {
+            let anime_episode_item = match cached_item {
+                Some(item) => item,
+                None => {
+                    progress.on_stage(input_file, Stage::Matching);
+                    DandanMatch::get_anime_episode_item(input_file, match_options, selector).await?
+                }
+            };
+            progress.on_stage(input_file, Stage::Fetching);
+            Self::fetch_comments_by_episode(
+                input_file,
+                &anime_episode_item,
+                simplified_or_traditional,
+            )
+            .await?
+        } else {
+            progress.on_stage(input_file, Stage::Fetching);
+            Self::fetch_comments_by_ext_urls(input_file, &ext_urls, simplified_or_traditional)
+                .await?
+        };
 
-        assert_eq!(
-            ass,
-            "[Script Info]\n; Script generated by danmu2ass\nTitle: test\nScript Updated By: danmu2ass (https://github.com/gwy15/danmu2ass)\nScriptType: v4.00+\nPlayResX: 1280\nPlayResY: 720\nAspect Ratio: 1280:720\nCollisions: Normal\nWrapStyle: 2\nScaledBorderAndShadow: yes\nYCbCr Matrix: TV.601\n\n\n[V4+ Styles]\nFormat: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding\nStyle: Float,黑体,25,&H4cFFFFFF,&H00FFFFFF,&H4c000000,&H00000000,0, 0, 0, 0, 100, 100, 0.00, 0.00, 1, 0.8, 0, 7, 0, 0, 0, 1\nStyle: Bottom,黑体,25,&H4cFFFFFF,&H00FFFFFF,&H4c000000,&H00000000,0, 0, 0, 0, 100, 100, 0.00, 0.00, 1, 0.8, 0, 7, 0, 0, 0, 1\nStyle: Top,黑体,25,&H4cFFFFFF,&H00FFFFFF,&H4c000000,&H00000000,0, 0, 0, 0, 100, 100, 0.00, 0.00, 1, 0.8, 0, 7, 0, 0, 0, 1\n\n[Events]\nFormat: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\nDialogue: 2,0:00:00.00,0:00:15.00,Float,,0,0,0,,{\\move(1280, 0, -60, 0)\\c&Hffffff&}頭香\nDialogue: 2,0:00:00.00,0:00:15.00,Float,,0,0,0,,{\\move(1280, 32, -39, 32)\\c&Hffffff&}:)\nDialogue: 2,0:00:00.00,0:00:15.00,Float,,0,0,0,,{\\move(1280, 64, -30, 64)\\c&Hffffff&}簽\nDialogue: 2,0:00:00.00,0:00:15.00,Float,,0,0,0,,{\\move(1280, 96, -30, 96)\\c&Hffffff&}簽\nDialogue: 2,0:00:00.00,0:00:15.00,Float,,0,0,0,,{\\move(1280, 128, -240, 128)\\c&Hffffff&}我已經等三年了！\nDialogue: 2,0:00:00.50,0:00:15.50,Float,,0,0,0,,{\\move(1280, 160, -399, 160)\\c&Hffffff&}22:00馬上簽到 2023/4/3\nDialogue: 2,0:00:00.50,0:00:15.50,Float,,0,0,0,,{\\move(1280, 192, -139, 192)\\c&Hffffff&}Kuma~~~\nDialogue: 2,0:00:00.60,0:00:15.60,Float,,0,0,0,,{\\move(1280, 224, -639, 224)\\c&Hffffff&}2023/04/16直接看完第一季過來 真的太爽啦\n"
-        );
+        if let Some(cached) = update_base {
+            let (added, kept) = comments_json.merge_cached(cached);
+            info!(
+                "{}",
+                input_file.log(&format!(
+                    "新增 {} 条弹幕，保留 {} 条已不再返回的弹幕",
+                    added, kept
+                ))
+            );
+        }
+
+        comments_json.record_source(&input_file.path, match_options.no_hash)?;
+        comments_json.episode_offset =
+            Some(match_options.episode_offset).filter(|offset| *offset != 0);
+        write_atomic(&json_path, serde_json::to_string(&comments_json)?)?;
+
+        progress.on_comments_fetched(input_file, comments_json.comments.len());
+        Ok((comments_json, None))
+    }
+
+    /// 读取弹幕缓存，无法解析时改名为 `.corrupt` 并当作没有缓存，避免之后每次都失败
+    fn load_cache(input_file: &InputFile, json_path: &PathBuf) -> Result<Option<CommentsJson>> {
+        let json = fs::read(json_path)?;
+        match serde_json::from_slice::<CommentsJson>(&json) {
+            Ok(comments_json) => Ok(Some(comments_json)),
+            Err(e) => {
+                let corrupt = json_path.with_extension("json.corrupt");
+                warn!(
+                    "{} {}，改名为 {} 后重新获取弹幕",
+                    input_file.log(&format!(
+                        "弹幕缓存 {} 无法解析",
+                        display_filename(json_path)
+                    )),
+                    e,
+                    display_filename(&corrupt)
+                );
+                fs::rename(json_path, &corrupt)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// 使用缓存时重新请求一次弹幕，比较数量判断缓存是否过时，失败时不影响本次转换
+    async fn check_updates(
+        input_file: &InputFile,
+        comments_json: &CommentsJson,
+        simplified_or_traditional: SimplifiedOrTraditional,
+        threshold: Option<f64>,
+    ) -> Option<UpdateCheck> {
+        let threshold = threshold?;
+        let Some(item) = comments_json.anime_episode_item() else {
+            warn!("{}", input_file.log("弹幕缓存中没有匹配结果，跳过检查更新"));
+            return None;
+        };
+        let live =
+            match Self::fetch_comments_by_episode(input_file, &item, simplified_or_traditional)
+                .await
+            {
+                Ok(live) => live,
+                Err(e) => {
+                    warn!("{} {:?}", input_file.log("检查弹幕更新失败"), e);
+                    return None;
+                }
+            };
+        let check = UpdateCheck::new(comments_json.comments.len(), live.comments.len(), threshold);
+        if check.stale {
+            warn!(
+                "{}",
+                input_file.log(&format!(
+                    "弹幕缓存已过时：缓存 {} 条，当前 {} 条，使用 --update 或 --force 参数更新",
+                    check.cached, check.live
+                ))
+            );
+        } else {
+            info!(
+                "{}",
+                input_file.log(&format!(
+                    "弹幕缓存无需更新：缓存 {} 条，当前 {} 条",
+                    check.cached, check.live
+                ))
+            );
+        }
+        Some(check)
+    }
+
+    /// 获取指定剧集的弹幕，不需要视频和匹配，也不读写缓存
+    pub async fn fetch_comments(
+        episode_id: i64,
+        opts: &FetchOptions,
+    ) -> Result<CommentsJson, Error> {
+        Ok(Self::request_comments(&format!("剧集 {}", episode_id), episode_id, opts).await?)
+    }
+
+    /// `name` 用于重试时的日志
+    async fn request_comments(
+        name: &str,
+        episode_id: i64,
+        opts: &FetchOptions,
+    ) -> Result<CommentsJson> {
+        let url = comments_url(episode_id, opts);
+        let mut comments_json: CommentsJson =
+            api::request(name, || reqwest::Client::new().get(&url)).await?;
+        comments_json.episode_id = Some(episode_id);
+        comments_json.ch_convert = Some(opts.simplified_or_traditional.clone());
+        comments_json.fetched_at = Some(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        );
+        Ok(comments_json)
+    }
+
+    async fn fetch_comments_by_episode(
+        input_file: &InputFile,
+        anime_episode_item: &AnimeEpisodeItem,
+        simplified_or_traditional: SimplifiedOrTraditional,
+    ) -> Result<CommentsJson> {
+        let mut comments_json = Self::request_comments(
+            &input_file.display_filename(),
+            anime_episode_item.episode_id,
+            &FetchOptions {
+                simplified_or_traditional,
+                with_related: true,
+            },
+        )
+        .await?;
+
+        comments_json.anime_id = Some(anime_episode_item.anime_id);
+        comments_json.anime_title = Some(anime_episode_item.anime_title.clone());
+        comments_json.episode_title = Some(anime_episode_item.episode_title.clone());
+
+        Ok(comments_json)
+    }
+
+    /// 通过 dandanplay 从第三方视频网址获取弹幕，多个网址的弹幕合并去重
+    async fn fetch_comments_by_ext_urls(
+        input_file: &InputFile,
+        ext_urls: &[String],
+        simplified_or_traditional: SimplifiedOrTraditional,
+    ) -> Result<CommentsJson> {
+        let ch_convert = ch_convert(&simplified_or_traditional);
+        let mut merged: Option<CommentsJson> = None;
+        for url in ext_urls {
+            let comments_json: CommentsJson = api::request(&input_file.display_filename(), || {
+                reqwest::Client::new()
+                    .get("https://api.dandanplay.net/api/v2/extcomment")
+                    .query(&[("url", url.as_str()), ("chConvert", ch_convert)])
+            })
+            .await
+            .with_context(|| format!("从 {} 获取弹幕失败", url))?;
+            info!(
+                "{}",
+                input_file.log(&format!(
+                    "从 {} 获取 {} 条弹幕",
+                    url,
+                    comments_json.comments.len()
+                ))
+            );
+            match merged.as_mut() {
+                Some(merged) => {
+                    merged.merge_ext(comments_json);
+                }
+                None => merged = Some(comments_json),
+            }
+        }
+        let mut comments_json = merged.context("没有指定 --ext-url")?;
+        comments_json.ext_urls = Some(ext_urls.to_vec());
+        comments_json.fetched_at = Some(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        );
+        Ok(comments_json)
+    }
+
+    /// ffmpeg 失败时带上 stderr 的最后几行，不会返回空的字幕
+    async fn built_in_ass_from(
+        input_path: &Path,
+        merge_built_in: String,
+        timeout: Duration,
+    ) -> Result<String> {
+        let mut command = ffmpeg_command();
+        command
+            .args(["-v", "error", "-i"])
+            .arg(ffmpeg_input(input_path))
+            .args(["-map", &format!("0:{}", merge_built_in)])
+            .args(["-f", "ass", "pipe:1"]);
+        let output = command_output_timeout(command, timeout).await?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "ffmpeg 提取字幕 {} 失败：{}",
+                merge_built_in,
+                stderr_tail(&output.stderr)
+            ));
+        }
+        let (ass, encoding) = decode_subtitle(output.stdout);
+        if let Some(encoding) = encoding {
+            warn!("字幕 {} 不是 UTF-8，按 {} 解码", merge_built_in, encoding);
+        }
+        Ok(ass)
+    }
+
+    /// 视频中的字幕流
+    async fn subtitle_streams(
+        input_path: &Path,
+        timeout: Duration,
+    ) -> Result<Vec<FfprobeSubStream>> {
+        let mut command = ffprobe_command();
+        command
+            .args(["-v", "error", "-of", "json"])
+            .args([
+                "-show_entries",
+                "stream=index,codec_name:stream_tags=language,title",
+            ])
+            .args(["-select_streams", "s", "-i"])
+            .arg(ffmpeg_input(input_path));
+        let output = command_output_timeout(command, timeout).await?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "ffprobe 读取字幕流失败：{}",
+                stderr_tail(&output.stderr)
+            ));
+        }
+        // 标题等标签不是 UTF-8 时替换无法解码的字符，只影响显示
+        let sub_json = String::from_utf8_lossy(&output.stdout);
+        Ok(serde_json::from_str::<FfprobeSubJson>(&sub_json)?.streams)
+    }
+
+    /// 按 index、语言或手动选择内置字幕，没有选择时为空
+    async fn built_in_ass_by(
+        input_path: &Path,
+        merge_built_in: &MergeBuiltIn,
+        selector: &Arc<dyn Selector>,
+    ) -> Result<Vec<BuiltInSubtitle>> {
+        let indexes: Vec<&str> = merge_built_in
+            .index
+            .split(',')
+            .map(str::trim)
+            .filter(|i| !i.is_empty())
+            .collect();
+        // (index, 后缀)
+        let mut selected: Vec<(String, String)> = vec![];
+        if !indexes.is_empty() {
+            // 图形字幕要等 ffmpeg 读完整个视频才会报错，提前检查，ffprobe 不可用时直接交给 ffmpeg
+            let streams = match Self::subtitle_streams(input_path, merge_built_in.timeout).await {
+                Ok(streams) => streams,
+                Err(e) => {
+                    debug!("无法读取字幕流，跳过字幕格式检查：{:#}", e);
+                    vec![]
+                }
+            };
+            for index in indexes {
+                check_text_stream(&streams, index)?;
+                let suffix = streams
+                    .iter()
+                    .find(|s| index.parse() == Ok(s.index))
+                    .map_or(index.to_string(), |s| s.language().to_lowercase());
+                selected.push((index.to_string(), suffix));
+            }
+        } else if !merge_built_in.lang.is_empty() || merge_built_in.interactive {
+            let streams = Self::subtitle_streams(input_path, merge_built_in.timeout).await?;
+            for lang in &merge_built_in.lang {
+                match select_stream_by_lang(&streams, lang, &merge_built_in.prefer) {
+                    Some(stream) => {
+                        info!("合并内置字幕 {}", stream.label());
+                        selected.push((stream.index.to_string(), lang.to_lowercase()));
+                    }
+                    None if !merge_built_in.interactive => {
+                        return Err(anyhow!(
+                            "没有语言为 {} 的文字字幕，视频中的字幕：{}",
+                            lang,
+                            labels(&streams)
+                        ));
+                    }
+                    None => {
+                        warn!("没有语言为 {} 的文字字幕，请手动选择", lang);
+                        let stream = Self::select_text_stream(&streams, selector).await?;
+                        selected.push((stream.index.to_string(), lang.to_lowercase()));
+                    }
+                }
+            }
+            if merge_built_in.lang.is_empty() {
+                let stream = Self::select_text_stream(&streams, selector).await?;
+                selected.push((stream.index.to_string(), stream.language().to_lowercase()));
+            }
+        }
+
+        // 同一语言有多条字幕时，后缀加上 index 区分
+        let duplicated: Vec<String> = selected
+            .iter()
+            .filter(|(_, suffix)| selected.iter().filter(|(_, s)| s == suffix).count() > 1)
+            .map(|(_, suffix)| suffix.clone())
+            .collect();
+        let mut built_in = vec![];
+        for (index, suffix) in selected {
+            let suffix = match duplicated.contains(&suffix) {
+                true => format!("{}-{}", suffix, index),
+                false => suffix,
+            };
+            let ass = Self::built_in_ass_from(input_path, index, merge_built_in.timeout).await?;
+            built_in.push(BuiltInSubtitle { suffix, ass });
+        }
+        Ok(built_in)
+    }
+
+    /// 手动选择一条文字字幕，图形字幕不显示在列表中。等待选择会阻塞，在阻塞线程中进行
+    async fn select_text_stream<'a>(
+        streams: &'a [FfprobeSubStream],
+        selector: &Arc<dyn Selector>,
+    ) -> Result<&'a FfprobeSubStream> {
+        let (text_streams, image_streams): (Vec<_>, Vec<_>) =
+            streams.iter().partition(|s| s.is_text());
+        if !image_streams.is_empty() {
+            info!(
+                "图形字幕无法转换为 ASS，不在列表中显示：{}",
+                labels(image_streams)
+            );
+        }
+        if text_streams.is_empty() {
+            return Err(anyhow!("视频中没有可以合并的文字字幕"));
+        }
+        let options: Vec<_> = text_streams.iter().map(|s| s.label()).collect();
+        let selector = selector.clone();
+        let idx =
+            tokio::task::spawn_blocking(move || selector.select("请选择合并的字幕", &options))
+                .await??;
+        text_streams
+            .get(idx)
+            .copied()
+            .context("Select matches not found")
+    }
+
+    /// 从 `provider` 获取弹幕并生成 ASS，处理进度和结果通过 `progress` 通知
+    pub async fn process_by_path(
+        input_file: &InputFile,
+        provider: &dyn DanmuProvider,
+        opts: &ProcessOptions,
+        selector: &Arc<dyn Selector>,
+        progress: &dyn Progress,
+        cancel: &CancelFlag,
+    ) -> Result<ConversionReport, Error> {
+        let t = std::time::Instant::now();
+        let result =
+            Self::process_by_path_inner(input_file, provider, opts, selector, progress, cancel)
+                .await;
+        progress.on_file_done(&FileReport {
+            file: input_file.clone(),
+            outcome: FileOutcome::from_result(&result),
+        });
+        match result {
+            Ok(report) => Ok(ConversionReport {
+                elapsed_s: t.elapsed().as_secs_f64(),
+                ..report
+            }),
+            Err(e) if is_skipped_error(&e) => {
+                info!("{}", input_file.log("已手动跳过"));
+                Ok(ConversionReport {
+                    skipped: true,
+                    elapsed_s: t.elapsed().as_secs_f64(),
+                    ..Default::default()
+                })
+            }
+            Err(e) => Err(Error::from(e)),
+        }
+    }
+
+    async fn process_by_path_inner(
+        input_file: &InputFile,
+        provider: &dyn DanmuProvider,
+        opts: &ProcessOptions,
+        selector: &Arc<dyn Selector>,
+        progress: &dyn Progress,
+        cancel: &CancelFlag,
+    ) -> Result<ConversionReport> {
+        Self::check_input(input_file, &opts.embed)?;
+        cancel.check()?;
+
+        // 不是 dandanplay 格式的弹幕按本地弹幕处理，每次都重新生成
+        let (comments_json, local_danmus) = match provider.fetch_comments_json(input_file).await? {
+            Some(comments_json) => (Some(comments_json), vec![]),
+            None => {
+                progress.on_stage(input_file, Stage::Fetching);
+                let danmus = provider.fetch(input_file).await?;
+                progress.on_comments_fetched(input_file, danmus.len());
+                let local = LocalDanmu {
+                    name: provider.name().to_string(),
+                    danmus,
+                };
+                (None, vec![local])
+            }
+        };
+        cancel.check()?;
+
+        Self::process_by_comments(
+            input_file,
+            comments_json,
+            &local_danmus,
+            opts,
+            selector,
+            progress,
+            cancel,
+        )
+        .await
+    }
+
+    /// 用已经获取的弹幕生成 ASS，`comments_json` 为 None 时只使用本地弹幕
+    pub async fn process_by_comments(
+        input_file: &InputFile,
+        comments_json: Option<CommentsJson>,
+        local_danmus: &[LocalDanmu],
+        opts: &ProcessOptions,
+        selector: &Arc<dyn Selector>,
+        progress: &dyn Progress,
+        cancel: &CancelFlag,
+    ) -> Result<ConversionReport> {
+        let ProcessOptions {
+            force,
+            ref canvas_config,
+            ref denylist,
+            ref export,
+            keep_filtered,
+            on_existing,
+            ref output_suffix,
+            ref sources,
+            ref merge_built_in,
+            ref split_output,
+            ref embed,
+        } = *opts;
+        let t = std::time::Instant::now();
+        progress.on_stage(input_file, Stage::Converting);
+
+        let built_in = Self::built_in_ass_by(&input_file.path, merge_built_in, selector).await?;
+
+        let (output_path, danmu_only_path) = match split_output {
+            Some(split_output) if !built_in.is_empty() => (
+                split_output.merged_path(input_file),
+                Some(split_output.danmu_path(input_file)),
+            ),
+            _ => (ass_path(input_file, output_suffix), None),
+        };
+        // 合并多条内置字幕时，每条字幕输出到带有语言后缀的 ASS，弹幕只排布一次
+        let mut merged_outputs: Vec<(PathBuf, String)> = match built_in.len() {
+            0 | 1 => built_in
+                .into_iter()
+                .map(|b| (output_path.clone(), b.ass))
+                .collect(),
+            _ => {
+                let base_suffix = match split_output {
+                    Some(split_output) => &split_output.merged_suffix,
+                    None => output_suffix,
+                };
+                built_in
+                    .into_iter()
+                    .map(|b| {
+                        let suffix = match base_suffix.is_empty() {
+                            true => b.suffix,
+                            false => format!("{}.{}", base_suffix, b.suffix),
+                        };
+                        (ass_path(input_file, &suffix), b.ass)
+                    })
+                    .collect()
+            }
+        };
+        let (output_path, built_in_ass) = match merged_outputs.is_empty() {
+            true => (output_path, None),
+            false => {
+                let (path, ass) = merged_outputs.remove(0);
+                (path, Some(ass))
+            }
+        };
+        let outputs = AssOutputs {
+            output_path,
+            danmu_only_path,
+            merged_outputs,
+        };
+
+        for path in outputs.paths() {
+            Self::check_output_path(path, force, on_existing)?;
+        }
+
+        let export = export.for_file(input_file);
+        let filename = input_file.file_name();
+        let title = match &comments_json {
+            Some(comments_json) => comments_json.ass_title(&filename),
+            None => filename,
+        };
+        let mut report = ConversionReport {
+            output_path: Some(outputs.output_path.clone()),
+            fetched: local_danmus.iter().map(|l| l.danmus.len()).sum(),
+            ..Default::default()
+        };
+        if let Some(comments_json) = &comments_json {
+            report.anime_title = comments_json.anime_title.clone();
+            report.episode_title = comments_json.episode_title.clone();
+            report.fetched += comments_json.comments.len();
+            report.cache_used = comments_json.from_cache;
+        }
+        // 只使用本地弹幕时没有缓存，每次都重新生成
+        let (danmus, cache) = match comments_json {
+            Some(mut comments_json) => {
+                let fingerprint = format!(
+                    "{:x}",
+                    md5::compute(format!(
+                        "{:?}|{:?}|{:?}|{:?}|{:?}|{:?}|{:?}|{:?}|{:?}|{:?}|{}",
+                        canvas_config,
+                        sources,
+                        denylist,
+                        built_in_ass,
+                        outputs.merged_outputs,
+                        keep_filtered,
+                        split_output,
+                        export,
+                        embed,
+                        local_danmus,
+                        serde_json::to_string(&comments_json.comments)?,
+                    ))
+                );
+                if outputs.paths().all(|p| p.exists()) {
+                    match comments_json.ass_fingerprint.as_ref() {
+                        Some(f) if *f == fingerprint && !force => {
+                            info!(
+                                "{}",
+                                input_file.log(
+                                    "弹幕和生成参数均未变化，跳过生成，使用 --force 参数强制更新"
+                                )
+                            );
+                            return Ok(ConversionReport {
+                                skipped: true,
+                                elapsed_s: t.elapsed().as_secs_f64(),
+                                ..report
+                            });
+                        }
+                        Some(f) if *f != fingerprint => {
+                            info!("{}", input_file.log("生成参数已变化，重新生成"));
+                        }
+                        _ => {}
+                    }
+                }
+
+                comments_json.ass_fingerprint = Some(fingerprint);
+                let cache = serde_json::to_string(&comments_json)?;
+                // 缓存保持 dandanplay 返回的内容，只转换这次使用的弹幕
+                comments_json.convert_chinese(canvas_config.ch_convert.as_ref(), &title);
+                (comments_json.into_danmus(&title), Some(cache))
+            }
+            None => (vec![], None),
+        };
+        let mut danmus = danmus;
+        let from_comments = danmus.len();
+        for local in local_danmus {
+            let added = local.merge_into(&mut danmus);
+            info!(
+                "{}",
+                input_file.log(&format!(
+                    "从 {} 添加 {} 条弹幕，跳过 {} 条重复弹幕",
+                    local.name,
+                    added,
+                    local.danmus.len() - added
+                ))
+            );
+        }
+
+        chinese::convert_danmus(
+            &mut danmus[from_comments..],
+            canvas_config.ch_convert.as_ref(),
+        );
+
+        cancel.check()?;
+        let output_path = outputs.output_path.clone();
+        // 排布弹幕和写入 ASS 会阻塞，在阻塞线程中进行
+        let converted = tokio::task::spawn_blocking({
+            let input_file = input_file.clone();
+            let canvas_config = canvas_config.clone();
+            let denylist = denylist.clone();
+            let sources = sources.clone();
+            move || {
+                let canvas_config =
+                    Self::apply_offset_suggestion(&input_file, &danmus, canvas_config);
+                let opts = ConvertOptions {
+                    title,
+                    canvas_config,
+                    denylist,
+                    keep_filtered,
+                    sources,
+                    built_in_ass,
+                    export_records: export.is_enabled(),
+                };
+                Self::process_by_json(&input_file, &outputs, danmus, &opts, &export, on_existing)
+            }
+        })
+        .await??;
+        let report = ConversionReport {
+            danmu_count: converted.danmu_count,
+            filtered: converted.filtered,
+            dropped: converted.dropped,
+            beyond_duration: converted.beyond_duration,
+            skipped: converted.skipped,
+            written: converted.written,
+            ..report
+        };
+
+        progress.on_danmu_written(input_file, report.danmu_count);
+
+        if let Some(cache) = cache {
+            write_atomic(&sibling_path(&input_file.path, "dandanplay.json"), cache)?;
+        }
+
+        if let Some(embed) = embed {
+            cancel.check()?;
+            progress.on_stage(input_file, Stage::Embedding);
+            let embed_path = tokio::task::spawn_blocking({
+                let embed = embed.clone();
+                let input_file = input_file.clone();
+                move || embed.embed(&input_file, &output_path)
+            })
+            .await??;
+            info!(
+                "{} {}",
+                input_file.log("弹幕已封装到"),
+                display_filename(&embed_path)
+            );
+        }
+
+        Ok(ConversionReport {
+            elapsed_s: t.elapsed().as_secs_f64(),
+            ..report
+        })
+    }
+
+    /// 有视频章节时输出建议的时间轴偏移，`auto_offset` 时直接使用
+    fn apply_offset_suggestion(
+        input_file: &InputFile,
+        danmus: &[Danmu],
+        mut canvas_config: CanvasConfig,
+    ) -> CanvasConfig {
+        let Some(chapters) = &canvas_config.chapters else {
+            return canvas_config;
+        };
+        match suggest_offset(chapters, danmus) {
+            Some(suggestion) => {
+                info!("{}", input_file.log(&suggestion.describe()));
+                if canvas_config.auto_offset {
+                    canvas_config.time_offset = suggestion.offset;
+                }
+            }
+            None if chapters.is_empty() => {
+                info!("{}", input_file.log("视频没有章节，无法推算时间轴偏移"))
+            }
+            None => info!(
+                "{}",
+                input_file.log("没有 OP、ED 章节或弹幕没有明显的峰值，无法推算时间轴偏移")
+            ),
+        }
+        canvas_config
+    }
+}
+
+impl Dandan {
+    /// 在 `convert` 的基础上处理已有文件、拆分输出和导出
+    pub(crate) fn process_by_json(
+        input_file: &InputFile,
+        ass_outputs: &AssOutputs,
+        danmus: Vec<Danmu>,
+        opts: &ConvertOptions,
+        export: &DanmuExport,
+        on_existing: OnExisting,
+    ) -> Result<ConversionReport> {
+        let AssOutputs {
+            output_path,
+            danmu_only_path,
+            merged_outputs,
+        } = ass_outputs;
+        if on_existing == OnExisting::Skip {
+            if let Some(path) = ass_outputs.paths().find(|p| p.exists()) {
+                info!(
+                    "{} {}",
+                    input_file.log("跳过已经存在的"),
+                    display_filename(path)
+                );
+                return Ok(ConversionReport {
+                    output_path: Some(output_path.clone()),
+                    skipped: true,
+                    ..Default::default()
+                });
+            }
+        }
+
+        // 全部生成成功后再备份和写入，失败时保留已有的文件
+        let mut outputs: Vec<(&PathBuf, Vec<u8>)> = vec![];
+        let report = match &opts.built_in_ass {
+            Some(built_in_ass) if danmu_only_path.is_some() || !merged_outputs.is_empty() => {
+                let (report, ass) = Self::draw_danmus(danmus, opts)?;
+                let mut merged = ass.clone();
+                merged.merge(built_in_ass.clone())?;
+                outputs.push((output_path, merged.buf));
+                if let Some(danmu_only_path) = danmu_only_path {
+                    outputs.push((danmu_only_path, ass.buf.clone()));
+                }
+                for (path, merged_ass) in merged_outputs {
+                    let mut merged = ass.clone();
+                    merged.merge(merged_ass.clone())?;
+                    outputs.push((path, merged.buf));
+                }
+                report
+            }
+            _ => {
+                let (report, ass) = Self::danmu_to_ass(danmus, opts)?;
+                outputs.push((output_path, ass.into_bytes()));
+                report
+            }
+        };
+
+        if export.is_enabled() {
+            export.write(&report.records)?;
+        }
+
+        if on_existing == OnExisting::Backup {
+            for (path, _) in &outputs {
+                if let Some(backup_path) = Self::backup_existing(path)? {
+                    info!(
+                        "{} {}",
+                        input_file.log("已有的 ASS 已备份到"),
+                        display_filename(&backup_path)
+                    );
+                }
+            }
+        }
+        let mut written = vec![];
+        for (path, buf) in outputs {
+            write_atomic(path, &buf).map_err(|source| Error::AssWrite {
+                path: path.clone(),
+                source,
+            })?;
+            written.push(path.clone());
+        }
+
+        Ok(ConversionReport {
+            output_path: Some(output_path.clone()),
+            written,
+            ..report
+        })
+    }
+
+    pub(crate) fn check_output_path(
+        path: &PathBuf,
+        force: bool,
+        on_existing: OnExisting,
+    ) -> Result<()> {
+        if path.is_dir() {
+            return Err(anyhow!(
+                "输出文件 {} 不能是一个目录",
+                display_filename(path)
+            ));
+        }
+        // 不覆盖字幕组等其他来源的同名字幕
+        if path.exists() && !force && on_existing == OnExisting::Overwrite {
+            let existing = fs::read(path)?;
+            if !AssCreator::is_generated(&String::from_utf8_lossy(&existing)) {
+                return Err(anyhow!(
+                    "{} 不是 ddp2ass 生成的字幕，使用 --output-suffix 参数输出到其他文件，或使用 --force 参数强制覆盖",
+                    display_filename(path)
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// 读取本地弹幕文件，支持弹幕 json 和 B 站格式的 xml
+    pub fn load_local_danmus(path: &PathBuf) -> Result<Vec<Danmu>> {
+        let content = read_to_string(path)?;
+        let is_xml = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("xml"));
+        if is_xml {
+            let (danmus, skipped) = bilibili_xml::parse(&content);
+            if skipped > 0 {
+                warn!(
+                    "跳过 {} 条无法解析或不支持的弹幕（{}）",
+                    skipped,
+                    display_filename(path)
+                );
+            }
+            Ok(danmus)
+        } else {
+            let comments_json = serde_json::from_str::<CommentsJson>(&content)
+                .with_context(|| format!("{} 不是弹幕 json", display_filename(path)))?;
+            Ok(comments_json.into_danmus(&display_filename(path)))
+        }
+    }
+
+    /// 离线转换弹幕 json 或 xml，不需要视频，也不会获取弹幕，
+    /// `output_path` 一般为 [`Dandan::local_output_path`]
+    pub fn process_by_local_file(
+        input_file: &InputFile,
+        opts: &ProcessOptions,
+        output_path: &Path,
+        progress: &dyn Progress,
+    ) -> Result<ConversionReport> {
+        let t = std::time::Instant::now();
+        progress.on_stage(input_file, Stage::Converting);
+        let mut danmus = Self::load_local_danmus(&input_file.path)?;
+        chinese::convert_danmus(&mut danmus, opts.canvas_config.ch_convert.as_ref());
+        let fetched = danmus.len();
+        let outputs = AssOutputs::single(output_path.to_path_buf());
+        Self::check_output_path(&outputs.output_path, opts.force, opts.on_existing)?;
+
+        let export = opts.export.for_file(input_file);
+        let convert_opts = ConvertOptions {
+            denylist: opts.denylist.clone(),
+            keep_filtered: opts.keep_filtered,
+            sources: opts.sources.clone(),
+            export_records: export.is_enabled(),
+            ..ConvertOptions::new(input_file.file_name(), opts.canvas_config.clone())
+        };
+        let report = Self::process_by_json(
+            input_file,
+            &outputs,
+            danmus,
+            &convert_opts,
+            &export,
+            opts.on_existing,
+        )?;
+        progress.on_danmu_written(input_file, report.danmu_count);
+        Ok(ConversionReport {
+            fetched,
+            elapsed_s: t.elapsed().as_secs_f64(),
+            ..report
+        })
+    }
+
+    /// `ep01.dandanplay.json`、`ep01.json` 和 `ep01.xml` 都输出为 `ep01.ass`
+    pub fn local_output_path(input_file: &InputFile, suffix: &str) -> PathBuf {
+        let name = input_file
+            .path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let stem = name
+            .strip_suffix(".dandanplay.json")
+            .or_else(|| name.strip_suffix(".json"))
+            .or_else(|| name.strip_suffix(".xml"))
+            .unwrap_or(&name);
+        // ass_path 只去掉视频扩展名，文件名中的其他 `.` 不受影响
+        let video = InputFile {
+            path: input_file.path.with_file_name(stem),
+        };
+        ass_path(&video, suffix)
+    }
+
+    /// 将已有文件重命名为 `name.ass.bak`，已被占用时依次使用 `name.ass.1.bak`、`name.ass.2.bak`
+    fn backup_existing(path: &PathBuf) -> Result<Option<PathBuf>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let name = path
+            .file_name()
+            .context("Filename not found")?
+            .to_string_lossy()
+            .to_string();
+        let mut backup_path = path.with_file_name(format!("{}.bak", name));
+        let mut n = 1;
+        while backup_path.exists() {
+            backup_path = path.with_file_name(format!("{}.{}.bak", name, n));
+            n += 1;
+        }
+        fs::rename(path, &backup_path)?;
+        Ok(Some(backup_path))
+    }
+
+    /// 过滤、排布弹幕并将 ASS 写入 `out`，不读写文件也不请求网络，
+    /// 可以在服务中直接将内存中的弹幕转换为 ASS，不需要 `network` 功能
+    ///
+    /// ```
+    /// use ddp2ass::{CanvasConfig, CommentsJson, ConvertOptions, Dandan};
+    ///
+    /// let comments: CommentsJson = serde_json::from_str(
+    ///     r#"{
+    ///         "count": 2,
+    ///         "comments": [
+    ///             { "cid": 1, "p": "1.00,1,16777215,[BiliBili]abc", "m": "前方高能" },
+    ///             { "cid": 2, "p": "2.50,5,16711680,[Gamer]def", "m": "顶部弹幕" }
+    ///         ]
+    ///     }"#,
+    /// )?;
+    /// let opts = ConvertOptions::new("第1话", CanvasConfig::default());
+    /// let mut ass = Vec::new();
+    /// let report = Dandan::convert(comments, &opts, &mut ass)?;
+    /// assert_eq!(report.danmu_count, 2);
+    /// assert!(String::from_utf8(ass)?.contains("顶部弹幕"));
+    /// # anyhow::Ok(())
+    /// ```
+    pub fn convert(
+        mut comments: CommentsJson,
+        opts: &ConvertOptions,
+        out: &mut impl Write,
+    ) -> Result<ConversionReport> {
+        comments.convert_chinese(opts.canvas_config.ch_convert.as_ref(), &opts.title);
+        let (report, ass) = Self::danmu_to_ass(comments.into_danmus(&opts.title), opts)?;
+        out.write_all(ass.as_bytes())?;
+        Ok(report)
+    }
+
+    /// 将任意来源的弹幕排布并生成 ASS，标题使用 `opts.title`，会合并 `opts.built_in_ass`
+    ///
+    /// ```
+    /// use ddp2ass::{CanvasConfig, ConvertOptions, Dandan, Danmu, DanmuType};
+    ///
+    /// let danmus = [(1.0, "开始了"), (1.5, "来了来了"), (3.0, "好耶")].map(|(timeline_s, content)| {
+    ///     Danmu {
+    ///         timeline_s,
+    ///         content: content.to_string(),
+    ///         r#type: DanmuType::Float,
+    ///         rgb: (255, 255, 255),
+    ///         ..Default::default()
+    ///     }
+    /// });
+    /// let opts = ConvertOptions::new("第1话", CanvasConfig::default());
+    /// let (report, ass) = Dandan::danmu_to_ass(danmus, &opts)?;
+    /// assert_eq!(report.danmu_count, 3);
+    /// assert!(ass.contains("Title: 第1话"));
+    /// assert!(ass.contains("来了来了"));
+    /// # anyhow::Ok(())
+    /// ```
+    pub fn danmu_to_ass(
+        danmus: impl IntoIterator<Item = Danmu>,
+        opts: &ConvertOptions,
+    ) -> Result<(ConversionReport, String)> {
+        let (report, mut ass) = Self::draw_danmus(danmus.into_iter().collect(), opts)?;
+        if let Some(built_in_ass) = &opts.built_in_ass {
+            ass.merge(built_in_ass.clone())?;
+        }
+        Ok((report, String::from_utf8(ass.buf)?))
+    }
+
+    /// 只绘制弹幕，不合并内置字幕
+    fn draw_danmus(
+        mut danmus: Vec<Danmu>,
+        opts: &ConvertOptions,
+    ) -> Result<(ConversionReport, AssCreator)> {
+        let ConvertOptions {
+            title,
+            canvas_config,
+            denylist,
+            keep_filtered,
+            sources,
+            export_records,
+            ..
+        } = opts;
+        let export_records = *export_records;
+        let mut ass = AssCreator::new(title.clone(), canvas_config.clone())?;
+
+        let mut report = ConversionReport::default();
+        let mut canvas = canvas_config.clone().canvas();
+        let t = std::time::Instant::now();
+
+        info!(
+            "弹幕来源：{}（{}）",
+            source::source_breakdown(&danmus),
+            title
+        );
+
+        let source_removed = sources.filter(&mut danmus);
+
+        for alignment in sources.align(&mut danmus) {
+            match alignment.offset {
+                Some(offset) => info!(
+                    "来源 {} 时间偏移 {:+.2} 秒，锚点 {} 个（{}）",
+                    alignment.source, offset, alignment.anchors, title
+                ),
+                None => info!(
+                    "来源 {} 锚点只有 {} 个，少于 {} 个，不修正时间偏移（{}）",
+                    alignment.source, alignment.anchors, sources.align_min_anchors, title
+                ),
+            }
+        }
+
+        danmus.sort_by(|a, b| {
+            a.timeline_s
+                .partial_cmp(&b.timeline_s)
+                .unwrap_or(Ordering::Equal)
+                .then(a.cid.cmp(&b.cid))
+        });
+
+        let deduped = sources.dedupe(&mut danmus);
+        if deduped > 0 {
+            info!("去掉 {} 条不同来源的重复弹幕（{}）", deduped, title);
+        }
+
+        let keep_dropped = matches!(keep_filtered, Some(KeepFilteredAsComments::All));
+        let mut blocked_users = 0;
+        for mut danmu in danmus {
+            danmu.normalize_line_breaks();
+            sources.apply_label(&mut danmu);
+            if let Some(reason) = denylist.as_ref().and_then(|d| d.check(&danmu)) {
+                let status = match reason {
+                    DenyReason::Keyword => ExportStatus::Denylisted,
+                    DenyReason::User => {
+                        blocked_users += 1;
+                        ExportStatus::BlockedUser
+                    }
+                };
+                report.filtered += 1;
+                if export_records {
+                    report.records.push(ExportRecord::new(&danmu, status));
+                }
+                if keep_filtered.is_some() {
+                    ass.write_comment(canvas.draw_filtered(danmu), status.as_str())?;
+                }
+                continue;
+            }
+            let original = (export_records || keep_dropped).then(|| danmu.clone());
+            let timeline_s = danmu.timeline_s + canvas.config.time_offset;
+            match canvas.draw(danmu)? {
+                Some(drawable) => {
+                    report.danmu_count += 1;
+                    ass.write(drawable)?;
+                    if let (true, Some(original)) = (export_records, original) {
+                        report
+                            .records
+                            .push(ExportRecord::new(&original, ExportStatus::Drawn));
+                    }
+                }
+                None => {
+                    report.dropped += 1;
+                    let status = if canvas.config.is_before_start(timeline_s) {
+                        ExportStatus::DroppedNegativeTime
+                    } else if canvas.config.is_beyond_duration(timeline_s) {
+                        report.beyond_duration += 1;
+                        ExportStatus::DroppedBeyondDuration
+                    } else {
+                        ExportStatus::DroppedNoLane
+                    };
+                    if let Some(original) = original {
+                        if export_records {
+                            report.records.push(ExportRecord::new(&original, status));
+                        }
+                        if keep_dropped {
+                            ass.write_comment(canvas.draw_filtered(original), status.as_str())?;
+                        }
+                    }
+                }
+            }
+        }
+
+        if blocked_users > 0 {
+            info!("屏蔽用户弹幕 {} 条（{}）", blocked_users, title);
+        }
+        if report.beyond_duration > 0 {
+            info!(
+                "丢弃超过视频时长的弹幕 {} 条（{}）",
+                report.beyond_duration, title
+            );
+        }
+        if !source_removed.is_empty() {
+            info!(
+                "来源过滤：{}（{}）",
+                source_removed
+                    .iter()
+                    .map(|(rule, count)| format!("{} 去掉 {} 条", rule, count))
+                    .collect::<Vec<_>>()
+                    .join("，"),
+                title
+            );
+        }
+
+        info!(
+            "弹幕数量：{}, 耗时 {:?}（{}）",
+            report.danmu_count,
+            t.elapsed(),
+            title
+        );
 
+        Ok((report, ass))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    #[cfg(feature = "network")]
+    use super::{
+        check_text_stream, comments_url, decode_subtitle, ffmpeg_input, select_stream_by_lang,
+        CacheOptions, FetchOptions, FfprobeSubJson, LocalDanmu, MatchOptions,
+        SimplifiedOrTraditional,
+    };
+    use super::{
+        AssOutputs, CommentsJson, ConvertOptions, OnExisting, Position, ProcessOptions, UpdateCheck,
+    };
+    #[cfg(feature = "network")]
+    use crate::{
+        Args, CancelFlag, DanmuProvider, Error, NoProgress, NonInteractiveSelector, ProviderFuture,
+        Selector,
+    };
+    use crate::{
+        CanvasConfig, Dandan, Danmu, DanmuExport, DanmuType, InputFile, NegativeOffset, Progress,
+        Stage,
+    };
+    use anyhow::Result;
+    #[cfg(feature = "network")]
+    use clap::Parser;
+    use std::{fs, path::PathBuf, sync::Mutex};
+    #[cfg(feature = "network")]
+    use std::{
+        fs::File,
+        path::Path,
+        sync::Arc,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    #[test]
+    fn test_parse_position() -> Result<()> {
+        let pos = Position::parse("12.30,5,16711680,[BiliBili]abc".to_string())?;
+        assert_eq!(pos.timestamp_s, 12.3);
+        assert_eq!(pos.mode, DanmuType::Top);
+        assert_eq!(pos.color, (255, 0, 0));
+        assert_eq!(pos.source, "BiliBili");
+        assert_eq!(pos.user_id, "[BiliBili]abc");
+        assert!(!pos.color_clamped);
+
+        let pos = Position::parse("12.30,1,4278255360,[BiliBili]abc".to_string())?;
+        assert_eq!(pos.color, (255, 255, 255));
+        assert!(pos.color_clamped);
         Ok(())
     }
+
+    #[cfg(feature = "network")]
+    #[test]
+    fn test_comments_url() {
+        assert_eq!(
+            comments_url(171220001, &FetchOptions::default()),
+            "https://api.dandanplay.net/api/v2/comment/171220001?withRelated=true&chConvert=1"
+        );
+        let opts = FetchOptions {
+            simplified_or_traditional: SimplifiedOrTraditional::Original,
+            with_related: false,
+        };
+        assert_eq!(
+            comments_url(171220001, &opts),
+            "https://api.dandanplay.net/api/v2/comment/171220001?withRelated=false&chConvert=0"
+        );
+    }
+
+    #[cfg(feature = "network")]
+    #[test]
+    fn test_cache_age() -> Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let json: CommentsJson = serde_json::from_str(&format!(
+            r#"{{ "count": 0, "fetchedAt": {}, "comments": [] }}"#,
+            now - 7200
+        ))?;
+        let age = json.cache_age(Path::new("not-exists.dandanplay.json"))?;
+        assert!((7200..7260).contains(&age.as_secs()));
+        Ok(())
+    }
+
+    #[cfg(feature = "network")]
+    #[test]
+    fn test_ass_title() -> Result<()> {
+        let json: CommentsJson = serde_json::from_str(
+            r#"{ "count": 0, "animeTitle": "葬送的芙莉莲", "episodeTitle": "第1话", "comments": [] }"#,
+        )?;
+        assert_eq!(json.ass_title("ep01.mkv"), "葬送的芙莉莲 第1话 (ep01.mkv)");
+        let json: CommentsJson = serde_json::from_str(r#"{ "count": 0, "comments": [] }"#)?;
+        assert_eq!(json.ass_title("ep01.mkv"), "ep01.mkv");
+        Ok(())
+    }
+
+    #[cfg(feature = "network")]
+    #[test]
+    fn test_source_changed() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let video = dir.path().join("ep01.mkv");
+        fs::write(&video, "old encode")?;
+
+        let mut json: CommentsJson = serde_json::from_str(r#"{ "count": 0, "comments": [] }"#)?;
+        assert_eq!(json.source_changed(&video, false)?, None);
+        json.record_source(&video, false)?;
+        assert_eq!(json.source_changed(&video, false)?, None);
+
+        // 大小和修改时间不变时不计算 hash
+        let modified = fs::metadata(&video)?.modified()?;
+        fs::write(&video, "new encode")?;
+        File::options()
+            .write(true)
+            .open(&video)?
+            .set_modified(modified)?;
+        assert_eq!(json.source_changed(&video, false)?, None);
+
+        File::options()
+            .write(true)
+            .open(&video)?
+            .set_modified(modified + Duration::from_secs(1))?;
+        assert_eq!(
+            json.source_changed(&video, false)?,
+            Some("视频 hash 不同".to_string())
+        );
+        assert_eq!(json.source_changed(&video, true)?, None);
+        fs::write(&video, "better encode")?;
+        assert_eq!(
+            json.source_changed(&video, false)?,
+            Some("视频大小 10 → 13".to_string())
+        );
+
+        json.record_source(&video, true)?;
+        assert_eq!(json.file_hash, None);
+        Ok(())
+    }
+
+    #[cfg(feature = "network")]
+    #[test]
+    fn test_load_corrupt_cache() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = dir.path().join("ep01.dandanplay.json");
+        let input_file = InputFile::from(&dir.path().join("ep01.mkv"));
+
+        fs::write(&cache, r#"{ "count": 0, "comments": [] }"#)?;
+        assert!(Dandan::load_cache(&input_file, &cache)?.is_some());
+
+        fs::write(
+            &cache,
+            include_str!("../tests/fixtures/cache/truncated.dandanplay.json"),
+        )?;
+        assert!(Dandan::load_cache(&input_file, &cache)?.is_none());
+        assert!(!cache.exists());
+        assert!(dir.path().join("ep01.dandanplay.json.corrupt").exists());
+        Ok(())
+    }
+
+    #[cfg(feature = "network")]
+    #[tokio::test]
+    async fn test_cache_episode_offset() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let input_file = InputFile::from(&dir.path().join("ep01.mkv"));
+        fs::write(&input_file.path, "video")?;
+        fs::write(
+            dir.path().join("ep01.dandanplay.json"),
+            r#"{ "count": 0, "comments": [], "episodeOffset": 12 }"#,
+        )?;
+        let opts = CacheOptions {
+            match_options: MatchOptions {
+                episode_offset: 12,
+                no_hash: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (comments_json, _) = Dandan::fetch_comments_json(
+            &input_file,
+            &opts,
+            &NonInteractiveSelector::default(),
+            &NoProgress,
+        )
+        .await?;
+        assert!(comments_json.from_cache);
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_cid() -> Result<()> {
+        let json: CommentsJson = serde_json::from_str(
+            r#"{ "count": 3, "comments": [
+                { "cid": 1, "p": "1.00,1,16777215,[BiliBili]a", "m": "本体" },
+                { "cid": 2, "p": "2.00,1,16777215,[BiliBili]b", "m": "弹幕" },
+                { "cid": 1, "p": "1.00,1,16777215,[Gamer]a", "m": "关联" }
+            ] }"#,
+        )?;
+        let danmus = json.into_danmus("test");
+        assert_eq!(
+            danmus
+                .iter()
+                .map(|d| d.content.as_str())
+                .collect::<Vec<_>>(),
+            ["本体", "弹幕"]
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "network")]
+    #[test]
+    fn test_merge_cached() -> Result<()> {
+        let comments = |cids: &[u64]| -> Result<CommentsJson> {
+            let comments: Vec<_> = cids
+                .iter()
+                .map(|cid| {
+                    format!(
+                        r#"{{ "cid": {}, "p": "1.00,1,16777215,1", "m": "{}" }}"#,
+                        cid, cid
+                    )
+                })
+                .collect();
+            Ok(serde_json::from_str(&format!(
+                r#"{{ "count": {}, "comments": [{}] }}"#,
+                cids.len(),
+                comments.join(",")
+            ))?)
+        };
+        let mut fresh = comments(&[2, 3, 4, 5])?;
+        let (added, kept) = fresh.merge_cached(comments(&[1, 2, 3])?);
+        assert_eq!((added, kept), (2, 1));
+        assert_eq!(fresh.count, 5);
+        let cids: Vec<_> = fresh.comments.iter().map(|c| c.cid).collect();
+        assert_eq!(cids, [1, 2, 3, 4, 5]);
+        Ok(())
+    }
+
+    #[cfg(feature = "network")]
+    #[test]
+    fn test_merge_ext() -> Result<()> {
+        let comments = |items: &[(u64, &str)]| -> Result<CommentsJson> {
+            let comments: Vec<_> = items
+                .iter()
+                .map(|(cid, m)| {
+                    format!(
+                        r#"{{ "cid": {}, "p": "1.00,1,16777215,[BiliBili]1", "m": "{}" }}"#,
+                        cid, m
+                    )
+                })
+                .collect();
+            Ok(serde_json::from_str(&format!(
+                r#"{{ "count": {}, "comments": [{}] }}"#,
+                items.len(),
+                comments.join(",")
+            ))?)
+        };
+        let mut merged = comments(&[(1, "a"), (2, "b")])?;
+        // 不同网址的 cid 可能相同，按参数和内容去重
+        assert_eq!(merged.merge_ext(comments(&[(1, "c"), (5, "a")])?), 1);
+        assert_eq!(merged.count, 3);
+        let contents: Vec<_> = merged.comments.iter().map(|c| c.m.as_str()).collect();
+        assert_eq!(contents, ["a", "b", "c"]);
+        assert!(merged.ext_urls.is_none());
+        Ok(())
+    }
+
+    #[cfg(feature = "network")]
+    #[test]
+    fn test_local_danmu_merge() {
+        let danmu = |t: f64, content: &str| Danmu {
+            timeline_s: t,
+            content: content.to_string(),
+            ..Default::default()
+        };
+        let mut pool = vec![danmu(1.0, "a"), danmu(2.0, "b")];
+        let local = LocalDanmu {
+            name: "extra.xml".to_string(),
+            danmus: vec![
+                danmu(1.0, "a"),
+                danmu(1.5, "a"),
+                danmu(2.0, "c"),
+                danmu(2.0, "c"),
+            ],
+        };
+        assert_eq!(local.merge_into(&mut pool), 2);
+        assert_eq!(pool.len(), 4);
+    }
+
+    #[test]
+    fn test_local_output_path() {
+        let input = |p: &str| InputFile {
+            path: PathBuf::from(p),
+        };
+        assert_eq!(
+            Dandan::local_output_path(&input("/a/ep.01.dandanplay.json"), ""),
+            PathBuf::from("/a/ep.01.ass")
+        );
+        assert_eq!(
+            Dandan::local_output_path(&input("/a/ep01.json"), "danmu"),
+            PathBuf::from("/a/ep01.danmu.ass")
+        );
+        assert_eq!(
+            Dandan::local_output_path(&input("/a/ep01.xml"), ""),
+            PathBuf::from("/a/ep01.ass")
+        );
+    }
+
+    #[test]
+    fn test_local_file_progress() -> Result<()> {
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<String>>);
+        impl Progress for Recorder {
+            fn on_stage(&self, _file: &InputFile, stage: Stage) {
+                self.0.lock().unwrap().push(stage.to_string());
+            }
+            fn on_danmu_written(&self, _file: &InputFile, count: u64) {
+                self.0.lock().unwrap().push(format!("写入 {}", count));
+            }
+        }
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("ep01.json");
+        fs::write(
+            &path,
+            r#"{ "count": 1, "comments": [{ "cid": 1, "p": "1.00,1,16777215,[Gamer]abc", "m": "ok" }] }"#,
+        )?;
+        let recorder = Recorder::default();
+        let report = Dandan::process_by_local_file(
+            &InputFile::from(&path),
+            &ProcessOptions::default(),
+            &Dandan::local_output_path(&InputFile::from(&path), ""),
+            &recorder,
+        )?;
+        assert_eq!(report.danmu_count, 1);
+        assert_eq!(report.fetched, 1);
+        assert_eq!(report.output_path, Some(dir.path().join("ep01.ass")));
+        assert!(dir.path().join("ep01.ass").is_file());
+        assert_eq!(*recorder.0.lock().unwrap(), ["生成 ASS", "写入 1"]);
+        Ok(())
+    }
+
+    #[cfg(feature = "network")]
+    #[tokio::test]
+    async fn test_process_by_path_with_provider() -> Result<()> {
+        struct MockProvider;
+        impl DanmuProvider for MockProvider {
+            fn name(&self) -> &str {
+                "mock"
+            }
+            fn fetch<'a>(&'a self, _input: &'a InputFile) -> ProviderFuture<'a, Vec<Danmu>> {
+                Box::pin(async {
+                    Ok(["第一条", "第二条"]
+                        .map(|content| Danmu {
+                            content: content.to_string(),
+                            timeline_s: 1.0,
+                            fontsize: 0,
+                            r#type: DanmuType::Float,
+                            rgb: (255, 255, 255),
+                            source: "mock".to_string(),
+                            user_id: String::new(),
+                            cid: 0,
+                        })
+                        .to_vec())
+                })
+            }
+        }
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("ep01.mkv");
+        fs::write(&path, "")?;
+        let opts = Args::parse_from(["test"]).process_options()?;
+        let selector: Arc<dyn Selector> = Arc::new(NonInteractiveSelector::default());
+        let report = Dandan::process_by_path(
+            &InputFile::from(&path),
+            &MockProvider,
+            &opts,
+            &selector,
+            &NoProgress,
+            &CancelFlag::default(),
+        )
+        .await?;
+        assert_eq!((report.fetched, report.danmu_count), (2, 2));
+        assert!(!report.cache_used && !report.skipped);
+        let ass = fs::read_to_string(dir.path().join("ep01.ass"))?;
+        assert!(ass.contains("第一条") && ass.contains("第二条"));
+        assert!(!dir.path().join("ep01.dandanplay.json").exists());
+
+        // 取消后不再获取和写入
+        fs::remove_file(dir.path().join("ep01.ass"))?;
+        let cancel = CancelFlag::default();
+        cancel.cancel();
+        let result = Dandan::process_by_path(
+            &InputFile::from(&path),
+            &MockProvider,
+            &opts,
+            &selector,
+            &NoProgress,
+            &cancel,
+        )
+        .await;
+        assert!(matches!(result, Err(Error::Cancelled)));
+        assert!(!dir.path().join("ep01.ass").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_existing() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("ep01.ass");
+        assert_eq!(Dandan::backup_existing(&path)?, None);
+
+        for (content, backup) in [("a", "ep01.ass.bak"), ("b", "ep01.ass.1.bak")] {
+            fs::write(&path, content)?;
+            assert_eq!(
+                Dandan::backup_existing(&path)?,
+                Some(dir.path().join(backup))
+            );
+            assert!(!path.exists());
+        }
+        assert_eq!(fs::read_to_string(dir.path().join("ep01.ass.bak"))?, "a");
+        assert_eq!(fs::read_to_string(dir.path().join("ep01.ass.1.bak"))?, "b");
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_check() {
+        assert!(!UpdateCheck::new(100, 110, 20.0).stale);
+        assert!(UpdateCheck::new(100, 121, 20.0).stale);
+        assert!(UpdateCheck::new(0, 1, 20.0).stale);
+        assert!(!UpdateCheck::new(0, 0, 20.0).stale);
+    }
+
+    #[test]
+    fn test_parse_color() -> Result<()> {
+        let parse = |s: &str| Position::parse_color(s.to_string());
+        assert_eq!(parse("-1")?, ((255, 255, 255), false));
+        assert_eq!(parse("0")?, ((0, 0, 0), false));
+        assert_eq!(parse("16777215")?, ((255, 255, 255), false));
+        assert_eq!(parse("-16777216")?, ((0, 0, 0), false));
+        assert_eq!(parse("-16711936")?, ((0, 255, 0), false));
+        // 高位有值时按白色处理
+        assert_eq!(parse("4278255360")?, ((255, 255, 255), true));
+        assert_eq!(parse("16777216")?, ((255, 255, 255), true));
+        assert_eq!(parse("16744448")?, ((255, 128, 0), false));
+        assert!(Position::parse_color("99999999999999999999".to_string()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_position_malformed() {
+        for p in [
+            "",
+            "12.3,1,16777215",
+            "12.3,1,16777215,user,extra",
+            "abc,1,16777215,user",
+            "12.3,x,16777215,user",
+            "12.3,1,white,user",
+        ] {
+            assert!(Position::parse(p.to_string()).is_err(), "{:?}", p);
+        }
+    }
+
+    #[test]
+    fn test_convert_skips_malformed() -> Result<()> {
+        let json: CommentsJson = serde_json::from_str(
+            r#"
+            {
+                "count": 2,
+                "comments": [
+                    { "cid": 1, "p": "12.3,1,16777215", "m": "truncated" },
+                    { "cid": 2, "p": "1.00,1,16777215,[Gamer]abc", "m": "ok" }
+                ]
+            }
+        "#,
+        )?;
+
+        let mut ass = Vec::new();
+        let report = Dandan::convert(
+            json,
+            &ConvertOptions::new("test", CanvasConfig::default()),
+            &mut ass,
+        )?;
+        assert_eq!(report.danmu_count, 1);
+        assert!(!String::from_utf8(ass)?.contains("truncated"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_line_breaks() -> Result<()> {
+        let json = r#"{
+            "count": 4,
+            "comments": [
+                { "cid": 1, "p": "1.00,1,16777215,[Gamer]a", "m": "滚动\r\n弹幕" },
+                { "cid": 2, "p": "2.00,5,16777215,[Gamer]a", "m": "顶部\r\n弹幕" },
+                { "cid": 3, "p": "3.00,4,16777215,[Gamer]a", "m": "底部\n弹幕\n\n" },
+                { "cid": 4, "p": "4.00,1,16777215,[Gamer]a", "m": "结尾\r" }
+            ]
+        }"#;
+        let ass = crate::test_util::json_to_ass(json, "test")?;
+        let texts: Vec<_> = ass
+            .lines()
+            .filter(|line| line.starts_with("Dialogue: 2,"))
+            .map(|line| line.rsplit_once('}').unwrap().1)
+            .collect();
+        assert_eq!(texts, ["滚动 弹幕", r"顶部\N弹幕", r"底部\N弹幕", "结尾"]);
+
+        // 多行弹幕按最长的一行计算长度
+        let config = CanvasConfig::default();
+        let danmu = |content: &str| Danmu {
+            content: content.to_string(),
+            r#type: DanmuType::Top,
+            ..Default::default()
+        };
+        assert_eq!(
+            danmu("顶部\n弹幕").length(&config),
+            danmu("弹幕").length(&config)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_same_timestamp_order() -> Result<()> {
+        let danmu = |cid: u64, content: &str| Danmu {
+            timeline_s: 1.0,
+            content: content.to_string(),
+            cid,
+            ..Default::default()
+        };
+        let opts = ConvertOptions::new("test", CanvasConfig::default());
+        let (_, ass) = Dandan::danmu_to_ass([danmu(2, "second"), danmu(1, "first")], &opts)?;
+        let (_, reversed) = Dandan::danmu_to_ass([danmu(1, "first"), danmu(2, "second")], &opts)?;
+        assert_eq!(ass, reversed);
+        assert!(ass.find("first") < ass.find("second"));
+        Ok(())
+    }
+
+    #[cfg(feature = "network")]
+    #[test]
+    fn test_ffprobe_sub_json() -> Result<()> {
+        let json = include_str!("../tests/fixtures/ffprobe/subtitles.json");
+        let streams = serde_json::from_str::<FfprobeSubJson>(json)?.streams;
+        assert_eq!(
+            streams.iter().map(|s| s.label()).collect::<Vec<_>>(),
+            ["2 chi", "3 eng", "4 CHI 繁體中文", "5 zho 简体中文"]
+        );
+
+        let json = include_str!("../tests/fixtures/ffprobe/subtitles_untagged.json");
+        let streams = serde_json::from_str::<FfprobeSubJson>(json)?.streams;
+        assert_eq!(
+            streams.iter().map(|s| s.label()).collect::<Vec<_>>(),
+            ["2 und", "3 und Signs", "4 jpn"]
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "network")]
+    #[test]
+    fn test_select_stream_by_lang() -> Result<()> {
+        let json = include_str!("../tests/fixtures/ffprobe/subtitles.json");
+        let streams = serde_json::from_str::<FfprobeSubJson>(json)?.streams;
+        let select = |lang: &str, prefer: SimplifiedOrTraditional| {
+            select_stream_by_lang(&streams, lang, &prefer).map(|s| s.index)
+        };
+        assert_eq!(select("chs", SimplifiedOrTraditional::Simplified), Some(5));
+        assert_eq!(select("chi", SimplifiedOrTraditional::Traditional), Some(4));
+        assert_eq!(select("zh", SimplifiedOrTraditional::Original), Some(4));
+        assert_eq!(select("EN", SimplifiedOrTraditional::Simplified), Some(3));
+        assert_eq!(select("jpn", SimplifiedOrTraditional::Simplified), None);
+
+        assert!(check_text_stream(&streams, "2")
+            .unwrap_err()
+            .to_string()
+            .contains("hdmv_pgs_subtitle"));
+        assert!(check_text_stream(&streams, "3").is_ok());
+        assert!(check_text_stream(&streams, "9").is_ok());
+        assert!(check_text_stream(&streams, "s:0").is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_negative_offset() -> Result<()> {
+        let danmus = [(5.0, "已经离开"), (20.0, "还在屏幕上"), (40.0, "正常")].map(
+            |(timeline_s, content)| Danmu {
+                timeline_s,
+                content: content.to_string(),
+                ..Default::default()
+            },
+        );
+        let dialogues = |ass: &str| -> Vec<String> {
+            ass.lines()
+                .filter(|line| line.starts_with("Dialogue: 2,"))
+                .map(str::to_string)
+                .collect()
+        };
+
+        let canvas_config = CanvasConfig {
+            time_offset: -30.0,
+            ..Default::default()
+        };
+        let opts = ConvertOptions::new("test", canvas_config.clone());
+        let (report, ass) = Dandan::danmu_to_ass(danmus.clone(), &opts)?;
+        assert_eq!(report.danmu_count, 1);
+        assert_eq!(report.dropped, 2);
+        assert!(!ass.contains(",-"));
+
+        let canvas_config = CanvasConfig {
+            negative_offset: NegativeOffset::Clamp,
+            ..canvas_config
+        };
+        let opts = ConvertOptions::new("test", canvas_config);
+        let (report, ass) = Dandan::danmu_to_ass(danmus, &opts)?;
+        assert_eq!(report.danmu_count, 2);
+        assert_eq!(report.dropped, 1);
+        assert!(!ass.contains(",-"));
+        // 持续 15 秒的弹幕在 -10 秒开始，从 0 秒开始显示剩下的 5 秒，起点移动到 2/3 处
+        let dialogues = dialogues(&ass);
+        assert!(dialogues[0].starts_with("Dialogue: 2,0:00:00.00,0:00:05.00,Float,"));
+        assert!(dialogues[0].contains("还在屏幕上"));
+        assert!(dialogues[0].contains(r"\move(287, 0, -210, 0)"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_drop_beyond_duration() -> Result<()> {
+        let danmus =
+            [(10.0, "正片"), (103.0, "片尾"), (200.0, "超过时长")].map(|(timeline_s, content)| {
+                Danmu {
+                    timeline_s,
+                    content: content.to_string(),
+                    ..Default::default()
+                }
+            });
+        let canvas_config = CanvasConfig {
+            time_offset: 1.0,
+            video_duration: Some(100.0),
+            ..Default::default()
+        };
+        let opts = ConvertOptions::new("test", canvas_config);
+        let (report, ass) = Dandan::danmu_to_ass(danmus.clone(), &opts)?;
+        assert_eq!(report.danmu_count, 2);
+        assert_eq!(report.dropped, 1);
+        assert_eq!(report.beyond_duration, 1);
+        assert!(ass.contains("片尾"));
+        assert!(!ass.contains("超过时长"));
+
+        let opts = ConvertOptions::new("test", CanvasConfig::default());
+        let (report, _) = Dandan::danmu_to_ass(danmus, &opts)?;
+        assert_eq!(report.danmu_count, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiple_merged_outputs() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let input_file = InputFile::from(&dir.path().join("ep01.mkv"));
+        let sub = |text: &str| {
+            format!(
+                "[Script Info]\nScriptType: v4.00+\n\n[Events]\nFormat: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\nDialogue: 0,0:00:01.00,0:00:02.00,Default,,0,0,0,,{}\n",
+                text
+            )
+        };
+        let danmus = vec![Danmu {
+            timeline_s: 1.0,
+            content: "弹幕".to_string(),
+            ..Default::default()
+        }];
+        let opts = ConvertOptions {
+            built_in_ass: Some(sub("简体字幕")),
+            ..ConvertOptions::new("test", CanvasConfig::default())
+        };
+        let output = dir.path().join("ep01.chs.ass");
+        let merged = dir.path().join("ep01.eng.ass");
+        let outputs = AssOutputs {
+            merged_outputs: vec![(merged.clone(), sub("English"))],
+            ..AssOutputs::single(output.clone())
+        };
+        let report = Dandan::process_by_json(
+            &input_file,
+            &outputs,
+            danmus,
+            &opts,
+            &DanmuExport::default(),
+            OnExisting::Overwrite,
+        )?;
+        assert_eq!(report.written, [output.clone(), merged.clone()]);
+        let chs = fs::read_to_string(&output)?;
+        assert!(chs.contains("弹幕") && chs.contains("简体字幕") && !chs.contains("English"));
+        let eng = fs::read_to_string(&merged)?;
+        assert!(eng.contains("弹幕") && eng.contains("English") && !eng.contains("简体字幕"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_output_keeps_existing() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let input_file = InputFile::from(&dir.path().join("ep01.mkv"));
+        let output = dir.path().join("ep01.ass");
+        fs::write(&output, "old")?;
+        let export = DanmuExport {
+            csv: Some(dir.path().join("missing/ep01.csv")),
+            ..Default::default()
+        };
+        let opts = ConvertOptions {
+            export_records: true,
+            ..ConvertOptions::new("test", CanvasConfig::default())
+        };
+        let danmus = vec![Danmu {
+            timeline_s: 1.0,
+            content: "弹幕".to_string(),
+            ..Default::default()
+        }];
+        let result = Dandan::process_by_json(
+            &input_file,
+            &AssOutputs::single(output.clone()),
+            danmus,
+            &opts,
+            &export,
+            OnExisting::Backup,
+        );
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&output)?, "old");
+        assert!(!dir.path().join("ep01.ass.bak").exists());
+        Ok(())
+    }
+
+    #[cfg(feature = "network")]
+    #[test]
+    fn test_decode_subtitle() {
+        assert_eq!(
+            decode_subtitle("简体字幕".as_bytes().to_vec()),
+            ("简体字幕".to_string(), None)
+        );
+        // GBK 编码的「简体字幕」
+        let gbk = vec![0xBC, 0xF2, 0xCC, 0xE5, 0xD7, 0xD6, 0xC4, 0xBB];
+        assert_eq!(
+            decode_subtitle(gbk),
+            ("简体字幕".to_string(), Some("gb18030"))
+        );
+        // Big5 编码的「繁體中文字幕，這是測試。」，按 GB18030 也能解码但标点落在私用区
+        let big5 = vec![
+            0xC1, 0x63, 0xC5, 0xE9, 0xA4, 0xA4, 0xA4, 0xE5, 0xA6, 0x72, 0xB9, 0xF5, 0xA1, 0x41,
+            0xB3, 0x6F, 0xAC, 0x4F, 0xB4, 0xFA, 0xB8, 0xD5, 0xA1, 0x43,
+        ];
+        assert!(encoding_rs::GB18030
+            .decode_without_bom_handling_and_without_replacement(&big5)
+            .is_some());
+        assert_eq!(
+            decode_subtitle(big5),
+            ("繁體中文字幕，這是測試。".to_string(), Some("Big5"))
+        );
+        let (text, encoding) = decode_subtitle(vec![b'a', 0xFF, b'b']);
+        assert_eq!(text, "a\u{FFFD}b");
+        assert!(encoding.is_some());
+        assert_eq!(
+            ffmpeg_input(&PathBuf::from("-Re:Zero.mkv")),
+            std::ffi::OsString::from("file:-Re:Zero.mkv")
+        );
+    }
 }
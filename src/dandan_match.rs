@@ -1,23 +1,30 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
 use md5;
-use promkit::{
-    crossterm::style::Stylize,
-    preset::{query_selector::QuerySelector, readline::Readline},
-    suggest::Suggest,
-};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::{
+    collections::{BTreeMap, HashSet},
     fmt,
-    fs::File,
+    fs::{self, File},
     io::{BufReader, Read},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
 };
 
-use crate::InputFile;
+use crate::{
+    api,
+    episode_map::EpisodeMap,
+    error::is_skipped_error,
+    selector::Selector,
+    util::{display_filename, modified_ms, sibling_path, underlined, write_atomic},
+    Error, InputFile,
+};
 
 pub struct MatchParams {
     pub match_name: String,
+    /// 视频所在的目录
+    pub dir: PathBuf,
     pub json: MatchParamsJson,
 }
 
@@ -68,7 +75,7 @@ pub struct MatchItem {
     pub shift: f64,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct MatchesJson {
     #[serde(rename = "isMatched")]
     pub is_matched: bool,
@@ -80,6 +87,49 @@ pub struct MatchesJson {
     pub error_message: String,
 }
 
+/// dandanplay 匹配接口的返回缓存，视频的大小和修改时间不变时不再计算 hash 和请求匹配，
+/// `match-result` 和获取弹幕时共用。与记录手动选择的 `.dandanplay.match.json` 不同，
+/// 可以随时删除
+#[derive(Serialize, Deserialize, Default)]
+struct MatchCache {
+    #[serde(rename = "fileSize")]
+    file_size: u64,
+    #[serde(rename = "modifiedMs")]
+    modified_ms: u64,
+    #[serde(rename = "fileHash", default, skip_serializing_if = "Option::is_none")]
+    file_hash: Option<String>,
+    /// 请求参数 -> 匹配结果
+    #[serde(default)]
+    matches: BTreeMap<String, MatchesJson>,
+}
+
+impl MatchCache {
+    fn path(input_file: &InputFile) -> PathBuf {
+        sibling_path(&input_file.path, "dandanplay.api-cache.json")
+    }
+
+    /// 视频变化或缓存无法读取时返回空的缓存
+    fn load(input_file: &InputFile) -> Result<Self> {
+        let metadata = input_file.path.metadata()?;
+        let file_size = metadata.len();
+        let modified_ms = modified_ms(&metadata)?;
+        let cached = fs::read_to_string(Self::path(input_file))
+            .ok()
+            .and_then(|json| serde_json::from_str::<Self>(&json).ok())
+            .filter(|c| c.file_size == file_size && c.modified_ms == modified_ms);
+        Ok(cached.unwrap_or(Self {
+            file_size,
+            modified_ms,
+            ..Default::default()
+        }))
+    }
+
+    fn save(&self, input_file: &InputFile) -> Result<()> {
+        write_atomic(&Self::path(input_file), serde_json::to_string(self)?)?;
+        Ok(())
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct SearchJson {
     #[serde(rename = "hasMore")]
@@ -108,22 +158,401 @@ pub struct SearchAnimeJson {
 #[derive(Serialize, Deserialize)]
 pub struct SearchAnimeEpisodeJson {
     #[serde(rename = "episodeId")]
-    episode_id: i64,
+    pub episode_id: i64,
     #[serde(rename = "episodeTitle")]
-    episode_title: String,
+    pub episode_title: String,
+    /// 只有动画详情中有
+    #[serde(
+        rename = "episodeNumber",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    episode_number: Option<String>,
+}
+
+/// `/search/anime` 的结果，不包括剧集
+#[derive(Deserialize)]
+struct SearchAnimeListJson {
+    animes: Vec<SearchAnimeBriefJson>,
+}
+
+#[derive(Deserialize)]
+struct SearchAnimeBriefJson {
+    #[serde(rename = "animeId")]
+    anime_id: i64,
+    #[serde(rename = "type")]
+    type_: String,
+    #[serde(rename = "typeDescription")]
+    type_description: String,
+}
+
+/// 搜索剧集时最多再补充的动画数量，每部需要请求一次动画详情
+const MAX_EXTRA_ANIMES: usize = 10;
+
+/// 用动画详情补上搜索结果中没有的动画，`episode` 为搜索的集数
+fn with_episodes(
+    anime: SearchAnimeBriefJson,
+    bangumi: BangumiDetailsJson,
+    episode: Option<&str>,
+) -> Option<SearchAnimeJson> {
+    let episodes: Vec<SearchAnimeEpisodeJson> = bangumi
+        .episodes
+        .into_iter()
+        .filter(|e| episode.is_none() || e.episode_number.as_deref() == episode)
+        .collect();
+    (!episodes.is_empty()).then_some(SearchAnimeJson {
+        anime_id: bangumi.anime_id,
+        anime_title: bangumi.anime_title,
+        type_: anime.type_,
+        type_description: anime.type_description,
+        episodes,
+    })
+}
+
+#[derive(Deserialize)]
+struct BangumiJson {
+    bangumi: Option<BangumiDetailsJson>,
+}
+
+#[derive(Deserialize)]
+struct BangumiDetailsJson {
+    #[serde(rename = "animeId")]
+    anime_id: i64,
+    #[serde(rename = "animeTitle")]
+    anime_title: String,
+    episodes: Vec<SearchAnimeEpisodeJson>,
+}
+
+/// 搜索结果中保留的动画类型
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+pub enum SearchType {
+    /// TV 动画
+    #[serde(rename = "tvseries")]
+    Tvseries,
+    /// 剧场版
+    #[serde(rename = "movie")]
+    Movie,
+    #[serde(rename = "ova")]
+    Ova,
+    /// 网络放送
+    #[serde(rename = "web")]
+    Web,
+    #[default]
+    #[serde(rename = "all")]
+    All,
+}
+
+impl SearchType {
+    /// `anime_type` 为 dandanplay 返回的 `type`，如 `tvseries`、`jpmovie`
+    pub fn matches(self, anime_type: &str) -> bool {
+        match self {
+            SearchType::Tvseries => anime_type == "tvseries",
+            SearchType::Movie => anime_type == "movie" || anime_type == "jpmovie",
+            SearchType::Ova => anime_type == "ova",
+            SearchType::Web => anime_type == "web",
+            SearchType::All => true,
+        }
+    }
+}
+
+/// 匹配弹幕时的选项
+#[derive(Debug, Clone, Default)]
+pub struct MatchOptions {
+    /// 不使用已保存的匹配结果，重新选择
+    pub change_match: bool,
+    /// 同一文件夹只手动选择一次动画，其余文件按集数匹配
+    pub season_match: bool,
+    /// 加到文件名中集数上的偏移
+    pub episode_offset: i32,
+    /// `--yes` 时自动选择搜索结果需要的最低相似度，None 表示手动选择
+    pub auto_select: Option<f64>,
+    /// 不计算视频 hash，只用文件名匹配
+    pub no_hash: bool,
+    /// 把手动选择的匹配结果提交给 dandanplay
+    pub report_match: bool,
+    /// 不使用匹配结果缓存
+    pub refresh: bool,
+    /// 搜索结果中保留的动画类型
+    pub search_type: SearchType,
+    /// 不读取和保存搜索名字的记录
+    pub no_history: bool,
+    /// 精确匹配到多个结果时报错
+    pub strict_match: bool,
+    /// `--season-match` 时本次运行中各文件夹选择的动画，clone 的选项之间共享
+    pub seasons: SeasonChoices,
+}
+
+/// 每个文件夹一把锁，同一文件夹的文件同时匹配时只让第一个文件选择动画，
+/// 不同文件夹之间互不等待
+#[derive(Debug, Clone, Default)]
+pub struct SeasonChoices(Arc<Mutex<BTreeMap<PathBuf, SeasonLock>>>);
+
+type SeasonLock = Arc<tokio::sync::Mutex<Option<SeasonRecord>>>;
+
+impl SeasonChoices {
+    fn get(&self, record_path: &Path) -> SeasonLock {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(record_path.to_path_buf())
+            .or_default()
+            .clone()
+    }
+}
+
+/// `--season-match` 时文件夹对应的动画
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct SeasonRecord {
+    #[serde(rename = "animeId")]
+    anime_id: i64,
+    #[serde(rename = "animeTitle")]
+    anime_title: String,
+}
+
+/// 从文件名中识别集数，支持 `S01E02`、`E02`/`EP02`、`第2话`、`[02]`、`[02v2]`、` - 02`，
+/// 超过 3 位的数字（如 `1080`、`2023`）不认为是集数
+pub fn parse_episode_number(name: &str) -> Option<u32> {
+    // 开头最多 3 位数字，可以带 `v2` 这样的版本号，返回集数和数字之后的内容
+    fn episode_prefix(s: &str) -> Option<(u32, &str)> {
+        let len = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        if len == 0 || len > 3 {
+            return None;
+        }
+        let mut rest = &s[len..];
+        if let Some(version) = rest.strip_prefix(['v', 'V']) {
+            if version.starts_with(|c: char| c.is_ascii_digit()) {
+                rest = version.trim_start_matches(|c: char| c.is_ascii_digit());
+            }
+        }
+        Some((s[..len].parse().ok()?, rest))
+    }
+    let upper = name.to_ascii_uppercase();
+
+    // S01E02
+    for (i, _) in upper.match_indices('S') {
+        let season = upper[i + 1..].trim_start_matches(|c: char| c.is_ascii_digit());
+        if season.len() < upper.len() - i - 1 {
+            if let Some((episode, _)) = season.strip_prefix('E').and_then(episode_prefix) {
+                return Some(episode);
+            }
+        }
+    }
+    // E02、EP02，前面不能是字母或数字
+    for (i, _) in upper.match_indices('E') {
+        if upper[..i].ends_with(|c: char| c.is_ascii_alphanumeric()) {
+            continue;
+        }
+        let rest = &upper[i + 1..];
+        let rest = rest.strip_prefix('P').unwrap_or(rest);
+        if let Some((episode, rest)) = episode_prefix(rest) {
+            if !rest.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+                return Some(episode);
+            }
+        }
+    }
+    // 第2话
+    for (i, _) in name.match_indices('第') {
+        let rest = &name[i + '第'.len_utf8()..];
+        let len = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if rest[len..].starts_with(['话', '話', '集']) {
+            if let Ok(episode) = rest[..len].parse() {
+                return Some(episode);
+            }
+        }
+    }
+    // [02]、【02】、[02v2]，跳过 [1080p]、[2023] 等
+    for (i, c) in name.match_indices(['[', '【']) {
+        if let Some((episode, rest)) = episode_prefix(&name[i + c.len()..]) {
+            if rest.starts_with([']', '】']) {
+                return Some(episode);
+            }
+        }
+    }
+    // Title - 02
+    for (i, _) in name.match_indices(" - ") {
+        if let Some((episode, rest)) = episode_prefix(&name[i + 3..]) {
+            if rest.is_empty() || rest.starts_with([' ', '.', '[', '(']) {
+                return Some(episode);
+            }
+        }
+    }
+    None
+}
+
+/// 去掉 `[字幕组]`、`【1080P】` 等括号中的内容和标点，转为小写
+fn normalize_title(s: &str) -> String {
+    let mut out = String::new();
+    let mut depth = 0;
+    for c in s.chars() {
+        match c {
+            '[' | '【' => depth += 1,
+            ']' | '】' => depth = (depth - 1).max(0),
+            _ if depth == 0 && c.is_alphanumeric() => out.extend(c.to_lowercase()),
+            _ => {}
+        }
+    }
+    out
+}
+
+/// 全角字母数字和符号转为半角并转为小写，近似 NFKC + lowercase，用于交互式选择时的过滤
+fn normalize_query(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '\u{3000}' => ' ',
+            '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+            _ => c,
+        })
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// 选项是否包含输入中空格分开的每个词，不区分大小写和全半角
+pub fn search_filter(query: &str, option: &str) -> bool {
+    let option = normalize_query(option);
+    normalize_query(query)
+        .split_whitespace()
+        .all(|token| option.contains(token))
+}
+
+/// 精确匹配结果的选项，如 `葬送的芙莉莲 第1话 (TV动画) 偏移 0s`
+fn exact_match_option(item: &MatchItem) -> String {
+    format!(
+        "{} {} ({}) 偏移 {}s",
+        item.anime_title, item.episode_title, item.type_description, item.shift
+    )
+}
+
+/// 标题相似度，归一化后按相邻字符对计算 Dice 系数，范围 0 ~ 1
+pub fn title_similarity(a: &str, b: &str) -> f64 {
+    let bigrams = |s: &str| -> Vec<(char, char)> {
+        let chars: Vec<char> = normalize_title(s).chars().collect();
+        let mut bigrams: Vec<_> = chars.windows(2).map(|w| (w[0], w[1])).collect();
+        if let [c] = chars[..] {
+            bigrams.push((c, c));
+        }
+        bigrams.sort();
+        bigrams
+    };
+    let (a, b) = (bigrams(a), bigrams(b));
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+    // 两个有序列表的交集大小，重复的字符对分别计数
+    let (mut i, mut j, mut common) = (0, 0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                common += 1;
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    2.0 * common as f64 / (a.len() + b.len()) as f64
+}
+
+const HASH_BYTES: u64 = 16 * 1024 * 1024;
+
+/// 每个目录最多保存的搜索记录数量
+const MAX_HISTORY: usize = 20;
+/// 最多保存搜索记录的目录数量
+const MAX_HISTORY_DIRS: usize = 100;
+
+/// 一个目录中输入过的搜索名字
+#[derive(Serialize, Deserialize)]
+struct HistoryEntry {
+    dir: PathBuf,
+    names: Vec<String>,
+}
+
+/// 搜索记录保存在用户缓存目录中，不在视频目录中写入文件，无法获取缓存目录时不保存
+fn history_path() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("ddp2ass").join("history.json"))
+}
+
+/// 全部目录的搜索记录，最近使用的目录在前
+fn load_history_entries(path: &Path) -> Vec<HistoryEntry> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+/// 目录中输入过的搜索名字，最近的在前
+fn load_history(path: &Path, dir: &Path) -> Vec<String> {
+    load_history_entries(path)
+        .into_iter()
+        .find(|entry| entry.dir == dir)
+        .map(|entry| entry.names)
+        .unwrap_or_default()
+}
+
+fn save_history(path: &Path, dir: &Path, input: &str) -> Result<()> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Ok(());
+    }
+    let mut entries = load_history_entries(path);
+    let mut names = entries
+        .iter()
+        .position(|entry| entry.dir == dir)
+        .map(|i| entries.remove(i).names)
+        .unwrap_or_default();
+    names.retain(|h| h != input);
+    names.insert(0, input.to_string());
+    names.truncate(MAX_HISTORY);
+    entries.insert(
+        0,
+        HistoryEntry {
+            dir: dir.to_path_buf(),
+            names,
+        },
+    );
+    entries.truncate(MAX_HISTORY_DIRS);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    write_atomic(path, serde_json::to_string(&entries)?)?;
+    Ok(())
+}
+
+/// 文件名中的集数加上 `--episode-offset`，结果小于 1 时无法使用
+pub fn apply_episode_offset(episode: u32, offset: i32) -> Option<u32> {
+    u32::try_from(i64::from(episode) + i64::from(offset))
+        .ok()
+        .filter(|e| *e >= 1)
 }
 
 #[derive(Clone)]
 pub enum SearchOption {
     SearchEditInput(SearchEditInput),
+    /// 动画，类型如 `TV动画`、`剧场版`
+    SearchAnime {
+        anime_id: i64,
+        title: String,
+        type_description: String,
+        episode_count: usize,
+    },
     SearchAnimeOption(AnimeEpisodeItem),
+    /// 直接输入 episodeId
+    EpisodeIdInput,
+    /// 跳过这个文件，例如 dandanplay 没有的 NCOP、特典
+    Skip,
 }
 
-#[derive(Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct AnimeEpisodeItem {
+    #[serde(rename = "animeId")]
     pub anime_id: i64,
+    #[serde(rename = "animeTitle")]
     pub anime_title: String,
+    #[serde(rename = "episodeId")]
     pub episode_id: i64,
+    #[serde(rename = "episodeTitle")]
     pub episode_title: String,
 }
 
@@ -134,28 +563,65 @@ impl fmt::Display for SearchOption {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             SearchOption::SearchEditInput(_) => write!(f, "Edit Search name"),
-            SearchOption::SearchAnimeOption(o) => {
-                write!(f, "{} {}", o.anime_title, o.episode_title)
-            }
+            SearchOption::EpisodeIdInput => write!(f, "Enter episodeId"),
+            SearchOption::Skip => write!(f, "Skip this file"),
+            SearchOption::SearchAnime {
+                title,
+                type_description,
+                episode_count,
+                ..
+            } => write!(f, "{} ({}) {} 集", title, type_description, episode_count),
+            SearchOption::SearchAnimeOption(o) => write!(f, "{}", o.episode_title),
         }
     }
 }
 
+/// 保存的手动匹配结果
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum MatchRecord {
+    Item(AnimeEpisodeItem),
+    Skipped { skipped: bool },
+}
+
 pub struct DandanMatch {}
 
 impl DandanMatch {
+    /// dandanplay 的文件 hash，即文件前 16 MiB（不足时为整个文件）的 md5
     pub fn get_file_hash(path: &PathBuf) -> Result<String> {
         let file = File::open(path)?;
-        let mut reader = BufReader::new(file);
-        let bytes_to_read: usize = 16 * 1024 * 1024;
-        let mut buf = vec![0u8; bytes_to_read];
-        reader.read(&mut buf)?;
+        let mut buf = Vec::with_capacity(HASH_BYTES as usize);
+        // read 可能只读到一部分，read_to_end 会一直读到 16 MiB 或文件结束
+        BufReader::new(file)
+            .take(HASH_BYTES)
+            .read_to_end(&mut buf)?;
         let hash = format!("{:x}", md5::compute(buf));
         Ok(hash)
     }
 
-    pub fn get_match_params(input_file: &InputFile) -> Result<MatchParams> {
-        let hash = Self::get_file_hash(&input_file.path)?;
+    /// `no_hash` 或无法读取文件计算 hash 时只用文件名匹配
+    /// `refresh` 时不使用匹配结果缓存中的 hash，计算的 hash 在请求匹配后才写入缓存
+    pub fn get_match_params(
+        input_file: &InputFile,
+        no_hash: bool,
+        refresh: bool,
+    ) -> Result<MatchParams> {
+        let cache = MatchCache::load(input_file)?;
+        let hash = match no_hash {
+            true => None,
+            false if !refresh && cache.file_hash.is_some() => cache.file_hash,
+            false => match Self::get_file_hash(&input_file.path) {
+                Ok(hash) => Some(hash),
+                Err(e) => {
+                    warn!(
+                        "{}: {}",
+                        input_file.log("无法计算视频 hash，只用文件名匹配"),
+                        e
+                    );
+                    None
+                }
+            },
+        };
         let folder_name = match input_file.path.parent() {
             Some(p) => match p.file_name() {
                 Some(p) => p.to_string_lossy().to_string(),
@@ -172,149 +638,1092 @@ impl DandanMatch {
 
         Ok(MatchParams {
             match_name,
+            dir: input_file
+                .path
+                .parent()
+                .map(|p| p.to_path_buf())
+                .unwrap_or_default(),
             json: MatchParamsJson {
                 file_name: filename,
-                file_hash: hash,
+                match_mode: hash.is_none().then_some(MatchMode::FileNameOnly),
+                file_hash: hash.unwrap_or_default(),
                 file_size: file_size.to_string(),
-                match_mode: None,
             },
         })
     }
 
-    pub async fn get_matches_json(match_params: &MatchParams) -> Result<MatchesJson> {
+    /// 视频没有变化时使用缓存的结果，`refresh` 时重新请求
+    pub async fn get_matches_json(
+        input_file: &InputFile,
+        match_params: &MatchParams,
+        refresh: bool,
+    ) -> Result<MatchesJson, Error> {
+        let mut cache = MatchCache::load(input_file)?;
+        let key = serde_json::to_string(&match_params.json).context("匹配参数无法序列化")?;
+        if !refresh {
+            if let Some(matches_json) = cache.matches.get(&key) {
+                debug!("{}", input_file.log("使用缓存的匹配结果"));
+                return Ok(matches_json.clone());
+            }
+        }
         let match_json = json!(match_params.json);
-        let matches_json = reqwest::Client::new()
-            .post("https://api.dandanplay.net/api/v2/match")
-            .json(&match_json)
-            .header("Accept", "application/json")
-            .header("User-Agent", "curl")
-            .send()
-            .await?
-            .json::<MatchesJson>()
-            .await?;
+        let matches_json: MatchesJson = api::request(&match_params.json.file_name, || {
+            reqwest::Client::new()
+                .post("https://api.dandanplay.net/api/v2/match")
+                .json(&match_json)
+        })
+        .await?;
+        if !match_params.json.file_hash.is_empty() {
+            cache.file_hash = Some(match_params.json.file_hash.clone());
+        }
+        cache.matches.insert(key, matches_json.clone());
+        cache.save(input_file)?;
         Ok(matches_json)
     }
 
-    pub async fn search_anime(
-        match_params: &MatchParams,
+    /// 搜索剧集，结果过多（`hasMore`）时再搜索动画，逐个补上没有返回的动画的剧集，
+    /// 最多补 `MAX_EXTRA_ANIMES` 部，仍然不全时 `has_more` 为 true，`filename` 用于重试日志
+    pub(crate) async fn search_episodes(
+        filename: &str,
         anime_name: &str,
         episode: Option<&str>,
-    ) -> Result<AnimeEpisodeItem> {
+    ) -> Result<SearchJson> {
         let mut query = vec![("anime", anime_name)];
         if let Some(episode) = episode {
             query.push(("episode", episode));
         }
-        let search_json = reqwest::Client::new()
-            .get("https://api.dandanplay.net/api/v2/search/episodes")
-            .query(&query)
-            .header("Accept", "application/json")
-            .header("User-Agent", "curl")
-            .send()
-            .await?
-            .json::<SearchJson>()
-            .await?;
-        if search_json.animes.is_empty() {
-            println!(
-                "搜索 {} 结果为空",
-                match_params.match_name.clone().underlined()
-            );
-            let new_anime_name = Self::input_search_params(anime_name)?;
-            return Box::pin(Self::search_anime(match_params, &new_anime_name, None)).await;
+        let mut search_json: SearchJson = api::request(filename, || {
+            reqwest::Client::new()
+                .get("https://api.dandanplay.net/api/v2/search/episodes")
+                .query(&query)
+        })
+        .await?;
+        if !search_json.has_more {
+            return Ok(search_json);
+        }
+
+        let anime_list: SearchAnimeListJson = api::request(filename, || {
+            reqwest::Client::new()
+                .get("https://api.dandanplay.net/api/v2/search/anime")
+                .query(&[("keyword", anime_name)])
+        })
+        .await?;
+        let found: HashSet<i64> = search_json.animes.iter().map(|a| a.anime_id).collect();
+        let missing: Vec<SearchAnimeBriefJson> = anime_list
+            .animes
+            .into_iter()
+            .filter(|a| !found.contains(&a.anime_id))
+            .collect();
+        search_json.has_more = missing.len() > MAX_EXTRA_ANIMES;
+        for anime in missing.into_iter().take(MAX_EXTRA_ANIMES) {
+            if let Some(bangumi) = Self::get_bangumi(filename, anime.anime_id).await? {
+                search_json
+                    .animes
+                    .extend(with_episodes(anime, bangumi, episode));
+            }
         }
-        let mut options: Vec<SearchOption> = vec![];
-        for anime in search_json.animes {
-            for episode in anime.episodes {
-                options.push(SearchOption::SearchAnimeOption(AnimeEpisodeItem {
+        Ok(search_json)
+    }
+
+    /// 按与文件名的相似度排序搜索结果，`auto_select` 时不进行交互，直接选择最相似的一项，
+    /// `report_match` 时提交手动选择的结果
+    pub async fn search_anime(
+        match_params: &MatchParams,
+        anime_name: &str,
+        episode: Option<&str>,
+        options: &MatchOptions,
+        selector: &dyn Selector,
+    ) -> Result<AnimeEpisodeItem, Error> {
+        Ok(Self::search_anime_inner(match_params, anime_name, episode, options, selector).await?)
+    }
+
+    async fn search_anime_inner(
+        match_params: &MatchParams,
+        anime_name: &str,
+        episode: Option<&str>,
+        options: &MatchOptions,
+        selector: &dyn Selector,
+    ) -> Result<AnimeEpisodeItem> {
+        let MatchOptions {
+            auto_select,
+            report_match,
+            ..
+        } = *options;
+        let search_json =
+            Self::search_episodes(&match_params.json.file_name, anime_name, episode).await?;
+        let truncated = search_json.has_more;
+        let animes: Vec<SearchAnimeJson> = search_json
+            .animes
+            .into_iter()
+            .filter(|anime| options.search_type.matches(&anime.type_))
+            .collect();
+        if animes.is_empty() {
+            // 文件名中识别的集数可能不对，先去掉集数再搜索一次
+            if episode.is_some() {
+                return Box::pin(Self::search_anime_inner(
+                    match_params,
+                    anime_name,
+                    None,
+                    options,
+                    selector,
+                ))
+                .await;
+            }
+            if auto_select.is_some() {
+                return Err(Error::NoMatch(format!("搜索 {} 结果为空", anime_name)).into());
+            }
+            println!("搜索 {} 结果为空", underlined(&match_params.match_name));
+            let new_anime_name =
+                Self::input_search_params(match_params, anime_name, options, selector)?;
+            return Box::pin(Self::search_anime_inner(
+                match_params,
+                &new_anime_name,
+                None,
+                options,
+                selector,
+            ))
+            .await;
+        }
+        // (相似度, 剧集, 动画类型)
+        let mut items: Vec<(f64, AnimeEpisodeItem, String)> = vec![];
+        for anime in &animes {
+            for episode in &anime.episodes {
+                let item = AnimeEpisodeItem {
                     anime_id: anime.anime_id,
                     anime_title: anime.anime_title.clone(),
                     episode_id: episode.episode_id,
                     episode_title: episode.episode_title.clone(),
-                }));
+                };
+                let score = title_similarity(
+                    &match_params.match_name,
+                    &format!("{} {}", item.anime_title, item.episode_title),
+                );
+                items.push((score, item, anime.type_description.clone()));
             }
         }
-        options.push(SearchOption::SearchEditInput(SearchEditInput {}));
-        let mut select_prompt = QuerySelector::new(&options, |text, items| {
-            items.iter().filter(|i| i.contains(text)).cloned().collect()
-        })
-        .title("请选择匹配的动画:")
-        .prompt()?;
-        let selected = select_prompt.run()?;
-        let option = options
+        // 稳定排序，相似度相同时保持 dandanplay 返回的顺序
+        items.sort_by(|(a, ..), (b, ..)| b.total_cmp(a));
+
+        if let Some(threshold) = auto_select {
+            // 搜索到的动画都没有剧集时没有可以选择的结果
+            let Some((score, item, _)) = items.first() else {
+                return Err(Error::NoMatch(format!("搜索 {} 的结果中没有剧集", anime_name)).into());
+            };
+            if *score < threshold {
+                let candidates = items
+                    .iter()
+                    .take(3)
+                    .map(|(score, item, _)| {
+                        format!("  {:.2} {} {}", score, item.anime_title, item.episode_title)
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                return Err(Error::NoMatch(format!(
+                    "没有相似度超过 {} 的搜索结果，最接近的是:\n{}",
+                    threshold, candidates
+                ))
+                .into());
+            }
+            info!(
+                "自动选择 {} {}，相似度 {:.2}",
+                item.anime_title, item.episode_title, score
+            );
+            return Ok(item.to_owned());
+        }
+
+        // 先选择动画再选择剧集，动画按其中最相似的剧集排序
+        let mut ranked: Vec<&SearchAnimeJson> = vec![];
+        for (_, item, _) in &items {
+            if !ranked.iter().any(|a| a.anime_id == item.anime_id) {
+                ranked.extend(animes.iter().find(|a| a.anime_id == item.anime_id));
+            }
+        }
+        let title_note = format!(
+            "{}{}",
+            match report_match {
+                true => "（选择结果会提交给 dandanplay）",
+                false => "",
+            },
+            match truncated {
+                true => "（结果过多，只显示了一部分，可以选择 Edit Search name 输入更准确的名字）",
+                false => "",
+            }
+        );
+        let anime = match ranked[..] {
+            [anime] => anime,
+            _ => {
+                let mut select_options: Vec<SearchOption> = ranked
+                    .iter()
+                    .map(|anime| SearchOption::SearchAnime {
+                        anime_id: anime.anime_id,
+                        title: anime.anime_title.clone(),
+                        type_description: anime.type_description.clone(),
+                        episode_count: anime.episodes.len(),
+                    })
+                    .collect();
+                select_options.push(SearchOption::SearchEditInput(SearchEditInput {}));
+                select_options.push(SearchOption::EpisodeIdInput);
+                select_options.push(SearchOption::Skip);
+                let selected = Self::select(
+                    selector,
+                    &select_options,
+                    format!("请选择匹配的动画{}:", title_note),
+                )?;
+                match selected {
+                    SearchOption::SearchAnime { anime_id, .. } => ranked
+                        .iter()
+                        .find(|a| a.anime_id == *anime_id)
+                        .context("Select anime not found")?,
+                    SearchOption::Skip => return Err(Error::Skipped.into()),
+                    SearchOption::EpisodeIdInput => {
+                        let item = Self::input_episode_id(match_params, selector).await?;
+                        return Self::accept_selection(match_params, item, report_match).await;
+                    }
+                    _ => {
+                        return Self::edit_search(match_params, anime_name, options, selector).await
+                    }
+                }
+            }
+        };
+
+        let mut select_options: Vec<SearchOption> = anime
+            .episodes
             .iter()
-            .find(|o| o.to_string() == selected)
-            .context("Select anime not found")?;
-        match option {
+            .map(|episode| {
+                SearchOption::SearchAnimeOption(AnimeEpisodeItem {
+                    anime_id: anime.anime_id,
+                    anime_title: anime.anime_title.clone(),
+                    episode_id: episode.episode_id,
+                    episode_title: episode.episode_title.clone(),
+                })
+            })
+            .collect();
+        // 文件名中的集数放在最前面
+        let file_episode = parse_episode_number(&match_params.json.file_name)
+            .and_then(|episode| apply_episode_offset(episode, options.episode_offset));
+        if let Some(i) = select_options.iter().position(|o| match o {
+            SearchOption::SearchAnimeOption(item) => {
+                file_episode.is_some() && parse_episode_number(&item.episode_title) == file_episode
+            }
+            _ => false,
+        }) {
+            let option = select_options.remove(i);
+            select_options.insert(0, option);
+        }
+        select_options.push(SearchOption::SearchEditInput(SearchEditInput {}));
+        select_options.push(SearchOption::EpisodeIdInput);
+        select_options.push(SearchOption::Skip);
+        let selected = Self::select(
+            selector,
+            &select_options,
+            format!(
+                "请选择 {} ({}) 的剧集{}:",
+                anime.anime_title, anime.type_description, title_note
+            ),
+        )?;
+        match selected {
             SearchOption::SearchAnimeOption(o) => {
-                Self::accept_match(
-                    o.episode_id,
-                    &MatchAcceptParamsJson {
-                        hash: None,
-                        file_name: None,
-                    },
-                )
-                .await?;
-                Ok(o.to_owned())
+                Self::accept_selection(match_params, o.to_owned(), report_match).await
             }
-            _ => {
-                let new_anime_name = Self::input_search_params(anime_name)?;
-                Box::pin(Self::search_anime(match_params, &new_anime_name, None)).await
+            SearchOption::Skip => Err(Error::Skipped.into()),
+            SearchOption::EpisodeIdInput => {
+                let item = Self::input_episode_id(match_params, selector).await?;
+                Self::accept_selection(match_params, item, report_match).await
+            }
+            _ => Self::edit_search(match_params, anime_name, options, selector).await,
+        }
+    }
+
+    /// 手动选择的结果，`report_match` 时提交给 dandanplay
+    async fn accept_selection(
+        match_params: &MatchParams,
+        item: AnimeEpisodeItem,
+        report_match: bool,
+    ) -> Result<AnimeEpisodeItem> {
+        if report_match {
+            Self::accept_match(match_params, item.episode_id).await?;
+            info!(
+                "已向 dandanplay 提交匹配结果 {} {}",
+                item.anime_title, item.episode_title
+            );
+        }
+        Ok(item)
+    }
+
+    /// 直接输入 episodeId，查询不到时重新输入
+    async fn input_episode_id(
+        match_params: &MatchParams,
+        selector: &dyn Selector,
+    ) -> Result<AnimeEpisodeItem> {
+        loop {
+            let input = selector.input("输入 episodeId（可以在 dandanplay 网站上找到）:", &[])?;
+            let Ok(episode_id) = input.trim().parse::<i64>() else {
+                println!("episodeId 应为数字：{}", input);
+                continue;
+            };
+            match Self::get_episode(&match_params.json.file_name, episode_id).await {
+                Ok(Some(item)) => return Ok(item),
+                Ok(None) => println!("找不到 episodeId {}", episode_id),
+                Err(e) => println!("查询 episodeId {} 失败：{:?}", episode_id, e),
             }
         }
     }
 
-    pub async fn accept_match(
-        episode_id: i64,
-        match_accept_params: &MatchAcceptParamsJson,
-    ) -> Result<()> {
-        let match_json = json!(match_accept_params);
-        reqwest::Client::new()
-            .post(format!(
-                "https://api.dandanplay.net/api/v2/match/{}",
+    /// 交互式选择，返回选中的一项
+    fn select<'a>(
+        selector: &dyn Selector,
+        select_options: &'a [SearchOption],
+        title: String,
+    ) -> Result<&'a SearchOption> {
+        let texts: Vec<String> = select_options.iter().map(|o| o.to_string()).collect();
+        let i = selector.select(&title, &texts)?;
+        select_options.get(i).context("Select anime not found")
+    }
+
+    /// 选择 Edit Search name 后输入新的名字重新搜索
+    async fn edit_search(
+        match_params: &MatchParams,
+        anime_name: &str,
+        options: &MatchOptions,
+        selector: &dyn Selector,
+    ) -> Result<AnimeEpisodeItem> {
+        let new_anime_name =
+            Self::input_search_params(match_params, anime_name, options, selector)?;
+        Box::pin(Self::search_anime_inner(
+            match_params,
+            &new_anime_name,
+            None,
+            options,
+            selector,
+        ))
+        .await
+    }
+
+    /// 向 dandanplay 提交手动选择的匹配结果
+    pub async fn accept_match(match_params: &MatchParams, episode_id: i64) -> Result<(), Error> {
+        let match_json = json!(MatchAcceptParamsJson {
+            hash: Some(match_params.json.file_hash.clone()).filter(|h| !h.is_empty()),
+            file_name: Some(match_params.json.file_name.clone()),
+        });
+        let _: serde::de::IgnoredAny = api::request(&match_params.json.file_name, || {
+            reqwest::Client::new()
+                .post(format!(
+                    "https://api.dandanplay.net/api/v2/match/{}",
+                    episode_id
+                ))
+                .json(&match_json)
+        })
+        .await
+        .context("提交匹配结果失败")?;
+        Ok(())
+    }
+
+    /// 同一目录中之前输入过的名字也可以用 tab 补全，`--no-history` 时不读取也不保存
+    pub fn input_search_params(
+        match_params: &MatchParams,
+        match_name: &str,
+        options: &MatchOptions,
+        selector: &dyn Selector,
+    ) -> Result<String> {
+        let history_path = history_path().filter(|_| !options.no_history);
+        let mut suggest = vec![match_name.to_string()];
+        if let Some(history_path) = &history_path {
+            suggest.extend(load_history(history_path, &match_params.dir));
+        }
+        let input = selector.input("输入要搜索的名字(用 tab 补全):", &suggest)?;
+        if let Some(history_path) = &history_path {
+            save_history(history_path, &match_params.dir, &input)?;
+        }
+        Ok(input)
+    }
+
+    /// 动画详情，包括全部剧集
+    async fn get_bangumi(filename: &str, anime_id: i64) -> Result<Option<BangumiDetailsJson>> {
+        let bangumi_json: BangumiJson = api::request(filename, || {
+            reqwest::Client::new().get(format!(
+                "https://api.dandanplay.net/api/v2/bangumi/{}",
+                anime_id
+            ))
+        })
+        .await?;
+        Ok(bangumi_json.bangumi)
+    }
+
+    /// 根据 episodeId 查询动画和剧集标题，episodeId 为 animeId * 10000 + 集数
+    async fn get_episode(filename: &str, episode_id: i64) -> Result<Option<AnimeEpisodeItem>> {
+        let bangumi = Self::get_bangumi(filename, episode_id / 10000).await?;
+        Ok(bangumi.and_then(|bangumi| {
+            let episode = bangumi
+                .episodes
+                .into_iter()
+                .find(|e| e.episode_id == episode_id)?;
+            Some(AnimeEpisodeItem {
+                anime_id: bangumi.anime_id,
+                anime_title: bangumi.anime_title,
+                episode_id,
+                episode_title: episode.episode_title,
+            })
+        }))
+    }
+
+    /// 视频目录中 `ddp2ass.map.toml` 指定的剧集
+    async fn match_by_map(input_file: &InputFile) -> Result<Option<AnimeEpisodeItem>> {
+        let Some(map) = EpisodeMap::load(&input_file.path)? else {
+            return Ok(None);
+        };
+        let filename = input_file.file_name();
+        let Some(episode_id) = map.find(&filename) else {
+            return Ok(None);
+        };
+        let invalid = || {
+            format!(
+                "{} 中 {} 对应的 episodeId {} 无效",
+                display_filename(&map.path),
+                filename,
                 episode_id
+            )
+        };
+        let item = Self::get_episode(&filename, episode_id)
+            .await
+            .with_context(invalid)?
+            .with_context(invalid)?;
+        Ok(Some(item))
+    }
+
+    /// 精确匹配有多个结果时（如多卷 BD 中相同的视频）进行选择，
+    /// 不能交互时使用第一个并列出其他结果，`strict_match` 时报错
+    fn select_exact_match<'a>(
+        input_file: &InputFile,
+        matches: &'a [MatchItem],
+        options: &MatchOptions,
+        selector: &dyn Selector,
+    ) -> Result<&'a MatchItem> {
+        let [first, rest @ ..] = matches else {
+            return Err(anyhow!("精确匹配的结果为空"));
+        };
+        if rest.is_empty() {
+            return Ok(first);
+        }
+        let candidates: Vec<String> = matches.iter().map(exact_match_option).collect();
+        if options.strict_match {
+            return Err(Error::NoMatch(format!(
+                "精确匹配到 {} 个结果，--strict-match 时不自动选择:\n  {}",
+                matches.len(),
+                candidates.join("\n  ")
             ))
-            .json(&match_json)
-            .header("Accept", "application/json")
-            .header("User-Agent", "curl")
-            .send()
-            .await?;
+            .into());
+        }
+        if options.auto_select.is_some() || !selector.is_interactive() {
+            warn!(
+                "{}",
+                input_file.log(&format!(
+                    "精确匹配到 {} 个结果，使用第一个 {}，其他结果:\n  {}",
+                    matches.len(),
+                    candidates[0],
+                    candidates[1..].join("\n  ")
+                ))
+            );
+            return Ok(first);
+        }
+        let i = selector.select(
+            &format!(
+                "{} 精确匹配到 {} 个结果，请选择:",
+                input_file.file_name(),
+                matches.len()
+            ),
+            &candidates,
+        )?;
+        let item = matches.get(i).context("Select matches not found")?;
+        // 保存选择的结果，下次不再询问
+        Self::save_match_record(
+            input_file,
+            &MatchRecord::Item(AnimeEpisodeItem {
+                anime_id: item.anime_id,
+                anime_title: item.anime_title.clone(),
+                episode_id: item.episode_id,
+                episode_title: item.episode_title.clone(),
+            }),
+        )?;
+        Ok(item)
+    }
+
+    /// 手动选择的匹配结果，保存后重新获取弹幕时不会再次自动匹配
+    fn match_record_path(input_file: &InputFile) -> PathBuf {
+        sibling_path(&input_file.path, "dandanplay.match.json")
+    }
+
+    /// 之前选择了跳过时返回 `Error::Skipped`
+    fn load_match_record(input_file: &InputFile) -> Result<Option<AnimeEpisodeItem>> {
+        let path = Self::match_record_path(input_file);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let json = fs::read_to_string(&path)?;
+        let record = serde_json::from_str(&json)
+            .with_context(|| format!("匹配记录 {} 无法解析", display_filename(&path)))?;
+        match record {
+            MatchRecord::Item(item) => Ok(Some(item)),
+            MatchRecord::Skipped { .. } => Err(Error::Skipped.into()),
+        }
+    }
+
+    fn save_match_record(input_file: &InputFile, record: &MatchRecord) -> Result<()> {
+        write_atomic(
+            &Self::match_record_path(input_file),
+            serde_json::to_string(record)?,
+        )?;
         Ok(())
     }
 
-    pub fn input_search_params(match_name: &str) -> Result<String> {
-        let mut match_name_prompt = Readline::default()
-            .title("输入要搜索的名字(用 tab 补全):")
-            .enable_suggest(Suggest::from_iter([&match_name]))
-            .enable_history()
-            .prompt()?;
-        match_name_prompt.run()
+    fn season_record_path(input_file: &InputFile) -> Option<PathBuf> {
+        Some(input_file.path.parent()?.join(".ddp2ass.season.json"))
     }
 
+    /// `--season-match`：文件夹中第一个需要手动匹配的文件选择动画后，
+    /// 其余文件根据文件名中的集数在同一部动画下自动匹配
+    async fn match_by_season(
+        input_file: &InputFile,
+        match_params: &MatchParams,
+        options: &MatchOptions,
+        selector: &dyn Selector,
+    ) -> Result<AnimeEpisodeItem> {
+        let record_path = Self::season_record_path(input_file).context("视频目录无法解析")?;
+        // 本次运行中已经选择的动画，--change-match 时不使用之前保存的记录
+        let season = options.seasons.get(&record_path);
+        let mut chosen = season.lock().await;
+        let record = match chosen.clone() {
+            Some(record) => Some(record),
+            None if !options.change_match && record_path.exists() => {
+                let json = fs::read_to_string(&record_path)?;
+                Some(
+                    serde_json::from_str::<SeasonRecord>(&json).with_context(|| {
+                        format!("动画记录 {} 无法解析", display_filename(&record_path))
+                    })?,
+                )
+            }
+            None => None,
+        };
+
+        let Some(record) = record else {
+            let item = Self::search_anime(
+                match_params,
+                &match_params.match_name,
+                None,
+                options,
+                selector,
+            )
+            .await?;
+            let record = SeasonRecord {
+                anime_id: item.anime_id,
+                anime_title: item.anime_title.clone(),
+            };
+            write_atomic(&record_path, serde_json::to_string(&record)?)?;
+            *chosen = Some(record);
+            return Ok(item);
+        };
+        *chosen = Some(record.clone());
+        drop(chosen);
+
+        let episode = parse_episode_number(&match_params.json.file_name)
+            .and_then(|episode| apply_episode_offset(episode, options.episode_offset));
+        let Some(episode) = episode else {
+            println!(
+                "无法从文件名识别集数 {}",
+                underlined(&match_params.match_name)
+            );
+            return Self::search_anime_inner(
+                match_params,
+                &match_params.match_name,
+                None,
+                options,
+                selector,
+            )
+            .await;
+        };
+        let search_json = Self::search_episodes(
+            &match_params.json.file_name,
+            &record.anime_title,
+            Some(&episode.to_string()),
+        )
+        .await?;
+        let found = search_json
+            .animes
+            .into_iter()
+            .filter(|anime| anime.anime_id == record.anime_id)
+            .find_map(|anime| {
+                let episode = anime.episodes.into_iter().next()?;
+                Some(AnimeEpisodeItem {
+                    anime_id: anime.anime_id,
+                    anime_title: anime.anime_title,
+                    episode_id: episode.episode_id,
+                    episode_title: episode.episode_title,
+                })
+            });
+        match found {
+            Some(item) => {
+                info!(
+                    "{}, {}, 话数 {}",
+                    input_file.log("按集数匹配弹幕"),
+                    underlined(&item.anime_title),
+                    underlined(&item.episode_title)
+                );
+                Ok(item)
+            }
+            None => {
+                println!(
+                    "{} 中找不到第 {} 集 {}",
+                    record.anime_title,
+                    episode,
+                    underlined(&match_params.match_name)
+                );
+                Self::search_anime_inner(
+                    match_params,
+                    &match_params.match_name,
+                    None,
+                    options,
+                    selector,
+                )
+                .await
+            }
+        }
+    }
+
+    /// 需要手动选择时通过 `selector` 交互
     pub async fn get_anime_episode_item(
         input_file: &InputFile,
-        change_match: bool,
+        options: &MatchOptions,
+        selector: &dyn Selector,
     ) -> Result<AnimeEpisodeItem> {
-        let match_params = Self::get_match_params(input_file)?;
-        let matches_json = Self::get_matches_json(&match_params).await?;
+        let MatchOptions {
+            change_match,
+            no_hash,
+            refresh,
+            ..
+        } = *options;
+        if let Some(item) = Self::match_by_map(input_file).await? {
+            info!(
+                "{}, {}, 话数 {}",
+                input_file.log("使用映射文件指定的剧集"),
+                underlined(&item.anime_title),
+                underlined(&item.episode_title)
+            );
+            return Ok(item);
+        }
+        if !change_match {
+            if let Some(item) = Self::load_match_record(input_file)? {
+                info!(
+                    "{}, {}, 话数 {}",
+                    input_file.log("使用已保存的匹配结果"),
+                    underlined(&item.anime_title),
+                    underlined(&item.episode_title)
+                );
+                return Ok(item);
+            }
+        }
 
-        Ok(if change_match {
-            Self::search_anime(&match_params, &match_params.match_name, None).await?
-        } else if matches_json.is_matched {
-            let match_item = &matches_json.matches[0];
+        let mut match_params = Self::get_match_params(input_file, no_hash, refresh)?;
+        let mut stage = match match_params.json.match_mode {
+            None => "hash 和文件名",
+            Some(_) => "文件名",
+        };
+        let mut matches_json = Self::get_matches_json(input_file, &match_params, refresh).await?;
+        // 重新编码的视频 hash 不会匹配，再只用文件名试一次
+        if !change_match && !matches_json.is_matched && match_params.json.match_mode.is_none() {
+            match_params.json.match_mode = Some(MatchMode::FileNameOnly);
+            stage = "文件名";
+            matches_json = Self::get_matches_json(input_file, &match_params, refresh).await?;
+        }
+
+        if !change_match && matches_json.is_matched {
+            let match_item =
+                Self::select_exact_match(input_file, &matches_json.matches, options, selector)?;
             info!(
                 "{}, {}, 话数 {}",
-                input_file.log("匹配弹幕"),
-                match_item.anime_title.clone().underlined().to_string(),
-                match_item.episode_title.clone().underlined().to_string()
+                input_file.log(&format!("按{}匹配弹幕", stage)),
+                underlined(&match_item.anime_title),
+                underlined(&match_item.episode_title)
             );
-            AnimeEpisodeItem {
+            return Ok(AnimeEpisodeItem {
                 anime_id: match_item.anime_id,
                 anime_title: match_item.anime_title.clone(),
                 episode_id: match_item.episode_id,
                 episode_title: match_item.episode_title.clone(),
-            }
-        } else {
+            });
+        }
+
+        if !change_match {
             println!("无法精确匹配 {}", match_params.match_name);
-            Self::search_anime(&match_params, &match_params.match_name, None).await?
-        })
+        }
+        let episode = parse_episode_number(&match_params.json.file_name)
+            .and_then(|episode| apply_episode_offset(episode, options.episode_offset))
+            .map(|episode| episode.to_string());
+        let item = if options.season_match {
+            Self::match_by_season(input_file, &match_params, options, selector).await
+        } else {
+            Self::search_anime_inner(
+                &match_params,
+                &match_params.match_name,
+                episode.as_deref(),
+                options,
+                selector,
+            )
+            .await
+        };
+        // 记住跳过的文件，使用 --change-match 时重新选择
+        let item = match item {
+            Err(e) if is_skipped_error(&e) => {
+                Self::save_match_record(input_file, &MatchRecord::Skipped { skipped: true })?;
+                return Err(e);
+            }
+            item => item?,
+        };
+        Self::save_match_record(input_file, &MatchRecord::Item(item.clone()))?;
+        Ok(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NonInteractiveSelector;
+
+    #[test]
+    fn test_search_type() {
+        assert!(SearchType::Tvseries.matches("tvseries"));
+        assert!(!SearchType::Tvseries.matches("tvspecial"));
+        assert!(SearchType::Movie.matches("jpmovie"));
+        assert!(!SearchType::Ova.matches("movie"));
+        assert!(SearchType::All.matches("musicvideo"));
+    }
+
+    #[test]
+    fn test_get_file_hash() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+
+        let path = dir.path().join("empty.mkv");
+        fs::write(&path, b"")?;
+        assert_eq!(
+            DandanMatch::get_file_hash(&path)?,
+            "d41d8cd98f00b204e9800998ecf8427e"
+        );
+
+        let path = dir.path().join("small.mkv");
+        fs::write(&path, b"hello")?;
+        assert_eq!(
+            DandanMatch::get_file_hash(&path)?,
+            "5d41402abc4b2a76b9719d911017c592"
+        );
+
+        // 超过 16 MiB 的部分不参与计算
+        let head = vec![7u8; HASH_BYTES as usize];
+        let path = dir.path().join("large.mkv");
+        fs::write(&path, [&head[..], b"tail"].concat())?;
+        assert_eq!(
+            DandanMatch::get_file_hash(&path)?,
+            format!("{:x}", md5::compute(&head))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_episode_number() {
+        let cases = [
+            ("Frieren.S01E02.1080p", Some(2)),
+            (
+                "Sousou.no.Frieren.S02E07.1080p.WEB-DL.AAC2.0.H.264",
+                Some(7),
+            ),
+            ("[Sub] Frieren E07 [1080p]", Some(7)),
+            ("Frieren.EP12.2160p.HEVC", Some(12)),
+            ("葬送的芙莉莲 第12话", Some(12)),
+            ("[Sub][葬送的芙莉莲][第03話][1080P]", Some(3)),
+            ("进击的巨人 第100集", Some(100)),
+            ("[Sub][Frieren][03][1080p]", Some(3)),
+            ("[Sub][Frieren][07v2][1080p][CHS]", Some(7)),
+            ("【Sub】【Frieren】【08】【1080P】", Some(8)),
+            ("[Sub] Frieren - 04 [1080p]", Some(4)),
+            ("[Sub] Frieren - 05v2 (1080p HEVC)", Some(5)),
+            ("Frieren - 06", Some(6)),
+            ("[Sub][2023][1080p]", None),
+            ("[Sub] Frieren [1080p][HEVC-10bit][AAC]", None),
+            ("Frieren.2023.1080p.x265.E-AC3", None),
+            ("Frieren - 1080p", None),
+            ("[Sub] Frieren [01-12][1080p]", None),
+            ("Frieren OVA", None),
+            ("Frieren Complete Edition", None),
+        ];
+        for (name, episode) in cases {
+            assert_eq!(parse_episode_number(name), episode, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_get_match_params_no_hash() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("Frieren - 03.mkv");
+        fs::write(&path, b"hello")?;
+        let input_file = InputFile::from(&path);
+
+        let params = DandanMatch::get_match_params(&input_file, false, false)?;
+        assert_eq!(params.json.file_hash, "5d41402abc4b2a76b9719d911017c592");
+        assert!(params.json.match_mode.is_none());
+
+        let params = DandanMatch::get_match_params(&input_file, true, false)?;
+        assert_eq!(params.json.file_name, "Frieren - 03");
+        assert_eq!(params.json.file_size, "5");
+        assert_eq!(
+            serde_json::to_value(&params.json)?,
+            json!({
+                "fileName": "Frieren - 03",
+                "fileHash": "",
+                "fileSize": "5",
+                "matchMode": "fileNameOnly",
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_match_cache() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("ep01.mkv");
+        fs::write(&path, b"hello")?;
+        let input_file = InputFile::from(&path);
+
+        // 只获取参数时不写入缓存
+        let params = DandanMatch::get_match_params(&input_file, false, false)?;
+        assert_eq!(params.json.file_hash, "5d41402abc4b2a76b9719d911017c592");
+        assert!(!MatchCache::path(&input_file).exists());
+
+        // 视频不变时使用缓存的 hash
+        let mut cache = MatchCache::load(&input_file)?;
+        cache.file_hash = Some("cached".to_string());
+        cache.save(&input_file)?;
+        let params = DandanMatch::get_match_params(&input_file, false, false)?;
+        assert_eq!(params.json.file_hash, "cached");
+        let params = DandanMatch::get_match_params(&input_file, false, true)?;
+        assert_eq!(params.json.file_hash, "5d41402abc4b2a76b9719d911017c592");
+
+        fs::write(&path, b"hello world")?;
+        assert!(MatchCache::load(&input_file)?.file_hash.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_episodes() -> Result<()> {
+        let bangumi = || -> Result<BangumiDetailsJson> {
+            Ok(serde_json::from_str(
+                r#"{
+                    "animeId": 17122,
+                    "animeTitle": "葬送的芙莉莲",
+                    "episodes": [
+                        { "episodeId": 171220001, "episodeTitle": "第1话", "episodeNumber": "1" },
+                        { "episodeId": 171220002, "episodeTitle": "第2话", "episodeNumber": "2" }
+                    ]
+                }"#,
+            )?)
+        };
+        let anime = || SearchAnimeBriefJson {
+            anime_id: 17122,
+            type_: "tvseries".to_string(),
+            type_description: "TV动画".to_string(),
+        };
+
+        let all = with_episodes(anime(), bangumi()?, None).unwrap();
+        assert_eq!(all.episodes.len(), 2);
+        assert_eq!(all.type_description, "TV动画");
+
+        let second = with_episodes(anime(), bangumi()?, Some("2")).unwrap();
+        assert_eq!(second.episodes.len(), 1);
+        assert_eq!(second.episodes[0].episode_id, 171220002);
+
+        assert!(with_episodes(anime(), bangumi()?, Some("3")).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_filter() {
+        assert!(search_filter("frieren", "Frieren (TV动画) 28 集"));
+        assert!(search_filter("ＦＲＩＥＲＥＮ", "Frieren (TV动画) 28 集"));
+        assert!(search_filter("葬送 02", "葬送的芙莉莲 第02话"));
+        assert!(search_filter("葬送　０２", "葬送的芙莉莲 第02话"));
+        assert!(search_filter("", "葬送的芙莉莲 第02话"));
+        assert!(!search_filter("葬送 03", "葬送的芙莉莲 第02话"));
+    }
+
+    #[test]
+    fn test_match_record() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("NCOP.mkv");
+        fs::write(&path, b"")?;
+        let input_file = InputFile::from(&path);
+        assert!(DandanMatch::load_match_record(&input_file)?.is_none());
+
+        let item = AnimeEpisodeItem {
+            anime_id: 17122,
+            anime_title: "葬送的芙莉莲".to_string(),
+            episode_id: 171220001,
+            episode_title: "第1话".to_string(),
+        };
+        DandanMatch::save_match_record(&input_file, &MatchRecord::Item(item))?;
+        let loaded = DandanMatch::load_match_record(&input_file)?.unwrap();
+        assert_eq!(loaded.episode_id, 171220001);
+
+        DandanMatch::save_match_record(&input_file, &MatchRecord::Skipped { skipped: true })?;
+        let err = DandanMatch::load_match_record(&input_file).err().unwrap();
+        assert!(is_skipped_error(&err));
+        Ok(())
+    }
+
+    #[test]
+    fn test_history() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("cache").join("history.json");
+        let (season1, season2) = (Path::new("/anime/s1"), Path::new("/anime/s2"));
+        assert!(load_history(&path, season1).is_empty());
+
+        save_history(&path, season1, "葬送的芙莉莲")?;
+        save_history(&path, season1, "Frieren")?;
+        save_history(&path, season1, " 葬送的芙莉莲 ")?;
+        save_history(&path, season1, "")?;
+        save_history(&path, season2, "Frieren 2")?;
+        assert_eq!(load_history(&path, season1), ["葬送的芙莉莲", "Frieren"]);
+        assert_eq!(load_history(&path, season2), ["Frieren 2"]);
+
+        for i in 0..MAX_HISTORY {
+            save_history(&path, season1, &i.to_string())?;
+        }
+        let history = load_history(&path, season1);
+        assert_eq!(history.len(), MAX_HISTORY);
+        assert_eq!(history[0], (MAX_HISTORY - 1).to_string());
+
+        for i in 0..MAX_HISTORY_DIRS {
+            save_history(&path, &dir.path().join(i.to_string()), "Frieren")?;
+        }
+        assert_eq!(load_history_entries(&path).len(), MAX_HISTORY_DIRS);
+        assert!(load_history(&path, season1).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_season_choices() {
+        let options = MatchOptions::default();
+        let cloned = options.clone();
+        let s1 = options
+            .seasons
+            .get(Path::new("/anime/s1/.ddp2ass.season.json"));
+        let s2 = options
+            .seasons
+            .get(Path::new("/anime/s2/.ddp2ass.season.json"));
+        // 不同文件夹的锁互不影响
+        let _s1 = s1.try_lock().unwrap();
+        assert!(s2.try_lock().is_ok());
+        // clone 的选项共享同一文件夹的选择，新的选项不共享
+        let shared = cloned
+            .seasons
+            .get(Path::new("/anime/s1/.ddp2ass.season.json"));
+        assert!(shared.try_lock().is_err());
+        let fresh = MatchOptions::default()
+            .seasons
+            .get(Path::new("/anime/s1/.ddp2ass.season.json"));
+        assert!(fresh.try_lock().is_ok());
+    }
+
+    #[test]
+    fn test_scripted_selector() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("Frieren - 03.mkv");
+        fs::write(&path, "")?;
+        let match_params = DandanMatch::get_match_params(&InputFile::from(&path), true, false)?;
+
+        let selector = NonInteractiveSelector::with_answers(["葬送的芙莉莲"]);
+        // 不写入用户缓存目录中的搜索记录
+        let options = MatchOptions {
+            no_history: true,
+            ..Default::default()
+        };
+        let name = DandanMatch::input_search_params(&match_params, "Frieren", &options, &selector)?;
+        assert_eq!(name, "葬送的芙莉莲");
+        assert!(
+            DandanMatch::input_search_params(&match_params, "Frieren", &options, &selector)
+                .is_err()
+        );
+
+        let select_options = [
+            SearchOption::SearchEditInput(SearchEditInput {}),
+            SearchOption::EpisodeIdInput,
+            SearchOption::Skip,
+        ];
+        let selector = NonInteractiveSelector::with_answers(["skip"]);
+        let selected = DandanMatch::select(&selector, &select_options, "剧集:".to_string())?;
+        assert!(matches!(selected, SearchOption::Skip));
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_exact_match() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("BD Vol.1 01.mkv");
+        fs::write(&path, "")?;
+        let input_file = InputFile::from(&path);
+        let item = |episode_id: i64, episode_title: &str| MatchItem {
+            episode_id,
+            anime_id: 17122,
+            anime_title: "葬送的芙莉莲".to_string(),
+            episode_title: episode_title.to_string(),
+            match_type: "tvseries".to_string(),
+            type_description: "TV动画".to_string(),
+            shift: 0.0,
+        };
+        let matches = [item(171220001, "第1话"), item(171220029, "第29话 总集篇")];
+        assert_eq!(
+            exact_match_option(&matches[1]),
+            "葬送的芙莉莲 第29话 总集篇 (TV动画) 偏移 0s"
+        );
+
+        let options = MatchOptions::default();
+        let selector = NonInteractiveSelector::default();
+        let only =
+            DandanMatch::select_exact_match(&input_file, &matches[1..], &options, &selector)?;
+        assert_eq!(only.episode_id, 171220029);
+        let first = DandanMatch::select_exact_match(&input_file, &matches, &options, &selector)?;
+        assert_eq!(first.episode_id, 171220001);
+        assert!(DandanMatch::load_match_record(&input_file)?.is_none());
+
+        let strict = MatchOptions {
+            strict_match: true,
+            ..Default::default()
+        };
+        assert!(
+            DandanMatch::select_exact_match(&input_file, &matches, &strict, &selector).is_err()
+        );
+
+        let selector = NonInteractiveSelector::with_answers(["总集篇"]);
+        let chosen = DandanMatch::select_exact_match(&input_file, &matches, &options, &selector)?;
+        assert_eq!(chosen.episode_id, 171220029);
+        let record = DandanMatch::load_match_record(&input_file)?.unwrap();
+        assert_eq!(record.episode_id, 171220029);
+        Ok(())
+    }
+
+    #[test]
+    fn test_title_similarity() {
+        assert_eq!(normalize_title("[Sub] Frieren - 03 【1080P】"), "frieren03");
+        assert_eq!(title_similarity("Frieren", "frieren"), 1.0);
+        assert_eq!(title_similarity("abc", "xyz"), 0.0);
+        assert_eq!(title_similarity("", "xyz"), 0.0);
+
+        let query = "葬送的芙莉莲 [Sub] 葬送的芙莉莲 - 03 [1080p]";
+        let right = title_similarity(query, "葬送的芙莉莲 第3话 勇者辛美尔");
+        let wrong = title_similarity(query, "芙莉莲的特别篇 第1话");
+        assert!(right > wrong, "{} {}", right, wrong);
+    }
+
+    #[test]
+    fn test_apply_episode_offset() {
+        assert_eq!(apply_episode_offset(1, 12), Some(13));
+        assert_eq!(apply_episode_offset(13, -12), Some(1));
+        assert_eq!(apply_episode_offset(12, -12), None);
+        assert_eq!(apply_episode_offset(2, 0), Some(2));
     }
 }
@@ -23,21 +23,46 @@ pub struct Danmu {
     /// 否在在调节分辨率的时候字体会发生变化。
     pub fontsize: u32,
     pub rgb: (u8, u8, u8),
+    /// 弹幕来源，如 `BiliBili`、`Gamer`
+    pub source: String,
+    /// 发送者 id，第三方来源带有来源前缀，如 `[BiliBili]abc123`
+    pub user_id: String,
+    /// 弹幕 id，时间相同时按 id 排序，保证每次生成的 ASS 相同。没有 id 的来源为 0
+    pub cid: u64,
 }
 
 impl Danmu {
-    /// 计算弹幕的“像素长度”，会乘上一个缩放因子
+    /// 计算弹幕的“像素长度”，会乘上一个缩放因子，多行时为最长的一行
     ///
     /// 汉字算一个全宽，英文算2/3宽
     pub fn length(&self, config: &CanvasConfig) -> f64 {
         let pts = config.font_size
             * self
                 .content
-                .chars()
-                .map(|ch| if ch.is_ascii() { 2 } else { 3 })
-                .sum::<u32>()
+                .split('\n')
+                .map(|line| {
+                    line.chars()
+                        .map(|ch| if ch.is_ascii() { 2 } else { 3 })
+                        .sum::<u32>()
+                })
+                .max()
+                .unwrap_or(0)
             / 3;
 
         pts as f64 * config.width_ratio
     }
+
+    /// 去掉 `\r` 和首尾的换行，顶部、底部弹幕保留换行，滚动弹幕的换行替换为空格，
+    /// 需要在计算长度之前调用
+    pub fn normalize_line_breaks(&mut self) {
+        if !self.content.contains(['\r', '\n']) {
+            return;
+        }
+        let content = self.content.replace('\r', "");
+        let content = content.trim_matches('\n');
+        self.content = match self.r#type {
+            DanmuType::Top | DanmuType::Bottom => content.to_string(),
+            DanmuType::Float | DanmuType::Reverse => content.replace('\n', " "),
+        };
+    }
 }
@@ -0,0 +1,100 @@
+//! 黑名单，每行一个关键词，`user:` 开头的行表示屏蔽该用户，如 `user:[BiliBili]abc123`，
+//! `#` 开头的行为注释
+use crate::Danmu;
+use anyhow::Result;
+use std::{collections::BTreeSet, path::Path};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DenyReason {
+    /// 内容包含关键词
+    Keyword,
+    /// 用户被屏蔽
+    User,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Denylist {
+    pub keywords: BTreeSet<String>,
+    /// 与弹幕的用户 id 完全匹配
+    pub users: BTreeSet<String>,
+}
+
+impl Denylist {
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(Self::parse(&std::fs::read_to_string(path)?))
+    }
+
+    /// 兼容 Windows 记事本保存的文件，去掉开头的 BOM 和行尾的 `\r`，跳过空行和注释
+    pub fn parse(s: &str) -> Self {
+        let mut denylist = Denylist::default();
+        let mut duplicated = vec![];
+        let lines = s
+            .trim_start_matches('\u{feff}')
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'));
+        for line in lines {
+            let inserted = match line.strip_prefix("user:") {
+                Some(user) => denylist.users.insert(user.trim().to_string()),
+                None => denylist.keywords.insert(line.to_string()),
+            };
+            if !inserted {
+                duplicated.push(line);
+            }
+        }
+        if !duplicated.is_empty() {
+            warn!("黑名单中有重复的行：{}", duplicated.join("，"));
+        }
+        denylist
+    }
+
+    pub fn check(&self, danmu: &Danmu) -> Option<DenyReason> {
+        if self.users.contains(&danmu.user_id) {
+            return Some(DenyReason::User);
+        }
+        if self.keywords.iter().any(|s| danmu.content.contains(s)) {
+            return Some(DenyReason::Keyword);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_denylist() {
+        let denylist = Denylist::parse("剧透\nuser:[BiliBili]abc123\n\n  前方高能  \n");
+        assert_eq!(denylist.keywords.len(), 2);
+        assert_eq!(denylist.users.len(), 1);
+
+        let danmu = |content: &str, user_id: &str| Danmu {
+            content: content.to_string(),
+            user_id: user_id.to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            denylist.check(&danmu("hello", "[BiliBili]abc123")),
+            Some(DenyReason::User)
+        );
+        assert_eq!(denylist.check(&danmu("hello", "[Gamer]abc123")), None);
+        assert_eq!(
+            denylist.check(&danmu("有剧透", "[Gamer]abc123")),
+            Some(DenyReason::Keyword)
+        );
+    }
+
+    #[test]
+    fn test_denylist_windows() {
+        let denylist = Denylist::parse(include_str!("../tests/fixtures/denylist/windows.txt"));
+        assert_eq!(
+            denylist.keywords,
+            BTreeSet::from(["剧透".to_string(), "前方高能".to_string()])
+        );
+        assert_eq!(
+            denylist.users,
+            BTreeSet::from(["[BiliBili]abc123".to_string()])
+        );
+    }
+}
@@ -20,6 +20,22 @@ impl Drawable {
             effect,
         }
     }
+
+    /// 开始时间为负数时从 0 秒开始，缩短绘制时间，滚动弹幕的起点移动到 0 秒时的位置
+    pub fn clamp_start(mut self) -> Self {
+        let elapsed = -self.danmu.timeline_s;
+        if elapsed <= 0.0 {
+            return self;
+        }
+        if let DrawEffect::Move { start, end } = &mut self.effect {
+            let progress = (elapsed / self.duration).min(1.0);
+            start.0 += ((end.0 - start.0) as f64 * progress).round() as i32;
+            start.1 += ((end.1 - start.1) as f64 * progress).round() as i32;
+        }
+        self.danmu.timeline_s = 0.0;
+        self.duration = (self.duration - elapsed).max(0.0);
+        self
+    }
 }
 
 pub enum DrawEffect {
@@ -0,0 +1,107 @@
+//! 将生成的 ASS 作为字幕轨封装回 mkv
+use crate::{
+    util::{command_output, display_filename, ffmpeg_command, ffprobe_command, sibling_path},
+    InputFile,
+};
+use anyhow::{anyhow, Context, Result};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone)]
+pub struct EmbedConfig {
+    /// 封装成功后替换原视频，否则输出到 `file.danmu.mkv`
+    pub in_place: bool,
+    /// 字幕轨语言标签
+    pub language: String,
+    /// 字幕轨标题
+    pub title: String,
+}
+
+impl EmbedConfig {
+    pub fn check(input_file: &InputFile) -> Result<()> {
+        let is_mkv = input_file
+            .path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("mkv"));
+        if !is_mkv {
+            return Err(anyhow!(
+                "--embed 只支持 mkv 文件，{} 不是 mkv",
+                input_file.display_filename()
+            ));
+        }
+        Ok(())
+    }
+
+    /// 返回封装后的视频路径
+    pub fn embed(&self, input_file: &InputFile, ass_path: &Path) -> Result<PathBuf> {
+        Self::check(input_file)?;
+
+        let sub_count = Self::subtitle_stream_count(&input_file.path)?;
+        if self.in_place {
+            let dir = input_file.path.parent().context("视频目录无法解析")?;
+            let tmp = tempfile::Builder::new()
+                .prefix(".ddp2ass-embed-")
+                .suffix(".mkv")
+                .tempfile_in(dir)?;
+            self.remux(&input_file.path, ass_path, tmp.path(), sub_count)?;
+            tmp.persist(&input_file.path)?;
+            Ok(input_file.path.clone())
+        } else {
+            let output_path = sibling_path(&input_file.path, "danmu.mkv");
+            if let Err(e) = self.remux(&input_file.path, ass_path, &output_path, sub_count) {
+                let _ = std::fs::remove_file(&output_path);
+                return Err(e);
+            }
+            Ok(output_path)
+        }
+    }
+
+    fn subtitle_stream_count(video_path: &Path) -> Result<usize> {
+        let mut command = ffprobe_command();
+        command
+            .args(["-v", "error", "-select_streams", "s"])
+            .args(["-show_entries", "stream=index", "-of", "csv=p=0"])
+            .arg(video_path);
+        let output = command_output(&mut command)?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "ffprobe 读取字幕轨失败：{}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .count())
+    }
+
+    fn remux(
+        &self,
+        video_path: &Path,
+        ass_path: &Path,
+        output_path: &Path,
+        sub_count: usize,
+    ) -> Result<()> {
+        let mut command = ffmpeg_command();
+        command
+            .args(["-v", "error", "-y", "-i"])
+            .arg(video_path)
+            .arg("-i")
+            .arg(ass_path)
+            .args(["-map", "0", "-map", "1", "-c", "copy"])
+            .arg(format!("-metadata:s:s:{}", sub_count))
+            .arg(format!("language={}", self.language))
+            .arg(format!("-metadata:s:s:{}", sub_count))
+            .arg(format!("title={}", self.title))
+            .args(["-f", "matroska"])
+            .arg(output_path);
+        let output = command_output(&mut command)?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "ffmpeg 封装 {} 失败：{}",
+                display_filename(&output_path.to_path_buf()),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(())
+    }
+}
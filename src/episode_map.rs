@@ -0,0 +1,117 @@
+//! 文件夹中的 `ddp2ass.map.toml`，为无法匹配的文件指定弹幕，每行一条 `"文件名通配符" = episodeId`
+use anyhow::{anyhow, Context, Result};
+use std::path::{Path, PathBuf};
+
+pub const EPISODE_MAP_FILE: &str = "ddp2ass.map.toml";
+
+#[derive(Debug)]
+pub struct EpisodeMap {
+    pub path: PathBuf,
+    /// 按文件中的顺序，前面的优先
+    entries: Vec<(String, i64)>,
+}
+
+impl EpisodeMap {
+    /// 读取视频所在目录的映射文件，不存在时返回 None
+    pub fn load(video_path: &Path) -> Result<Option<Self>> {
+        let Some(dir) = video_path.parent() else {
+            return Ok(None);
+        };
+        let path = dir.join(EPISODE_MAP_FILE);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let entries = parse(&std::fs::read_to_string(&path)?)
+            .with_context(|| format!("读取 {} 失败", path.display()))?;
+        Ok(Some(Self { path, entries }))
+    }
+
+    /// 文件名对应的 episodeId
+    pub fn find(&self, filename: &str) -> Option<i64> {
+        self.entries
+            .iter()
+            .find(|(pattern, _)| glob_match(pattern, filename))
+            .map(|(_, episode_id)| *episode_id)
+    }
+}
+
+fn parse(s: &str) -> Result<Vec<(String, i64)>> {
+    // toml 开启了 preserve_order，表中的顺序与文件一致
+    let table: toml::Table = s.parse()?;
+    table
+        .into_iter()
+        .map(|(pattern, value)| match value.as_integer() {
+            Some(episode_id) if episode_id > 0 => Ok((pattern, episode_id)),
+            _ => Err(anyhow!("{} 对应的 episodeId {} 无效", pattern, value)),
+        })
+        .collect()
+}
+
+/// 支持 `*` 和 `?` 的通配符匹配
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // 上一个 `*` 的位置和当时匹配到的文件名位置，失配时回退到这里让 `*` 多匹配一个字符
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((sp, sn)) => {
+                    star = Some((sp, sn + 1));
+                    p = sp + 1;
+                    n = sn + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*OVA*.mkv", "[Sub] Frieren OVA 01.mkv"));
+        assert!(glob_match("ep0?.mkv", "ep01.mkv"));
+        assert!(glob_match("*", "anything"));
+        assert!(!glob_match("ep0?.mkv", "ep10.mkv"));
+        assert!(!glob_match("*.mp4", "ep01.mkv"));
+    }
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let map = EpisodeMap {
+            path: PathBuf::from(EPISODE_MAP_FILE),
+            entries: parse(
+                r#"
+# 特别篇
+"*OVA 01*" = 171220001
+"*OVA*" = 171220002 # 其余 OVA
+"#,
+            )?,
+        };
+        assert_eq!(map.find("Frieren OVA 01.mkv"), Some(171220001));
+        assert_eq!(map.find("Frieren OVA 02.mkv"), Some(171220002));
+        assert_eq!(map.find("Frieren 01.mkv"), None);
+
+        // 不带引号时 `.` 会被当作 TOML 的点分键
+        assert!(parse("ep01.mkv = 1").is_err());
+        assert!(parse(r#""ep01.mkv" = abc"#).is_err());
+        assert!(parse(r#""ep01.mkv" = 0"#).is_err());
+        assert!(parse(r#""ep01.mkv" = "1""#).is_err());
+        assert!(parse("[ep01]\nid = 1").is_err());
+        Ok(())
+    }
+}
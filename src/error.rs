@@ -0,0 +1,126 @@
+//! 库对外返回的错误，调用方可以按类型决定重试、提示还是跳过，CLI 中仍然使用 anyhow 显示
+use std::path::PathBuf;
+
+use crate::util::display_filename;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// 视频、弹幕等输入文件不存在，`what` 为文件的说明，如 `视频文件`
+    #[error("{what} {} 不存在", display_filename(path))]
+    FileNotFound { what: &'static str, path: PathBuf },
+
+    /// dandanplay 返回的错误信息
+    #[error("dandanplay 返回错误 {code}: {message}")]
+    Api { code: i64, message: String },
+
+    /// dandanplay 返回了失败的 HTTP 状态，`body` 只保留开头部分
+    #[cfg(feature = "network")]
+    #[error("dandanplay 请求失败 {status}: {body}")]
+    Http {
+        status: reqwest::StatusCode,
+        body: String,
+    },
+
+    /// 不进行交互时没有找到可以使用的匹配结果
+    #[error("{0}")]
+    NoMatch(String),
+
+    /// 找不到 ffmpeg 或 ffprobe
+    #[error(
+        "找不到 {0}，合并内置字幕（--merge-built-in、-m）和 --embed 需要 ffmpeg。\
+        请安装 ffmpeg 并加入 PATH，或使用 --ffmpeg-path、--ffprobe-path \
+        （环境变量 FFMPEG、FFPROBE）指定 ffmpeg.exe、ffprobe.exe 的路径"
+    )]
+    FfmpegMissing(String),
+
+    #[error("ASS 文件 {} 写入失败", display_filename(path))]
+    AssWrite {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// 手动匹配时选择了跳过这个文件，不是错误，不输出 ASS
+    #[error("已手动跳过")]
+    Skipped,
+
+    /// 通过 `CancelFlag` 取消了处理
+    #[error("已取消")]
+    Cancelled,
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl From<anyhow::Error> for Error {
+    /// 内部用 anyhow 传递错误，最外层就是已经分类的错误时取出它，
+    /// 外层有上下文时作为 `Other` 保留完整的错误链。跳过和取消不需要上下文，总是取出
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast_ref::<Error>() {
+            Some(Error::Skipped) => return Error::Skipped,
+            Some(Error::Cancelled) => return Error::Cancelled,
+            _ => {}
+        }
+        if !(*e).is::<Error>() {
+            return Error::Other(e);
+        }
+        match e.downcast::<Error>() {
+            Ok(e) => e,
+            Err(e) => Error::Other(e),
+        }
+    }
+}
+
+/// anyhow 错误链中有 `Error::Skipped`，即手动选择了跳过
+#[cfg(feature = "network")]
+pub(crate) fn is_skipped_error(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<Error>(), Some(Error::Skipped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_from_anyhow() {
+        let e: anyhow::Error = Error::Api {
+            code: 101,
+            message: "节目不存在".to_string(),
+        }
+        .into();
+        let e = Error::from(e);
+        assert!(matches!(e, Error::Api { code: 101, .. }));
+        assert_eq!(e.to_string(), "dandanplay 返回错误 101: 节目不存在");
+
+        // 外层的上下文保留在错误链中
+        let e: anyhow::Error = Error::Api {
+            code: 101,
+            message: "节目不存在".to_string(),
+        }
+        .into();
+        let e = Error::from(e.context("获取弹幕失败"));
+        assert!(matches!(e, Error::Other(_)));
+        assert_eq!(
+            format!("{:#}", e),
+            "获取弹幕失败: dandanplay 返回错误 101: 节目不存在"
+        );
+        let e = Error::from(anyhow::Error::from(e).context("处理失败"));
+        assert_eq!(
+            format!("{:#}", e),
+            "处理失败: 获取弹幕失败: dandanplay 返回错误 101: 节目不存在"
+        );
+
+        let e = Error::from(anyhow::Error::from(Error::Skipped).context("匹配失败"));
+        assert!(matches!(e, Error::Skipped));
+        let e = Error::from(anyhow::Error::from(Error::Cancelled).context("获取弹幕"));
+        assert!(matches!(e, Error::Cancelled));
+
+        let e = Error::from(anyhow!("弹幕缓存无法解析").context("读取失败"));
+        assert!(matches!(e, Error::Other(_)));
+        assert_eq!(format!("{:#}", e), "读取失败: 弹幕缓存无法解析");
+    }
+}
@@ -0,0 +1,216 @@
+//! 导出经过过滤和排版后的弹幕列表，方便做数据分析
+use crate::{util::sibling_path, Danmu, DanmuType, InputFile};
+use anyhow::Result;
+use serde::Serialize;
+use std::{borrow::Cow, fs, path::PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ExportStatus {
+    /// 已绘制到 ASS 中
+    #[serde(rename = "drawn")]
+    Drawn,
+    /// 命中黑名单
+    #[serde(rename = "denylisted")]
+    Denylisted,
+    /// 发送者被屏蔽
+    #[serde(rename = "blocked-user")]
+    BlockedUser,
+    /// 没有可用的弹幕槽位
+    #[serde(rename = "dropped-no-lane")]
+    DroppedNoLane,
+    /// 时间轴偏移后时间小于 0
+    #[serde(rename = "dropped-negative-time")]
+    DroppedNegativeTime,
+    /// 时间轴偏移后超过视频时长
+    #[serde(rename = "dropped-beyond-duration")]
+    DroppedBeyondDuration,
+}
+
+impl ExportStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExportStatus::Drawn => "drawn",
+            ExportStatus::Denylisted => "denylisted",
+            ExportStatus::BlockedUser => "blocked-user",
+            ExportStatus::DroppedNoLane => "dropped-no-lane",
+            ExportStatus::DroppedNegativeTime => "dropped-negative-time",
+            ExportStatus::DroppedBeyondDuration => "dropped-beyond-duration",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportRecord {
+    pub timeline_s: f64,
+    pub r#type: &'static str,
+    /// #RRGGBB
+    pub color: String,
+    /// 发送者 id
+    pub user: String,
+    pub content: String,
+    /// 直接导出弹幕缓存时为 None
+    pub status: Option<ExportStatus>,
+}
+
+impl ExportRecord {
+    pub fn new(danmu: &Danmu, status: ExportStatus) -> Self {
+        ExportRecord {
+            status: Some(status),
+            ..ExportRecord::from_danmu(danmu)
+        }
+    }
+
+    /// 不经过过滤和排版的弹幕，没有处理结果
+    pub fn from_danmu(danmu: &Danmu) -> Self {
+        let (r, g, b) = danmu.rgb;
+        ExportRecord {
+            timeline_s: danmu.timeline_s,
+            r#type: match danmu.r#type {
+                DanmuType::Float => "float",
+                DanmuType::Top => "top",
+                DanmuType::Bottom => "bottom",
+                DanmuType::Reverse => "reverse",
+            },
+            color: format!("#{r:02X}{g:02X}{b:02X}"),
+            user: danmu.user_id.clone(),
+            content: danmu.content.clone(),
+            status: None,
+        }
+    }
+}
+
+/// 导出的目标路径，路径为目录时按视频文件名生成
+#[derive(Debug, Clone, Default)]
+pub struct DanmuExport {
+    pub csv: Option<PathBuf>,
+    pub json: Option<PathBuf>,
+    /// 一次处理多个视频，路径为文件时也在文件名中加上视频文件名，避免后处理的视频覆盖之前的结果
+    pub per_file: bool,
+}
+
+impl DanmuExport {
+    pub fn is_enabled(&self) -> bool {
+        self.csv.is_some() || self.json.is_some()
+    }
+
+    /// 解析出某个视频文件对应的导出路径，`per_file` 时 `all.csv` 对应 `all.ep01.csv`
+    pub fn for_file(&self, input_file: &InputFile) -> DanmuExport {
+        let resolve = |path: &Option<PathBuf>, ext: &str| {
+            path.as_ref().map(|p| {
+                let name_for = |ext: &str| {
+                    sibling_path(&input_file.path, ext)
+                        .file_name()
+                        .unwrap_or_default()
+                        .to_os_string()
+                };
+                if p.is_dir() {
+                    p.join(name_for(ext))
+                } else if self.per_file {
+                    let mut name = p.file_stem().unwrap_or_default().to_os_string();
+                    name.push(".");
+                    name.push(name_for(
+                        &p.extension().unwrap_or_default().to_string_lossy(),
+                    ));
+                    p.with_file_name(name)
+                } else {
+                    p.clone()
+                }
+            })
+        };
+        DanmuExport {
+            csv: resolve(&self.csv, "danmu.csv"),
+            json: resolve(&self.json, "danmu.json"),
+            per_file: false,
+        }
+    }
+
+    pub fn write(&self, records: &[ExportRecord]) -> Result<()> {
+        if let Some(path) = self.csv.as_ref() {
+            fs::write(path, to_csv(records))?;
+        }
+        if let Some(path) = self.json.as_ref() {
+            fs::write(path, serde_json::to_string_pretty(records)?)?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn to_csv(records: &[ExportRecord]) -> String {
+    let mut s = String::from("timestamp,type,color,user,content,status\n");
+    for r in records {
+        s.push_str(&format!(
+            "{:.2},{},{},{},{},{}\n",
+            r.timeline_s,
+            r.r#type,
+            r.color,
+            escape_csv(&r.user),
+            escape_csv(&r.content),
+            r.status.map_or("", |s| s.as_str())
+        ));
+    }
+    s
+}
+
+fn escape_csv(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::from(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::from(field)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_csv() {
+        assert_eq!(escape_csv("簽"), "簽");
+        assert_eq!(escape_csv("a,b"), "\"a,b\"");
+        assert_eq!(escape_csv("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(escape_csv("a\nb"), "\"a\nb\"");
+    }
+
+    #[test]
+    fn test_for_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let input_file = InputFile::from(&dir.path().join("ep01.mkv"));
+        let mut export = DanmuExport {
+            csv: Some(dir.path().join("all.csv")),
+            json: Some(dir.path().to_path_buf()),
+            per_file: false,
+        };
+        let resolved = export.for_file(&input_file);
+        assert_eq!(resolved.csv, Some(dir.path().join("all.csv")));
+        assert_eq!(resolved.json, Some(dir.path().join("ep01.danmu.json")));
+
+        export.per_file = true;
+        let resolved = export.for_file(&input_file);
+        assert_eq!(resolved.csv, Some(dir.path().join("all.ep01.csv")));
+        assert_eq!(resolved.json, Some(dir.path().join("ep01.danmu.json")));
+    }
+
+    #[test]
+    fn test_to_csv() {
+        let danmu = Danmu {
+            timeline_s: 1.5,
+            content: "1,2".to_string(),
+            rgb: (255, 0, 16),
+            user_id: "[Gamer]a".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            to_csv(&[
+                ExportRecord::new(&danmu, ExportStatus::Denylisted),
+                ExportRecord::from_danmu(&danmu)
+            ]),
+            [
+                "timestamp,type,color,user,content,status",
+                "1.50,float,#FF0010,[Gamer]a,\"1,2\",denylisted",
+                "1.50,float,#FF0010,[Gamer]a,\"1,2\",",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+}
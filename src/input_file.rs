@@ -1,17 +1,24 @@
 use std::path::{absolute, PathBuf};
 
-use crate::util::display_filename;
+use crate::util::{self, display_filename};
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct InputFile {
     pub path: PathBuf,
 }
 
 impl InputFile {
+    /// 相对路径会基于当前目录转换为绝对路径
     pub fn from(filepath: &PathBuf) -> Self {
         let filepath = absolute(filepath).unwrap_or_else(|_| filepath.clone());
         Self { path: filepath }
     }
 
+    /// 不带样式的文件名，用于表格、标题等需要原样输出的地方
+    pub fn file_name(&self) -> String {
+        util::file_name(&self.path)
+    }
+
     pub fn display_filename(self: &Self) -> String {
         display_filename(&self.path)
     }
@@ -20,3 +27,22 @@ impl InputFile {
         format!("{} {}", self.display_filename(), s)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_relative() {
+        let input = InputFile::from(&PathBuf::from("video/ep01.mkv"));
+        assert!(input.path.is_absolute());
+        assert_eq!(
+            input.path,
+            std::env::current_dir().unwrap().join("video/ep01.mkv")
+        );
+        assert_eq!(input.file_name(), "ep01.mkv");
+
+        let absolute_path = std::env::temp_dir().join("ep01.mkv");
+        assert_eq!(InputFile::from(&absolute_path).path, absolute_path);
+    }
+}
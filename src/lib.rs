@@ -1,20 +1,72 @@
 #[macro_use]
 extern crate log;
 
+#[cfg(feature = "network")]
+mod api;
 mod ass_creator;
+mod bilibili_xml;
+mod cancel;
 mod canvas;
+#[cfg(feature = "network")]
+mod chapter;
+mod chinese;
+#[cfg(feature = "network")]
 mod cli;
 mod dandan;
+#[cfg(feature = "network")]
 mod dandan_match;
 mod danmu;
+mod denylist;
 mod drawable;
+mod embed;
+#[cfg(feature = "network")]
+mod episode_map;
+mod error;
+mod export;
 mod input_file;
+mod progress;
+#[cfg(feature = "network")]
+mod provider;
+#[cfg(feature = "network")]
+mod selector;
+mod source;
+mod stats;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
 mod util;
 
-pub use ass_creator::AssCreator;
-pub use canvas::{Canvas, Config as CanvasConfig};
+pub use ass_creator::{AssCreator, AssCreatorBuilder, AssEvent, ParsedAss, StyleSpec};
+pub use cancel::CancelFlag;
+pub use canvas::{Canvas, Config as CanvasConfig, NegativeOffset};
+#[cfg(feature = "network")]
+pub use chapter::{Chapter, OffsetSuggestion};
+pub use chinese::SimplifiedOrTraditional;
+#[cfg(feature = "network")]
 pub use cli::{Args, Cli, Commands};
-pub use dandan::Dandan;
+#[cfg(feature = "network")]
+pub use dandan::{CacheOptions, FetchOptions, MergeBuiltIn};
+pub use dandan::{
+    CommentItem, CommentsJson, ConversionReport, ConvertOptions, Dandan, KeepFilteredAsComments,
+    LocalDanmu, OnExisting, ProcessOptions, SplitOutput, UpdateCheck,
+};
+#[cfg(feature = "network")]
+pub use dandan_match::SearchType;
 pub use danmu::{Danmu, DanmuType};
+pub use denylist::{DenyReason, Denylist};
 pub use drawable::{DrawEffect, Drawable};
+pub use embed::EmbedConfig;
+pub use error::Error;
+pub use export::{DanmuExport, ExportRecord, ExportStatus};
 pub use input_file::InputFile;
+pub use progress::{FileOutcome, FileReport, LogProgress, NoProgress, Progress, Stage};
+#[cfg(feature = "network")]
+pub use provider::{DandanplayProvider, DanmuProvider, ProviderFuture};
+#[cfg(feature = "interactive")]
+pub use selector::TerminalSelector;
+#[cfg(feature = "network")]
+pub use selector::{NonInteractiveSelector, Selector};
+pub use source::{SourceAlignment, SourceOptions};
+pub use stats::{ContentCount, DanmuStats, TypeCounts};
+pub use util::set_ffmpeg_paths;
+#[cfg(feature = "interactive")]
+pub use util::PromptAwareLogger;
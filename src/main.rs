@@ -1,6 +1,6 @@
 use anyhow::Result;
 use clap::Parser;
-use ddp2ass::{Args, Cli, Commands};
+use ddp2ass::{Args, CancelFlag, Cli, Commands};
 use simplelog::*;
 
 #[tokio::main]
@@ -9,18 +9,58 @@ async fn main() -> Result<()> {
         .set_time_offset_to_local()
         .unwrap()
         .build();
-    CombinedLogger::init(vec![TermLogger::new(
+    let logger = CombinedLogger::new(vec![TermLogger::new(
         LevelFilter::Info,
         config,
         TerminalMode::Mixed,
         ColorChoice::Auto,
-    )])?;
+    )]);
+    // 交互式提示期间暂存其他文件的日志
+    #[cfg(feature = "interactive")]
+    let logger = Box::new(ddp2ass::PromptAwareLogger::new(logger));
+    log::set_max_level(LevelFilter::Info);
+    log::set_boxed_logger(logger)?;
 
     let cli = Cli::parse();
     return match cli.command {
         Some(Commands::Download(args)) => download(args).await,
+        Some(Commands::Fetch(mut args)) => {
+            args.args.check()?;
+            args.args.set_ffmpeg_paths();
+            args.process(&cancel_on_ctrl_c()).await
+        }
+        Some(Commands::Regen(mut args)) => {
+            args.args.check()?;
+            args.args.set_ffmpeg_paths();
+            args.process()
+        }
         Some(Commands::MatchParams(args)) => args.process(),
         Some(Commands::MatchResult(args)) => args.process().await,
+        Some(Commands::Convert(mut args)) => {
+            args.args.check()?;
+            args.args.set_ffmpeg_paths();
+            args.process()
+        }
+        Some(Commands::Merge(mut args)) => {
+            args.args.check()?;
+            args.args.set_ffmpeg_paths();
+            args.process()
+        }
+        Some(Commands::Play(mut args)) => {
+            args.args.check()?;
+            args.args.set_ffmpeg_paths();
+            args.process(&cancel_on_ctrl_c()).await
+        }
+        Some(Commands::Search(args)) => args.process().await,
+        Some(Commands::Stats(args)) => args.process().await,
+        Some(Commands::Diff(args)) => args.process().await,
+        Some(Commands::Export(args)) => args.process(),
+        Some(Commands::Info(args)) => args.process(),
+        Some(Commands::Rename(args)) => args.process().await,
+        Some(Commands::Clean(args)) => args.process(),
+        Some(Commands::Denylist(args)) => args.process(),
+        Some(Commands::Verify(args)) => args.process(),
+        Some(Commands::Doctor(args)) => args.process().await,
         None => {
             let args = Args::parse();
             download(args).await
@@ -30,10 +70,11 @@ async fn main() -> Result<()> {
 
 async fn download(mut args: Args) -> Result<()> {
     args.check()?;
+    args.set_ffmpeg_paths();
 
     let pause = args.pause;
 
-    let ret = args.process().await;
+    let ret = args.process(&cancel_on_ctrl_c()).await;
     if pause {
         if let Err(e) = ret.as_ref() {
             println!();
@@ -46,3 +87,19 @@ async fn download(mut args: Args) -> Result<()> {
 
     ret
 }
+
+/// 第一次 Ctrl-C 处理完当前阶段后停止并输出统计，第二次直接退出
+fn cancel_on_ctrl_c() -> CancelFlag {
+    let cancel = CancelFlag::default();
+    let ctrl_c = cancel.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            log::warn!("正在取消，再次按 Ctrl-C 强制退出");
+            ctrl_c.cancel();
+            if tokio::signal::ctrl_c().await.is_ok() {
+                std::process::exit(130);
+            }
+        }
+    });
+    cancel
+}
@@ -0,0 +1,103 @@
+//! 处理进度的回调，图形界面等调用方可以显示每个文件的进度，CLI 使用 `LogProgress`
+use std::fmt;
+
+use crate::InputFile;
+#[cfg(feature = "network")]
+use crate::{error::is_skipped_error, ConversionReport};
+
+/// 处理单个文件的阶段
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// 匹配弹幕，可能需要交互选择
+    Matching,
+    /// 从 dandanplay 获取弹幕
+    Fetching,
+    /// 过滤、排布弹幕并写入 ASS
+    Converting,
+    /// 将 ASS 封装到视频
+    Embedding,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Stage::Matching => "匹配弹幕",
+            Stage::Fetching => "获取弹幕",
+            Stage::Converting => "生成 ASS",
+            Stage::Embedding => "封装字幕",
+        })
+    }
+}
+
+/// 单个文件的处理结果
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileOutcome {
+    /// 写入的弹幕数量，弹幕和参数都没有变化而跳过生成时为 0
+    Converted { danmu_count: u64 },
+    /// 手动选择了跳过
+    Skipped,
+    /// 错误信息
+    Failed(String),
+}
+
+#[cfg(feature = "network")]
+impl FileOutcome {
+    pub(crate) fn from_result(result: &anyhow::Result<ConversionReport>) -> Self {
+        match result {
+            Ok(report) => FileOutcome::Converted {
+                danmu_count: report.danmu_count,
+            },
+            Err(e) if is_skipped_error(e) => FileOutcome::Skipped,
+            Err(e) => FileOutcome::Failed(format!("{:#}", e)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileReport {
+    pub file: InputFile,
+    pub outcome: FileOutcome,
+}
+
+/// 所有方法都有空的默认实现，只需要实现关心的事件，
+/// 提前获取弹幕时会在多个任务中同时调用
+pub trait Progress: Send + Sync {
+    fn on_stage(&self, _file: &InputFile, _stage: Stage) {}
+
+    /// 获取到弹幕，包括使用缓存的情况
+    fn on_comments_fetched(&self, _file: &InputFile, _count: usize) {}
+
+    /// ASS 已写入，`count` 为写入的弹幕数量
+    fn on_danmu_written(&self, _file: &InputFile, _count: u64) {}
+
+    fn on_file_done(&self, _report: &FileReport) {}
+}
+
+/// 不处理任何事件
+pub struct NoProgress;
+
+impl Progress for NoProgress {}
+
+/// 以 debug 级别输出到日志，CLI 中的其他日志已经包含主要信息
+pub struct LogProgress;
+
+impl Progress for LogProgress {
+    fn on_stage(&self, file: &InputFile, stage: Stage) {
+        debug!("{}", file.log(&stage.to_string()));
+    }
+
+    fn on_comments_fetched(&self, file: &InputFile, count: usize) {
+        debug!("{}", file.log(&format!("获取到 {} 条弹幕", count)));
+    }
+
+    fn on_danmu_written(&self, file: &InputFile, count: u64) {
+        debug!("{}", file.log(&format!("写入 {} 条弹幕", count)));
+    }
+
+    fn on_file_done(&self, report: &FileReport) {
+        debug!(
+            "{}",
+            report.file.log(&format!("处理完成 {:?}", report.outcome))
+        );
+    }
+}
@@ -0,0 +1,79 @@
+//! 弹幕来源，默认从 dandanplay 匹配并获取，调用方可以实现 `DanmuProvider` 使用其他来源，
+//! 如本地的 xml 或内部的弹幕缓存
+use anyhow::Result;
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use crate::{
+    dandan::{CacheOptions, CommentsJson},
+    progress::Progress,
+    selector::Selector,
+    Dandan, Danmu, InputFile, UpdateCheck,
+};
+
+pub type ProviderFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+/// 处理多个文件时会在多个任务中同时调用
+pub trait DanmuProvider: Send + Sync {
+    /// 来源名称，用于日志
+    fn name(&self) -> &str;
+
+    /// 获取视频对应的弹幕
+    fn fetch<'a>(&'a self, input: &'a InputFile) -> ProviderFuture<'a, Vec<Danmu>>;
+
+    /// 获取 dandanplay 格式的弹幕，返回时可以使用其中的动画标题和 ASS 指纹，
+    /// 弹幕和参数都没有变化时跳过生成。默认返回 None，只使用 `fetch` 的结果
+    fn fetch_comments_json<'a>(
+        &'a self,
+        _input: &'a InputFile,
+    ) -> ProviderFuture<'a, Option<CommentsJson>> {
+        Box::pin(async { Ok(None) })
+    }
+}
+
+/// 匹配视频并从 dandanplay 获取弹幕，使用视频旁边的 `.dandanplay.json` 缓存
+pub struct DandanplayProvider {
+    pub options: CacheOptions,
+    pub selector: Arc<dyn Selector>,
+    pub progress: Arc<dyn Progress>,
+}
+
+impl DandanplayProvider {
+    /// 同时返回 `check_updates` 开启时检查弹幕缓存是否过时的结果
+    pub async fn fetch_with_update_check(
+        &self,
+        input: &InputFile,
+    ) -> Result<(CommentsJson, Option<UpdateCheck>)> {
+        Dandan::fetch_comments_json(
+            input,
+            &self.options,
+            self.selector.as_ref(),
+            self.progress.as_ref(),
+        )
+        .await
+    }
+}
+
+impl DanmuProvider for DandanplayProvider {
+    fn name(&self) -> &str {
+        "dandanplay"
+    }
+
+    fn fetch<'a>(&'a self, input: &'a InputFile) -> ProviderFuture<'a, Vec<Danmu>> {
+        Box::pin(async move {
+            let comments_json = self.fetch_comments_json(input).await?;
+            Ok(comments_json
+                .map(|c| c.into_danmus(&input.file_name()))
+                .unwrap_or_default())
+        })
+    }
+
+    fn fetch_comments_json<'a>(
+        &'a self,
+        input: &'a InputFile,
+    ) -> ProviderFuture<'a, Option<CommentsJson>> {
+        Box::pin(async move {
+            let (comments_json, _) = self.fetch_with_update_check(input).await?;
+            Ok(Some(comments_json))
+        })
+    }
+}
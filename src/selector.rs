@@ -0,0 +1,133 @@
+//! 匹配和选择字幕时的交互，CLI 在终端中使用 `TerminalSelector`，
+//! 没有终端（服务、图形界面调用）时使用 `NonInteractiveSelector`。
+//! `TerminalSelector` 需要 `interactive` 功能，关闭后不依赖 promkit
+use anyhow::{anyhow, Result};
+#[cfg(feature = "interactive")]
+use promkit::{
+    preset::{query_selector::QuerySelector, readline::Readline},
+    suggest::Suggest,
+};
+use std::{collections::VecDeque, sync::Mutex};
+
+use crate::dandan_match::search_filter;
+#[cfg(feature = "interactive")]
+use crate::util::prompt_lock;
+
+pub trait Selector: Send + Sync {
+    /// 从 `options` 中选择一项，返回选中项的下标
+    fn select(&self, title: &str, options: &[String]) -> Result<usize>;
+
+    /// 输入一行文字，`suggest` 为可以用 tab 补全的内容
+    fn input(&self, title: &str, suggest: &[String]) -> Result<String>;
+
+    /// 不能交互时，可以不选择的地方直接使用默认的选项
+    fn is_interactive(&self) -> bool {
+        true
+    }
+}
+
+/// 使用 promkit 在终端中提示
+#[cfg(feature = "interactive")]
+pub struct TerminalSelector;
+
+#[cfg(feature = "interactive")]
+impl Selector for TerminalSelector {
+    fn select(&self, title: &str, options: &[String]) -> Result<usize> {
+        let selected = {
+            let _prompt = prompt_lock();
+            let mut select_prompt = QuerySelector::new(options, |text, items| {
+                items
+                    .iter()
+                    .filter(|i| search_filter(text, i))
+                    .cloned()
+                    .collect()
+            })
+            .title(title)
+            .prompt()?;
+            select_prompt.run()?
+        };
+        options
+            .iter()
+            .position(|o| *o == selected)
+            .ok_or_else(|| anyhow!("Select option not found"))
+    }
+
+    fn input(&self, title: &str, suggest: &[String]) -> Result<String> {
+        let _prompt = prompt_lock();
+        let mut readline = Readline::default().title(title);
+        if !suggest.is_empty() {
+            readline = readline.enable_suggest(Suggest::from_iter(suggest));
+        }
+        let mut input_prompt = readline.enable_history().prompt()?;
+        input_prompt.run()
+    }
+}
+
+/// 不进行交互，按顺序使用预设的回答，没有回答时返回错误。
+/// 选择时回答与选项完全相同或按输入过滤的规则匹配，取第一个匹配的选项
+#[derive(Debug, Default)]
+pub struct NonInteractiveSelector {
+    answers: Mutex<VecDeque<String>>,
+}
+
+impl NonInteractiveSelector {
+    pub fn with_answers<T: Into<String>>(answers: impl IntoIterator<Item = T>) -> Self {
+        Self {
+            answers: Mutex::new(answers.into_iter().map(Into::into).collect()),
+        }
+    }
+
+    fn next_answer(&self, title: &str) -> Result<String> {
+        self.answers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .pop_front()
+            .ok_or_else(|| anyhow!("当前不是交互模式，无法回答 {}", title))
+    }
+}
+
+impl Selector for NonInteractiveSelector {
+    fn select(&self, title: &str, options: &[String]) -> Result<usize> {
+        let answer = self.next_answer(title)?;
+        options
+            .iter()
+            .position(|o| *o == answer)
+            .or_else(|| options.iter().position(|o| search_filter(&answer, o)))
+            .ok_or_else(|| anyhow!("{} 中没有与 {} 对应的选项", title, answer))
+    }
+
+    fn input(&self, title: &str, _suggest: &[String]) -> Result<String> {
+        self.next_answer(title)
+    }
+
+    /// 还有预设的回答时视为可以交互
+    fn is_interactive(&self) -> bool {
+        !self
+            .answers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_non_interactive_selector() {
+        let options = ["第1话 出发".to_string(), "第2话 魔法".to_string()];
+        let selector = NonInteractiveSelector::with_answers(["第2话 魔法", "第1话", "葬送"]);
+        assert_eq!(selector.select("剧集:", &options).unwrap(), 1);
+        assert_eq!(selector.select("剧集:", &options).unwrap(), 0);
+        assert!(selector.select("剧集:", &options).is_err());
+        assert!(selector.input("名字:", &[]).is_err());
+        assert!(!selector.is_interactive());
+
+        let selector = NonInteractiveSelector::default();
+        assert_eq!(
+            selector.select("剧集:", &options).unwrap_err().to_string(),
+            "当前不是交互模式，无法回答 剧集:"
+        );
+    }
+}
@@ -0,0 +1,302 @@
+//! 弹幕来源，`withRelated=true` 时第三方来源的用户 id 带有 `[BiliBili]` 等前缀
+use crate::Danmu;
+use std::collections::{BTreeMap, HashMap};
+
+/// 弹弹 play 自身的弹幕没有来源前缀
+pub const DANDANPLAY_SOURCE: &str = "DanDanPlay";
+
+/// 把 `[BiliBili]abc` 拆成来源和用户 id
+pub fn split_user_id(user_id: &str) -> (&str, &str) {
+    user_id
+        .strip_prefix('[')
+        .and_then(|s| s.split_once(']'))
+        .filter(|(source, _)| !source.is_empty())
+        .unwrap_or((DANDANPLAY_SOURCE, user_id))
+}
+
+/// 来源的简短标签
+pub fn source_tag(source: &str) -> &str {
+    match source.to_ascii_lowercase().as_str() {
+        "bilibili" => "B站",
+        "gamer" => "巴哈",
+        "acfun" => "A站",
+        "tucao" => "吐槽",
+        "dandanplay" => "弹弹",
+        _ => source,
+    }
+}
+
+/// 各来源的弹幕数量，如 `BiliBili 120, Gamer 30`
+pub fn source_breakdown(danmus: &[Danmu]) -> String {
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for danmu in danmus {
+        *counts.entry(danmu.source.as_str()).or_default() += 1;
+    }
+    counts
+        .iter()
+        .map(|(source, count)| format!("{} {}", source, count))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SourceOptions {
+    /// 不同来源的相同弹幕在这个时间窗口内只保留一条，单位为秒，0 表示不去重
+    pub dedupe_window: f64,
+    /// 在弹幕前加上来源标签
+    pub label: bool,
+    /// 只保留这些来源，为空表示全部保留，不区分大小写
+    pub include: Vec<String>,
+    /// 去掉这些来源，不区分大小写
+    pub exclude: Vec<String>,
+    /// 根据不同来源的相同弹幕估计各来源的时间偏移并修正
+    pub align: bool,
+    /// 修正时间偏移至少需要的锚点数量
+    pub align_min_anchors: usize,
+}
+
+/// 一个来源的时间偏移估计结果
+#[derive(Debug, Clone)]
+pub struct SourceAlignment {
+    pub source: String,
+    /// 用于估计的锚点（两个来源中都只出现一次的相同弹幕）数量
+    pub anchors: usize,
+    /// 锚点足够时的偏移，单位为秒，已经加到该来源的弹幕上
+    pub offset: Option<f64>,
+}
+
+/// 锚点的时间差超过这个值时认为是巧合，单位为秒
+const ALIGN_MAX_DELTA: f64 = 60.0;
+
+impl SourceOptions {
+    /// 去掉不同来源的重复弹幕，需要已经按时间排序，返回去掉的数量
+    pub fn dedupe(&self, danmus: &mut Vec<Danmu>) -> usize {
+        if self.dedupe_window <= 0.0 {
+            return 0;
+        }
+        let before = danmus.len();
+        // 内容 -> (时间, 来源)
+        let mut last_seen: HashMap<String, (f64, String)> = HashMap::new();
+        danmus.retain(|danmu| {
+            if let Some((t, source)) = last_seen.get(&danmu.content) {
+                if *source != danmu.source && danmu.timeline_s - t <= self.dedupe_window {
+                    return false;
+                }
+            }
+            last_seen.insert(
+                danmu.content.clone(),
+                (danmu.timeline_s, danmu.source.clone()),
+            );
+            true
+        });
+        before - danmus.len()
+    }
+
+    /// 按 include/exclude 过滤来源，返回每条规则去掉的数量
+    pub fn filter(&self, danmus: &mut Vec<Danmu>) -> BTreeMap<String, usize> {
+        let mut removed: BTreeMap<String, usize> = BTreeMap::new();
+        if self.include.is_empty() && self.exclude.is_empty() {
+            return removed;
+        }
+        danmus.retain(|danmu| {
+            if !self.include.is_empty()
+                && !self
+                    .include
+                    .iter()
+                    .any(|s| s.eq_ignore_ascii_case(&danmu.source))
+            {
+                *removed.entry("--sources".to_string()).or_default() += 1;
+                return false;
+            }
+            if let Some(s) = self
+                .exclude
+                .iter()
+                .find(|s| s.eq_ignore_ascii_case(&danmu.source))
+            {
+                *removed
+                    .entry(format!("--exclude-sources {}", s))
+                    .or_default() += 1;
+                return false;
+            }
+            true
+        });
+        removed
+    }
+
+    /// 以弹幕最多的来源为基准，用只出现一次的相同弹幕作为锚点，
+    /// 取时间差的中位数作为其他来源的偏移
+    pub fn align(&self, danmus: &mut [Danmu]) -> Vec<SourceAlignment> {
+        if !self.align {
+            return vec![];
+        }
+        // 来源 -> 内容 -> 出现的时间
+        let mut by_source: BTreeMap<&str, HashMap<&str, Vec<f64>>> = BTreeMap::new();
+        for danmu in danmus.iter() {
+            by_source
+                .entry(&danmu.source)
+                .or_default()
+                .entry(&danmu.content)
+                .or_default()
+                .push(danmu.timeline_s);
+        }
+        let Some(base) = by_source
+            .iter()
+            .max_by_key(|(_, contents)| contents.values().map(Vec::len).sum::<usize>())
+            .map(|(source, _)| *source)
+        else {
+            return vec![];
+        };
+
+        let mut alignments = Vec::new();
+        for (source, contents) in by_source.iter().filter(|(s, _)| **s != base) {
+            let mut deltas: Vec<f64> = contents
+                .iter()
+                .filter_map(
+                    |(content, times)| match (&times[..], by_source[base].get(content)) {
+                        ([t], Some(base_times)) if base_times.len() == 1 => Some(base_times[0] - t),
+                        _ => None,
+                    },
+                )
+                .filter(|d| d.abs() <= ALIGN_MAX_DELTA)
+                .collect();
+            deltas.sort_by(|a, b| a.total_cmp(b));
+            let offset =
+                (deltas.len() >= self.align_min_anchors.max(1)).then(|| deltas[deltas.len() / 2]);
+            alignments.push(SourceAlignment {
+                source: source.to_string(),
+                anchors: deltas.len(),
+                offset,
+            });
+        }
+
+        for alignment in &alignments {
+            if let Some(offset) = alignment.offset {
+                danmus
+                    .iter_mut()
+                    .filter(|d| d.source == alignment.source)
+                    .for_each(|d| d.timeline_s += offset);
+            }
+        }
+        alignments
+    }
+
+    pub fn apply_label(&self, danmu: &mut Danmu) {
+        if self.label {
+            danmu.content = format!("[{}]{}", source_tag(&danmu.source), danmu.content);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn danmu(t: f64, content: &str, source: &str) -> Danmu {
+        Danmu {
+            timeline_s: t,
+            content: content.to_string(),
+            source: source.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_split_user_id() {
+        assert_eq!(split_user_id("[BiliBili]abc"), ("BiliBili", "abc"));
+        assert_eq!(split_user_id("[Gamer]"), ("Gamer", ""));
+        assert_eq!(split_user_id("12345"), (DANDANPLAY_SOURCE, "12345"));
+        assert_eq!(split_user_id("[]abc"), (DANDANPLAY_SOURCE, "[]abc"));
+    }
+
+    #[test]
+    fn test_dedupe() {
+        let options = SourceOptions {
+            dedupe_window: 2.0,
+            ..Default::default()
+        };
+        let mut danmus = vec![
+            danmu(1.0, "草", "BiliBili"),
+            danmu(1.5, "草", "Gamer"),
+            danmu(1.6, "草", "BiliBili"),
+            danmu(5.0, "草", "Gamer"),
+        ];
+        assert_eq!(options.dedupe(&mut danmus), 1);
+        let kept: Vec<_> = danmus
+            .iter()
+            .map(|d| (d.timeline_s, d.source.as_str()))
+            .collect();
+        assert_eq!(kept, [(1.0, "BiliBili"), (1.6, "BiliBili"), (5.0, "Gamer")]);
+    }
+
+    #[test]
+    fn test_filter() {
+        let danmus = vec![
+            danmu(1.0, "a", "BiliBili"),
+            danmu(1.0, "b", "Gamer"),
+            danmu(1.0, "c", DANDANPLAY_SOURCE),
+            danmu(1.0, "d", "AcFun"),
+        ];
+
+        let options = SourceOptions {
+            include: vec!["bilibili".to_string(), "dandanplay".to_string()],
+            ..Default::default()
+        };
+        let mut kept = danmus.clone();
+        let removed = options.filter(&mut kept);
+        assert_eq!(kept.len(), 2);
+        assert_eq!(removed.get("--sources"), Some(&2));
+
+        let options = SourceOptions {
+            exclude: vec!["acfun".to_string()],
+            ..Default::default()
+        };
+        let mut kept = danmus.clone();
+        let removed = options.filter(&mut kept);
+        assert_eq!(kept.len(), 3);
+        assert_eq!(removed.get("--exclude-sources acfun"), Some(&1));
+    }
+
+    #[test]
+    fn test_align() {
+        let mut danmus = vec![
+            danmu(10.0, "a", "BiliBili"),
+            danmu(20.0, "b", "BiliBili"),
+            danmu(30.0, "c", "BiliBili"),
+            danmu(40.0, "d", "BiliBili"),
+            danmu(45.0, "e", "BiliBili"),
+            danmu(13.0, "a", "Gamer"),
+            danmu(23.0, "b", "Gamer"),
+            danmu(33.5, "c", "Gamer"),
+            danmu(50.0, "x", "Gamer"),
+            danmu(10.0, "a", "AcFun"),
+        ];
+        let options = SourceOptions {
+            align: true,
+            align_min_anchors: 3,
+            ..Default::default()
+        };
+        let alignments = options.align(&mut danmus);
+        assert_eq!(alignments.len(), 2);
+
+        let acfun = alignments.iter().find(|a| a.source == "AcFun").unwrap();
+        assert_eq!(acfun.anchors, 1);
+        assert_eq!(acfun.offset, None);
+
+        let gamer = alignments.iter().find(|a| a.source == "Gamer").unwrap();
+        assert_eq!(gamer.anchors, 3);
+        assert_eq!(gamer.offset, Some(-3.0));
+        assert_eq!(danmus[5].timeline_s, 10.0);
+        assert_eq!(danmus[8].timeline_s, 47.0);
+        assert_eq!(danmus[9].timeline_s, 10.0);
+    }
+
+    #[test]
+    fn test_source_breakdown() {
+        let danmus = [
+            danmu(1.0, "a", "Gamer"),
+            danmu(1.0, "b", "BiliBili"),
+            danmu(1.0, "c", "Gamer"),
+        ];
+        assert_eq!(source_breakdown(&danmus), "BiliBili 1, Gamer 2");
+    }
+}
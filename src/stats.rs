@@ -0,0 +1,119 @@
+//! 弹幕统计，每分钟的弹幕数量、各类型的数量和出现最多的弹幕
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::{Danmu, DanmuType};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DanmuStats {
+    pub total: usize,
+    /// 第 i 项为第 i 分钟（`[i * 60, (i + 1) * 60)` 秒）的弹幕数量
+    pub per_minute: Vec<usize>,
+    /// 弹幕最多的一分钟，数量相同时取最早的，没有弹幕时为 None
+    pub peak_minute: Option<usize>,
+    pub types: TypeCounts,
+    /// 按出现次数从多到少，次数相同时按内容排序
+    pub top_contents: Vec<ContentCount>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TypeCounts {
+    pub float: usize,
+    pub top: usize,
+    pub bottom: usize,
+    pub reverse: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ContentCount {
+    pub content: String,
+    pub count: usize,
+}
+
+impl DanmuStats {
+    /// `top` 为保留的出现最多的弹幕数量，内容去掉首尾空白后比较
+    pub fn from_danmus<'a>(danmus: impl IntoIterator<Item = &'a Danmu>, top: usize) -> Self {
+        let mut stats = DanmuStats::default();
+        let mut contents: HashMap<&str, usize> = HashMap::new();
+        for danmu in danmus {
+            stats.total += 1;
+            let minute = (danmu.timeline_s.max(0.0) / 60.0) as usize;
+            if stats.per_minute.len() <= minute {
+                stats.per_minute.resize(minute + 1, 0);
+            }
+            stats.per_minute[minute] += 1;
+            match danmu.r#type {
+                DanmuType::Float => stats.types.float += 1,
+                DanmuType::Top => stats.types.top += 1,
+                DanmuType::Bottom => stats.types.bottom += 1,
+                DanmuType::Reverse => stats.types.reverse += 1,
+            }
+            *contents.entry(danmu.content.trim()).or_default() += 1;
+        }
+
+        stats.peak_minute = stats
+            .per_minute
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .max_by(|(a_minute, a), (b_minute, b)| a.cmp(b).then(b_minute.cmp(a_minute)))
+            .map(|(minute, _)| minute);
+
+        let mut contents: Vec<_> = contents.into_iter().collect();
+        contents.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+        stats.top_contents = contents
+            .into_iter()
+            .take(top)
+            .map(|(content, count)| ContentCount {
+                content: content.to_string(),
+                count,
+            })
+            .collect();
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_danmus() {
+        let danmu = |timeline_s: f64, content: &str, r#type: DanmuType| Danmu {
+            timeline_s,
+            content: content.to_string(),
+            r#type,
+            ..Default::default()
+        };
+        let danmus = [
+            danmu(1.0, "草", DanmuType::Float),
+            danmu(59.9, "草 ", DanmuType::Float),
+            danmu(130.0, "好耶", DanmuType::Top),
+            danmu(150.0, "好耶", DanmuType::Bottom),
+            danmu(170.0, "来了", DanmuType::Float),
+        ];
+        let stats = DanmuStats::from_danmus(&danmus, 2);
+        assert_eq!(stats.total, 5);
+        assert_eq!(stats.per_minute, [2, 0, 3]);
+        assert_eq!(stats.peak_minute, Some(2));
+        assert_eq!(
+            stats.types,
+            TypeCounts {
+                float: 3,
+                top: 1,
+                bottom: 1,
+                reverse: 0
+            }
+        );
+        let top: Vec<_> = stats
+            .top_contents
+            .iter()
+            .map(|c| (c.content.as_str(), c.count))
+            .collect();
+        assert_eq!(top, [("好耶", 2), ("草", 2)]);
+
+        let stats = DanmuStats::from_danmus(&[], 20);
+        assert_eq!(stats.peak_minute, None);
+        assert!(stats.per_minute.is_empty());
+    }
+}
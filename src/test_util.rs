@@ -0,0 +1,71 @@
+//! 快照测试的工具，开启 `test-util` 功能后对外提供，fork 可以用同样的方式测试自己的修改。
+//! 设置环境变量 `UPDATE_SNAPSHOTS=1` 运行测试时写入新的快照，而不是比较
+use anyhow::Result;
+use std::{fs, path::Path};
+
+use crate::{CanvasConfig, CommentsJson, ConvertOptions, Dandan};
+
+/// 按默认的画布参数将 dandanplay 格式的 json 转换成 ASS
+pub fn json_to_ass(json: &str, title: &str) -> Result<String> {
+    let comments: CommentsJson = serde_json::from_str(json)?;
+    let mut ass = Vec::new();
+    Dandan::convert(
+        comments,
+        &ConvertOptions::new(title, CanvasConfig::default()),
+        &mut ass,
+    )?;
+    Ok(String::from_utf8(ass)?)
+}
+
+/// 与 `path` 中的快照比较，不同时 panic 并显示第一处不同的行
+pub fn assert_snapshot(path: impl AsRef<Path>, actual: &str) {
+    let path = path.as_ref();
+    if std::env::var_os("UPDATE_SNAPSHOTS").is_some_and(|v| !v.is_empty() && v != "0") {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        fs::write(path, actual)
+            .unwrap_or_else(|e| panic!("快照 {} 写入失败：{}", path.display(), e));
+        return;
+    }
+
+    let expected = fs::read_to_string(path).unwrap_or_else(|e| {
+        panic!(
+            "快照 {} 读取失败：{}，可以设置 UPDATE_SNAPSHOTS=1 生成",
+            path.display(),
+            e
+        )
+    });
+    if expected == actual {
+        return;
+    }
+    let line = expected
+        .lines()
+        .zip(actual.lines())
+        .position(|(e, a)| e != a)
+        .unwrap_or_else(|| expected.lines().count().min(actual.lines().count()));
+    panic!(
+        "与快照 {} 不同，第 {} 行\n预期：{}\n实际：{}\n确认修改无误后设置 UPDATE_SNAPSHOTS=1 更新快照",
+        path.display(),
+        line + 1,
+        expected.lines().nth(line).unwrap_or("<结束>"),
+        actual.lines().nth(line).unwrap_or("<结束>"),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assert_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.ass");
+        fs::write(&path, "a\nb\n").unwrap();
+        assert_snapshot(&path, "a\nb\n");
+
+        let e = std::panic::catch_unwind(|| assert_snapshot(&path, "a\nc\n")).unwrap_err();
+        let message = e.downcast_ref::<String>().unwrap();
+        assert!(message.contains("第 2 行"), "{}", message);
+    }
+}
@@ -1,10 +1,541 @@
-use std::path::PathBuf;
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{Command, Output},
+    sync::RwLock,
+};
 
-use promkit::crossterm::style::Stylize;
+use crate::Error;
+
+const VIDEO_EXTS: [&str; 9] = [
+    "mp4", "mov", "wmv", "avi", "flv", "f4v", "swf", "mkv", "webm",
+];
+
+/// 扩展名是否为支持的视频格式，不区分大小写，不检查文件是否存在
+pub fn has_video_ext(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| VIDEO_EXTS.iter().any(|v| e.eq_ignore_ascii_case(v)))
+}
+
+/// 视频旁边的同名文件，只去掉已知的视频扩展名（不区分大小写）再加上 `ext`。
+/// `Show.S01E01.v2` 为 `Show.S01E01.v2.ass`，不会与 `Show.S01E01.mkv` 的 `Show.S01E01.ass` 冲突
+pub fn sibling_path(path: &Path, ext: &str) -> PathBuf {
+    let mut name = match has_video_ext(path) {
+        true => path.with_extension(""),
+        false => path.to_path_buf(),
+    }
+    .into_os_string();
+    name.push(".");
+    name.push(ext);
+    PathBuf::from(name)
+}
+
+#[cfg(feature = "network")]
+/// 同目录下 `path` 所属的视频，例如 `ep01.ass`、`ep01.dandanplay.json` 对应的 `ep01.mkv`
+pub fn video_of(path: &Path, is_of: impl Fn(&Path) -> bool) -> Option<PathBuf> {
+    path.parent()?
+        .read_dir()
+        .ok()?
+        .filter_map(|f| f.ok())
+        .map(|f| f.path())
+        .filter(|f| has_video_ext(f) && f.is_file())
+        .find(|video| is_of(video))
+}
+
+#[cfg(feature = "network")]
+/// 秒数显示为 `h:mm:ss`，负数按 0 显示
+pub fn time_label(t: f64) -> String {
+    let secs = t.max(0.0) as u64;
+    format!("{}:{:02}:{:02}", secs / 3600, secs % 3600 / 60, secs % 60)
+}
+
+/// 路径中的文件名，没有文件名时为空
+pub fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
 
 pub fn display_filename(path: &PathBuf) -> String {
     match path.file_name() {
-        Some(name) => name.to_string_lossy().underlined().to_string(),
+        Some(name) => underlined(&name.to_string_lossy()),
         None => path.display().to_string(),
     }
 }
+
+/// 终端中显示为带下划线的文字，没有 `interactive` 功能时不带样式
+pub fn underlined(s: &str) -> String {
+    #[cfg(feature = "interactive")]
+    {
+        use promkit::crossterm::style::Stylize;
+        s.underlined().to_string()
+    }
+    #[cfg(not(feature = "interactive"))]
+    {
+        s.to_string()
+    }
+}
+
+static FFMPEG_PATH: RwLock<Option<PathBuf>> = RwLock::new(None);
+static FFPROBE_PATH: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+/// 指定 ffmpeg、ffprobe 的路径，为 None 时使用环境变量 `FFMPEG`、`FFPROBE`，都没有时使用 PATH 中的程序。
+/// 对整个进程生效，只在程序启动时调用一次
+pub fn set_ffmpeg_paths(ffmpeg: Option<PathBuf>, ffprobe: Option<PathBuf>) {
+    *FFMPEG_PATH.write().unwrap_or_else(|e| e.into_inner()) = ffmpeg;
+    *FFPROBE_PATH.write().unwrap_or_else(|e| e.into_inner()) = ffprobe;
+}
+
+pub fn ffmpeg_command() -> Command {
+    ffmpeg_command_with(
+        FFMPEG_PATH
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone(),
+    )
+}
+
+pub fn ffprobe_command() -> Command {
+    ffprobe_command_with(
+        FFPROBE_PATH
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone(),
+    )
+}
+
+/// 使用 `path` 而不是 [`set_ffmpeg_paths`] 设置的路径
+pub fn ffmpeg_command_with(path: Option<PathBuf>) -> Command {
+    Command::new(program_path(path, "FFMPEG", "ffmpeg"))
+}
+
+/// 使用 `path` 而不是 [`set_ffmpeg_paths`] 设置的路径
+pub fn ffprobe_command_with(path: Option<PathBuf>) -> Command {
+    Command::new(program_path(path, "FFPROBE", "ffprobe"))
+}
+
+fn program_path(path: Option<PathBuf>, env: &str, program: &str) -> PathBuf {
+    path.or_else(|| {
+        std::env::var_os(env)
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+    })
+    .unwrap_or_else(|| PathBuf::from(program))
+}
+
+#[cfg(feature = "network")]
+/// 使用 ffprobe 读取视频容器的时长，单位为秒
+pub fn video_duration(video: &Path) -> anyhow::Result<f64> {
+    let output = command_output(
+        ffprobe_command()
+            .args(["-v", "error", "-show_entries", "format=duration"])
+            .args(["-of", "csv=p=0"])
+            .arg(video),
+    )?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "ffprobe 读取视频时长失败：{}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout
+        .trim()
+        .parse()
+        .map_err(|_| anyhow::anyhow!("ffprobe 输出的视频时长无法解析：{}", stdout.trim()))
+}
+
+#[cfg(feature = "network")]
+/// 使用 ffprobe 读取第一个视频流的分辨率，按 SAR 换算为显示时的宽度
+pub fn video_resolution(video: &Path) -> anyhow::Result<(u32, u32)> {
+    let output = command_output(
+        ffprobe_command()
+            .args(["-v", "error", "-select_streams", "v:0"])
+            .args(["-show_entries", "stream=width,height,sample_aspect_ratio"])
+            .args(["-of", "csv=p=0"])
+            .arg(video),
+    )?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "ffprobe 读取视频分辨率失败：{}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    parse_resolution(&stdout)
+        .ok_or_else(|| anyhow::anyhow!("ffprobe 输出的视频分辨率无法解析：{}", stdout.trim()))
+}
+
+#[cfg(feature = "network")]
+/// 解析 `1440,1080,4:3`，SAR 为 `N/A`、`0:1` 时按 1:1 处理
+fn parse_resolution(csv: &str) -> Option<(u32, u32)> {
+    let mut fields = csv.trim().split(',');
+    let width: u32 = fields.next()?.trim().parse().ok()?;
+    let height: u32 = fields.next()?.trim().parse().ok()?;
+    if width == 0 || height == 0 {
+        return None;
+    }
+    let sar = fields
+        .next()
+        .and_then(|sar| sar.trim().split_once(':'))
+        .and_then(|(num, den)| Some((num.parse::<u32>().ok()?, den.parse::<u32>().ok()?)))
+        .filter(|&(num, den)| num > 0 && den > 0);
+    match sar {
+        Some((num, den)) => Some(((width as u64 * num as u64 / den as u64) as u32, height)),
+        None => Some((width, height)),
+    }
+}
+
+#[cfg(feature = "network")]
+/// 使用 ffprobe 读取视频的章节，没有章节时为空
+pub fn video_chapters(video: &Path) -> anyhow::Result<Vec<crate::Chapter>> {
+    let output = command_output(
+        ffprobe_command()
+            .args(["-v", "error", "-show_chapters", "-of", "json"])
+            .arg(video),
+    )?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "ffprobe 读取视频章节失败：{}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    crate::chapter::parse_chapters(&String::from_utf8_lossy(&output.stdout))
+        .map_err(|e| anyhow::anyhow!("ffprobe 输出的视频章节无法解析：{}", e))
+}
+
+/// 运行 ffmpeg、ffprobe 并等待输出，找不到程序时返回 `Error::FfmpegMissing`
+pub fn command_output(command: &mut Command) -> anyhow::Result<Output> {
+    command.output().map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => {
+            Error::FfmpegMissing(command.get_program().to_string_lossy().to_string()).into()
+        }
+        _ => e.into(),
+    })
+}
+
+/// 异步运行 ffmpeg、ffprobe，超过 `timeout` 时结束进程并返回错误
+#[cfg(feature = "network")]
+pub async fn command_output_timeout(
+    command: Command,
+    timeout: std::time::Duration,
+) -> anyhow::Result<Output> {
+    let program = command.get_program().to_string_lossy().to_string();
+    let mut command = tokio::process::Command::from(command);
+    command
+        .kill_on_drop(true)
+        .stdin(std::process::Stdio::null());
+    match tokio::time::timeout(timeout, command.output()).await {
+        Ok(Ok(output)) => Ok(output),
+        Ok(Err(e)) if e.kind() == io::ErrorKind::NotFound => {
+            Err(Error::FfmpegMissing(program).into())
+        }
+        Ok(Err(e)) => Err(e.into()),
+        Err(_) => Err(anyhow::anyhow!(
+            "{} 运行超过 {} 秒，已停止，视频可能已损坏",
+            program,
+            timeout.as_secs_f64()
+        )),
+    }
+}
+
+/// stderr 的最后几行，用于错误信息
+#[cfg(feature = "network")]
+pub fn stderr_tail(stderr: &[u8]) -> String {
+    const LINES: usize = 5;
+    let stderr = String::from_utf8_lossy(stderr);
+    let lines: Vec<_> = stderr.trim().lines().collect();
+    lines[lines.len().saturating_sub(LINES)..].join("\n")
+}
+
+/// 文件的修改时间，unix 时间戳，单位为毫秒，用于判断视频是否变化
+#[cfg(feature = "network")]
+pub fn modified_ms(metadata: &fs::Metadata) -> io::Result<u64> {
+    Ok(metadata
+        .modified()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default())
+}
+
+/// 先写入同目录下的临时文件，写入磁盘后再重命名，中断或断电时不会留下不完整的文件
+pub fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let name = file_name(path);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = format!(".{}.", name);
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(&prefix).suffix(".tmp");
+    // 临时文件默认只有所有者可以读写，改为与 File::create 一样由 umask 决定，
+    // 媒体服务器等其他用户也能读取字幕
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        builder.permissions(fs::Permissions::from_mode(0o666));
+    }
+    // 失败时 NamedTempFile 在 drop 时删除临时文件
+    let mut file = builder.tempfile_in(dir)?;
+    file.write_all(contents.as_ref())?;
+    file.as_file().sync_all()?;
+    file.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// 提示期间暂存的日志，None 表示当前没有提示
+#[cfg(feature = "interactive")]
+static HELD_LOGS: std::sync::Mutex<Option<Vec<(log::Level, String, String)>>> =
+    std::sync::Mutex::new(None);
+
+/// 持有期间其他任务的日志由 [`PromptAwareLogger`] 暂存，结束时再输出
+#[cfg(feature = "interactive")]
+pub struct PromptGuard {
+    _lock: std::sync::MutexGuard<'static, ()>,
+}
+
+#[cfg(feature = "interactive")]
+impl Drop for PromptGuard {
+    fn drop(&mut self) {
+        let held = HELD_LOGS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+            .unwrap_or_default();
+        for (level, target, message) in held {
+            log!(target: &target, level, "{}", message);
+        }
+    }
+}
+
+/// 交互式提示同时只能有一个，提前获取弹幕时避免多个提示争抢终端
+#[cfg(feature = "interactive")]
+pub fn prompt_lock() -> PromptGuard {
+    static PROMPT_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    let lock = PROMPT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    *HELD_LOGS.lock().unwrap_or_else(|e| e.into_inner()) = Some(vec![]);
+    PromptGuard { _lock: lock }
+}
+
+/// 包装终端的 logger，交互式提示期间暂存后台任务的日志，避免打乱提示
+#[cfg(feature = "interactive")]
+pub struct PromptAwareLogger {
+    inner: Box<dyn log::Log>,
+}
+
+#[cfg(feature = "interactive")]
+impl PromptAwareLogger {
+    pub fn new(inner: Box<dyn log::Log>) -> Self {
+        Self { inner }
+    }
+}
+
+#[cfg(feature = "interactive")]
+impl log::Log for PromptAwareLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if let Some(held) = HELD_LOGS.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+            held.push((
+                record.level(),
+                record.target().to_string(),
+                record.args().to_string(),
+            ));
+            return;
+        }
+        self.inner.log(record);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_has_video_ext() {
+        assert!(has_video_ext(Path::new("/a/EPISODE.MKV")));
+        assert!(has_video_ext(Path::new("movie.Mp4")));
+        assert!(!has_video_ext(Path::new("ep01.mkv.part")));
+        assert!(!has_video_ext(Path::new("mkv")));
+        assert!(!has_video_ext(Path::new("ep01")));
+    }
+
+    #[cfg(feature = "network")]
+    #[test]
+    fn test_video_of() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        for name in ["ep01.mkv", "ep01.dandanplay.json", "ep02.dandanplay.json"] {
+            fs::write(dir.path().join(name), "")?;
+        }
+        let cache_video =
+            |cache: &Path| video_of(cache, |v| sibling_path(v, "dandanplay.json") == cache);
+        assert_eq!(
+            cache_video(&dir.path().join("ep01.dandanplay.json")),
+            Some(dir.path().join("ep01.mkv"))
+        );
+        assert_eq!(cache_video(&dir.path().join("ep02.dandanplay.json")), None);
+        Ok(())
+    }
+
+    #[cfg(feature = "network")]
+    #[test]
+    fn test_time_label() {
+        assert_eq!(time_label(0.0), "0:00:00");
+        assert_eq!(time_label(3725.9), "1:02:05");
+        assert_eq!(time_label(-5.0), "0:00:00");
+    }
+
+    #[test]
+    fn test_sibling_path() {
+        let sibling = |p: &str, ext: &str| sibling_path(Path::new(p), ext);
+        assert_eq!(
+            sibling("/a/Show.S01E01.1080p.mkv", "ass"),
+            PathBuf::from("/a/Show.S01E01.1080p.ass")
+        );
+        assert_eq!(
+            sibling("/a/Show.S01E01.v2", "ass"),
+            PathBuf::from("/a/Show.S01E01.v2.ass")
+        );
+        assert_eq!(
+            sibling("/a/EP01.MKV", "dandanplay.json"),
+            PathBuf::from("/a/EP01.dandanplay.json")
+        );
+        assert_eq!(sibling("/a/ep01", "ass"), PathBuf::from("/a/ep01.ass"));
+        assert_eq!(
+            sibling("ep01.webm", "danmu.ass"),
+            PathBuf::from("ep01.danmu.ass")
+        );
+    }
+
+    #[test]
+    fn test_command_output_missing() {
+        let e = command_output(&mut Command::new("ddp2ass-not-exists")).unwrap_err();
+        assert!(matches!(
+            Error::from(e),
+            Error::FfmpegMissing(program) if program == "ddp2ass-not-exists"
+        ));
+    }
+
+    #[cfg(all(unix, feature = "network"))]
+    #[tokio::test]
+    async fn test_command_output_timeout() -> anyhow::Result<()> {
+        use std::time::Duration;
+        let script = |s: &str| {
+            let mut command = Command::new("sh");
+            command.args(["-c", s]);
+            command
+        };
+        let output = command_output_timeout(
+            script("echo a >&2; echo b >&2; echo ok; exit 1"),
+            Duration::from_secs(10),
+        )
+        .await?;
+        assert!(!output.status.success());
+        assert_eq!(output.stdout, b"ok\n");
+        assert_eq!(stderr_tail(&output.stderr), "a\nb");
+
+        let e = command_output_timeout(script("sleep 10"), Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("运行超过 0.1 秒"));
+        let e = command_output_timeout(Command::new("ddp2ass-not-exists"), Duration::from_secs(10))
+            .await
+            .unwrap_err();
+        assert!(matches!(Error::from(e), Error::FfmpegMissing(_)));
+        Ok(())
+    }
+
+    #[cfg(feature = "network")]
+    #[test]
+    fn test_parse_resolution() {
+        assert_eq!(parse_resolution("1920,1080,1:1\n"), Some((1920, 1080)));
+        assert_eq!(parse_resolution("1440,1080,4:3"), Some((1920, 1080)));
+        assert_eq!(parse_resolution("1280,720,N/A"), Some((1280, 720)));
+        assert_eq!(parse_resolution("1280,720,0:1"), Some((1280, 720)));
+        assert_eq!(parse_resolution("1280,720"), Some((1280, 720)));
+        assert_eq!(parse_resolution(""), None);
+        assert_eq!(parse_resolution("0,0,N/A"), None);
+    }
+
+    #[test]
+    fn test_program_path() {
+        assert_eq!(
+            program_path(None, "DDP2ASS_NOT_EXISTS", "ffmpeg"),
+            PathBuf::from("ffmpeg")
+        );
+        let path = Some(PathBuf::from(r"C:\ffmpeg\bin\ffmpeg.exe"));
+        assert_eq!(
+            program_path(path, "DDP2ASS_NOT_EXISTS", "ffmpeg"),
+            PathBuf::from(r"C:\ffmpeg\bin\ffmpeg.exe")
+        );
+    }
+
+    #[test]
+    fn test_write_atomic() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("ep01.ass");
+        fs::write(&path, "old")?;
+        write_atomic(&path, "new".as_bytes())?;
+        assert_eq!(fs::read_to_string(&path)?, "new");
+        assert_eq!(fs::read_dir(dir.path())?.count(), 1);
+
+        assert!(write_atomic(&dir.path().join("missing/ep01.ass"), b"").is_err());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let created = dir.path().join("created.ass");
+            fs::write(&created, "")?;
+            let mode = |path: &Path| -> anyhow::Result<u32> {
+                Ok(fs::metadata(path)?.permissions().mode() & 0o777)
+            };
+            assert_eq!(mode(&path)?, mode(&created)?);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "interactive")]
+    #[test]
+    fn test_prompt_aware_logger() {
+        struct Capture(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+        impl log::Log for Capture {
+            fn enabled(&self, _: &log::Metadata) -> bool {
+                true
+            }
+            fn log(&self, record: &log::Record) {
+                self.0.lock().unwrap().push(record.args().to_string());
+            }
+            fn flush(&self) {}
+        }
+
+        let captured = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let logger = PromptAwareLogger::new(Box::new(Capture(captured.clone())));
+        let log = |message: &str| {
+            log::Log::log(
+                &logger,
+                &log::Record::builder()
+                    .args(format_args!("{}", message))
+                    .build(),
+            )
+        };
+        {
+            let _prompt = prompt_lock();
+            log("后台任务");
+            assert!(captured.lock().unwrap().is_empty());
+            assert_eq!(HELD_LOGS.lock().unwrap().as_ref().map(Vec::len), Some(1));
+        }
+        assert!(HELD_LOGS.lock().unwrap().is_none());
+        log("提示结束");
+        assert_eq!(*captured.lock().unwrap(), ["提示结束"]);
+    }
+}
@@ -0,0 +1,24 @@
+//! `tests/fixtures` 中每个 json 转换后与 `tests/snapshots` 中同名的 ASS 比较，
+//! 有意修改输出后运行 `UPDATE_SNAPSHOTS=1 cargo test --test golden` 更新快照
+use std::{fs, path::Path};
+
+use ddp2ass::test_util::{assert_snapshot, json_to_ass};
+
+#[test]
+fn test_fixtures() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let mut fixtures: Vec<_> = fs::read_dir(root.join("fixtures"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    fixtures.sort();
+    assert!(!fixtures.is_empty());
+
+    for fixture in fixtures {
+        let name = fixture.file_stem().unwrap().to_str().unwrap();
+        let json = fs::read_to_string(&fixture).unwrap();
+        let ass = json_to_ass(&json, "test").unwrap_or_else(|e| panic!("{}: {:#}", name, e));
+        assert_snapshot(root.join("snapshots").join(format!("{}.ass", name)), &ass);
+    }
+}